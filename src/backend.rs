@@ -0,0 +1,1042 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    convert::TryFrom,
+    io,
+    os::raw::c_int,
+};
+
+use either::Either;
+use uuid::Uuid;
+
+use crate::{
+    activate::{CryptActivateFlags, CryptDeactivateFlags},
+    device::CryptDevice,
+    err::LibcryptErr,
+    format::EncryptionFormat,
+    keyslot::{KeyslotInfo, KeyslotPriority, LUKS2_KEYSLOTS_MAX},
+    luks2_token::{token_keyslots, CryptTokenInfo, LUKS2_KEYRING_TOKEN_TYPE, LUKS2_TOKENS_MAX},
+    status::CryptStatusInfo,
+    Bool,
+};
+
+/// Operations on a LUKS2 device that application logic such as key rotation and
+/// enrollment is typically built on
+///
+/// `CryptDevice` implements this trait by calling into libcryptsetup. `SimulatedBackend`
+/// implements it in memory so that code generic over the backend can be unit tested
+/// without root privileges or block devices. Errors are reported the same way by both
+/// implementations: as `LibcryptErr::IOError` wrapping the errno that libcryptsetup
+/// would have returned. The token helpers of this crate, such as `CryptSystemdTokens`, are
+/// generic over this trait.
+pub trait LibcryptBackend {
+    /// Format the device as LUKS2 with a newly generated volume key
+    fn format_luks2(
+        &mut self,
+        cipher_and_mode: (&str, &str),
+        volume_key_size: usize,
+    ) -> Result<(), LibcryptErr>;
+
+    /// Load the LUKS2 header from the device
+    fn load(&mut self) -> Result<(), LibcryptErr>;
+
+    /// Get the UUID of the device
+    fn get_uuid(&mut self) -> Result<Uuid, LibcryptErr>;
+
+    /// Add a keyslot using an existing passphrase to unlock the volume key
+    fn keyslot_add_by_passphrase(
+        &mut self,
+        keyslot: Option<c_int>,
+        passphrase: &[u8],
+        new_passphrase: &[u8],
+    ) -> Result<c_int, LibcryptErr>;
+
+    /// Change the passphrase of a keyslot, optionally moving it to another keyslot
+    fn keyslot_change_by_passphrase(
+        &mut self,
+        keyslot_old: Option<c_int>,
+        keyslot_new: Option<c_int>,
+        passphrase: &[u8],
+        new_passphrase: &[u8],
+    ) -> Result<c_int, LibcryptErr>;
+
+    /// Destroy a keyslot
+    fn keyslot_destroy(&mut self, keyslot: c_int) -> Result<(), LibcryptErr>;
+
+    /// Get the status of a keyslot
+    fn keyslot_status(&mut self, keyslot: c_int) -> Result<KeyslotInfo, LibcryptErr>;
+
+    /// Get the priority of a keyslot
+    fn keyslot_get_priority(&mut self, keyslot: c_int) -> Result<KeyslotPriority, LibcryptErr>;
+
+    /// Set the priority of a keyslot
+    fn keyslot_set_priority(
+        &mut self,
+        keyslot: c_int,
+        priority: KeyslotPriority,
+    ) -> Result<(), LibcryptErr>;
+
+    /// Get the JSON contents of a token
+    fn token_json_get(&mut self, token: c_int) -> Result<serde_json::Value, LibcryptErr>;
+
+    /// Set the JSON contents of a token, allocating a new token if `token` is `None`
+    fn token_json_set(
+        &mut self,
+        token: Option<c_int>,
+        json: &serde_json::Value,
+    ) -> Result<c_int, LibcryptErr>;
+
+    /// Remove a token from the header
+    fn token_remove(&mut self, token: c_int) -> Result<(), LibcryptErr>;
+
+    /// Assign a token to a keyslot
+    fn token_assign_keyslot(&mut self, token: c_int, keyslot: c_int) -> Result<(), LibcryptErr>;
+
+    /// Unassign a token from a keyslot
+    fn token_unassign_keyslot(&mut self, token: c_int, keyslot: c_int) -> Result<(), LibcryptErr>;
+
+    /// Check whether a token is assigned to a keyslot
+    fn token_is_assigned(&mut self, token: c_int, keyslot: c_int) -> Result<Bool, LibcryptErr>;
+
+    /// Get the status and type of a token
+    fn token_status(&mut self, token: c_int) -> Result<(CryptTokenInfo, String), LibcryptErr>;
+
+    /// Activate the device by passphrase, returning the keyslot that was opened
+    ///
+    /// A value of `None` for the name will only check the passphrase.
+    fn activate_by_passphrase(
+        &mut self,
+        name: Option<&str>,
+        keyslot: Option<c_int>,
+        passphrase: &[u8],
+        flags: CryptActivateFlags,
+    ) -> Result<c_int, LibcryptErr>;
+
+    /// Deactivate a mapping by name
+    fn deactivate(&mut self, name: &str, flags: CryptDeactivateFlags) -> Result<(), LibcryptErr>;
+
+    /// Get the activation status of a mapping by name
+    fn status(&mut self, name: &str) -> Result<CryptStatusInfo, LibcryptErr>;
+}
+
+impl LibcryptBackend for CryptDevice {
+    fn format_luks2(
+        &mut self,
+        cipher_and_mode: (&str, &str),
+        volume_key_size: usize,
+    ) -> Result<(), LibcryptErr> {
+        self.context_handle()
+            .format::<()>(
+                EncryptionFormat::Luks2,
+                cipher_and_mode,
+                None,
+                Either::Right(volume_key_size),
+                None,
+            )
+            .map(|_| ())
+    }
+
+    fn load(&mut self) -> Result<(), LibcryptErr> {
+        self.context_handle()
+            .load::<()>(EncryptionFormat::Luks2, None)
+            .map(|_| ())
+    }
+
+    fn get_uuid(&mut self) -> Result<Uuid, LibcryptErr> {
+        self.status_handle().get_uuid()
+    }
+
+    fn keyslot_add_by_passphrase(
+        &mut self,
+        keyslot: Option<c_int>,
+        passphrase: &[u8],
+        new_passphrase: &[u8],
+    ) -> Result<c_int, LibcryptErr> {
+        self.keyslot_handle(keyslot)
+            .add_by_passphrase(passphrase, new_passphrase)
+    }
+
+    fn keyslot_change_by_passphrase(
+        &mut self,
+        keyslot_old: Option<c_int>,
+        keyslot_new: Option<c_int>,
+        passphrase: &[u8],
+        new_passphrase: &[u8],
+    ) -> Result<c_int, LibcryptErr> {
+        self.keyslot_handle(None).change_by_passphrase(
+            keyslot_old.unwrap_or(libcryptsetup_rs_sys::CRYPT_ANY_SLOT),
+            keyslot_new.unwrap_or(libcryptsetup_rs_sys::CRYPT_ANY_SLOT),
+            passphrase,
+            new_passphrase,
+        )
+    }
+
+    fn keyslot_destroy(&mut self, keyslot: c_int) -> Result<(), LibcryptErr> {
+        self.keyslot_handle(Some(keyslot)).destroy()
+    }
+
+    fn keyslot_status(&mut self, keyslot: c_int) -> Result<KeyslotInfo, LibcryptErr> {
+        self.keyslot_handle(Some(keyslot)).status()
+    }
+
+    fn keyslot_get_priority(&mut self, keyslot: c_int) -> Result<KeyslotPriority, LibcryptErr> {
+        self.keyslot_handle(Some(keyslot)).get_priority()
+    }
+
+    fn keyslot_set_priority(
+        &mut self,
+        keyslot: c_int,
+        priority: KeyslotPriority,
+    ) -> Result<(), LibcryptErr> {
+        self.keyslot_handle(Some(keyslot)).set_priority(priority)
+    }
+
+    fn token_json_get(&mut self, token: c_int) -> Result<serde_json::Value, LibcryptErr> {
+        self.token_handle(token).json_get()
+    }
+
+    fn token_json_set(
+        &mut self,
+        token: Option<c_int>,
+        json: &serde_json::Value,
+    ) -> Result<c_int, LibcryptErr> {
+        match token {
+            Some(t) => self.token_handle(t).json_set(json, false),
+            None => self
+                .token_handle(libcryptsetup_rs_sys::CRYPT_ANY_TOKEN)
+                .json_set(json, true),
+        }
+    }
+
+    fn token_remove(&mut self, token: c_int) -> Result<(), LibcryptErr> {
+        self.token_handle(token).remove()
+    }
+
+    fn token_assign_keyslot(&mut self, token: c_int, keyslot: c_int) -> Result<(), LibcryptErr> {
+        self.token_handle(token).assign_keyslot(keyslot)
+    }
+
+    fn token_unassign_keyslot(&mut self, token: c_int, keyslot: c_int) -> Result<(), LibcryptErr> {
+        self.token_handle(token).unassign_keyslot(keyslot)
+    }
+
+    fn token_is_assigned(&mut self, token: c_int, keyslot: c_int) -> Result<Bool, LibcryptErr> {
+        self.token_handle(token).is_assigned(keyslot)
+    }
+
+    fn token_status(&mut self, token: c_int) -> Result<(CryptTokenInfo, String), LibcryptErr> {
+        self.token_handle(token).status()
+    }
+
+    fn activate_by_passphrase(
+        &mut self,
+        name: Option<&str>,
+        keyslot: Option<c_int>,
+        passphrase: &[u8],
+        flags: CryptActivateFlags,
+    ) -> Result<c_int, LibcryptErr> {
+        self.activate_handle()
+            .activate_by_passphrase(name, keyslot, passphrase, flags)
+    }
+
+    fn deactivate(&mut self, name: &str, flags: CryptDeactivateFlags) -> Result<(), LibcryptErr> {
+        self.activate_handle().deactivate(name, flags)
+    }
+
+    fn status(&mut self, name: &str) -> Result<CryptStatusInfo, LibcryptErr> {
+        self.status_handle().status(name)
+    }
+}
+
+/// Operations of `LibcryptBackend` for which a failure can be injected into
+/// `SimulatedBackend`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SimulatedOperation {
+    /// `LibcryptBackend::format_luks2`
+    Format,
+    /// `LibcryptBackend::load`
+    Load,
+    /// `LibcryptBackend::keyslot_add_by_passphrase`
+    KeyslotAdd,
+    /// `LibcryptBackend::keyslot_change_by_passphrase`
+    KeyslotChange,
+    /// `LibcryptBackend::keyslot_destroy`
+    KeyslotDestroy,
+    /// `LibcryptBackend::keyslot_set_priority`
+    KeyslotSetPriority,
+    /// `LibcryptBackend::token_json_set`
+    TokenSet,
+    /// `LibcryptBackend::token_remove`
+    TokenRemove,
+    /// `LibcryptBackend::token_assign_keyslot` and `LibcryptBackend::token_unassign_keyslot`
+    TokenAssign,
+    /// `LibcryptBackend::activate_by_passphrase`
+    Activate,
+    /// `LibcryptBackend::deactivate`
+    Deactivate,
+}
+
+struct SimulatedKeyslot {
+    passphrase: Vec<u8>,
+    priority: KeyslotPriority,
+}
+
+struct SimulatedHeader {
+    uuid: Uuid,
+    cipher: String,
+    cipher_mode: String,
+    volume_key_size: usize,
+    keyslots: BTreeMap<c_int, SimulatedKeyslot>,
+    tokens: BTreeMap<c_int, serde_json::Value>,
+}
+
+/// In-memory implementation of `LibcryptBackend`
+///
+/// The simulator models a single LUKS2 device: the header that has been written to
+/// it, whether that header has been loaded into the context, its keyslots with their
+/// passphrases and priorities, its tokens with their keyslot assignments, and the
+/// mappings that have been activated from it. Failures are reported with the same
+/// errno values libcryptsetup uses, for example `EPERM` for a wrong passphrase,
+/// `EEXIST` for an already active mapping and `ENOENT` for a missing token.
+/// Additional failures can be injected with `SimulatedBackend::fail_next`.
+pub struct SimulatedBackend {
+    header: Option<SimulatedHeader>,
+    loaded: bool,
+    volume_key_in_context: bool,
+    active: HashMap<String, u32>,
+    failures: HashMap<SimulatedOperation, c_int>,
+}
+
+fn sim_err(errno: c_int) -> LibcryptErr {
+    LibcryptErr::IOError(io::Error::from_raw_os_error(errno))
+}
+
+impl SimulatedBackend {
+    /// Create a simulated device without a LUKS header
+    pub fn new() -> Self {
+        SimulatedBackend {
+            header: None,
+            loaded: false,
+            volume_key_in_context: false,
+            active: HashMap::new(),
+            failures: HashMap::new(),
+        }
+    }
+
+    /// Make the next call of `operation` fail with `errno` without changing any state
+    pub fn fail_next(&mut self, operation: SimulatedOperation, errno: c_int) {
+        self.failures.insert(operation, errno);
+    }
+
+    /// Discard the loaded context as if the `CryptDevice` had been dropped and
+    /// reinitialized, leaving the on-disk header and active mappings untouched
+    pub fn reinit(&mut self) {
+        self.loaded = false;
+        self.volume_key_in_context = false;
+    }
+
+    /// Get the cipher and cipher mode of the on-disk header
+    pub fn cipher(&self) -> Option<(&str, &str)> {
+        self.header
+            .as_ref()
+            .map(|h| (h.cipher.as_str(), h.cipher_mode.as_str()))
+    }
+
+    /// Get the volume key size of the on-disk header
+    pub fn volume_key_size(&self) -> Option<usize> {
+        self.header.as_ref().map(|h| h.volume_key_size)
+    }
+
+    /// Get the names of all active mappings
+    pub fn active_names(&self) -> BTreeSet<String> {
+        self.active.keys().cloned().collect()
+    }
+
+    /// Get the flags a mapping was activated with
+    pub fn active_flags(&self, name: &str) -> Option<CryptActivateFlags> {
+        self.active
+            .get(name)
+            .and_then(|f| CryptActivateFlags::try_from(*f).ok())
+    }
+
+    fn check_failure(&mut self, operation: SimulatedOperation) -> Result<(), LibcryptErr> {
+        match self.failures.remove(&operation) {
+            Some(errno) => Err(sim_err(errno)),
+            None => Ok(()),
+        }
+    }
+
+    fn header(&self) -> Result<&SimulatedHeader, LibcryptErr> {
+        match self.header {
+            Some(ref h) if self.loaded => Ok(h),
+            _ => Err(sim_err(libc::EINVAL)),
+        }
+    }
+
+    fn header_mut(&mut self) -> Result<&mut SimulatedHeader, LibcryptErr> {
+        match self.header {
+            Some(ref mut h) if self.loaded => Ok(h),
+            _ => Err(sim_err(libc::EINVAL)),
+        }
+    }
+
+    fn check_keyslot_range(keyslot: c_int) -> Result<(), LibcryptErr> {
+        if !(0..LUKS2_KEYSLOTS_MAX).contains(&keyslot) {
+            Err(sim_err(libc::EINVAL))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_token_range(token: c_int) -> Result<(), LibcryptErr> {
+        if !(0..LUKS2_TOKENS_MAX).contains(&token) {
+            Err(sim_err(libc::EINVAL))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Find the keyslot opened by `passphrase`, honoring `CRYPT_SLOT_PRIORITY_IGNORE`
+    /// when any keyslot may be used
+    fn open_keyslot(
+        &self,
+        keyslot: Option<c_int>,
+        passphrase: &[u8],
+    ) -> Result<c_int, LibcryptErr> {
+        let header = self.header()?;
+        match keyslot {
+            Some(k) => {
+                Self::check_keyslot_range(k)?;
+                match header.keyslots.get(&k) {
+                    Some(ks) if ks.passphrase.as_slice() == passphrase => Ok(k),
+                    Some(_) => Err(sim_err(libc::EPERM)),
+                    None => Err(sim_err(libc::ENOENT)),
+                }
+            }
+            None => {
+                let mut candidates = header
+                    .keyslots
+                    .iter()
+                    .filter(|(_, ks)| ks.priority as i32 != KeyslotPriority::Ignore as i32)
+                    .collect::<Vec<_>>();
+                candidates.sort_by_key(|(_, ks)| -(ks.priority as i32));
+                candidates
+                    .into_iter()
+                    .find(|(_, ks)| ks.passphrase.as_slice() == passphrase)
+                    .map(|(k, _)| *k)
+                    .ok_or_else(|| sim_err(libc::EPERM))
+            }
+        }
+    }
+
+    fn free_keyslot(
+        header: &SimulatedHeader,
+        keyslot: Option<c_int>,
+    ) -> Result<c_int, LibcryptErr> {
+        match keyslot {
+            Some(k) => {
+                Self::check_keyslot_range(k)?;
+                if header.keyslots.contains_key(&k) {
+                    Err(sim_err(libc::EINVAL))
+                } else {
+                    Ok(k)
+                }
+            }
+            None => (0..LUKS2_KEYSLOTS_MAX)
+                .find(|k| !header.keyslots.contains_key(k))
+                .ok_or_else(|| sim_err(libc::EINVAL)),
+        }
+    }
+
+    fn set_token_keyslots(json: &mut serde_json::Value, keyslots: &[c_int]) {
+        json["keyslots"] = serde_json::Value::Array(
+            keyslots
+                .iter()
+                .map(|k| serde_json::Value::String(k.to_string()))
+                .collect(),
+        );
+    }
+}
+
+impl Default for SimulatedBackend {
+    fn default() -> Self {
+        SimulatedBackend::new()
+    }
+}
+
+impl LibcryptBackend for SimulatedBackend {
+    fn format_luks2(
+        &mut self,
+        cipher_and_mode: (&str, &str),
+        volume_key_size: usize,
+    ) -> Result<(), LibcryptErr> {
+        self.check_failure(SimulatedOperation::Format)?;
+        if self.loaded || volume_key_size == 0 {
+            return Err(sim_err(libc::EINVAL));
+        }
+        if !self.active.is_empty() {
+            return Err(sim_err(libc::EBUSY));
+        }
+        let (cipher, cipher_mode) = cipher_and_mode;
+        self.header = Some(SimulatedHeader {
+            uuid: Uuid::from_bytes([
+                0x6c, 0x7d, 0x4e, 0x0f, 0x8a, 0x12, 0x4b, 0x3c, 0x9d, 0x5e, 0x7f, 0x60, 0xa1, 0xb2,
+                0xc3, 0xd4,
+            ]),
+            cipher: cipher.to_string(),
+            cipher_mode: cipher_mode.to_string(),
+            volume_key_size,
+            keyslots: BTreeMap::new(),
+            tokens: BTreeMap::new(),
+        });
+        self.loaded = true;
+        self.volume_key_in_context = true;
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<(), LibcryptErr> {
+        self.check_failure(SimulatedOperation::Load)?;
+        if self.header.is_none() {
+            return Err(sim_err(libc::EINVAL));
+        }
+        self.loaded = true;
+        Ok(())
+    }
+
+    fn get_uuid(&mut self) -> Result<Uuid, LibcryptErr> {
+        self.header().map(|h| h.uuid)
+    }
+
+    fn keyslot_add_by_passphrase(
+        &mut self,
+        keyslot: Option<c_int>,
+        passphrase: &[u8],
+        new_passphrase: &[u8],
+    ) -> Result<c_int, LibcryptErr> {
+        self.check_failure(SimulatedOperation::KeyslotAdd)?;
+        // Directly after formatting, libcryptsetup uses the volume key held in the
+        // context and ignores the passphrase.
+        if !(self.volume_key_in_context && self.header()?.keyslots.is_empty()) {
+            self.open_keyslot(None, passphrase)?;
+        }
+        let header = self.header_mut()?;
+        let new_keyslot = Self::free_keyslot(header, keyslot)?;
+        header.keyslots.insert(
+            new_keyslot,
+            SimulatedKeyslot {
+                passphrase: new_passphrase.to_vec(),
+                priority: KeyslotPriority::Normal,
+            },
+        );
+        Ok(new_keyslot)
+    }
+
+    fn keyslot_change_by_passphrase(
+        &mut self,
+        keyslot_old: Option<c_int>,
+        keyslot_new: Option<c_int>,
+        passphrase: &[u8],
+        new_passphrase: &[u8],
+    ) -> Result<c_int, LibcryptErr> {
+        self.check_failure(SimulatedOperation::KeyslotChange)?;
+        let old = self.open_keyslot(keyslot_old, passphrase)?;
+        let header = self.header_mut()?;
+        let new = match keyslot_new {
+            Some(k) if k != old => Self::free_keyslot(header, Some(k))?,
+            _ => old,
+        };
+        let priority = header
+            .keyslots
+            .remove(&old)
+            .map(|ks| ks.priority)
+            .unwrap_or(KeyslotPriority::Normal);
+        header.keyslots.insert(
+            new,
+            SimulatedKeyslot {
+                passphrase: new_passphrase.to_vec(),
+                priority,
+            },
+        );
+        if new != old {
+            for json in header.tokens.values_mut() {
+                let keyslots = token_keyslots(json)
+                    .into_iter()
+                    .map(|k| if k == old { new } else { k })
+                    .collect::<Vec<_>>();
+                Self::set_token_keyslots(json, &keyslots);
+            }
+        }
+        Ok(new)
+    }
+
+    fn keyslot_destroy(&mut self, keyslot: c_int) -> Result<(), LibcryptErr> {
+        self.check_failure(SimulatedOperation::KeyslotDestroy)?;
+        Self::check_keyslot_range(keyslot)?;
+        let header = self.header_mut()?;
+        if header.keyslots.remove(&keyslot).is_none() {
+            return Err(sim_err(libc::EINVAL));
+        }
+        for json in header.tokens.values_mut() {
+            let keyslots = token_keyslots(json)
+                .into_iter()
+                .filter(|k| *k != keyslot)
+                .collect::<Vec<_>>();
+            Self::set_token_keyslots(json, &keyslots);
+        }
+        Ok(())
+    }
+
+    fn keyslot_status(&mut self, keyslot: c_int) -> Result<KeyslotInfo, LibcryptErr> {
+        let header = self.header()?;
+        if !(0..LUKS2_KEYSLOTS_MAX).contains(&keyslot) {
+            return Ok(KeyslotInfo::Invalid);
+        }
+        Ok(if !header.keyslots.contains_key(&keyslot) {
+            KeyslotInfo::Inactive
+        } else if header.keyslots.len() == 1 {
+            KeyslotInfo::ActiveLast
+        } else {
+            KeyslotInfo::Active
+        })
+    }
+
+    fn keyslot_get_priority(&mut self, keyslot: c_int) -> Result<KeyslotPriority, LibcryptErr> {
+        let header = self.header()?;
+        Ok(header
+            .keyslots
+            .get(&keyslot)
+            .map(|ks| ks.priority)
+            .unwrap_or(KeyslotPriority::Invalid))
+    }
+
+    fn keyslot_set_priority(
+        &mut self,
+        keyslot: c_int,
+        priority: KeyslotPriority,
+    ) -> Result<(), LibcryptErr> {
+        self.check_failure(SimulatedOperation::KeyslotSetPriority)?;
+        if let KeyslotPriority::Invalid = priority {
+            return Err(sim_err(libc::EINVAL));
+        }
+        match self.header_mut()?.keyslots.get_mut(&keyslot) {
+            Some(ks) => {
+                ks.priority = priority;
+                Ok(())
+            }
+            None => Err(sim_err(libc::EINVAL)),
+        }
+    }
+
+    fn token_json_get(&mut self, token: c_int) -> Result<serde_json::Value, LibcryptErr> {
+        Self::check_token_range(token)?;
+        self.header()?
+            .tokens
+            .get(&token)
+            .cloned()
+            .ok_or_else(|| sim_err(libc::ENOENT))
+    }
+
+    fn token_json_set(
+        &mut self,
+        token: Option<c_int>,
+        json: &serde_json::Value,
+    ) -> Result<c_int, LibcryptErr> {
+        self.check_failure(SimulatedOperation::TokenSet)?;
+        if !json["type"].is_string() || !json["keyslots"].is_array() {
+            return Err(sim_err(libc::EINVAL));
+        }
+        let header = self.header_mut()?;
+        if token_keyslots(json)
+            .iter()
+            .any(|k| !header.keyslots.contains_key(k))
+        {
+            return Err(sim_err(libc::EINVAL));
+        }
+        let token = match token {
+            Some(t) => {
+                Self::check_token_range(t)?;
+                t
+            }
+            None => (0..LUKS2_TOKENS_MAX)
+                .find(|t| !header.tokens.contains_key(t))
+                .ok_or_else(|| sim_err(libc::EINVAL))?,
+        };
+        header.tokens.insert(token, json.clone());
+        Ok(token)
+    }
+
+    fn token_remove(&mut self, token: c_int) -> Result<(), LibcryptErr> {
+        self.check_failure(SimulatedOperation::TokenRemove)?;
+        Self::check_token_range(token)?;
+        self.header_mut()?
+            .tokens
+            .remove(&token)
+            .map(|_| ())
+            .ok_or_else(|| sim_err(libc::ENOENT))
+    }
+
+    fn token_assign_keyslot(&mut self, token: c_int, keyslot: c_int) -> Result<(), LibcryptErr> {
+        self.check_failure(SimulatedOperation::TokenAssign)?;
+        Self::check_token_range(token)?;
+        let header = self.header_mut()?;
+        if !header.keyslots.contains_key(&keyslot) {
+            return Err(sim_err(libc::EINVAL));
+        }
+        let json = header
+            .tokens
+            .get_mut(&token)
+            .ok_or_else(|| sim_err(libc::EINVAL))?;
+        let mut keyslots = token_keyslots(json);
+        if !keyslots.contains(&keyslot) {
+            keyslots.push(keyslot);
+            Self::set_token_keyslots(json, &keyslots);
+        }
+        Ok(())
+    }
+
+    fn token_unassign_keyslot(&mut self, token: c_int, keyslot: c_int) -> Result<(), LibcryptErr> {
+        self.check_failure(SimulatedOperation::TokenAssign)?;
+        Self::check_token_range(token)?;
+        let json = self
+            .header_mut()?
+            .tokens
+            .get_mut(&token)
+            .ok_or_else(|| sim_err(libc::EINVAL))?;
+        let keyslots = token_keyslots(json)
+            .into_iter()
+            .filter(|k| *k != keyslot)
+            .collect::<Vec<_>>();
+        Self::set_token_keyslots(json, &keyslots);
+        Ok(())
+    }
+
+    fn token_is_assigned(&mut self, token: c_int, keyslot: c_int) -> Result<Bool, LibcryptErr> {
+        Self::check_token_range(token)?;
+        Self::check_keyslot_range(keyslot)?;
+        let json = self
+            .header()?
+            .tokens
+            .get(&token)
+            .ok_or_else(|| sim_err(libc::EINVAL))?;
+        Ok(if token_keyslots(json).contains(&keyslot) {
+            Bool::Yes
+        } else {
+            Bool::No
+        })
+    }
+
+    fn token_status(&mut self, token: c_int) -> Result<(CryptTokenInfo, String), LibcryptErr> {
+        Self::check_token_range(token)?;
+        // No handlers are registered with the simulation, so only the built-in keyring
+        // token type is known
+        Ok(match self.header()?.tokens.get(&token) {
+            Some(json) => {
+                let type_ = json["type"].as_str().unwrap_or_default().to_string();
+                let info = if type_ == LUKS2_KEYRING_TOKEN_TYPE {
+                    CryptTokenInfo::Internal
+                } else {
+                    CryptTokenInfo::ExternalUnknown
+                };
+                (info, type_)
+            }
+            None => (CryptTokenInfo::Inactive, String::new()),
+        })
+    }
+
+    fn activate_by_passphrase(
+        &mut self,
+        name: Option<&str>,
+        keyslot: Option<c_int>,
+        passphrase: &[u8],
+        flags: CryptActivateFlags,
+    ) -> Result<c_int, LibcryptErr> {
+        self.check_failure(SimulatedOperation::Activate)?;
+        if let Some(n) = name {
+            if self.active.contains_key(n) {
+                return Err(sim_err(libc::EEXIST));
+            }
+        }
+        let opened = self.open_keyslot(keyslot, passphrase)?;
+        if let Some(n) = name {
+            self.active.insert(n.to_string(), flags.into());
+        }
+        Ok(opened)
+    }
+
+    fn deactivate(&mut self, name: &str, _flags: CryptDeactivateFlags) -> Result<(), LibcryptErr> {
+        self.check_failure(SimulatedOperation::Deactivate)?;
+        self.active
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| sim_err(libc::ENODEV))
+    }
+
+    fn status(&mut self, name: &str) -> Result<CryptStatusInfo, LibcryptErr> {
+        Ok(if self.active.contains_key(name) {
+            CryptStatusInfo::Active
+        } else {
+            CryptStatusInfo::Inactive
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn errno(err: LibcryptErr) -> Option<i32> {
+        match err {
+            LibcryptErr::IOError(e) => e.raw_os_error(),
+            _ => None,
+        }
+    }
+
+    fn matches_info(info: KeyslotInfo, expected: KeyslotInfo) -> bool {
+        info as u32 == expected as u32
+    }
+
+    fn formatted() -> SimulatedBackend {
+        let mut backend = SimulatedBackend::new();
+        backend
+            .format_luks2(("aes", "xts-plain64"), 64)
+            .expect("Should succeed");
+        backend
+            .keyslot_add_by_passphrase(None, b"", b"first")
+            .expect("Should succeed");
+        backend
+    }
+
+    /// Example of application logic written against the backend trait
+    fn rotate<B: LibcryptBackend>(
+        backend: &mut B,
+        old: &[u8],
+        new: &[u8],
+    ) -> Result<c_int, LibcryptErr> {
+        let old_keyslot =
+            backend.activate_by_passphrase(None, None, old, CryptActivateFlags::empty())?;
+        let new_keyslot = backend.keyslot_add_by_passphrase(None, old, new)?;
+        for token in 0..LUKS2_TOKENS_MAX {
+            if let Bool::Yes = backend
+                .token_is_assigned(token, old_keyslot)
+                .unwrap_or(Bool::No)
+            {
+                backend.token_assign_keyslot(token, new_keyslot)?;
+            }
+        }
+        backend.keyslot_destroy(old_keyslot)?;
+        Ok(new_keyslot)
+    }
+
+    #[test]
+    fn test_unformatted_device() {
+        let mut backend = SimulatedBackend::new();
+        assert_eq!(errno(backend.load().unwrap_err()), Some(libc::EINVAL));
+        assert_eq!(
+            errno(
+                backend
+                    .keyslot_add_by_passphrase(None, b"", b"first")
+                    .unwrap_err()
+            ),
+            Some(libc::EINVAL)
+        );
+    }
+
+    #[test]
+    fn test_keyslot_lifecycle() {
+        let mut backend = formatted();
+        backend.reinit();
+        backend.load().expect("Should succeed");
+        assert_eq!(
+            errno(
+                backend
+                    .keyslot_add_by_passphrase(None, b"wrong", b"second")
+                    .unwrap_err()
+            ),
+            Some(libc::EPERM)
+        );
+        assert_eq!(
+            backend
+                .keyslot_add_by_passphrase(Some(5), b"first", b"second")
+                .unwrap(),
+            5
+        );
+        assert_eq!(
+            errno(
+                backend
+                    .keyslot_add_by_passphrase(Some(5), b"first", b"third")
+                    .unwrap_err()
+            ),
+            Some(libc::EINVAL)
+        );
+        assert!(matches_info(
+            backend.keyslot_status(0).unwrap(),
+            KeyslotInfo::Active
+        ));
+        backend.keyslot_destroy(0).expect("Should succeed");
+        assert!(matches_info(
+            backend.keyslot_status(0).unwrap(),
+            KeyslotInfo::Inactive
+        ));
+        assert!(matches_info(
+            backend.keyslot_status(5).unwrap(),
+            KeyslotInfo::ActiveLast
+        ));
+        assert!(matches_info(
+            backend.keyslot_status(32).unwrap(),
+            KeyslotInfo::Invalid
+        ));
+        assert_eq!(
+            backend
+                .keyslot_change_by_passphrase(Some(5), Some(1), b"second", b"changed")
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            backend
+                .activate_by_passphrase(None, None, b"changed", CryptActivateFlags::empty())
+                .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_priority_ignore() {
+        let mut backend = formatted();
+        backend
+            .keyslot_set_priority(0, KeyslotPriority::Ignore)
+            .expect("Should succeed");
+        assert_eq!(
+            errno(
+                backend
+                    .activate_by_passphrase(None, None, b"first", CryptActivateFlags::empty())
+                    .unwrap_err()
+            ),
+            Some(libc::EPERM)
+        );
+        assert_eq!(
+            backend
+                .activate_by_passphrase(None, Some(0), b"first", CryptActivateFlags::empty())
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_tokens() {
+        let mut backend = formatted();
+        let json = serde_json::json!({ "type": "example", "keyslots": [] });
+        assert_eq!(
+            errno(
+                backend
+                    .token_json_set(None, &serde_json::json!({ "keyslots": [] }))
+                    .unwrap_err()
+            ),
+            Some(libc::EINVAL)
+        );
+        let token = backend.token_json_set(None, &json).unwrap();
+        assert_eq!(token, 0);
+        let (info, type_) = backend.token_status(token).unwrap();
+        assert!(matches!(info, CryptTokenInfo::ExternalUnknown));
+        assert_eq!(type_, "example");
+        backend.token_assign_keyslot(token, 0).unwrap();
+        assert_eq!(backend.token_is_assigned(token, 0).unwrap(), Bool::Yes);
+        assert_eq!(
+            backend.token_json_get(token).unwrap()["keyslots"],
+            serde_json::json!(["0"])
+        );
+        assert_eq!(
+            errno(backend.token_assign_keyslot(token, 3).unwrap_err()),
+            Some(libc::EINVAL)
+        );
+        backend.token_remove(token).unwrap();
+        assert_eq!(
+            errno(backend.token_json_get(token).unwrap_err()),
+            Some(libc::ENOENT)
+        );
+        assert!(matches!(
+            backend.token_status(token).unwrap().0,
+            CryptTokenInfo::Inactive
+        ));
+    }
+
+    #[test]
+    fn test_activation() {
+        let mut backend = formatted();
+        backend
+            .activate_by_passphrase(Some("sim"), None, b"first", CryptActivateFlags::empty())
+            .unwrap();
+        assert!(backend.active_names().contains("sim"));
+        assert!(backend.active_flags("sim").is_some());
+        assert_eq!(
+            errno(
+                backend
+                    .activate_by_passphrase(
+                        Some("sim"),
+                        None,
+                        b"first",
+                        CryptActivateFlags::empty()
+                    )
+                    .unwrap_err()
+            ),
+            Some(libc::EEXIST)
+        );
+        assert_eq!(
+            errno(
+                backend
+                    .format_luks2(("aes", "xts-plain64"), 64)
+                    .unwrap_err()
+            ),
+            Some(libc::EINVAL)
+        );
+        backend
+            .deactivate("sim", CryptDeactivateFlags::empty())
+            .unwrap();
+        assert_eq!(
+            errno(
+                backend
+                    .deactivate("sim", CryptDeactivateFlags::empty())
+                    .unwrap_err()
+            ),
+            Some(libc::ENODEV)
+        );
+    }
+
+    #[test]
+    fn test_rotate_with_tokens() {
+        let mut backend = formatted();
+        let token = backend
+            .token_json_set(
+                None,
+                &serde_json::json!({ "type": "example", "keyslots": ["0"] }),
+            )
+            .unwrap();
+        let new_keyslot = rotate(&mut backend, b"first", b"second").unwrap();
+        assert_eq!(new_keyslot, 1);
+        assert_eq!(
+            backend.token_json_get(token).unwrap()["keyslots"],
+            serde_json::json!(["1"])
+        );
+        assert_eq!(
+            errno(rotate(&mut backend, b"first", b"third").unwrap_err()),
+            Some(libc::EPERM)
+        );
+    }
+
+    #[test]
+    fn test_injected_failure() {
+        let mut backend = formatted();
+        backend.fail_next(SimulatedOperation::KeyslotDestroy, libc::EIO);
+        assert_eq!(
+            errno(rotate(&mut backend, b"first", b"second").unwrap_err()),
+            Some(libc::EIO)
+        );
+        assert!(matches_info(
+            backend.keyslot_status(0).unwrap(),
+            KeyslotInfo::Active
+        ));
+        assert_eq!(rotate(&mut backend, b"first", b"third").unwrap(), 2);
+    }
+}
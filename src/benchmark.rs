@@ -0,0 +1,73 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{convert::TryFrom, ptr};
+
+use crate::{err::LibcryptErr, settings::CryptPbkdfType};
+
+/// Handle for cipher and PBKDF benchmarks
+pub struct CryptBenchmark;
+
+impl CryptBenchmark {
+    /// Benchmark a cipher in memory, returning encryption and decryption speed in MiB/s
+    pub fn cipher(
+        cipher_and_mode: (&str, &str),
+        volume_key_size: usize,
+        iv_size: usize,
+        buffer_size: usize,
+    ) -> Result<(f64, f64), LibcryptErr> {
+        let (cipher, cipher_mode) = cipher_and_mode;
+        let cipher_cstring = to_cstring!(cipher)?;
+        let cipher_mode_cstring = to_cstring!(cipher_mode)?;
+        let mut encryption_mbs = 0f64;
+        let mut decryption_mbs = 0f64;
+        errno!(unsafe {
+            libcryptsetup_rs_sys::crypt_benchmark(
+                ptr::null_mut(),
+                cipher_cstring.as_ptr(),
+                cipher_mode_cstring.as_ptr(),
+                volume_key_size,
+                iv_size,
+                buffer_size,
+                &mut encryption_mbs as *mut _,
+                &mut decryption_mbs as *mut _,
+            )
+        })
+        .map(|_| (encryption_mbs, decryption_mbs))
+    }
+
+    /// Benchmark a PBKDF, returning the parameters with iterations and memory cost
+    /// calculated for the requested time
+    pub fn pbkdf(
+        pbkdf: &CryptPbkdfType,
+        password: &[u8],
+        salt: &[u8],
+        volume_key_size: usize,
+    ) -> Result<CryptPbkdfType, LibcryptErr> {
+        let hash_cstring = to_cstring!(pbkdf.hash)?;
+        let mut inner = libcryptsetup_rs_sys::crypt_pbkdf_type {
+            type_: pbkdf.type_.as_ptr(),
+            hash: hash_cstring.as_ptr(),
+            time_ms: pbkdf.time_ms,
+            iterations: pbkdf.iterations,
+            max_memory_kb: pbkdf.max_memory_kb,
+            parallel_threads: pbkdf.parallel_threads,
+            flags: (&pbkdf.flags).into(),
+        };
+        errno!(unsafe {
+            libcryptsetup_rs_sys::crypt_benchmark_pbkdf(
+                ptr::null_mut(),
+                &mut inner as *mut _,
+                to_byte_ptr!(password),
+                password.len(),
+                to_byte_ptr!(salt),
+                salt.len(),
+                volume_key_size,
+                None,
+                ptr::null_mut(),
+            )
+        })
+        .and_then(|_| CryptPbkdfType::try_from(&inner))
+    }
+}
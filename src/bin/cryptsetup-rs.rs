@@ -0,0 +1,738 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    env::args,
+    fs::{self, File},
+    io::{self, Read, Write},
+    mem::ManuallyDrop,
+    os::{raw::c_int, unix::io::FromRawFd},
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
+
+use openssl::rand::rand_bytes;
+use zeroize::Zeroizing;
+
+use libcryptsetup_rs::{
+    CryptActivateFlag, CryptActivateFlags, CryptBenchmark, CryptDeactivateFlags, CryptDevice,
+    CryptErase, CryptInit, CryptParamsIntegrity, CryptParamsLuks2, CryptParamsReencrypt,
+    CryptReencryptDirectionInfo, CryptReencryptFlags, CryptReencryptModeInfo, CryptSettings,
+    CryptStatusInfo, CryptVolumeKeyFlags, CryptWipePattern, Either, EncryptionFormat, KeyslotInfo,
+    LibcryptErr, LuksType, Passphrase, PassphrasePrompt,
+};
+
+const USAGE: &str = "Usage: cryptsetup-rs [OPTIONS] <COMMAND> <ARGS>
+
+Commands:
+    luksFormat <device>
+    open <device> <name>
+    close <name>
+    status <name>
+    luksDump <device>
+    luksAddKey <device>
+    luksRemoveKey <device>
+    luksChangeKey <device>
+    luksKillSlot <device> <keyslot>
+    token import|export|remove <device>
+    luksHeaderBackup <device> <file>
+    luksHeaderRestore <device> <file>
+    luksSuspend <name>
+    luksResume <name>
+    resize <name>
+    reencrypt <device>
+    benchmark
+    erase <device>
+
+Options:
+    --cipher <cipher>         cipher specification (default aes-xts-plain64)
+    --key-size <bits>         volume key size in bits (default 512, reencrypt keeps
+                              the current size)
+    --key-file <file>         read the passphrase from a file instead of prompting
+    --new-key-file <file>     read the new passphrase from a file instead of prompting
+    --tries <n>               number of passphrase attempts (default 3)
+    --timeout <seconds>       timeout for passphrase prompts
+    --key-slot <n>            keyslot to use
+    --header <file>           detached header file
+    --size <sectors>          new size for resize in 512-byte sectors
+    --token-id <n>            token to export or remove
+    --json-file <file>        read token JSON from a file instead of stdin
+    --dump-json-metadata      print luksDump output as JSON
+    --batch-mode              do not ask for confirmation";
+
+const DEFAULT_KEY_SIZE: usize = 512;
+
+enum Command {
+    LuksFormat(PathBuf),
+    Open(PathBuf, String),
+    Close(String),
+    Status(String),
+    LuksDump(PathBuf),
+    AddKey(PathBuf),
+    RemoveKey(PathBuf),
+    ChangeKey(PathBuf),
+    KillSlot(PathBuf, c_int),
+    TokenImport(PathBuf),
+    TokenExport(PathBuf),
+    TokenRemove(PathBuf),
+    HeaderBackup(PathBuf, PathBuf),
+    HeaderRestore(PathBuf, PathBuf),
+    Suspend(String),
+    Resume(String),
+    Resize(String),
+    Reencrypt(PathBuf),
+    Benchmark,
+    Erase(PathBuf),
+}
+
+struct Options {
+    cipher: String,
+    key_size: Option<usize>,
+    key_file: Option<PathBuf>,
+    new_key_file: Option<PathBuf>,
+    key_slot: Option<c_int>,
+    header: Option<PathBuf>,
+    size: u64,
+    token_id: Option<c_int>,
+    json_file: Option<PathBuf>,
+    dump_json: bool,
+    batch_mode: bool,
+    tries: u32,
+    timeout: Option<u64>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            cipher: "aes-xts-plain64".to_string(),
+            key_size: None,
+            key_file: None,
+            new_key_file: None,
+            key_slot: None,
+            header: None,
+            size: 0,
+            token_id: None,
+            json_file: None,
+            dump_json: false,
+            batch_mode: false,
+            tries: 3,
+            timeout: None,
+        }
+    }
+}
+
+impl Options {
+    fn key_size(&self) -> usize {
+        self.key_size.unwrap_or(DEFAULT_KEY_SIZE)
+    }
+}
+
+fn usage_err(msg: &str) -> LibcryptErr {
+    LibcryptErr::Other(format!("{}\n\n{}", msg, USAGE))
+}
+
+fn parse_number<T: std::str::FromStr>(
+    option: &str,
+    value: Option<String>,
+) -> Result<T, LibcryptErr> {
+    value
+        .as_ref()
+        .and_then(|v| v.parse::<T>().ok())
+        .ok_or_else(|| usage_err(&format!("Option {} requires a numeric argument", option)))
+}
+
+fn parse_path(option: &str, value: Option<String>) -> Result<PathBuf, LibcryptErr> {
+    value
+        .map(PathBuf::from)
+        .ok_or_else(|| usage_err(&format!("Option {} requires a path argument", option)))
+}
+
+fn parse_args() -> Result<(Command, Options), LibcryptErr> {
+    let mut options = Options::default();
+    let mut positional = Vec::new();
+    let mut args = args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cipher" => {
+                options.cipher = args
+                    .next()
+                    .ok_or_else(|| usage_err("Option --cipher requires an argument"))?
+            }
+            "--key-size" => {
+                let key_size: usize = parse_number(&arg, args.next())?;
+                if key_size == 0 || key_size % 8 != 0 {
+                    return Err(usage_err("Key size must be a positive multiple of 8 bits"));
+                }
+                options.key_size = Some(key_size)
+            }
+            "--key-file" => options.key_file = Some(parse_path(&arg, args.next())?),
+            "--new-key-file" => options.new_key_file = Some(parse_path(&arg, args.next())?),
+            "--key-slot" => options.key_slot = Some(parse_number(&arg, args.next())?),
+            "--header" => options.header = Some(parse_path(&arg, args.next())?),
+            "--size" => options.size = parse_number(&arg, args.next())?,
+            "--token-id" => options.token_id = Some(parse_number(&arg, args.next())?),
+            "--json-file" => options.json_file = Some(parse_path(&arg, args.next())?),
+            "--tries" | "-T" => options.tries = parse_number(&arg, args.next())?,
+            "--timeout" | "-t" => options.timeout = Some(parse_number(&arg, args.next())?),
+            "--dump-json-metadata" => options.dump_json = true,
+            "--batch-mode" | "-q" => options.batch_mode = true,
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            s if s.starts_with("--") => {
+                return Err(usage_err(&format!("Unrecognized option {}", s)))
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let command = positional
+        .next()
+        .ok_or_else(|| usage_err("Missing command"))?;
+    let mut device = || {
+        positional
+            .next()
+            .map(PathBuf::from)
+            .ok_or_else(|| usage_err(&format!("Command {} requires a device", command)))
+    };
+    let command = match command.as_str() {
+        "luksFormat" => Command::LuksFormat(device()?),
+        "open" => {
+            let dev = device()?;
+            let name = device()?;
+            Command::Open(dev, path_to_name(name)?)
+        }
+        "close" => Command::Close(path_to_name(device()?)?),
+        "status" => Command::Status(path_to_name(device()?)?),
+        "luksDump" => Command::LuksDump(device()?),
+        "luksAddKey" => Command::AddKey(device()?),
+        "luksRemoveKey" => Command::RemoveKey(device()?),
+        "luksChangeKey" => Command::ChangeKey(device()?),
+        "luksKillSlot" => {
+            let dev = device()?;
+            let keyslot = path_to_name(device()?)?
+                .parse::<c_int>()
+                .map_err(|_| usage_err("Keyslot must be a number"))?;
+            Command::KillSlot(dev, keyslot)
+        }
+        "token" => {
+            let action = path_to_name(device()?)?;
+            let dev = device()?;
+            match action.as_str() {
+                "import" => Command::TokenImport(dev),
+                "export" => Command::TokenExport(dev),
+                "remove" => Command::TokenRemove(dev),
+                s => return Err(usage_err(&format!("Unrecognized token action {}", s))),
+            }
+        }
+        "luksHeaderBackup" => {
+            let dev = device()?;
+            Command::HeaderBackup(dev, device()?)
+        }
+        "luksHeaderRestore" => {
+            let dev = device()?;
+            Command::HeaderRestore(dev, device()?)
+        }
+        "luksSuspend" => Command::Suspend(path_to_name(device()?)?),
+        "luksResume" => Command::Resume(path_to_name(device()?)?),
+        "resize" => Command::Resize(path_to_name(device()?)?),
+        "reencrypt" => Command::Reencrypt(device()?),
+        "benchmark" => Command::Benchmark,
+        "erase" | "luksErase" => Command::Erase(device()?),
+        s => return Err(usage_err(&format!("Unrecognized command {}", s))),
+    };
+    Ok((command, options))
+}
+
+fn path_to_name(path: PathBuf) -> Result<String, LibcryptErr> {
+    path.into_os_string()
+        .into_string()
+        .map_err(|_| LibcryptErr::InvalidConversion)
+}
+
+fn split_cipher(cipher: &str) -> Result<(&str, &str), LibcryptErr> {
+    let mut split = cipher.splitn(2, '-');
+    match (split.next(), split.next()) {
+        (Some(c), Some(m)) if !c.is_empty() && !m.is_empty() => Ok((c, m)),
+        _ => Err(LibcryptErr::Other(format!(
+            "Cipher {} must be in the form cipher-mode",
+            cipher
+        ))),
+    }
+}
+
+fn prompt(message: &str, options: &Options) -> PassphrasePrompt {
+    let mut prompt = PassphrasePrompt::new(message);
+    prompt
+        .tries(options.tries)
+        .timeout(options.timeout.map(Duration::from_secs));
+    prompt
+}
+
+/// Read a passphrase from a key file or prompt for it
+fn read_passphrase(
+    prompt: &PassphrasePrompt,
+    key_file: Option<&Path>,
+) -> Result<Passphrase, LibcryptErr> {
+    match key_file {
+        Some(path) => fs::read(path)
+            .map(Passphrase::new)
+            .map_err(LibcryptErr::IOError),
+        None => prompt.read(),
+    }
+}
+
+/// Read one line of stdin without buffering, so later passphrase prompts see the
+/// remaining input
+fn read_stdin_line() -> Result<String, LibcryptErr> {
+    // Stdin stays open after the handle is dropped
+    let mut stdin = ManuallyDrop::new(unsafe { File::from_raw_fd(0) });
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    while stdin.read(&mut byte).map_err(LibcryptErr::IOError)? == 1 && byte[0] != b'\n' {
+        line.push(byte[0]);
+    }
+    String::from_utf8(line).map_err(|e| LibcryptErr::Utf8Error(e.utf8_error()))
+}
+
+fn confirm(options: &Options, msg: &str) -> Result<(), LibcryptErr> {
+    if options.batch_mode {
+        return Ok(());
+    }
+    eprint!("{}\nAre you sure? (Type 'YES' in capital letters): ", msg);
+    io::stderr().flush().map_err(LibcryptErr::IOError)?;
+    if read_stdin_line()?.trim() == "YES" {
+        Ok(())
+    } else {
+        Err(LibcryptErr::Other("Operation aborted".to_string()))
+    }
+}
+
+fn load(path: &Path, options: &Options) -> Result<CryptDevice, LibcryptErr> {
+    let mut device = match options.header {
+        Some(ref header) => CryptInit::init_with_data_device(Either::Right((header, path)))?,
+        None => CryptInit::init(path)?,
+    };
+    device
+        .context_handle()
+        .load::<()>(EncryptionFormat::Luks2, None)?;
+    Ok(device)
+}
+
+fn load_by_name(name: &str, options: &Options) -> Result<CryptDevice, LibcryptErr> {
+    CryptInit::init_by_name_and_header(name, options.header.as_deref())
+}
+
+fn luks_format(path: &Path, options: &Options) -> Result<(), LibcryptErr> {
+    confirm(
+        options,
+        &format!(
+            "This will overwrite data on {} irrevocably.",
+            path.display()
+        ),
+    )?;
+    let passphrase = read_passphrase(
+        prompt("Enter passphrase", options).confirm(true),
+        options.key_file.as_deref(),
+    )?;
+    let mut device = match options.header {
+        Some(ref header) => CryptInit::init_with_data_device(Either::Right((header, path)))?,
+        None => CryptInit::init(path)?,
+    };
+    device.context_handle().format::<()>(
+        EncryptionFormat::Luks2,
+        split_cipher(&options.cipher)?,
+        None,
+        Either::Right(options.key_size() / 8),
+        None,
+    )?;
+    let keyslot = device.keyslot_handle(options.key_slot).add_by_key(
+        None,
+        &passphrase,
+        CryptVolumeKeyFlags::empty(),
+    )?;
+    println!("Key slot {} created.", keyslot);
+    Ok(())
+}
+
+fn open(path: &Path, name: &str, options: &Options) -> Result<(), LibcryptErr> {
+    let mut device = load(path, options)?;
+    match options.key_file {
+        Some(ref key_file) => device.activate_handle().activate_by_passphrase(
+            Some(name),
+            options.key_slot,
+            &read_passphrase(&prompt("Enter passphrase", options), Some(key_file))?,
+            CryptActivateFlags::empty(),
+        )?,
+        None => prompt("Enter passphrase", options).activate(
+            &mut device,
+            Some(name),
+            options.key_slot,
+            CryptActivateFlags::empty(),
+        )?,
+    };
+    Ok(())
+}
+
+fn close(name: &str, options: &Options) -> Result<(), LibcryptErr> {
+    load_by_name(name, options)?
+        .activate_handle()
+        .deactivate(name, CryptDeactivateFlags::empty())
+}
+
+fn status(name: &str, options: &Options) -> Result<(), LibcryptErr> {
+    let mut device = load_by_name(name, options)?;
+    let mapped = Path::new("/dev/mapper").join(name);
+    match device.status_handle().status(name)? {
+        CryptStatusInfo::Active => println!("{} is active.", mapped.display()),
+        CryptStatusInfo::Busy => println!("{} is active and is in use.", mapped.display()),
+        CryptStatusInfo::Inactive | CryptStatusInfo::Invalid => {
+            println!("{} is inactive.", mapped.display());
+            return Ok(());
+        }
+    }
+    let mut status = device.status_handle();
+    println!(
+        "  cipher:  {}-{}",
+        status.get_cipher()?,
+        status.get_cipher_mode()?
+    );
+    println!("  keysize: {} bits", status.get_volume_key_size() * 8);
+    println!("  device:  {}", status.get_device_path()?.display());
+    if let Some(header) = status.get_metadata_device_path()? {
+        println!("  header:  {}", header.display());
+    }
+    println!("  sector size:  {}", status.get_sector_size());
+    let active = device.runtime_handle(name).get_active_device()?;
+    println!("  offset:  {} sectors", active.offset);
+    println!("  size:    {} sectors", active.size);
+    let flags: u32 = active.flags.into();
+    let readonly: u32 = CryptActivateFlag::Readonly.into();
+    println!(
+        "  mode:    {}",
+        if flags & readonly != 0 {
+            "readonly"
+        } else {
+            "read/write"
+        }
+    );
+    Ok(())
+}
+
+fn luks_dump(path: &Path, options: &Options) -> Result<(), LibcryptErr> {
+    let dump = load(path, options)?.status_handle().device_dump()?;
+    if options.dump_json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&dump).map_err(LibcryptErr::JsonError)?
+        );
+    } else {
+        print!("{}", dump);
+    }
+    Ok(())
+}
+
+fn add_key(path: &Path, options: &Options) -> Result<(), LibcryptErr> {
+    let mut device = load(path, options)?;
+    let passphrase = read_passphrase(
+        &prompt("Enter any existing passphrase", options),
+        options.key_file.as_deref(),
+    )?;
+    let new_passphrase = read_passphrase(
+        prompt("Enter new passphrase for key slot", options).confirm(true),
+        options.new_key_file.as_deref(),
+    )?;
+    let keyslot = device
+        .keyslot_handle(options.key_slot)
+        .add_by_passphrase(&passphrase, &new_passphrase)?;
+    println!("Key slot {} created.", keyslot);
+    Ok(())
+}
+
+fn remove_key(path: &Path, options: &Options) -> Result<(), LibcryptErr> {
+    let mut device = load(path, options)?;
+    let keyslot = match options.key_file {
+        Some(ref key_file) => device.activate_handle().activate_by_passphrase(
+            None,
+            options.key_slot,
+            &read_passphrase(&prompt("Enter passphrase", options), Some(key_file))?,
+            CryptActivateFlags::empty(),
+        )?,
+        None => prompt("Enter passphrase to be deleted", options).activate(
+            &mut device,
+            None,
+            options.key_slot,
+            CryptActivateFlags::empty(),
+        )?,
+    };
+    device.keyslot_handle(Some(keyslot)).destroy()?;
+    println!("Key slot {} removed.", keyslot);
+    Ok(())
+}
+
+fn change_key(path: &Path, options: &Options) -> Result<(), LibcryptErr> {
+    let mut device = load(path, options)?;
+    let passphrase = read_passphrase(
+        &prompt("Enter passphrase to be changed", options),
+        options.key_file.as_deref(),
+    )?;
+    let new_passphrase = read_passphrase(
+        prompt("Enter new passphrase", options).confirm(true),
+        options.new_key_file.as_deref(),
+    )?;
+    let keyslot = options.key_slot.unwrap_or(-1);
+    let keyslot = device.keyslot_handle(None).change_by_passphrase(
+        keyslot,
+        keyslot,
+        &passphrase,
+        &new_passphrase,
+    )?;
+    println!("Key slot {} changed.", keyslot);
+    Ok(())
+}
+
+fn kill_slot(path: &Path, keyslot: c_int, options: &Options) -> Result<(), LibcryptErr> {
+    let mut device = load(path, options)?;
+    if let KeyslotInfo::ActiveLast = device.keyslot_handle(Some(keyslot)).status()? {
+        confirm(
+            options,
+            "This is the last keyslot. Device will become unusable after purging this key.",
+        )?;
+    }
+    device.keyslot_handle(Some(keyslot)).destroy()
+}
+
+fn token_import(path: &Path, options: &Options) -> Result<(), LibcryptErr> {
+    let mut device = load(path, options)?;
+    let json = match options.json_file {
+        Some(ref file) => fs::read_to_string(file).map_err(LibcryptErr::IOError)?,
+        None => {
+            let mut json = String::new();
+            io::stdin()
+                .read_to_string(&mut json)
+                .map_err(LibcryptErr::IOError)?;
+            json
+        }
+    };
+    let json: serde_json::Value = serde_json::from_str(&json).map_err(LibcryptErr::JsonError)?;
+    let token = device
+        .token_handle(options.token_id.unwrap_or(-1))
+        .json_set(&json, options.token_id.is_none())?;
+    println!("Token {} created.", token);
+    Ok(())
+}
+
+fn token_id(options: &Options) -> Result<c_int, LibcryptErr> {
+    options
+        .token_id
+        .ok_or_else(|| usage_err("Option --token-id is required"))
+}
+
+fn token_export(path: &Path, options: &Options) -> Result<(), LibcryptErr> {
+    let mut device = load(path, options)?;
+    let json = device.token_handle(token_id(options)?).json_get()?;
+    println!(
+        "{}",
+        serde_json::to_string(&json).map_err(LibcryptErr::JsonError)?
+    );
+    Ok(())
+}
+
+fn token_remove(path: &Path, options: &Options) -> Result<(), LibcryptErr> {
+    let mut device = load(path, options)?;
+    device.token_handle(token_id(options)?).remove()
+}
+
+fn header_backup(path: &Path, file: &Path, options: &Options) -> Result<(), LibcryptErr> {
+    load(path, options)?
+        .backup_handle()
+        .header_backup(EncryptionFormat::Luks2, file)
+}
+
+fn header_restore(path: &Path, file: &Path, options: &Options) -> Result<(), LibcryptErr> {
+    confirm(
+        options,
+        &format!(
+            "Device {} will be overwritten with the header from {}.",
+            path.display(),
+            file.display()
+        ),
+    )?;
+    let mut device = match options.header {
+        Some(ref header) => CryptInit::init_with_data_device(Either::Right((header, path)))?,
+        None => CryptInit::init(path)?,
+    };
+    device
+        .backup_handle()
+        .header_restore(EncryptionFormat::Luks2, file)
+}
+
+fn suspend(name: &str, options: &Options) -> Result<(), LibcryptErr> {
+    load_by_name(name, options)?.context_handle().suspend(name)
+}
+
+fn resume(name: &str, options: &Options) -> Result<(), LibcryptErr> {
+    let mut device = load_by_name(name, options)?;
+    let passphrase = read_passphrase(
+        &prompt("Enter passphrase", options),
+        options.key_file.as_deref(),
+    )?;
+    device.context_handle().resume_by_passphrase(
+        name,
+        options.key_slot.unwrap_or(-1),
+        std::str::from_utf8(&passphrase).map_err(LibcryptErr::Utf8Error)?,
+    )?;
+    Ok(())
+}
+
+fn resize(name: &str, options: &Options) -> Result<(), LibcryptErr> {
+    load_by_name(name, options)?
+        .context_handle()
+        .resize(name, options.size)
+}
+
+fn reencrypt(path: &Path, options: &Options) -> Result<(), LibcryptErr> {
+    let mut device = load(path, options)?;
+    let passphrase = read_passphrase(
+        &prompt("Enter passphrase", options),
+        options.key_file.as_deref(),
+    )?;
+    let sector_size = device.status_handle().get_sector_size() as u32;
+    let params = CryptParamsReencrypt {
+        mode: CryptReencryptModeInfo::Reencrypt,
+        direction: CryptReencryptDirectionInfo::Forward,
+        resilience: "checksum".to_string(),
+        hash: "sha256".to_string(),
+        data_shift: 0,
+        max_hotzone_size: 0,
+        device_size: 0,
+        luks2: CryptParamsLuks2 {
+            pbkdf: CryptSettings::get_pbkdf_default(&LuksType::Luks2)?,
+            integrity: None,
+            integrity_params: CryptParamsIntegrity::default(),
+            data_alignment: 0,
+            data_device: path.to_path_buf(),
+            sector_size,
+            label: String::new(),
+            subsystem: String::new(),
+        },
+        flags: CryptReencryptFlags::empty(),
+    };
+    let keyslot_old = options.key_slot.unwrap_or(-1);
+    // Without a volume key libcryptsetup generates one of the current size
+    let volume_key = match options.key_size {
+        Some(key_size) => {
+            let mut volume_key = Zeroizing::new(vec![0u8; key_size / 8]);
+            rand_bytes(&mut volume_key).map_err(LibcryptErr::OpensslError)?;
+            Some(volume_key)
+        }
+        None => None,
+    };
+    let keyslot_new = device.keyslot_handle(None).add_by_key(
+        volume_key.as_ref().map(|key| key.as_slice()),
+        &passphrase,
+        CryptVolumeKeyFlags::new(vec![libcryptsetup_rs::CryptVolumeKeyFlag::NoSegment]),
+    )?;
+    let mut handle = device.reencrypt_handle();
+    handle.reencrypt_init_by_passphrase(
+        None,
+        &passphrase,
+        keyslot_old,
+        keyslot_new,
+        split_cipher(&options.cipher)?,
+        params,
+    )?;
+    handle.reencrypt(None)
+}
+
+fn benchmark(options: &Options) -> Result<(), LibcryptErr> {
+    let pbkdf = CryptSettings::get_pbkdf_default(&LuksType::Luks2)?;
+    let kdf = CryptBenchmark::pbkdf(&pbkdf, b"foo", &[0u8; 32], options.key_size() / 8)?;
+    println!(
+        "{:<10} {} iterations, {} memory, {} parallel threads (CPUs) for {}-bit key (requested {} ms time)",
+        kdf.type_.as_str(),
+        kdf.iterations,
+        kdf.max_memory_kb,
+        kdf.parallel_threads,
+        options.key_size(),
+        kdf.time_ms
+    );
+
+    println!("#     Algorithm |       Key |      Encryption |      Decryption");
+    let ciphers: &[(&str, &str, usize, usize)] = &[
+        ("aes", "cbc", 128, 16),
+        ("aes", "cbc", 256, 16),
+        ("aes", "xts", 256, 16),
+        ("aes", "xts", 512, 16),
+        ("serpent", "xts", 512, 16),
+        ("twofish", "xts", 512, 16),
+    ];
+    for &(cipher, mode, key_bits, iv_size) in ciphers {
+        let name = format!("{}-{}", cipher, mode);
+        match CryptBenchmark::cipher((cipher, mode), key_bits / 8, iv_size, 1024 * 1024) {
+            Ok((enc, dec)) => println!(
+                "{:>15} {:>8}b {:>11.1} MiB/s {:>11.1} MiB/s",
+                name, key_bits, enc, dec
+            ),
+            Err(_) => println!("{:>15} {:>8}b {:>17} {:>17}", name, key_bits, "N/A", "N/A"),
+        }
+    }
+    Ok(())
+}
+
+fn erase(path: &Path, options: &Options) -> Result<(), LibcryptErr> {
+    confirm(
+        options,
+        &format!(
+            "WARNING: all keyslots on {} will be wiped and the data will be lost.",
+            path.display()
+        ),
+    )?;
+    let report = CryptErase::erase(
+        options.header.as_deref().unwrap_or(path),
+        CryptWipePattern::Random,
+        false,
+    )?;
+    println!(
+        "Destroyed keyslots {:?}, removed tokens {:?}",
+        report.destroyed_keyslots, report.removed_tokens
+    );
+    Ok(())
+}
+
+fn run() -> Result<(), LibcryptErr> {
+    let (command, options) = parse_args()?;
+    match command {
+        Command::LuksFormat(ref path) => luks_format(path, &options),
+        Command::Open(ref path, ref name) => open(path, name, &options),
+        Command::Close(ref name) => close(name, &options),
+        Command::Status(ref name) => status(name, &options),
+        Command::LuksDump(ref path) => luks_dump(path, &options),
+        Command::AddKey(ref path) => add_key(path, &options),
+        Command::RemoveKey(ref path) => remove_key(path, &options),
+        Command::ChangeKey(ref path) => change_key(path, &options),
+        Command::KillSlot(ref path, keyslot) => kill_slot(path, keyslot, &options),
+        Command::TokenImport(ref path) => token_import(path, &options),
+        Command::TokenExport(ref path) => token_export(path, &options),
+        Command::TokenRemove(ref path) => token_remove(path, &options),
+        Command::HeaderBackup(ref path, ref file) => header_backup(path, file, &options),
+        Command::HeaderRestore(ref path, ref file) => header_restore(path, file, &options),
+        Command::Suspend(ref name) => suspend(name, &options),
+        Command::Resume(ref name) => resume(name, &options),
+        Command::Resize(ref name) => resize(name, &options),
+        Command::Reencrypt(ref path) => reencrypt(path, &options),
+        Command::Benchmark => benchmark(&options),
+        Command::Erase(ref path) => erase(path, &options),
+    }
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
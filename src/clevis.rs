@@ -0,0 +1,878 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    fmt::{self, Display},
+    os::raw::c_int,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::LibcryptBackend,
+    err::LibcryptErr,
+    luks2_token::{list_tokens, TokenList},
+};
+
+#[cfg(feature = "clevis")]
+use crate::{activate::CryptActivateFlags, jose, tang};
+
+#[cfg(feature = "clevis")]
+use std::convert::TryFrom;
+
+#[cfg(feature = "clevis")]
+use openssl::bn::{BigNum, BigNumContext};
+
+#[cfg(feature = "clevis")]
+use zeroize::Zeroizing;
+
+/// Token type of clevis enrollments
+pub const CLEVIS_TOKEN_TYPE: &str = "clevis";
+
+/// Size in bytes of the random passphrases and SSS secrets generated by clevis
+#[cfg(feature = "clevis")]
+const CLEVIS_KEY_BYTES: usize = 32;
+
+/// JWE in flattened JSON serialization as stored in clevis tokens
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClevisJwe {
+    /// Base64url encoded protected header
+    pub protected: String,
+    /// Base64url encoded encrypted key, empty for direct encryption and ECDH-ES
+    #[serde(default)]
+    pub encrypted_key: String,
+    /// Base64url encoded initialization vector
+    pub iv: String,
+    /// Base64url encoded ciphertext
+    pub ciphertext: String,
+    /// Base64url encoded authentication tag
+    pub tag: String,
+}
+
+impl ClevisJwe {
+    /// Parse a JWE in compact serialization
+    pub fn from_compact(compact: &str) -> Result<Self, LibcryptErr> {
+        let parts = compact.trim().split('.').collect::<Vec<_>>();
+        if parts.len() != 5 {
+            return Err(LibcryptErr::Other(
+                "Compact JWE must have five parts".to_string(),
+            ));
+        }
+        Ok(ClevisJwe {
+            protected: parts[0].to_string(),
+            encrypted_key: parts[1].to_string(),
+            iv: parts[2].to_string(),
+            ciphertext: parts[3].to_string(),
+            tag: parts[4].to_string(),
+        })
+    }
+
+    /// Convert the JWE to compact serialization as used by `clevis encrypt`
+    pub fn to_compact(&self) -> String {
+        format!(
+            "{}.{}.{}.{}.{}",
+            self.protected, self.encrypted_key, self.iv, self.ciphertext, self.tag
+        )
+    }
+
+    /// Decode the protected header
+    pub fn header(&self) -> Result<serde_json::Value, LibcryptErr> {
+        let header = base64::decode_config(&self.protected, base64::URL_SAFE_NO_PAD)
+            .map_err(|e| LibcryptErr::Other(format!("Invalid JWE header: {}", e)))?;
+        serde_json::from_slice(&header).map_err(LibcryptErr::JsonError)
+    }
+
+    /// Describe the pin policy from the clevis section of the protected header
+    pub fn policy(&self) -> Result<ClevisPolicy, LibcryptErr> {
+        let header = self.header()?;
+        let clevis = header
+            .get("clevis")
+            .ok_or_else(|| LibcryptErr::Other("JWE header has no clevis section".to_string()))?;
+        let pin = clevis
+            .get("pin")
+            .and_then(|p| p.as_str())
+            .ok_or_else(|| LibcryptErr::Other("JWE header has no clevis pin".to_string()))?;
+        match pin {
+            "tang" => Ok(ClevisPolicy::Tang {
+                url: clevis
+                    .get("tang")
+                    .and_then(|t| t.get("url"))
+                    .and_then(|u| u.as_str())
+                    .ok_or_else(|| LibcryptErr::Other("Tang pin has no URL".to_string()))?
+                    .to_string(),
+                kid: header
+                    .get("kid")
+                    .and_then(|k| k.as_str())
+                    .map(|k| k.to_string()),
+            }),
+            "sss" => {
+                let sss = clevis
+                    .get("sss")
+                    .ok_or_else(|| LibcryptErr::Other("SSS pin has no parameters".to_string()))?;
+                let threshold = sss
+                    .get("t")
+                    .and_then(|t| t.as_u64())
+                    .ok_or_else(|| LibcryptErr::Other("SSS pin has no threshold".to_string()))?;
+                let pins = sss
+                    .get("jwe")
+                    .and_then(|j| j.as_array())
+                    .ok_or_else(|| LibcryptErr::Other("SSS pin has no JWEs".to_string()))?
+                    .iter()
+                    .map(|j| {
+                        j.as_str()
+                            .ok_or_else(|| {
+                                LibcryptErr::Other("SSS JWE is not a string".to_string())
+                            })
+                            .and_then(ClevisJwe::from_compact)
+                            .and_then(|jwe| jwe.policy())
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(ClevisPolicy::Sss { threshold, pins })
+            }
+            other => Ok(ClevisPolicy::Other(other.to_string())),
+        }
+    }
+}
+
+/// Pin policy of a clevis JWE
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClevisPolicy {
+    /// Key recovered from a Tang server
+    Tang {
+        /// URL of the Tang server
+        url: String,
+        /// Thumbprint of the exchange key of the server
+        kid: Option<String>,
+    },
+    /// Shamir secret sharing over several pins
+    Sss {
+        /// Number of pins needed to recover the key
+        threshold: u64,
+        /// Pins holding the shares
+        pins: Vec<ClevisPolicy>,
+    },
+    /// Pin not supported by this library, such as `tpm2`
+    Other(String),
+}
+
+impl Display for ClevisPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClevisPolicy::Tang { ref url, .. } => write!(f, "tang({})", url),
+            ClevisPolicy::Sss {
+                threshold,
+                ref pins,
+            } => {
+                write!(f, "sss(t={}", threshold)?;
+                for pin in pins.iter() {
+                    write!(f, ", {}", pin)?;
+                }
+                write!(f, ")")
+            }
+            ClevisPolicy::Other(ref pin) => write!(f, "{}", pin),
+        }
+    }
+}
+
+/// `clevis` LUKS2 token
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClevisToken {
+    /// Keyslots whose passphrase is encrypted in the JWE
+    pub keyslots: Vec<String>,
+    /// JWE encrypting the keyslot passphrase
+    pub jwe: ClevisJwe,
+}
+
+impl ClevisToken {
+    /// Parse token JSON as returned by `CryptLuks2Token::json_get`
+    pub fn from_json(json: &serde_json::Value) -> Result<Self, LibcryptErr> {
+        match json.get("type").and_then(|t| t.as_str()) {
+            Some(CLEVIS_TOKEN_TYPE) => (),
+            _ => {
+                return Err(LibcryptErr::Other(
+                    "Token is not a clevis token".to_string(),
+                ))
+            }
+        }
+        serde_json::from_value(json.clone()).map_err(LibcryptErr::JsonError)
+    }
+
+    /// Convert the token to JSON suitable for `CryptLuks2Token::json_set`
+    pub fn to_json(&self) -> Result<serde_json::Value, LibcryptErr> {
+        let mut value = serde_json::to_value(self).map_err(LibcryptErr::JsonError)?;
+        if let Some(map) = value.as_object_mut() {
+            map.insert(
+                "type".to_string(),
+                serde_json::Value::from(CLEVIS_TOKEN_TYPE),
+            );
+        }
+        Ok(value)
+    }
+
+    /// Get the keyslots unlocked by the token
+    pub fn keyslots(&self) -> Result<Vec<c_int>, LibcryptErr> {
+        self.keyslots
+            .iter()
+            .map(|k| {
+                k.parse::<c_int>()
+                    .map_err(|_| LibcryptErr::Other(format!("Invalid keyslot {}", k)))
+            })
+            .collect()
+    }
+}
+
+/// Pin configuration for binding with clevis
+#[cfg(feature = "clevis")]
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClevisPin {
+    /// Bind to a Tang server
+    Tang {
+        /// URL of the Tang server
+        url: String,
+        /// Trusted thumbprint of a signing key of the server
+        thumbprint: Option<String>,
+        /// Advertisement to use instead of fetching it from the server
+        advertisement: Option<serde_json::Value>,
+    },
+    /// Split the key with Shamir secret sharing
+    Sss {
+        /// Number of pins needed to recover the key
+        threshold: usize,
+        /// Pins holding the shares
+        pins: Vec<ClevisPin>,
+    },
+}
+
+#[cfg(feature = "clevis")]
+impl ClevisPin {
+    /// Parse a pin configuration in the JSON format accepted by `clevis encrypt`
+    ///
+    /// Binding to a Tang server requires either its `thp` or its `adv`, as the interactive
+    /// trust prompt of `clevis` is not available.
+    pub fn from_config(pin: &str, config: &serde_json::Value) -> Result<Self, LibcryptErr> {
+        match pin {
+            "tang" => {
+                let url = config
+                    .get("url")
+                    .and_then(|u| u.as_str())
+                    .ok_or_else(|| LibcryptErr::Other("Tang config has no url".to_string()))?;
+                let thumbprint = config
+                    .get("thp")
+                    .and_then(|t| t.as_str())
+                    .map(|t| t.to_string());
+                let advertisement = config.get("adv").cloned();
+                if thumbprint.is_none() && advertisement.is_none() {
+                    return Err(LibcryptErr::Other(
+                        "Tang config must contain thp or adv to trust the server".to_string(),
+                    ));
+                }
+                Ok(ClevisPin::Tang {
+                    url: url.to_string(),
+                    thumbprint,
+                    advertisement,
+                })
+            }
+            "sss" => {
+                let threshold = config.get("t").and_then(|t| t.as_u64()).unwrap_or(1) as usize;
+                let mut pins = Vec::new();
+                let configured = config
+                    .get("pins")
+                    .and_then(|p| p.as_object())
+                    .ok_or_else(|| LibcryptErr::Other("SSS config has no pins".to_string()))?;
+                for (name, configs) in configured.iter() {
+                    match configs.as_array() {
+                        Some(configs) => {
+                            for c in configs.iter() {
+                                pins.push(ClevisPin::from_config(name, c)?);
+                            }
+                        }
+                        None => pins.push(ClevisPin::from_config(name, configs)?),
+                    }
+                }
+                Ok(ClevisPin::Sss { threshold, pins })
+            }
+            _ => Err(LibcryptErr::Other(format!(
+                "Unsupported clevis pin {}",
+                pin
+            ))),
+        }
+    }
+
+    /// Encrypt `plaintext` with the pin, the equivalent of `clevis encrypt`
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<ClevisJwe, LibcryptErr> {
+        match *self {
+            ClevisPin::Tang {
+                ref url,
+                ref thumbprint,
+                ref advertisement,
+            } => tang::encrypt(
+                url,
+                thumbprint.as_ref().map(|t| t.as_str()),
+                advertisement.as_ref(),
+                plaintext,
+            ),
+            ClevisPin::Sss {
+                threshold,
+                ref pins,
+            } => sss_encrypt(threshold, pins, plaintext),
+        }
+    }
+}
+
+/// Decrypt a clevis JWE, the equivalent of `clevis decrypt`
+#[cfg(feature = "clevis")]
+pub fn clevis_decrypt(jwe: &ClevisJwe) -> Result<Vec<u8>, LibcryptErr> {
+    let header = jwe.header()?;
+    let pin = header
+        .get("clevis")
+        .and_then(|c| c.get("pin"))
+        .and_then(|p| p.as_str())
+        .ok_or_else(|| LibcryptErr::Other("JWE header has no clevis pin".to_string()))?;
+    match pin {
+        "tang" => tang::decrypt(jwe),
+        "sss" => sss_decrypt(jwe, &header),
+        _ => Err(LibcryptErr::Other(format!(
+            "Unsupported clevis pin {}",
+            pin
+        ))),
+    }
+}
+
+#[cfg(feature = "clevis")]
+fn openssl_err(e: openssl::error::ErrorStack) -> LibcryptErr {
+    LibcryptErr::OpensslError(e)
+}
+
+/// Split a random key into shares for `pins` and encrypt `plaintext` with it
+///
+/// The key is the constant term of a random polynomial of degree `threshold - 1` over
+/// a prime field. Each pin encrypts one point of the polynomial.
+#[cfg(feature = "clevis")]
+fn sss_encrypt(
+    threshold: usize,
+    pins: &[ClevisPin],
+    plaintext: &[u8],
+) -> Result<ClevisJwe, LibcryptErr> {
+    if threshold == 0 || threshold > pins.len() {
+        return Err(LibcryptErr::Other(format!(
+            "SSS threshold {} is invalid for {} pins",
+            threshold,
+            pins.len()
+        )));
+    }
+    let mut ctx = BigNumContext::new().map_err(openssl_err)?;
+    let mut p = BigNum::new().map_err(openssl_err)?;
+    p.generate_prime((CLEVIS_KEY_BYTES * 8) as i32, true, None, None)
+        .map_err(openssl_err)?;
+    let mut coefficients = Vec::with_capacity(threshold);
+    for _ in 0..threshold {
+        let mut e = BigNum::new().map_err(openssl_err)?;
+        p.rand_range(&mut e).map_err(openssl_err)?;
+        coefficients.push(e);
+    }
+
+    let mut shares = Vec::with_capacity(pins.len());
+    for pin in pins.iter() {
+        let mut x = BigNum::new().map_err(openssl_err)?;
+        while x.num_bits() == 0 {
+            p.rand_range(&mut x).map_err(openssl_err)?;
+        }
+        // Horner evaluation of the polynomial at x
+        let mut y = BigNum::new().map_err(openssl_err)?;
+        for e in coefficients.iter().rev() {
+            let mut product = BigNum::new().map_err(openssl_err)?;
+            product.mod_mul(&y, &x, &p, &mut ctx).map_err(openssl_err)?;
+            y.mod_add(&product, e, &p, &mut ctx).map_err(openssl_err)?;
+        }
+        let mut point = Zeroizing::new(
+            x.to_vec_padded(CLEVIS_KEY_BYTES as i32)
+                .map_err(openssl_err)?,
+        );
+        point.extend(
+            Zeroizing::new(
+                y.to_vec_padded(CLEVIS_KEY_BYTES as i32)
+                    .map_err(openssl_err)?,
+            )
+            .iter(),
+        );
+        shares.push(serde_json::Value::from(pin.encrypt(&point)?.to_compact()));
+    }
+
+    let key = Zeroizing::new(
+        coefficients[0]
+            .to_vec_padded(CLEVIS_KEY_BYTES as i32)
+            .map_err(openssl_err)?,
+    );
+    let header = serde_json::json!({
+        "alg": "dir",
+        "enc": "A256GCM",
+        "clevis": {
+            "pin": "sss",
+            "sss": {
+                "t": threshold,
+                "p": jose::b64u_encode(&p.to_vec_padded(CLEVIS_KEY_BYTES as i32).map_err(openssl_err)?),
+                "jwe": shares,
+            },
+        },
+    });
+    jose::encrypt(&header, &key, plaintext)
+}
+
+/// Recover the SSS key from `threshold` decrypted shares and decrypt the JWE
+#[cfg(feature = "clevis")]
+fn sss_decrypt(jwe: &ClevisJwe, header: &serde_json::Value) -> Result<Vec<u8>, LibcryptErr> {
+    let sss = header
+        .get("clevis")
+        .and_then(|c| c.get("sss"))
+        .ok_or_else(|| LibcryptErr::Other("SSS pin has no parameters".to_string()))?;
+    let threshold = sss
+        .get("t")
+        .and_then(|t| t.as_u64())
+        .ok_or_else(|| LibcryptErr::Other("SSS pin has no threshold".to_string()))?
+        as usize;
+    let p_bytes = jose::b64u_decode(
+        sss.get("p")
+            .and_then(|p| p.as_str())
+            .ok_or_else(|| LibcryptErr::Other("SSS pin has no prime".to_string()))?,
+    )?;
+    let p = BigNum::from_slice(&p_bytes).map_err(openssl_err)?;
+    let shares = sss
+        .get("jwe")
+        .and_then(|j| j.as_array())
+        .ok_or_else(|| LibcryptErr::Other("SSS pin has no JWEs".to_string()))?;
+
+    let mut points = Vec::new();
+    let mut errors = Vec::new();
+    for share in shares.iter() {
+        if points.len() == threshold {
+            break;
+        }
+        let decrypted = share
+            .as_str()
+            .ok_or_else(|| LibcryptErr::Other("SSS JWE is not a string".to_string()))
+            .and_then(ClevisJwe::from_compact)
+            .and_then(|jwe| clevis_decrypt(&jwe))
+            .map(Zeroizing::new);
+        match decrypted {
+            Ok(point) => {
+                if point.len() != 2 * p_bytes.len() {
+                    errors.push("share has an invalid length".to_string());
+                    continue;
+                }
+                let (x, y) = point.split_at(p_bytes.len());
+                points.push((
+                    BigNum::from_slice(x).map_err(openssl_err)?,
+                    BigNum::from_slice(y).map_err(openssl_err)?,
+                ));
+            }
+            Err(e) => errors.push(e.to_string()),
+        }
+    }
+    if points.len() < threshold {
+        return Err(LibcryptErr::Other(format!(
+            "Only {} of {} required SSS shares could be decrypted: {}",
+            points.len(),
+            threshold,
+            errors.join("; ")
+        )));
+    }
+
+    let key = Zeroizing::new(
+        sss_recover(&p, &points)?
+            .to_vec_padded(p_bytes.len() as i32)
+            .map_err(openssl_err)?,
+    );
+    jose::decrypt(jwe, &key)
+}
+
+/// Lagrange interpolation of the polynomial through `points` at 0
+#[cfg(feature = "clevis")]
+fn sss_recover(p: &BigNum, points: &[(BigNum, BigNum)]) -> Result<BigNum, LibcryptErr> {
+    let mut ctx = BigNumContext::new().map_err(openssl_err)?;
+    let mut secret = BigNum::new().map_err(openssl_err)?;
+    for (i, (xi, yi)) in points.iter().enumerate() {
+        let mut numerator = BigNum::from_u32(1).map_err(openssl_err)?;
+        let mut denominator = BigNum::from_u32(1).map_err(openssl_err)?;
+        for (j, (xj, _)) in points.iter().enumerate() {
+            if i == j {
+                continue;
+            }
+            let mut product = BigNum::new().map_err(openssl_err)?;
+            product
+                .mod_mul(&numerator, xj, p, &mut ctx)
+                .map_err(openssl_err)?;
+            numerator = product;
+
+            let mut difference = BigNum::new().map_err(openssl_err)?;
+            difference
+                .mod_sub(xj, xi, p, &mut ctx)
+                .map_err(openssl_err)?;
+            let mut product = BigNum::new().map_err(openssl_err)?;
+            product
+                .mod_mul(&denominator, &difference, p, &mut ctx)
+                .map_err(openssl_err)?;
+            denominator = product;
+        }
+        let mut inverse = BigNum::new().map_err(openssl_err)?;
+        inverse
+            .mod_inverse(&denominator, p, &mut ctx)
+            .map_err(openssl_err)?;
+        let mut basis = BigNum::new().map_err(openssl_err)?;
+        basis
+            .mod_mul(&numerator, &inverse, p, &mut ctx)
+            .map_err(openssl_err)?;
+        let mut term = BigNum::new().map_err(openssl_err)?;
+        term.mod_mul(yi, &basis, p, &mut ctx).map_err(openssl_err)?;
+        let mut sum = BigNum::new().map_err(openssl_err)?;
+        sum.mod_add(&secret, &term, p, &mut ctx)
+            .map_err(openssl_err)?;
+        secret = sum;
+    }
+    Ok(secret)
+}
+
+/// Handle for clevis LUKS2 tokens
+pub struct CryptClevis;
+
+impl CryptClevis {
+    /// List the clevis tokens of a device with a loaded LUKS2 header
+    ///
+    /// Clevis tokens that cannot be parsed are skipped and reported in
+    /// `TokenList::malformed`.
+    pub fn list<B: LibcryptBackend>(device: &mut B) -> Result<TokenList<ClevisToken>, LibcryptErr> {
+        list_tokens(device, &[CLEVIS_TOKEN_TYPE], ClevisToken::from_json)
+    }
+
+    /// Write a clevis token, allocating a new token if `token` is `None`
+    pub fn write<B: LibcryptBackend>(
+        device: &mut B,
+        token: Option<c_int>,
+        clevis_token: &ClevisToken,
+    ) -> Result<c_int, LibcryptErr> {
+        device.token_json_set(token, &clevis_token.to_json()?)
+    }
+
+    /// Bind a new keyslot to `pin`, the equivalent of `clevis luks bind`
+    ///
+    /// `passphrase` must unlock an existing keyslot. A random passphrase is added in a new
+    /// keyslot and stored encrypted with the pin in a new clevis token. Returns the new
+    /// keyslot and token.
+    #[cfg(feature = "clevis")]
+    pub fn bind<B: LibcryptBackend>(
+        device: &mut B,
+        passphrase: &[u8],
+        pin: &ClevisPin,
+    ) -> Result<(c_int, c_int), LibcryptErr> {
+        let mut key = Zeroizing::new([0u8; CLEVIS_KEY_BYTES]);
+        openssl::rand::rand_bytes(&mut *key).map_err(openssl_err)?;
+        // clevis uses the base64url encoded key as the keyslot passphrase
+        let new_passphrase = Zeroizing::new(jose::b64u_encode(&*key).into_bytes());
+        Self::bind_passphrase(device, passphrase, pin, &new_passphrase)
+    }
+
+    #[cfg(feature = "clevis")]
+    fn bind_passphrase<B: LibcryptBackend>(
+        device: &mut B,
+        passphrase: &[u8],
+        pin: &ClevisPin,
+        new_passphrase: &[u8],
+    ) -> Result<(c_int, c_int), LibcryptErr> {
+        let jwe = pin.encrypt(new_passphrase)?;
+        let keyslot = device.keyslot_add_by_passphrase(None, passphrase, new_passphrase)?;
+        let clevis_token = ClevisToken {
+            keyslots: vec![keyslot.to_string()],
+            jwe,
+        };
+        match Self::write(device, None, &clevis_token) {
+            Ok(token) => Ok((keyslot, token)),
+            Err(e) => {
+                device.keyslot_destroy(keyslot)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Unlock the device with the first clevis token that can be decrypted, the
+    /// equivalent of `clevis luks unlock`
+    ///
+    /// Passing `None` as `name` only checks that a token unlocks the device. Returns the
+    /// keyslot that was unlocked.
+    #[cfg(feature = "clevis")]
+    pub fn unlock<B: LibcryptBackend>(
+        device: &mut B,
+        name: Option<&str>,
+        flags: CryptActivateFlags,
+    ) -> Result<c_int, LibcryptErr> {
+        let flags: u32 = flags.into();
+        let list = Self::list(device)?;
+        let mut errors = list
+            .malformed
+            .iter()
+            .map(|(token, e)| format!("token {}: {}", token, e))
+            .collect::<Vec<_>>();
+        for (token, clevis_token) in list.tokens {
+            let passphrase = match clevis_decrypt(&clevis_token.jwe) {
+                Ok(p) => Zeroizing::new(p),
+                Err(e) => {
+                    errors.push(format!("token {}: {}", token, e));
+                    continue;
+                }
+            };
+            let mut result = Err(LibcryptErr::Other("Token has no keyslots".to_string()));
+            for keyslot in clevis_token.keyslots()? {
+                result = device.activate_by_passphrase(
+                    name,
+                    Some(keyslot),
+                    &passphrase,
+                    CryptActivateFlags::try_from(flags)?,
+                );
+                if result.is_ok() {
+                    break;
+                }
+            }
+            match result {
+                Ok(keyslot) => return Ok(keyslot),
+                Err(e) => errors.push(format!("token {}: {}", token, e)),
+            }
+        }
+        Err(LibcryptErr::Other(if errors.is_empty() {
+            "Device has no clevis tokens".to_string()
+        } else {
+            format!("No clevis token unlocked the device: {}", errors.join("; "))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::backend::SimulatedBackend;
+
+    #[cfg(feature = "clevis")]
+    use crate::{backend::SimulatedOperation, keyslot::KeyslotInfo};
+
+    fn formatted() -> SimulatedBackend {
+        let mut backend = SimulatedBackend::new();
+        backend.format_luks2(("aes", "xts-plain64"), 64).unwrap();
+        backend
+            .keyslot_add_by_passphrase(None, b"", b"first")
+            .unwrap();
+        backend
+    }
+
+    #[test]
+    fn test_policy() {
+        let tang = |url: &str| {
+            let header = serde_json::json!({
+                "alg": "ECDH-ES",
+                "enc": "A256GCM",
+                "kid": "kid",
+                "clevis": {"pin": "tang", "tang": {"url": url}},
+            });
+            ClevisJwe {
+                protected: base64::encode_config(
+                    &serde_json::to_vec(&header).unwrap(),
+                    base64::URL_SAFE_NO_PAD,
+                ),
+                encrypted_key: String::new(),
+                iv: String::new(),
+                ciphertext: String::new(),
+                tag: String::new(),
+            }
+        };
+        let header = serde_json::json!({
+            "alg": "dir",
+            "enc": "A256GCM",
+            "clevis": {
+                "pin": "sss",
+                "sss": {
+                    "t": 1,
+                    "p": "",
+                    "jwe": [
+                        tang("http://tang1").to_compact(),
+                        tang("http://tang2").to_compact(),
+                    ],
+                },
+            },
+        });
+        let jwe = ClevisJwe::from_compact(&format!(
+            "{}....",
+            base64::encode_config(
+                &serde_json::to_vec(&header).unwrap(),
+                base64::URL_SAFE_NO_PAD
+            )
+        ))
+        .unwrap();
+        let policy = jwe.policy().unwrap();
+        assert_eq!(
+            policy.to_string(),
+            "sss(t=1, tang(http://tang1), tang(http://tang2))"
+        );
+        match policy {
+            ClevisPolicy::Sss { ref pins, .. } => assert_eq!(
+                pins[0],
+                ClevisPolicy::Tang {
+                    url: "http://tang1".to_string(),
+                    kid: Some("kid".to_string())
+                }
+            ),
+            _ => panic!("Expected an SSS policy"),
+        }
+        assert!(ClevisJwe::from_compact("a.b.c").is_err());
+    }
+
+    #[test]
+    fn test_token_json() {
+        let json = serde_json::json!({
+            "type": "clevis",
+            "keyslots": ["1"],
+            "jwe": {
+                "protected": "eyJhbGciOiJkaXIifQ",
+                "encrypted_key": "",
+                "iv": "aXY",
+                "ciphertext": "Y3Q",
+                "tag": "dGFn",
+            },
+        });
+        let token = ClevisToken::from_json(&json).unwrap();
+        assert_eq!(token.keyslots().unwrap(), vec![1]);
+        assert_eq!(token.to_json().unwrap(), json);
+        assert!(ClevisToken::from_json(&serde_json::json!({"type": "luks2-keyring"})).is_err());
+    }
+
+    #[test]
+    fn test_list_write() {
+        let mut backend = formatted();
+        let clevis_token = ClevisToken {
+            keyslots: vec!["0".to_string()],
+            jwe: ClevisJwe::from_compact("eyJhbGciOiJkaXIifQ..aXY.Y3Q.dGFn").unwrap(),
+        };
+        backend
+            .token_json_set(
+                None,
+                &serde_json::json!({"type": "luks2-keyring", "keyslots": ["0"]}),
+            )
+            .unwrap();
+        let token = CryptClevis::write(&mut backend, None, &clevis_token).unwrap();
+        let malformed = backend
+            .token_json_set(
+                None,
+                &serde_json::json!({"type": CLEVIS_TOKEN_TYPE, "keyslots": ["0"]}),
+            )
+            .unwrap();
+        let list = CryptClevis::list(&mut backend).unwrap();
+        assert_eq!(list.tokens, vec![(token, clevis_token.clone())]);
+        assert_eq!(
+            list.malformed.iter().map(|(t, _)| *t).collect::<Vec<_>>(),
+            vec![malformed]
+        );
+        assert_eq!(
+            CryptClevis::write(&mut backend, Some(token), &clevis_token).unwrap(),
+            token
+        );
+        assert_eq!(CryptClevis::list(&mut backend).unwrap().tokens.len(), 1);
+    }
+
+    #[cfg(feature = "clevis")]
+    #[test]
+    fn test_sss_recover() {
+        let p = BigNum::from_u32(7919).unwrap();
+        // f(x) = 1234 + 166x + 94x^2 mod 7919
+        let f = |x: u32| (1234 + 166 * x + 94 * x * x) % 7919;
+        let points = [2u32, 4, 5]
+            .iter()
+            .map(|x| {
+                (
+                    BigNum::from_u32(*x).unwrap(),
+                    BigNum::from_u32(f(*x)).unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sss_recover(&p, &points).unwrap(),
+            BigNum::from_u32(1234).unwrap()
+        );
+    }
+
+    #[cfg(feature = "clevis")]
+    #[test]
+    fn test_tang_and_sss_round_trip() {
+        let server = crate::tests::tang::TangServer::start();
+        let other = crate::tests::tang::TangServer::start();
+        let tang = ClevisPin::from_config(
+            "tang",
+            &serde_json::json!({"url": server.url(), "thp": server.thumbprint()}),
+        )
+        .unwrap();
+        let jwe = tang.encrypt(b"secret").unwrap();
+        assert_eq!(
+            jwe.policy().unwrap(),
+            ClevisPolicy::Tang {
+                url: server.url(),
+                kid: jwe.header().unwrap()["kid"].as_str().map(|k| k.to_string()),
+            }
+        );
+        assert_eq!(clevis_decrypt(&jwe).unwrap(), b"secret");
+
+        let sss = ClevisPin::from_config(
+            "sss",
+            &serde_json::json!({
+                "t": 1,
+                "pins": {
+                    "tang": [
+                        {"url": "http://127.0.0.1:1", "adv": server.advertisement()},
+                        {"url": other.url(), "thp": other.thumbprint()},
+                    ],
+                },
+            }),
+        )
+        .unwrap();
+        let jwe = sss.encrypt(b"secret").unwrap();
+        assert_eq!(clevis_decrypt(&jwe).unwrap(), b"secret");
+
+        let sss = ClevisPin::Sss {
+            threshold: 2,
+            pins: vec![tang.clone(), tang],
+        };
+        let jwe = sss.encrypt(b"secret").unwrap();
+        assert_eq!(clevis_decrypt(&jwe).unwrap(), b"secret");
+        server.stop();
+        assert!(clevis_decrypt(&jwe).is_err());
+        other.stop();
+
+        assert!(ClevisPin::from_config("tang", &serde_json::json!({"url": "http://x"})).is_err());
+    }
+
+    #[cfg(feature = "clevis")]
+    #[test]
+    fn test_bind_unlock() {
+        let server = crate::tests::tang::TangServer::start();
+        let pin = ClevisPin::from_config(
+            "tang",
+            &serde_json::json!({"url": server.url(), "thp": server.thumbprint()}),
+        )
+        .unwrap();
+        let mut backend = formatted();
+        assert!(CryptClevis::unlock(&mut backend, None, CryptActivateFlags::empty()).is_err());
+
+        let (keyslot, token) = CryptClevis::bind(&mut backend, b"first", &pin).unwrap();
+        assert_eq!(CryptClevis::list(&mut backend).unwrap().tokens[0].0, token);
+        assert_eq!(
+            CryptClevis::unlock(&mut backend, None, CryptActivateFlags::empty()).unwrap(),
+            keyslot
+        );
+
+        // The new keyslot is removed again if the token cannot be written
+        backend.fail_next(SimulatedOperation::TokenSet, libc::ENOSPC);
+        assert!(CryptClevis::bind(&mut backend, b"first", &pin).is_err());
+        assert!(matches!(
+            backend.keyslot_status(keyslot + 1).unwrap(),
+            KeyslotInfo::Inactive
+        ));
+
+        server.stop();
+        assert!(CryptClevis::unlock(&mut backend, None, CryptActivateFlags::empty()).is_err());
+    }
+}
@@ -80,11 +80,19 @@ impl<'a> CryptContext<'a> {
 
     /// Set UUID of crypt device
     pub fn set_uuid(&mut self, uuid: Option<Uuid>) -> Result<(), LibcryptErr> {
-        let uptr = match uuid {
-            Some(u) => u.as_bytes().as_ptr() as *const c_char,
-            None => std::ptr::null(),
+        let uuid_cstring = match uuid {
+            Some(u) => Some(to_cstring!(u.to_hyphenated().to_string())?),
+            None => None,
         };
-        errno!(unsafe { libcryptsetup_rs_sys::crypt_set_uuid(self.reference.as_ptr(), uptr) })
+        errno!(unsafe {
+            libcryptsetup_rs_sys::crypt_set_uuid(
+                self.reference.as_ptr(),
+                uuid_cstring
+                    .as_ref()
+                    .map(|cs| cs.as_ptr())
+                    .unwrap_or_else(std::ptr::null),
+            )
+        })
     }
 
     /// Set LUKS2 device label
@@ -102,8 +110,14 @@ impl<'a> CryptContext<'a> {
         errno!(unsafe {
             libcryptsetup_rs_sys::crypt_set_label(
                 self.reference.as_ptr(),
-                lcstring.map(|cs| cs.as_ptr()).unwrap_or(ptr::null()),
-                slcstring.map(|cs| cs.as_ptr()).unwrap_or(ptr::null()),
+                lcstring
+                    .as_ref()
+                    .map(|cs| cs.as_ptr())
+                    .unwrap_or_else(ptr::null),
+                slcstring
+                    .as_ref()
+                    .map(|cs| cs.as_ptr())
+                    .unwrap_or_else(ptr::null),
             )
         })
     }
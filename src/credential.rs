@@ -0,0 +1,137 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{convert::TryFrom, os::raw::c_int};
+
+use zeroize::Zeroizing;
+
+use crate::{
+    activate::CryptActivateFlags,
+    device::CryptDevice,
+    err::LibcryptErr,
+    luks2_token::{token_keyslots, CryptTokenInfo, LUKS2_KEYRING_TOKEN_TYPE},
+};
+
+/// Credential used to unlock the volume key of a LUKS device
+pub enum CryptCredential<'a> {
+    /// Passphrase for a keyslot
+    Passphrase {
+        /// Passphrase unlocking the keyslot
+        passphrase: &'a [u8],
+        /// Keyslot to try or `None` to try all keyslots
+        keyslot: Option<c_int>,
+    },
+    /// Passphrase stored in the kernel keyring
+    Keyring {
+        /// Description of the key in the kernel keyring
+        key_description: &'a str,
+        /// Keyslot to try or `None` to try all keyslots
+        keyslot: Option<c_int>,
+    },
+    /// Volume keys of the device, in any order
+    ///
+    /// A device has more than one volume key only while it is being reencrypted, in which
+    /// case the keys of all segments are required.
+    VolumeKeys(&'a [&'a [u8]]),
+    /// Token providing the passphrase of the keyslots it is assigned to
+    ///
+    /// `luks2-keyring` tokens are resolved through the kernel keyring and other tokens
+    /// through the handler registered with `CryptLuks2Token::register`. If the token is
+    /// assigned to a single keyslot, only that keyslot is tried.
+    Token(c_int),
+}
+
+impl<'a> CryptCredential<'a> {
+    /// Activate the device or, if `name` is `None`, only unlock its volume key
+    pub(crate) fn activate(
+        &self,
+        device: &mut CryptDevice,
+        name: Option<&str>,
+        flags: CryptActivateFlags,
+    ) -> Result<c_int, LibcryptErr> {
+        match *self {
+            CryptCredential::Passphrase {
+                passphrase,
+                keyslot,
+            } => device
+                .activate_handle()
+                .activate_by_passphrase(name, keyslot, passphrase, flags),
+            CryptCredential::Keyring {
+                key_description,
+                keyslot,
+            } => {
+                device
+                    .activate_handle()
+                    .activate_by_keyring(name, key_description, keyslot, flags)
+            }
+            CryptCredential::VolumeKeys(volume_keys) => {
+                let flags: u32 = flags.into();
+                let mut result = Err(LibcryptErr::Other("No volume key given".to_string()));
+                for volume_key in volume_keys.iter() {
+                    result = device.activate_handle().activate_by_volume_key(
+                        name,
+                        Some(volume_key),
+                        CryptActivateFlags::try_from(flags)?,
+                    );
+                    if result.is_ok() {
+                        break;
+                    }
+                }
+                result.map(|_| 0)
+            }
+            CryptCredential::Token(token) => device
+                .token_handle(token)
+                .activate_by_token::<()>(name, token, None, flags),
+        }
+    }
+}
+
+/// Passphrase source a token resolves to
+pub(crate) enum ResolvedToken {
+    /// Passphrase stored in the kernel keyring
+    Keyring {
+        key_description: String,
+        keyslot: Option<c_int>,
+    },
+    /// Passphrase returned by the registered token handler
+    Passphrase {
+        passphrase: Zeroizing<Vec<u8>>,
+        keyslot: Option<c_int>,
+    },
+}
+
+/// Resolve a token to the passphrase it provides, for operations libcryptsetup does not
+/// accept tokens for
+pub(crate) fn resolve_token(
+    device: &mut CryptDevice,
+    token: c_int,
+) -> Result<ResolvedToken, LibcryptErr> {
+    let mut handle = device.token_handle(token);
+    let (status, type_) = handle.status()?;
+    let keyslot = match status {
+        CryptTokenInfo::Invalid | CryptTokenInfo::Inactive => {
+            return Err(LibcryptErr::Other(format!("Token {} is not active", token)))
+        }
+        _ => match token_keyslots(&handle.json_get()?).as_slice() {
+            [keyslot] => Some(*keyslot),
+            _ => None,
+        },
+    };
+    match status {
+        CryptTokenInfo::Internal if type_ == LUKS2_KEYRING_TOKEN_TYPE => {
+            Ok(ResolvedToken::Keyring {
+                key_description: handle.luks2_keyring_get()?,
+                keyslot,
+            })
+        }
+        CryptTokenInfo::External => Ok(ResolvedToken::Passphrase {
+            passphrase: handle.handler_passphrase()?,
+            keyslot,
+        }),
+        _ => Err(LibcryptErr::Other(format!(
+            "No handler is registered for token type {}",
+            type_
+        ))),
+    }
+}
@@ -4,6 +4,8 @@
 
 use std::{
     ffi::CString,
+    fs::File,
+    io::{Seek, SeekFrom},
     os::raw::{c_char, c_int, c_void},
     path::Path,
     ptr,
@@ -14,13 +16,21 @@ use libcryptsetup_rs_sys::crypt_device;
 use either::Either;
 
 use crate::{
-    activate::CryptActivation, backup::CryptBackup, context::CryptContext, debug::CryptDebug,
-    err::LibcryptErr, format::CryptFormat, key::CryptVolumeKey, keyfile::CryptKeyfile,
-    keyslot::CryptKeyslot, log::CryptLog, luks2_flags::CryptLuks2Flags,
-    luks2_reencrypt::CryptLuks2Reencrypt, luks2_token::CryptLuks2Token, runtime::CryptRuntime,
-    settings::CryptSettings, status::CryptDeviceStatus, wipe::CryptWipe,
+    activate::CryptActivation, backup::CryptBackup, benchmark::CryptBenchmark,
+    context::CryptContext, debug::CryptDebug, err::LibcryptErr, format::CryptFormat,
+    integrity::CryptIntegrity, key::CryptVolumeKey, keyfile::CryptKeyfile, keyslot::CryptKeyslot,
+    log::CryptLog, luks2_flags::CryptLuks2Flags, luks2_reencrypt::CryptLuks2Reencrypt,
+    luks2_token::CryptLuks2Token, reencrypt_resume::CryptReencryptResume, runtime::CryptRuntime,
+    settings::CryptSettings, status::CryptDeviceStatus, tcrypt::CryptTcrypt, wipe::CryptWipe,
 };
 
+/// Get the size in bytes of a block device or file
+pub(crate) fn device_size(path: &Path) -> Result<u64, LibcryptErr> {
+    File::open(path)
+        .and_then(|mut f| f.seek(SeekFrom::End(0)))
+        .map_err(LibcryptErr::IOError)
+}
+
 type ConfirmCallback = unsafe extern "C" fn(msg: *const c_char, usrptr: *mut c_void) -> c_int;
 
 /// Initialization handle for devices
@@ -165,6 +175,11 @@ impl CryptDevice {
         CryptDebug
     }
 
+    /// Get crypt benchmark option handle
+    pub fn benchmark_handle() -> CryptBenchmark {
+        CryptBenchmark
+    }
+
     /// Get crypt device keyfile option handle
     pub fn keyfile_handle(&mut self) -> CryptKeyfile {
         CryptKeyfile::new(self)
@@ -185,6 +200,21 @@ impl CryptDevice {
         CryptLuks2Reencrypt::new(self)
     }
 
+    /// Get crypt device reencryption recovery and resume option handle
+    pub fn reencrypt_resume_handle(&mut self) -> CryptReencryptResume {
+        CryptReencryptResume::new(self)
+    }
+
+    /// Get crypt device standalone integrity option handle
+    pub fn integrity_handle(&mut self) -> CryptIntegrity {
+        CryptIntegrity::new(self)
+    }
+
+    /// Get crypt device TrueCrypt and VeraCrypt option handle
+    pub fn tcrypt_handle(&mut self) -> CryptTcrypt {
+        CryptTcrypt::new(self)
+    }
+
     /// Set the callback that prompts the user to confirm an action
     pub fn set_confirm_callback<T>(
         &mut self,
@@ -0,0 +1,314 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    fmt::{self, Display},
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use uuid::Uuid;
+
+use crate::{err::LibcryptErr, luks2_header};
+
+/// Directory listing all block devices and partitions
+const SYS_CLASS_BLOCK: &str = "/sys/class/block";
+/// Magic of a dm-verity superblock
+const VERITY_MAGIC: &[u8] = b"verity\0\0";
+/// Magic of a dm-integrity superblock
+const INTEGRITY_MAGIC: &[u8] = b"integrt\0";
+/// Number of bytes read to probe for verity and integrity superblocks
+const PROBE_SIZE: usize = 512;
+
+/// Format of a discovered volume
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiscoveredFormat {
+    /// LUKS1 header
+    Luks1,
+    /// LUKS2 header
+    Luks2,
+    /// dm-verity hash device superblock
+    Verity,
+    /// dm-integrity superblock
+    Integrity,
+}
+
+impl DiscoveredFormat {
+    /// Prefix of the device-mapper UUIDs libcryptsetup uses for mappings of this format
+    fn dm_uuid_prefix(self) -> &'static str {
+        match self {
+            DiscoveredFormat::Luks1 | DiscoveredFormat::Luks2 => "CRYPT-LUKS",
+            DiscoveredFormat::Verity => "CRYPT-VERITY",
+            DiscoveredFormat::Integrity => "CRYPT-INTEGRITY",
+        }
+    }
+}
+
+impl Display for DiscoveredFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DiscoveredFormat::Luks1 => write!(f, "LUKS1"),
+            DiscoveredFormat::Luks2 => write!(f, "LUKS2"),
+            DiscoveredFormat::Verity => write!(f, "VERITY"),
+            DiscoveredFormat::Integrity => write!(f, "INTEGRITY"),
+        }
+    }
+}
+
+/// Volume found by probing a device or image file
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiscoveredVolume {
+    /// Path of the device or image file
+    pub path: PathBuf,
+    /// Detected format
+    pub format: DiscoveredFormat,
+    /// UUID, which dm-integrity superblocks do not have
+    pub uuid: Option<String>,
+    /// LUKS2 label
+    pub label: Option<String>,
+    /// LUKS2 subsystem
+    pub subsystem: Option<String>,
+    /// Names of the active device-mapper mappings of the volume
+    pub active_mappings: Vec<String>,
+}
+
+impl DiscoveredVolume {
+    /// Whether the volume has an active mapping
+    pub fn is_active(&self) -> bool {
+        !self.active_mappings.is_empty()
+    }
+}
+
+/// Handle for discovering encrypted and integrity protected volumes
+pub struct CryptDiscovery;
+
+impl CryptDiscovery {
+    /// Probe every block device in `/sys/class/block` and the given image files
+    ///
+    /// Devices that cannot be read, for example because of missing permissions, are
+    /// skipped. Image files that cannot be read are reported as errors.
+    pub fn scan(images: &[&Path]) -> Result<Vec<DiscoveredVolume>, LibcryptErr> {
+        let mut volumes = Vec::new();
+        let mut names = fs::read_dir(SYS_CLASS_BLOCK)
+            .map_err(LibcryptErr::IOError)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            let sys_path = Path::new(SYS_CLASS_BLOCK).join(&name);
+            let size = fs::read_to_string(sys_path.join("size"))
+                .ok()
+                .and_then(|s| s.trim().parse::<u64>().ok())
+                .unwrap_or(0);
+            if size == 0 {
+                continue;
+            }
+            if let Ok(Some(mut volume)) = Self::probe(&Path::new("/dev").join(&name)) {
+                for holder in holder_mappings(&sys_path, volume.format) {
+                    if !volume.active_mappings.contains(&holder) {
+                        volume.active_mappings.push(holder);
+                    }
+                }
+                volumes.push(volume);
+            }
+        }
+        for image in images {
+            if let Some(volume) = Self::probe(image)? {
+                volumes.push(volume);
+            }
+        }
+        Ok(volumes)
+    }
+
+    /// Find the volume with the given UUID among the devices and image files probed by
+    /// `scan`
+    pub fn find_by_uuid(
+        uuid: &str,
+        images: &[&Path],
+    ) -> Result<Option<DiscoveredVolume>, LibcryptErr> {
+        let uuid = uuid.to_lowercase();
+        Ok(Self::scan(images)?
+            .into_iter()
+            .find(|v| v.uuid.as_ref() == Some(&uuid)))
+    }
+
+    /// Probe a single device or image file, returning `None` if no signature is found
+    pub fn probe(path: &Path) -> Result<Option<DiscoveredVolume>, LibcryptErr> {
+        let mut buf = vec![0u8; PROBE_SIZE];
+        let mut file = File::open(path).map_err(LibcryptErr::IOError)?;
+        let read = file.read(&mut buf).map_err(LibcryptErr::IOError)?;
+        buf.truncate(read);
+
+        let (format, uuid, label, subsystem) = match luks2_header::read_version(path) {
+            Ok(1) => (
+                DiscoveredFormat::Luks1,
+                Some(luks2_header::read_luks1(path)?.uuid),
+                None,
+                None,
+            ),
+            Ok(2) => {
+                let binary = luks2_header::read_luks2(path)?.binary;
+                (
+                    DiscoveredFormat::Luks2,
+                    Some(binary.uuid),
+                    non_empty(binary.label),
+                    non_empty(binary.subsystem),
+                )
+            }
+            Ok(_) => return Ok(None),
+            Err(_) => match probe_superblock(&buf) {
+                Some((format, uuid)) => (format, uuid, None, None),
+                None => return Ok(None),
+            },
+        };
+        let active_mappings = match uuid {
+            Some(ref uuid) => active_mappings(format.dm_uuid_prefix(), uuid),
+            None => Vec::new(),
+        };
+        Ok(Some(DiscoveredVolume {
+            path: path.to_path_buf(),
+            format,
+            uuid,
+            label,
+            subsystem,
+            active_mappings,
+        }))
+    }
+}
+
+fn non_empty(s: String) -> Option<String> {
+    if s.is_empty() {
+        None
+    } else {
+        Some(s)
+    }
+}
+
+/// Detect dm-verity and dm-integrity superblocks
+fn probe_superblock(buf: &[u8]) -> Option<(DiscoveredFormat, Option<String>)> {
+    if buf.len() >= 32 && &buf[0..8] == VERITY_MAGIC {
+        let mut uuid = [0u8; 16];
+        uuid.copy_from_slice(&buf[16..32]);
+        Some((
+            DiscoveredFormat::Verity,
+            Some(Uuid::from_bytes(uuid).to_string()),
+        ))
+    } else if buf.len() >= 8 && &buf[0..8] == INTEGRITY_MAGIC {
+        Some((DiscoveredFormat::Integrity, None))
+    } else {
+        None
+    }
+}
+
+/// Find the names of device-mapper devices of the given dm UUID prefix created from the
+/// volume with `uuid`
+pub(crate) fn active_mappings(prefix: &str, uuid: &str) -> Vec<String> {
+    let needle = format!("-{}-", uuid.replace("-", ""));
+    let entries = match fs::read_dir("/sys/block") {
+        Ok(e) => e,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path().join("dm"))
+        .filter_map(|dm| {
+            let dm_uuid = fs::read_to_string(dm.join("uuid")).ok()?;
+            if dm_uuid.starts_with(prefix) && dm_uuid.contains(&needle) {
+                fs::read_to_string(dm.join("name"))
+                    .ok()
+                    .map(|n| n.trim().to_string())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Find the names of device-mapper devices of the given format stacked on a block device
+fn holder_mappings(sys_path: &Path, format: DiscoveredFormat) -> Vec<String> {
+    let entries = match fs::read_dir(sys_path.join("holders")) {
+        Ok(e) => e,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path().join("dm"))
+        .filter_map(|dm| {
+            let dm_uuid = fs::read_to_string(dm.join("uuid")).ok()?;
+            if dm_uuid.starts_with(format.dm_uuid_prefix()) {
+                fs::read_to_string(dm.join("name"))
+                    .ok()
+                    .map(|n| n.trim().to_string())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Write;
+
+    #[test]
+    fn test_probe_superblock() {
+        let mut verity = vec![0u8; PROBE_SIZE];
+        verity[0..8].copy_from_slice(VERITY_MAGIC);
+        verity[8..12].copy_from_slice(&1u32.to_le_bytes());
+        verity[16..32].copy_from_slice(&[
+            0x6f, 0x2a, 0x3b, 0x5c, 0x1d, 0x4e, 0x4f, 0x60, 0x81, 0x92, 0xa3, 0xb4, 0xc5, 0xd6,
+            0xe7, 0xf8,
+        ]);
+        assert_eq!(
+            probe_superblock(&verity),
+            Some((
+                DiscoveredFormat::Verity,
+                Some("6f2a3b5c-1d4e-4f60-8192-a3b4c5d6e7f8".to_string())
+            ))
+        );
+
+        let mut integrity = vec![0u8; PROBE_SIZE];
+        integrity[0..8].copy_from_slice(INTEGRITY_MAGIC);
+        assert_eq!(
+            probe_superblock(&integrity),
+            Some((DiscoveredFormat::Integrity, None))
+        );
+
+        assert_eq!(probe_superblock(&[0u8; PROBE_SIZE]), None);
+        assert_eq!(probe_superblock(b"verity"), None);
+    }
+
+    #[test]
+    fn test_probe_image() {
+        let path = std::env::temp_dir().join(format!(
+            "libcryptsetup-rs-discover-image-{}-{:x}",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        let mut buf = vec![0u8; 4096];
+        buf[0..8].copy_from_slice(INTEGRITY_MAGIC);
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .and_then(|mut f| f.write_all(&buf))
+            .unwrap();
+        let volume = CryptDiscovery::probe(&path).unwrap().unwrap();
+        assert_eq!(volume.format, DiscoveredFormat::Integrity);
+        assert_eq!(volume.uuid, None);
+        assert!(!volume.is_active());
+
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .and_then(|mut f| f.write_all(&[0u8; 4096]))
+            .unwrap();
+        assert_eq!(CryptDiscovery::probe(&path).unwrap(), None);
+        fs::remove_file(&path).unwrap();
+    }
+}
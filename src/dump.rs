@@ -0,0 +1,646 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    path::Path,
+};
+
+use serde::Serialize;
+
+use crate::{
+    err::LibcryptErr,
+    luks2_header::{self, json_ids, json_strings, json_u64, Luks1Header},
+};
+
+/// Keyslot of a device dump
+#[derive(Debug, Serialize)]
+pub struct DumpKeyslot {
+    /// Keyslot type such as `luks2`
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// Size of the volume key stored in the keyslot in bytes
+    pub key_size: u64,
+    /// Keyslot priority if set
+    pub priority: Option<String>,
+    /// Cipher used to encrypt the keyslot area
+    pub cipher: Option<String>,
+    /// Key size of the keyslot area cipher in bytes
+    pub cipher_key_size: Option<u64>,
+    /// Key derivation function
+    pub pbkdf: String,
+    /// Hash used by PBKDF2
+    pub hash: Option<String>,
+    /// PBKDF2 iterations
+    pub iterations: Option<u64>,
+    /// Argon2 time cost
+    pub time_cost: Option<u64>,
+    /// Argon2 memory cost in KiB
+    pub memory: Option<u64>,
+    /// Argon2 parallel threads
+    pub threads: Option<u64>,
+    /// Base64 encoded KDF salt
+    pub salt: String,
+    /// Number of anti-forensic stripes
+    pub af_stripes: Option<u64>,
+    /// Hash used by the anti-forensic splitter
+    pub af_hash: Option<String>,
+    /// Offset of the keyslot area in bytes
+    pub area_offset: u64,
+    /// Length of the keyslot area in bytes
+    pub area_length: u64,
+    /// Digests that this keyslot is assigned to
+    pub digests: Vec<u32>,
+}
+
+/// Token of a device dump
+#[derive(Debug, Serialize)]
+pub struct DumpToken {
+    /// Token type such as `luks2-keyring`
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// Keyslots the token is assigned to
+    pub keyslots: Vec<u32>,
+    /// Complete token JSON
+    pub json: serde_json::Value,
+}
+
+/// Digest of a device dump
+#[derive(Debug, Serialize)]
+pub struct DumpDigest {
+    /// Digest type such as `pbkdf2`
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// Hash used to compute the digest
+    pub hash: Option<String>,
+    /// Iterations used to compute the digest
+    pub iterations: Option<u64>,
+    /// Base64 encoded salt
+    pub salt: String,
+    /// Base64 encoded digest
+    pub digest: String,
+    /// Keyslots verified by this digest
+    pub keyslots: Vec<u32>,
+    /// Segments verified by this digest
+    pub segments: Vec<u32>,
+}
+
+/// Data segment of a device dump
+#[derive(Debug, Serialize)]
+pub struct DumpSegment {
+    /// Segment type such as `crypt` or `linear`
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// Offset of the segment on the data device in bytes
+    pub offset: u64,
+    /// Size of the segment in bytes or `None` if it extends to the end of the device
+    pub size: Option<u64>,
+    /// Initialization vector tweak
+    pub iv_tweak: u64,
+    /// Segment cipher
+    pub cipher: Option<String>,
+    /// Encryption sector size in bytes
+    pub sector_size: Option<u64>,
+    /// Integrity algorithm
+    pub integrity: Option<String>,
+    /// Segment flags
+    pub flags: Vec<String>,
+}
+
+/// Structured representation of LUKS header metadata
+#[derive(Debug, Serialize)]
+pub struct DeviceDump {
+    /// Header format, either `LUKS1` or `LUKS2`
+    pub format: String,
+    /// Header version
+    pub version: u16,
+    /// Header sequence ID, incremented on every metadata update (always 0 for LUKS1)
+    pub epoch: u64,
+    /// Device UUID
+    pub uuid: String,
+    /// Device label
+    pub label: Option<String>,
+    /// Device subsystem
+    pub subsystem: Option<String>,
+    /// Cipher specification of the data segment
+    pub cipher: String,
+    /// Encryption sector size in bytes
+    pub sector_size: u64,
+    /// Offset of the data segment in bytes
+    pub data_offset: u64,
+    /// Size of each metadata area copy in bytes
+    pub metadata_size: u64,
+    /// Size of the keyslots area in bytes
+    pub keyslots_size: u64,
+    /// Persistent activation flags
+    pub flags: Vec<String>,
+    /// Mandatory requirements
+    pub requirements: Vec<String>,
+    /// Active keyslots
+    pub keyslots: BTreeMap<u32, DumpKeyslot>,
+    /// Tokens
+    pub tokens: BTreeMap<u32, DumpToken>,
+    /// Digests
+    pub digests: BTreeMap<u32, DumpDigest>,
+    /// Segments
+    pub segments: BTreeMap<u32, DumpSegment>,
+}
+
+fn json_string(value: &serde_json::Value) -> Option<String> {
+    value.as_str().map(|s| s.to_string())
+}
+
+fn priority_name(priority: u64) -> String {
+    match priority {
+        0 => "ignored".to_string(),
+        1 => "normal".to_string(),
+        2 => "preferred".to_string(),
+        p => p.to_string(),
+    }
+}
+
+/// Iterate over the entries of a LUKS2 JSON object keyed by numeric IDs
+fn json_objects(value: &serde_json::Value) -> Vec<(u32, &serde_json::Value)> {
+    value
+        .as_object()
+        .map(|o| {
+            o.iter()
+                .filter_map(|(k, v)| k.parse::<u32>().ok().map(|id| (id, v)))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+impl DeviceDump {
+    /// Read the header metadata of the device containing a LUKS header
+    pub(crate) fn read(metadata_device: &Path) -> Result<Self, LibcryptErr> {
+        match luks2_header::read_version(metadata_device)? {
+            1 => Ok(Self::from_luks1(&luks2_header::read_luks1(
+                metadata_device,
+            )?)),
+            2 => {
+                let header = luks2_header::read_luks2(metadata_device)?;
+                let json = header.json()?;
+                Self::from_luks2_json(
+                    header.binary.seqid,
+                    header.binary.hdr_size,
+                    &header.binary.uuid,
+                    &header.binary.label,
+                    &header.binary.subsystem,
+                    &json,
+                )
+            }
+            v => Err(LibcryptErr::Other(format!(
+                "Unsupported LUKS version {}",
+                v
+            ))),
+        }
+    }
+
+    fn from_luks1(header: &Luks1Header) -> Self {
+        let cipher = format!("{}-{}", header.cipher_name, header.cipher_mode);
+        let keyslots = header
+            .keyslots
+            .iter()
+            .enumerate()
+            .filter(|(_, k)| k.active)
+            .map(|(i, k)| {
+                (
+                    i as u32,
+                    DumpKeyslot {
+                        type_: "luks1".to_string(),
+                        key_size: u64::from(header.key_bytes),
+                        priority: None,
+                        cipher: Some(cipher.clone()),
+                        cipher_key_size: Some(u64::from(header.key_bytes)),
+                        pbkdf: "pbkdf2".to_string(),
+                        hash: Some(header.hash_spec.clone()),
+                        iterations: Some(u64::from(k.iterations)),
+                        time_cost: None,
+                        memory: None,
+                        threads: None,
+                        salt: base64::encode(&k.salt),
+                        af_stripes: Some(u64::from(k.stripes)),
+                        af_hash: Some(header.hash_spec.clone()),
+                        area_offset: u64::from(k.key_material_offset) * 512,
+                        area_length: u64::from(header.key_bytes) * u64::from(k.stripes),
+                        digests: vec![0],
+                    },
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+        let mut digests = BTreeMap::new();
+        digests.insert(
+            0,
+            DumpDigest {
+                type_: "pbkdf2".to_string(),
+                hash: Some(header.hash_spec.clone()),
+                iterations: Some(u64::from(header.mk_digest_iterations)),
+                salt: base64::encode(&header.mk_digest_salt),
+                digest: base64::encode(&header.mk_digest),
+                keyslots: keyslots.keys().cloned().collect(),
+                segments: vec![0],
+            },
+        );
+        let data_offset = u64::from(header.payload_offset) * 512;
+        let mut segments = BTreeMap::new();
+        segments.insert(
+            0,
+            DumpSegment {
+                type_: "crypt".to_string(),
+                offset: data_offset,
+                size: None,
+                iv_tweak: 0,
+                cipher: Some(cipher.clone()),
+                sector_size: Some(512),
+                integrity: None,
+                flags: Vec::new(),
+            },
+        );
+        DeviceDump {
+            format: "LUKS1".to_string(),
+            version: 1,
+            epoch: 0,
+            uuid: header.uuid.clone(),
+            label: None,
+            subsystem: None,
+            cipher,
+            sector_size: 512,
+            data_offset,
+            metadata_size: 4096,
+            keyslots_size: data_offset.saturating_sub(4096),
+            flags: Vec::new(),
+            requirements: Vec::new(),
+            keyslots,
+            tokens: BTreeMap::new(),
+            digests,
+            segments,
+        }
+    }
+
+    pub(crate) fn from_luks2_json(
+        seqid: u64,
+        hdr_size: u64,
+        uuid: &str,
+        label: &str,
+        subsystem: &str,
+        json: &serde_json::Value,
+    ) -> Result<Self, LibcryptErr> {
+        let digests = json_objects(&json["digests"])
+            .into_iter()
+            .map(|(id, d)| {
+                (
+                    id,
+                    DumpDigest {
+                        type_: json_string(&d["type"]).unwrap_or_default(),
+                        hash: json_string(&d["hash"]),
+                        iterations: json_u64(&d["iterations"]),
+                        salt: json_string(&d["salt"]).unwrap_or_default(),
+                        digest: json_string(&d["digest"]).unwrap_or_default(),
+                        keyslots: json_ids(&d["keyslots"]),
+                        segments: json_ids(&d["segments"]),
+                    },
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let keyslots = json_objects(&json["keyslots"])
+            .into_iter()
+            .map(|(id, k)| {
+                let kdf = &k["kdf"];
+                let area = &k["area"];
+                (
+                    id,
+                    DumpKeyslot {
+                        type_: json_string(&k["type"]).unwrap_or_default(),
+                        key_size: json_u64(&k["key_size"]).unwrap_or(0),
+                        priority: json_u64(&k["priority"]).map(priority_name),
+                        cipher: json_string(&area["encryption"]),
+                        cipher_key_size: json_u64(&area["key_size"]),
+                        pbkdf: json_string(&kdf["type"]).unwrap_or_default(),
+                        hash: json_string(&kdf["hash"]),
+                        iterations: json_u64(&kdf["iterations"]),
+                        time_cost: json_u64(&kdf["time"]),
+                        memory: json_u64(&kdf["memory"]),
+                        threads: json_u64(&kdf["cpus"]),
+                        salt: json_string(&kdf["salt"]).unwrap_or_default(),
+                        af_stripes: json_u64(&k["af"]["stripes"]),
+                        af_hash: json_string(&k["af"]["hash"]),
+                        area_offset: json_u64(&area["offset"]).unwrap_or(0),
+                        area_length: json_u64(&area["size"]).unwrap_or(0),
+                        digests: digests
+                            .iter()
+                            .filter(|(_, d)| d.keyslots.contains(&id))
+                            .map(|(d_id, _)| *d_id)
+                            .collect(),
+                    },
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let tokens = json_objects(&json["tokens"])
+            .into_iter()
+            .map(|(id, t)| {
+                (
+                    id,
+                    DumpToken {
+                        type_: json_string(&t["type"]).unwrap_or_default(),
+                        keyslots: json_ids(&t["keyslots"]),
+                        json: t.clone(),
+                    },
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let segments = json_objects(&json["segments"])
+            .into_iter()
+            .map(|(id, s)| {
+                (
+                    id,
+                    DumpSegment {
+                        type_: json_string(&s["type"]).unwrap_or_default(),
+                        offset: json_u64(&s["offset"]).unwrap_or(0),
+                        size: json_u64(&s["size"]),
+                        iv_tweak: json_u64(&s["iv_tweak"]).unwrap_or(0),
+                        cipher: json_string(&s["encryption"]),
+                        sector_size: json_u64(&s["sector_size"]),
+                        integrity: json_string(&s["integrity"]["type"]),
+                        flags: json_strings(&s["flags"]),
+                    },
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let data_segment = segments
+            .values()
+            .find(|s| s.type_ == "crypt")
+            .or_else(|| segments.values().next());
+
+        Ok(DeviceDump {
+            format: "LUKS2".to_string(),
+            version: 2,
+            epoch: seqid,
+            uuid: uuid.to_string(),
+            label: if label.is_empty() {
+                None
+            } else {
+                Some(label.to_string())
+            },
+            subsystem: if subsystem.is_empty() {
+                None
+            } else {
+                Some(subsystem.to_string())
+            },
+            cipher: data_segment
+                .and_then(|s| s.cipher.clone())
+                .unwrap_or_default(),
+            sector_size: data_segment.and_then(|s| s.sector_size).unwrap_or(512),
+            data_offset: data_segment.map(|s| s.offset).unwrap_or(0),
+            metadata_size: hdr_size,
+            keyslots_size: json_u64(&json["config"]["keyslots_size"]).unwrap_or(0),
+            flags: json_strings(&json["config"]["flags"]),
+            requirements: json_strings(&json["config"]["requirements"]["mandatory"]),
+            keyslots,
+            tokens,
+            digests,
+            segments,
+        })
+    }
+}
+
+/// Write a base64 encoded value as hex bytes in the layout used by `cryptsetup luksDump`
+fn write_hex(f: &mut fmt::Formatter, base64_value: &str) -> fmt::Result {
+    let bytes = match base64::decode(base64_value) {
+        Ok(b) => b,
+        Err(_) => return writeln!(f, "{}", base64_value),
+    };
+    for (i, chunk) in bytes.chunks(16).enumerate() {
+        if i > 0 {
+            write!(f, "\t            ")?;
+        }
+        let line = chunk
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(f, "{} ", line)?;
+    }
+    Ok(())
+}
+
+fn write_list(f: &mut fmt::Formatter, name: &str, list: &[String], empty: &str) -> fmt::Result {
+    if list.is_empty() {
+        writeln!(f, "{}\t{}", name, empty)
+    } else {
+        writeln!(f, "{}\t{} ", name, list.join(" "))
+    }
+}
+
+impl Display for DeviceDump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "LUKS header information")?;
+        writeln!(f, "Version:       \t{}", self.version)?;
+        writeln!(f, "Epoch:         \t{}", self.epoch)?;
+        writeln!(f, "Metadata area: \t{} [bytes]", self.metadata_size)?;
+        writeln!(f, "Keyslots area: \t{} [bytes]", self.keyslots_size)?;
+        writeln!(f, "UUID:          \t{}", self.uuid)?;
+        writeln!(
+            f,
+            "Label:         \t{}",
+            self.label.as_ref().map_or("(no label)", |l| l.as_str())
+        )?;
+        writeln!(
+            f,
+            "Subsystem:     \t{}",
+            self.subsystem
+                .as_ref()
+                .map_or("(no subsystem)", |s| s.as_str())
+        )?;
+        write_list(f, "Flags:       ", &self.flags, "(no flags)")?;
+        if !self.requirements.is_empty() {
+            write_list(f, "Requirements:", &self.requirements, "")?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Data segments:")?;
+        for (id, segment) in &self.segments {
+            writeln!(f, "  {}: {}", id, segment.type_)?;
+            writeln!(f, "\toffset: {} [bytes]", segment.offset)?;
+            match segment.size {
+                Some(size) => writeln!(f, "\tlength: {} [bytes]", size)?,
+                None => writeln!(f, "\tlength: (whole device)")?,
+            }
+            if let Some(ref cipher) = segment.cipher {
+                writeln!(f, "\tcipher: {}", cipher)?;
+            }
+            if let Some(sector_size) = segment.sector_size {
+                writeln!(f, "\tsector: {} [bytes]", sector_size)?;
+            }
+            if let Some(ref integrity) = segment.integrity {
+                writeln!(f, "\tintegrity: {}", integrity)?;
+            }
+            if !segment.flags.is_empty() {
+                writeln!(f, "\tflags : {}", segment.flags.join(" "))?;
+            }
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Keyslots:")?;
+        for (id, keyslot) in &self.keyslots {
+            writeln!(f, "  {}: {}", id, keyslot.type_)?;
+            writeln!(f, "\tKey:        {} bits", keyslot.key_size * 8)?;
+            if let Some(ref priority) = keyslot.priority {
+                writeln!(f, "\tPriority:   {}", priority)?;
+            }
+            if let Some(ref cipher) = keyslot.cipher {
+                writeln!(f, "\tCipher:     {}", cipher)?;
+            }
+            if let Some(key_size) = keyslot.cipher_key_size {
+                writeln!(f, "\tCipher key: {} bits", key_size * 8)?;
+            }
+            writeln!(f, "\tPBKDF:      {}", keyslot.pbkdf)?;
+            if let Some(ref hash) = keyslot.hash {
+                writeln!(f, "\tHash:       {}", hash)?;
+            }
+            if let Some(iterations) = keyslot.iterations {
+                writeln!(f, "\tIterations: {}", iterations)?;
+            }
+            if let Some(time_cost) = keyslot.time_cost {
+                writeln!(f, "\tTime cost:  {}", time_cost)?;
+            }
+            if let Some(memory) = keyslot.memory {
+                writeln!(f, "\tMemory:     {}", memory)?;
+            }
+            if let Some(threads) = keyslot.threads {
+                writeln!(f, "\tThreads:    {}", threads)?;
+            }
+            write!(f, "\tSalt:       ")?;
+            write_hex(f, &keyslot.salt)?;
+            if let Some(stripes) = keyslot.af_stripes {
+                writeln!(f, "\tAF stripes: {}", stripes)?;
+            }
+            if let Some(ref af_hash) = keyslot.af_hash {
+                writeln!(f, "\tAF hash:    {}", af_hash)?;
+            }
+            writeln!(f, "\tArea offset:{} [bytes]", keyslot.area_offset)?;
+            writeln!(f, "\tArea length:{} [bytes]", keyslot.area_length)?;
+            for digest in &keyslot.digests {
+                writeln!(f, "\tDigest ID:  {}", digest)?;
+            }
+        }
+
+        writeln!(f, "Tokens:")?;
+        for (id, token) in &self.tokens {
+            writeln!(f, "  {}: {}", id, token.type_)?;
+            if let Some(description) = token.json["key_description"].as_str() {
+                writeln!(f, "\tKey description: {}", description)?;
+            }
+            for keyslot in &token.keyslots {
+                writeln!(f, "\tKeyslot:    {}", keyslot)?;
+            }
+        }
+
+        writeln!(f, "Digests:")?;
+        for (id, digest) in &self.digests {
+            writeln!(f, "  {}: {}", id, digest.type_)?;
+            if let Some(ref hash) = digest.hash {
+                writeln!(f, "\tHash:       {}", hash)?;
+            }
+            if let Some(iterations) = digest.iterations {
+                writeln!(f, "\tIterations: {}", iterations)?;
+            }
+            write!(f, "\tSalt:       ")?;
+            write_hex(f, &digest.salt)?;
+            write!(f, "\tDigest:     ")?;
+            write_hex(f, &digest.digest)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn luks2_json() -> serde_json::Value {
+        serde_json::json!({
+            "keyslots": {
+                "0": {
+                    "type": "luks2",
+                    "key_size": 64,
+                    "af": { "type": "luks1", "stripes": 4000, "hash": "sha256" },
+                    "area": {
+                        "type": "raw",
+                        "offset": "32768",
+                        "size": "258048",
+                        "encryption": "aes-xts-plain64",
+                        "key_size": 64
+                    },
+                    "kdf": {
+                        "type": "argon2i",
+                        "time": 4,
+                        "memory": 1048576,
+                        "cpus": 4,
+                        "salt": "AAECAwQFBgcICQoLDA0ODw=="
+                    }
+                }
+            },
+            "tokens": {
+                "0": { "type": "luks2-keyring", "keyslots": ["0"], "key_description": "key" }
+            },
+            "segments": {
+                "0": {
+                    "type": "crypt",
+                    "offset": "16777216",
+                    "size": "dynamic",
+                    "iv_tweak": "0",
+                    "encryption": "aes-xts-plain64",
+                    "sector_size": 512
+                }
+            },
+            "digests": {
+                "0": {
+                    "type": "pbkdf2",
+                    "keyslots": ["0"],
+                    "segments": ["0"],
+                    "hash": "sha256",
+                    "iterations": 1000,
+                    "salt": "AAECAwQFBgcICQoLDA0ODw==",
+                    "digest": "AAECAwQFBgcICQoLDA0ODw=="
+                }
+            },
+            "config": {
+                "json_size": "12288",
+                "keyslots_size": "16744448",
+                "flags": ["allow-discards"]
+            }
+        })
+    }
+
+    #[test]
+    fn test_from_luks2_json() {
+        let dump = DeviceDump::from_luks2_json(3, 16384, "uuid", "", "sub", &luks2_json()).unwrap();
+        assert_eq!(dump.epoch, 3);
+        assert_eq!(dump.label, None);
+        assert_eq!(dump.subsystem, Some("sub".to_string()));
+        assert_eq!(dump.cipher, "aes-xts-plain64");
+        assert_eq!(dump.data_offset, 16_777_216);
+        assert_eq!(dump.keyslots_size, 16_744_448);
+        assert_eq!(dump.flags, vec!["allow-discards".to_string()]);
+        assert_eq!(dump.keyslots[&0].area_offset, 32768);
+        assert_eq!(dump.keyslots[&0].digests, vec![0]);
+        assert_eq!(dump.segments[&0].size, None);
+        assert_eq!(dump.tokens[&0].keyslots, vec![0]);
+
+        let text = dump.to_string();
+        assert!(text.contains("Epoch:         \t3"));
+        assert!(text.contains("\tlength: (whole device)"));
+        assert!(text.contains("\tSalt:       00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f"));
+
+        let json = serde_json::to_value(&dump).unwrap();
+        assert_eq!(json["keyslots"]["0"]["type"], "luks2");
+        assert_eq!(json["segments"]["0"]["offset"], 16_777_216);
+    }
+}
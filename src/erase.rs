@@ -0,0 +1,334 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    os::raw::c_int,
+    path::Path,
+};
+
+use crate::{
+    device::{device_size, CryptDevice, CryptInit},
+    dump::DeviceDump,
+    err::LibcryptErr,
+    format::EncryptionFormat,
+    keyslot::{CryptKeyslot, KeyslotInfo},
+    luks2_header,
+    luks2_token::{CryptTokenInfo, LUKS2_TOKENS_MAX},
+    wipe::CryptWipePattern,
+};
+
+/// Block size used for wiping
+const WIPE_BLOCK_SIZE: usize = 1024 * 1024;
+/// Alignment required by `crypt_wipe` for offsets and lengths
+const SECTOR_SIZE: u64 = 512;
+/// Alignment of the end of the wiped header region
+const HEADER_ALIGN: u64 = 4096;
+
+/// Result of a successful erase
+pub struct EraseReport {
+    /// Format of the erased header
+    pub format: EncryptionFormat,
+    /// Keyslots that were active and have been destroyed
+    pub destroyed_keyslots: Vec<c_int>,
+    /// Areas overwritten as `(offset, length)` in bytes: the keyslots area and any keyslot
+    /// area the header places outside of it
+    pub wiped_areas: Vec<(u64, u64)>,
+    /// Tokens that have been removed
+    pub removed_tokens: Vec<c_int>,
+    /// Length in bytes of the header region that was overwritten, if requested
+    pub wiped_header_length: Option<u64>,
+}
+
+/// Handle for securely erasing LUKS devices
+pub struct CryptErase;
+
+impl CryptErase {
+    /// Erase all key material from the LUKS header on `device_path`
+    ///
+    /// `device_path` is the device containing the header, which is the header file for
+    /// devices with a detached header. Every active keyslot is destroyed, all LUKS2
+    /// tokens are removed and the whole keyslots area is overwritten with `pattern`, which
+    /// must be either `CryptWipePattern::Zero` or `CryptWipePattern::Random`. If
+    /// `wipe_header` is set, the whole metadata region including both LUKS2 header
+    /// copies is overwritten as well and the device is no longer recognized as LUKS.
+    ///
+    /// The result is verified by reloading the header and checking that no keyslot or
+    /// token remains, or that no header remains if it was wiped. Areas wiped with zeros
+    /// are read back and checked.
+    pub fn erase(
+        device_path: &Path,
+        pattern: CryptWipePattern,
+        wipe_header: bool,
+    ) -> Result<EraseReport, LibcryptErr> {
+        match pattern {
+            CryptWipePattern::Zero | CryptWipePattern::Random => (),
+            _ => {
+                return Err(LibcryptErr::Other(
+                    "Only zero and random patterns can be used for erasing".to_string(),
+                ))
+            }
+        }
+        let format = luks2_header::read_format(device_path)?;
+        let dump = DeviceDump::read(device_path)?;
+
+        let mut device = luks2_header::load(device_path, format)?;
+        let max_keyslots = CryptKeyslot::max_keyslots(format)?;
+        let mut areas = keyslots_area(format, &dump, device_size(device_path)?)
+            .into_iter()
+            .collect::<Vec<_>>();
+        let mut destroyed_keyslots = Vec::new();
+        for keyslot in 0..max_keyslots {
+            let status = device.keyslot_handle(Some(keyslot)).status()?;
+            // Keyslot areas normally lie within the keyslots area, but are wiped on their
+            // own if the header describes them elsewhere
+            if let Ok((offset, length)) = device.keyslot_handle(Some(keyslot)).area() {
+                let area = (offset, round_up(length, SECTOR_SIZE));
+                if !areas.iter().any(|a| contains(*a, area)) {
+                    areas.push(area);
+                }
+            }
+            match status {
+                KeyslotInfo::Invalid | KeyslotInfo::Inactive => (),
+                _ => destroyed_keyslots.push(keyslot),
+            }
+        }
+
+        let mut removed_tokens = Vec::new();
+        if let EncryptionFormat::Luks2 = format {
+            for token in 0..LUKS2_TOKENS_MAX {
+                match device.token_handle(token).status()?.0 {
+                    CryptTokenInfo::Invalid | CryptTokenInfo::Inactive => (),
+                    _ => {
+                        device.token_handle(token).remove()?;
+                        removed_tokens.push(token);
+                    }
+                }
+            }
+        }
+        for keyslot in destroyed_keyslots.iter() {
+            device.keyslot_handle(Some(*keyslot)).destroy()?;
+        }
+        drop(device);
+
+        // Wipe through a context without a loaded header so that libcryptsetup does not
+        // operate on stale metadata
+        let mut device = CryptInit::init(device_path)?;
+        for &(offset, length) in areas.iter() {
+            wipe(&mut device, device_path, pattern, offset, length)?;
+        }
+        let wiped_header_length = if wipe_header {
+            let layout_end = match format {
+                EncryptionFormat::Luks1 => dump.metadata_size + dump.keyslots_size,
+                _ => 2 * dump.metadata_size + dump.keyslots_size,
+            };
+            let length = areas
+                .iter()
+                .map(|&(offset, length)| round_up(offset + length, HEADER_ALIGN))
+                .fold(layout_end, std::cmp::max);
+            wipe(&mut device, device_path, pattern, 0, length)?;
+            Some(length)
+        } else {
+            None
+        };
+        drop(device);
+
+        verify(device_path, format, pattern, &areas, wiped_header_length)?;
+        Ok(EraseReport {
+            format,
+            destroyed_keyslots,
+            wiped_areas: areas,
+            removed_tokens,
+            wiped_header_length,
+        })
+    }
+}
+
+fn round_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) / align * align
+}
+
+/// Get `(offset, length)` of the whole keyslots area, which includes the space of
+/// inactive keyslots and unused space that may hold remnants of old keyslots
+///
+/// The area ends at the data offset for LUKS1 and is limited to `device_size` for
+/// detached headers.
+fn keyslots_area(
+    format: EncryptionFormat,
+    dump: &DeviceDump,
+    device_size: u64,
+) -> Option<(u64, u64)> {
+    let offset = match format {
+        EncryptionFormat::Luks1 => dump.metadata_size,
+        _ => 2 * dump.metadata_size,
+    };
+    let end = std::cmp::min(offset + dump.keyslots_size, device_size) / SECTOR_SIZE * SECTOR_SIZE;
+    if end > offset {
+        Some((offset, end - offset))
+    } else {
+        None
+    }
+}
+
+/// Whether the area `outer` contains the area `inner`
+fn contains(outer: (u64, u64), inner: (u64, u64)) -> bool {
+    inner.0 >= outer.0 && inner.0 + inner.1 <= outer.0 + outer.1
+}
+
+fn wipe(
+    device: &mut CryptDevice,
+    device_path: &Path,
+    pattern: CryptWipePattern,
+    offset: u64,
+    length: u64,
+) -> Result<(), LibcryptErr> {
+    device.wipe_handle().wipe::<()>(
+        device_path,
+        pattern,
+        offset,
+        length,
+        WIPE_BLOCK_SIZE,
+        false,
+        None,
+        &mut (),
+    )
+}
+
+fn verify(
+    device_path: &Path,
+    format: EncryptionFormat,
+    pattern: CryptWipePattern,
+    areas: &[(u64, u64)],
+    wiped_header_length: Option<u64>,
+) -> Result<(), LibcryptErr> {
+    if let CryptWipePattern::Zero = pattern {
+        let mut file = File::open(device_path).map_err(LibcryptErr::IOError)?;
+        let regions = match wiped_header_length {
+            Some(length) => vec![(0, length)],
+            None => areas.to_vec(),
+        };
+        for (offset, length) in regions {
+            if !is_zeroed(&mut file, offset, length).map_err(LibcryptErr::IOError)? {
+                return Err(LibcryptErr::Other(format!(
+                    "Area at offset {} with length {} was not zeroed",
+                    offset, length
+                )));
+            }
+        }
+    }
+
+    if wiped_header_length.is_some() {
+        return match luks2_header::read_version(device_path) {
+            Ok(_) => Err(LibcryptErr::Other(
+                "LUKS header is still present after wiping".to_string(),
+            )),
+            Err(_) => Ok(()),
+        };
+    }
+
+    let mut device = luks2_header::load(device_path, format)?;
+    for keyslot in 0..CryptKeyslot::max_keyslots(format)? {
+        match device.keyslot_handle(Some(keyslot)).status()? {
+            KeyslotInfo::Invalid | KeyslotInfo::Inactive => (),
+            _ => {
+                return Err(LibcryptErr::Other(format!(
+                    "Keyslot {} is still active after erasing",
+                    keyslot
+                )))
+            }
+        }
+    }
+    if let EncryptionFormat::Luks2 = format {
+        for token in 0..LUKS2_TOKENS_MAX {
+            match device.token_handle(token).status()?.0 {
+                CryptTokenInfo::Invalid | CryptTokenInfo::Inactive => (),
+                _ => {
+                    return Err(LibcryptErr::Other(format!(
+                        "Token {} is still present after erasing",
+                        token
+                    )))
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_zeroed(file: &mut File, offset: u64, length: u64) -> Result<bool, io::Error> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buffer = vec![0u8; WIPE_BLOCK_SIZE];
+    let mut remaining = length;
+    while remaining > 0 {
+        let chunk = std::cmp::min(remaining, buffer.len() as u64) as usize;
+        file.read_exact(&mut buffer[..chunk])?;
+        if buffer[..chunk].iter().any(|b| *b != 0) {
+            return Ok(false);
+        }
+        remaining -= chunk as u64;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_up() {
+        assert_eq!(round_up(0, SECTOR_SIZE), 0);
+        assert_eq!(round_up(128_000, SECTOR_SIZE), 128_000);
+        assert_eq!(round_up(128_001, SECTOR_SIZE), 128_512);
+        assert_eq!(round_up(4097, HEADER_ALIGN), 8192);
+    }
+
+    #[test]
+    fn test_keyslots_area() {
+        let json = serde_json::json!({
+            "keyslots": {},
+            "tokens": {},
+            "segments": {},
+            "digests": {},
+            "config": {"json_size": "12288", "keyslots_size": "16744448"},
+        });
+        let dump = DeviceDump::from_luks2_json(1, 16384, "uuid", "", "", &json).unwrap();
+        let area = keyslots_area(EncryptionFormat::Luks2, &dump, 64 * 1024 * 1024).unwrap();
+        assert_eq!(area, (32768, 16_744_448));
+        assert!(contains(area, (32768, 258_048)));
+        assert!(!contains(area, (16_744_448, 65536)));
+        // Detached headers may be smaller than the keyslots area
+        assert_eq!(
+            keyslots_area(EncryptionFormat::Luks2, &dump, 1024 * 1024 + 100),
+            Some((32768, 1024 * 1024 - 32768))
+        );
+        assert_eq!(keyslots_area(EncryptionFormat::Luks2, &dump, 32768), None);
+    }
+
+    #[test]
+    fn test_is_zeroed() {
+        use std::{fs::OpenOptions, io::Write, process};
+
+        let path = std::env::temp_dir().join(format!(
+            "libcryptsetup-rs-erase-zeroed-{}-{:x}",
+            process::id(),
+            rand::random::<u64>()
+        ));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&[0u8; 4096]).unwrap();
+        file.write_all(&[1u8]).unwrap();
+        file.write_all(&[0u8; 4095]).unwrap();
+        drop(file);
+
+        let mut file = File::open(&path).unwrap();
+        assert!(is_zeroed(&mut file, 0, 4096).unwrap());
+        assert!(!is_zeroed(&mut file, 0, 8192).unwrap());
+        assert!(is_zeroed(&mut file, 4097, 4095).unwrap());
+        assert!(is_zeroed(&mut file, 8192, 1).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -25,6 +25,8 @@ pub enum LibcryptErr {
     Utf8Error(Utf8Error),
     /// Wrapper for `serde_json::Error`
     JsonError(serde_json::Error),
+    /// Wrapper for `openssl::error::ErrorStack`
+    OpensslError(openssl::error::ErrorStack),
     /// Indicates that a Rust/C conversion was unsuccessful
     InvalidConversion,
     /// Indicates that a pointer returned was null signifying an error
@@ -53,6 +55,7 @@ impl Display for LibcryptErr {
             LibcryptErr::JsonError(ref e) => {
                 write!(f, "Failed to parse the provided string into JSON: {}", e)
             }
+            LibcryptErr::OpensslError(ref e) => write!(f, "OpenSSL operation failed: {}", e),
             LibcryptErr::InvalidConversion => {
                 write!(f, "Failed to perform the specified conversion")
             }
@@ -0,0 +1,154 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::os::raw::c_int;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::{device::CryptDevice, err::LibcryptErr, keyslot::CryptVolumeKeyFlags, keywrap};
+
+/// Version of the escrow format written by `CryptEscrow::export`
+pub const ESCROW_VERSION: u32 = 1;
+/// Name of the wrapping algorithm in the escrow format
+const WRAP_ALGORITHM: &str = "aes-kw";
+
+/// Volume key wrapped with a key encryption key, suitable for storing in an escrow
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct VolumeKeyEscrow {
+    /// Escrow format version
+    pub version: u32,
+    /// UUID of the LUKS device the volume key belongs to
+    pub uuid: String,
+    /// Algorithm used to wrap the volume key
+    pub wrap_algorithm: String,
+    /// Base64 encoded wrapped volume key
+    pub wrapped_key: String,
+    /// Base64 encoded SHA-256 digest of the volume key
+    pub key_digest: String,
+}
+
+impl VolumeKeyEscrow {
+    /// Serialize the escrow to JSON
+    pub fn to_json(&self) -> Result<String, LibcryptErr> {
+        serde_json::to_string(self)
+            .map_err(|e| LibcryptErr::Other(format!("Failed to serialize escrow: {}", e)))
+    }
+
+    /// Deserialize an escrow from JSON
+    pub fn from_json(json: &str) -> Result<Self, LibcryptErr> {
+        let escrow: VolumeKeyEscrow = serde_json::from_str(json)
+            .map_err(|e| LibcryptErr::Other(format!("Invalid escrow: {}", e)))?;
+        if escrow.version != ESCROW_VERSION || escrow.wrap_algorithm != WRAP_ALGORITHM {
+            return Err(LibcryptErr::Other(format!(
+                "Unsupported escrow version {} with algorithm {}",
+                escrow.version, escrow.wrap_algorithm
+            )));
+        }
+        Ok(escrow)
+    }
+}
+
+/// Handle for escrowing volume keys
+///
+/// Volume keys are wrapped with AES key wrap (RFC 3394) under a caller supplied 128, 192
+/// or 256 bit key encryption key. The KEK is typically held by a recovery service and
+/// never stored next to the escrow.
+pub struct CryptEscrow;
+
+impl CryptEscrow {
+    /// Extract the volume key of a device with a loaded LUKS header and wrap it with `kek`
+    ///
+    /// `passphrase` must be valid UTF-8.
+    pub fn export(
+        device: &mut CryptDevice,
+        keyslot: Option<c_int>,
+        passphrase: &[u8],
+        kek: &[u8],
+    ) -> Result<VolumeKeyEscrow, LibcryptErr> {
+        let uuid = device.status_handle().get_uuid()?;
+        let mut volume_key = Zeroizing::new(vec![
+            0u8;
+            device.status_handle().get_volume_key_size()
+                as usize
+        ]);
+        let (_, size) = device.volume_key_handle().get(
+            keyslot.unwrap_or(libcryptsetup_rs_sys::CRYPT_ANY_SLOT),
+            &mut volume_key,
+            std::str::from_utf8(passphrase).map_err(LibcryptErr::Utf8Error)?,
+        )?;
+        let key = &volume_key[..size];
+        let wrapped = keywrap::wrap(kek, key)?;
+        let digest = Sha256::digest(key);
+        Ok(VolumeKeyEscrow {
+            version: ESCROW_VERSION,
+            uuid: uuid.to_string(),
+            wrap_algorithm: WRAP_ALGORITHM.to_string(),
+            wrapped_key: base64::encode(&wrapped),
+            key_digest: base64::encode(&digest),
+        })
+    }
+
+    /// Unwrap an escrowed volume key and add a keyslot for `new_passphrase`
+    ///
+    /// The escrow must belong to the device with the loaded LUKS header and the unwrapped
+    /// key must match both the escrowed digest and the volume key digest in the header.
+    /// Returns the new keyslot.
+    pub fn restore(
+        device: &mut CryptDevice,
+        escrow: &VolumeKeyEscrow,
+        kek: &[u8],
+        keyslot: Option<c_int>,
+        new_passphrase: &[u8],
+    ) -> Result<c_int, LibcryptErr> {
+        let uuid = device.status_handle().get_uuid()?;
+        if uuid.to_string() != escrow.uuid {
+            return Err(LibcryptErr::Other(format!(
+                "Escrow belongs to device {}, not {}",
+                escrow.uuid, uuid
+            )));
+        }
+        let wrapped = base64::decode(&escrow.wrapped_key)
+            .map_err(|e| LibcryptErr::Other(format!("Invalid wrapped key: {}", e)))?;
+        let digest = base64::decode(&escrow.key_digest)
+            .map_err(|e| LibcryptErr::Other(format!("Invalid key digest: {}", e)))?;
+        let volume_key = keywrap::unwrap(kek, &wrapped)?;
+        if Sha256::digest(&volume_key).as_slice() != digest.as_slice() {
+            return Err(LibcryptErr::Other(
+                "Unwrapped volume key does not match the escrowed digest".to_string(),
+            ));
+        }
+        device.volume_key_handle().verify(&volume_key)?;
+        device.keyslot_handle(keyslot).add_by_key(
+            Some(&volume_key),
+            new_passphrase,
+            CryptVolumeKeyFlags::empty(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_escrow_json() {
+        let escrow = VolumeKeyEscrow {
+            version: ESCROW_VERSION,
+            uuid: "a6e5c5b6-1b47-4c2e-8a39-6c1f3b2c8e11".to_string(),
+            wrap_algorithm: WRAP_ALGORITHM.to_string(),
+            wrapped_key: base64::encode(&keywrap::wrap(&[1u8; 32], &[2u8; 64]).unwrap()),
+            key_digest: base64::encode(&Sha256::digest(&[2u8; 64])),
+        };
+        let json = escrow.to_json().unwrap();
+        assert_eq!(VolumeKeyEscrow::from_json(&json).unwrap(), escrow);
+
+        let unsupported = VolumeKeyEscrow {
+            version: ESCROW_VERSION + 1,
+            ..escrow
+        };
+        assert!(VolumeKeyEscrow::from_json(&unsupported.to_json().unwrap()).is_err());
+    }
+}
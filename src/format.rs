@@ -10,6 +10,8 @@ use std::{
     ptr,
 };
 
+use zeroize::Zeroizing;
+
 use crate::{
     device::CryptDevice,
     err::LibcryptErr,
@@ -32,7 +34,30 @@ bitflags_to_from_struct!(
     u32
 );
 
+struct_ref_to_bitflags!(CryptVerityFlags, CryptVerityFlag, u32);
+
+consts_to_from_enum!(
+    /// TrueCrypt and VeraCrypt format flags
+    CryptTcryptFlag,
+    u32,
+    LegacyModes => libcryptsetup_rs_sys::CRYPT_TCRYPT_LEGACY_MODES,
+    HiddenHeader => libcryptsetup_rs_sys::CRYPT_TCRYPT_HIDDEN_HEADER,
+    BackupHeader => libcryptsetup_rs_sys::CRYPT_TCRYPT_BACKUP_HEADER,
+    SystemHeader => libcryptsetup_rs_sys::CRYPT_TCRYPT_SYSTEM_HEADER,
+    VeraModes => libcryptsetup_rs_sys::CRYPT_TCRYPT_VERA_MODES
+);
+
+bitflags_to_from_struct!(
+    /// Set of flags for TrueCrypt and VeraCrypt format
+    CryptTcryptFlags,
+    CryptTcryptFlag,
+    u32
+);
+
+struct_ref_to_bitflags!(CryptTcryptFlags, CryptTcryptFlag, u32);
+
 /// Device formatting type options
+#[derive(Copy, Clone)]
 pub enum EncryptionFormat {
     #[allow(missing_docs)]
     Plain,
@@ -94,6 +119,21 @@ impl EncryptionFormat {
     }
 }
 
+/// LUKS2 parameters with every field unset, for calls that require parameters but ignore
+/// them, such as conversion between LUKS versions and LUKS2 repair
+pub(crate) fn empty_luks2_params() -> libcryptsetup_rs_sys::crypt_params_luks2 {
+    libcryptsetup_rs_sys::crypt_params_luks2 {
+        pbkdf: ptr::null(),
+        integrity: ptr::null(),
+        integrity_params: ptr::null(),
+        data_alignment: 0,
+        data_device: ptr::null(),
+        sector_size: 0,
+        label: ptr::null(),
+        subsystem: ptr::null(),
+    }
+}
+
 /// A struct representing a reference with a lifetime to a `CryptParamsLuks2Ref`
 /// struct
 pub struct CryptParamsLuks2Ref<'a> {
@@ -176,6 +216,23 @@ impl<'a> TryInto<CryptParamsLuks2Ref<'a>> for &'a CryptParamsLuks2 {
     }
 }
 
+/// A struct representing a reference with a lifetime to a `CryptParamsVerity`
+/// struct
+pub struct CryptParamsVerityRef<'a> {
+    #[allow(missing_docs)]
+    pub inner: libcryptsetup_rs_sys::crypt_params_verity,
+    #[allow(dead_code)]
+    reference: &'a CryptParamsVerity,
+    #[allow(dead_code)]
+    hash_name_cstring: CString,
+    #[allow(dead_code)]
+    data_device_cstring: CString,
+    #[allow(dead_code)]
+    hash_device_cstring: CString,
+    #[allow(dead_code)]
+    fec_device_cstring_opt: Option<CString>,
+}
+
 /// Parameters specific to Verity
 pub struct CryptParamsVerity {
     #[allow(missing_docs)]
@@ -206,6 +263,48 @@ pub struct CryptParamsVerity {
     pub flags: CryptVerityFlags,
 }
 
+impl<'a> TryInto<CryptParamsVerityRef<'a>> for &'a CryptParamsVerity {
+    type Error = LibcryptErr;
+
+    fn try_into(self) -> Result<CryptParamsVerityRef<'a>, Self::Error> {
+        let hash_name_cstring = to_cstring!(self.hash_name)?;
+        let data_device_cstring = path_to_cstring!(self.data_device.as_path())?;
+        let hash_device_cstring = path_to_cstring!(self.hash_device.as_path())?;
+        let fec_device_cstring_opt = if self.fec_device.as_os_str().is_empty() {
+            None
+        } else {
+            Some(path_to_cstring!(self.fec_device.as_path())?)
+        };
+        let inner = libcryptsetup_rs_sys::crypt_params_verity {
+            hash_name: hash_name_cstring.as_ptr(),
+            data_device: data_device_cstring.as_ptr(),
+            hash_device: hash_device_cstring.as_ptr(),
+            fec_device: fec_device_cstring_opt
+                .as_ref()
+                .map(|cs| cs.as_ptr())
+                .unwrap_or(ptr::null()),
+            salt: to_byte_ptr!(self.salt),
+            salt_size: self.salt.len() as u32,
+            hash_type: self.hash_type,
+            data_block_size: self.data_block_size,
+            hash_block_size: self.hash_block_size,
+            data_size: self.data_size,
+            hash_area_offset: self.hash_area_offset,
+            fec_area_offset: self.fec_area_offset,
+            fec_roots: self.fec_roots,
+            flags: (&self.flags).into(),
+        };
+        Ok(CryptParamsVerityRef {
+            inner,
+            reference: self,
+            hash_name_cstring,
+            data_device_cstring,
+            hash_device_cstring,
+            fec_device_cstring_opt,
+        })
+    }
+}
+
 impl<'a> TryFrom<&'a libcryptsetup_rs_sys::crypt_params_verity> for CryptParamsVerity {
     type Error = LibcryptErr;
 
@@ -246,6 +345,9 @@ pub struct CryptParamsIntegrityRef<'a> {
 }
 
 /// Parameters for integrity checking
+///
+/// The default leaves every parameter unset so libcryptsetup uses its defaults.
+#[derive(Default)]
 pub struct CryptParamsIntegrity {
     #[allow(missing_docs)]
     pub journal_size: u64,
@@ -341,6 +443,110 @@ impl<'a> TryFrom<&'a libcryptsetup_rs_sys::crypt_params_integrity> for CryptPara
     }
 }
 
+/// A struct representing a reference with a lifetime to a `CryptParamsTcrypt`
+/// struct
+pub struct CryptParamsTcryptRef<'a> {
+    #[allow(missing_docs)]
+    pub inner: libcryptsetup_rs_sys::crypt_params_tcrypt,
+    #[allow(dead_code)]
+    reference: &'a CryptParamsTcrypt,
+    #[allow(dead_code)]
+    keyfile_cstrings: Vec<CString>,
+    #[allow(dead_code)]
+    keyfile_ptrs: Vec<*const c_char>,
+    #[allow(dead_code)]
+    hash_name_cstring_opt: Option<CString>,
+    #[allow(dead_code)]
+    cipher_cstring_opt: Option<CString>,
+    #[allow(dead_code)]
+    mode_cstring_opt: Option<CString>,
+}
+
+/// Parameters specific to TrueCrypt and VeraCrypt
+///
+/// `hash_name`, `cipher` and `mode` restrict the combinations that are tried when
+/// loading the header; all supported combinations are tried if they are `None`.
+pub struct CryptParamsTcrypt {
+    /// Passphrase, zeroized when the parameters are dropped
+    pub passphrase: Zeroizing<Vec<u8>>,
+    #[allow(missing_docs)]
+    pub keyfiles: Vec<PathBuf>,
+    #[allow(missing_docs)]
+    pub hash_name: Option<String>,
+    #[allow(missing_docs)]
+    pub cipher: Option<String>,
+    #[allow(missing_docs)]
+    pub mode: Option<String>,
+    #[allow(missing_docs)]
+    pub key_size: crate::size_t,
+    #[allow(missing_docs)]
+    pub flags: CryptTcryptFlags,
+    /// VeraCrypt personal iterations multiplier, 0 for the default iteration count
+    pub veracrypt_pim: u32,
+}
+
+impl<'a> TryInto<CryptParamsTcryptRef<'a>> for &'a CryptParamsTcrypt {
+    type Error = LibcryptErr;
+
+    fn try_into(self) -> Result<CryptParamsTcryptRef<'a>, Self::Error> {
+        let keyfile_cstrings = self
+            .keyfiles
+            .iter()
+            .map(|p| path_to_cstring!(p.as_path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut keyfile_ptrs = keyfile_cstrings
+            .iter()
+            .map(|cs| cs.as_ptr())
+            .collect::<Vec<_>>();
+        let hash_name_cstring_opt = match self.hash_name {
+            Some(ref h) => Some(to_cstring!(h)?),
+            None => None,
+        };
+        let cipher_cstring_opt = match self.cipher {
+            Some(ref c) => Some(to_cstring!(c)?),
+            None => None,
+        };
+        let mode_cstring_opt = match self.mode {
+            Some(ref m) => Some(to_cstring!(m)?),
+            None => None,
+        };
+        let inner = libcryptsetup_rs_sys::crypt_params_tcrypt {
+            passphrase: to_byte_ptr!(self.passphrase),
+            passphrase_size: self.passphrase.len(),
+            keyfiles: if keyfile_ptrs.is_empty() {
+                ptr::null_mut()
+            } else {
+                keyfile_ptrs.as_mut_ptr()
+            },
+            keyfiles_count: keyfile_ptrs.len() as c_uint,
+            hash_name: hash_name_cstring_opt
+                .as_ref()
+                .map(|cs| cs.as_ptr())
+                .unwrap_or(ptr::null()),
+            cipher: cipher_cstring_opt
+                .as_ref()
+                .map(|cs| cs.as_ptr())
+                .unwrap_or(ptr::null()),
+            mode: mode_cstring_opt
+                .as_ref()
+                .map(|cs| cs.as_ptr())
+                .unwrap_or(ptr::null()),
+            key_size: self.key_size,
+            flags: (&self.flags).into(),
+            veracrypt_pim: self.veracrypt_pim,
+        };
+        Ok(CryptParamsTcryptRef {
+            inner,
+            reference: self,
+            keyfile_cstrings,
+            keyfile_ptrs,
+            hash_name_cstring_opt,
+            cipher_cstring_opt,
+            mode_cstring_opt,
+        })
+    }
+}
+
 /// Handle for format operations on a device
 pub struct CryptFormat<'a> {
     reference: &'a mut CryptDevice,
@@ -363,3 +569,39 @@ impl<'a> CryptFormat<'a> {
         EncryptionFormat::from_ptr(unsafe { libcryptsetup_rs_sys::crypt_get_default_type() })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tcrypt_params_ref() {
+        let params = CryptParamsTcrypt {
+            passphrase: Zeroizing::new(b"passphrase".to_vec()),
+            keyfiles: vec![PathBuf::from("/tmp/a"), PathBuf::from("/tmp/b")],
+            hash_name: Some("sha512".to_string()),
+            cipher: None,
+            mode: None,
+            key_size: 0,
+            flags: CryptTcryptFlags::new(vec![
+                CryptTcryptFlag::HiddenHeader,
+                CryptTcryptFlag::VeraModes,
+            ]),
+            veracrypt_pim: 7,
+        };
+        let params_ref: CryptParamsTcryptRef<'_> = (&params).try_into().unwrap();
+        assert_eq!(params_ref.inner.passphrase_size, 10);
+        assert_eq!(params_ref.inner.keyfiles_count, 2);
+        let keyfile = unsafe { CStr::from_ptr(*params_ref.inner.keyfiles.offset(1)) };
+        assert_eq!(keyfile.to_str().unwrap(), "/tmp/b");
+        let hash_name = unsafe { CStr::from_ptr(params_ref.inner.hash_name) };
+        assert_eq!(hash_name.to_str().unwrap(), "sha512");
+        assert!(params_ref.inner.cipher.is_null());
+        assert_eq!(
+            params_ref.inner.flags,
+            libcryptsetup_rs_sys::CRYPT_TCRYPT_HIDDEN_HEADER
+                | libcryptsetup_rs_sys::CRYPT_TCRYPT_VERA_MODES
+        );
+        assert_eq!(params_ref.inner.veracrypt_pim, 7);
+    }
+}
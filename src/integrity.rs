@@ -0,0 +1,315 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{collections::HashMap, convert::TryInto, path::Path, thread, time::Duration};
+
+use crate::{
+    activate::{CryptActivateFlag, CryptActivateFlags, CryptDeactivateFlags},
+    device::{CryptDevice, CryptInit},
+    err::LibcryptErr,
+    format::{CryptParamsIntegrity, CryptParamsIntegrityRef, EncryptionFormat},
+    wipe::CryptWipePattern,
+};
+
+use either::Either;
+
+/// Mode used by dm-integrity to guarantee atomicity of data and tag writes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IntegrityJournalMode {
+    /// Write data and tags through the journal
+    Journal,
+    /// Write data and tags directly without a journal
+    Direct,
+    /// Track dirty regions in a bitmap instead of using a journal
+    Bitmap,
+}
+
+impl IntegrityJournalMode {
+    fn as_flag(self) -> Option<CryptActivateFlag> {
+        match self {
+            IntegrityJournalMode::Journal => None,
+            IntegrityJournalMode::Direct => Some(CryptActivateFlag::NoJournal),
+            IntegrityJournalMode::Bitmap => Some(CryptActivateFlag::NoJournalBitmap),
+        }
+    }
+}
+
+/// Options for activating a standalone dm-integrity device
+pub struct IntegrityActivation {
+    /// Journal mode for the mapping
+    pub mode: IntegrityJournalMode,
+    /// Recalculate integrity tags in the background on activation
+    pub recalculate: bool,
+    /// Activate the mapping read-only
+    pub readonly: bool,
+    /// Additional activation flags to pass to libcryptsetup
+    pub flags: Vec<CryptActivateFlag>,
+}
+
+impl Default for IntegrityActivation {
+    fn default() -> Self {
+        IntegrityActivation {
+            mode: IntegrityJournalMode::Journal,
+            recalculate: false,
+            readonly: false,
+            flags: Vec::new(),
+        }
+    }
+}
+
+impl IntegrityActivation {
+    fn to_flags(&self) -> CryptActivateFlags {
+        let mut flags = self.flags.clone();
+        if let Some(flag) = self.mode.as_flag() {
+            flags.push(flag);
+        }
+        if self.recalculate {
+            flags.push(CryptActivateFlag::Recalculate);
+        }
+        if self.readonly {
+            flags.push(CryptActivateFlag::Readonly);
+        }
+        CryptActivateFlags::new(flags)
+    }
+}
+
+/// Handle for standalone dm-integrity operations
+pub struct CryptIntegrity<'a> {
+    reference: &'a mut CryptDevice,
+}
+
+impl<'a> CryptIntegrity<'a> {
+    pub(crate) fn new(reference: &'a mut CryptDevice) -> Self {
+        CryptIntegrity { reference }
+    }
+
+    /// Format the device with a standalone dm-integrity superblock
+    ///
+    /// `integrity_key` is required only for keyed integrity algorithms such as `hmac(sha256)`.
+    /// Journal integrity and journal encryption keys in `params` are not stored on disk and
+    /// must be supplied again with `load` before every activation.
+    pub fn format(
+        &mut self,
+        params: &CryptParamsIntegrity,
+        integrity_key: Option<&[u8]>,
+    ) -> Result<(), LibcryptErr> {
+        let mut params_ref: CryptParamsIntegrityRef<'_> = params.try_into()?;
+        // Standalone integrity devices have no cipher, so the cipher and mode are ignored
+        self.reference
+            .context_handle()
+            .format::<libcryptsetup_rs_sys::crypt_params_integrity>(
+                EncryptionFormat::Integrity,
+                ("", ""),
+                None,
+                match integrity_key {
+                    Some(key) => Either::Left(key),
+                    None => Either::Right(0),
+                },
+                Some(&mut params_ref.inner),
+            )?;
+        Ok(())
+    }
+
+    /// Load the dm-integrity superblock from the device
+    ///
+    /// `journal_keys` must be provided if the device was formatted with journal integrity
+    /// or journal encryption keys.
+    pub fn load(&mut self, journal_keys: Option<&CryptParamsIntegrity>) -> Result<(), LibcryptErr> {
+        match journal_keys {
+            Some(params) => {
+                let mut params_ref: CryptParamsIntegrityRef<'_> = params.try_into()?;
+                self.reference
+                    .context_handle()
+                    .load::<libcryptsetup_rs_sys::crypt_params_integrity>(
+                        EncryptionFormat::Integrity,
+                        Some(&mut params_ref.inner),
+                    )?;
+            }
+            None => {
+                self.reference
+                    .context_handle()
+                    .load::<()>(EncryptionFormat::Integrity, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Activate a loaded dm-integrity device
+    ///
+    /// `integrity_key` is required only for keyed integrity algorithms such as `hmac(sha256)`.
+    pub fn activate(
+        &mut self,
+        name: &str,
+        integrity_key: Option<&[u8]>,
+        options: &IntegrityActivation,
+    ) -> Result<(), LibcryptErr> {
+        self.reference.activate_handle().activate_by_volume_key(
+            Some(name),
+            integrity_key,
+            options.to_flags(),
+        )
+    }
+
+    /// Deactivate a dm-integrity mapping
+    pub fn deactivate(
+        &mut self,
+        name: &str,
+        flags: CryptDeactivateFlags,
+    ) -> Result<(), LibcryptErr> {
+        self.reference.activate_handle().deactivate(name, flags)
+    }
+
+    /// Initialize all integrity tags by zeroing an active mapping
+    ///
+    /// This is the equivalent of the wipe that `integritysetup format` performs after
+    /// formatting and avoids integrity failures when reading never-written sectors.
+    pub fn wipe_tags(&mut self, name: &str, size: u64) -> Result<(), LibcryptErr> {
+        let mapped_path = Path::new("/dev/mapper").join(name);
+        self.reference.wipe_handle().wipe::<()>(
+            &mapped_path,
+            CryptWipePattern::Zero,
+            0,
+            size,
+            1024 * 1024,
+            false,
+            None,
+            &mut (),
+        )
+    }
+
+    /// Get detected number of integrity mismatches for an active mapping
+    pub fn failures(&mut self, name: &str) -> Result<u64, LibcryptErr> {
+        self.reference
+            .runtime_handle(name)
+            .get_active_integrity_failures()
+    }
+}
+
+/// Change in the number of integrity mismatches reported for a mapping
+#[derive(Debug, PartialEq, Eq)]
+pub struct IntegrityFailureChange {
+    /// Name of the device mapper mapping
+    pub name: String,
+    /// Number of mismatches at the previous poll
+    pub previous: u64,
+    /// Number of mismatches at the current poll
+    pub current: u64,
+}
+
+/// Polling monitor for integrity mismatch counts of active mappings
+pub struct IntegrityMonitor {
+    counts: HashMap<String, Option<u64>>,
+}
+
+impl IntegrityMonitor {
+    /// Create a monitor for the given mapping names
+    pub fn new<I, S>(names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        IntegrityMonitor {
+            counts: names.into_iter().map(|n| (n.into(), None)).collect(),
+        }
+    }
+
+    /// Add a mapping to the set of monitored mappings
+    pub fn add(&mut self, name: &str) {
+        self.counts.entry(name.to_string()).or_insert(None);
+    }
+
+    /// Stop monitoring a mapping
+    pub fn remove(&mut self, name: &str) {
+        self.counts.remove(name);
+    }
+
+    /// Query all monitored mappings and return those whose mismatch count changed since
+    /// the last poll
+    ///
+    /// The first poll of a mapping records a baseline and only reports the mapping if it
+    /// already has a non-zero count.
+    pub fn poll(&mut self) -> Result<Vec<IntegrityFailureChange>, LibcryptErr> {
+        let mut changes = Vec::new();
+        for (name, count) in self.counts.iter_mut() {
+            let mut device = CryptInit::init_by_name_and_header(name, None)?;
+            let current = device
+                .runtime_handle(name)
+                .get_active_integrity_failures()?;
+            if let Some(change) = Self::compare(name, *count, current) {
+                changes.push(change);
+            }
+            *count = Some(current);
+        }
+        changes.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(changes)
+    }
+
+    /// Poll every `interval` and pass changes to `callback` until it returns `false`
+    pub fn run<F>(&mut self, interval: Duration, mut callback: F) -> Result<(), LibcryptErr>
+    where
+        F: FnMut(&[IntegrityFailureChange]) -> bool,
+    {
+        loop {
+            let changes = self.poll()?;
+            if !callback(&changes) {
+                return Ok(());
+            }
+            thread::sleep(interval);
+        }
+    }
+
+    fn compare(name: &str, previous: Option<u64>, current: u64) -> Option<IntegrityFailureChange> {
+        match previous {
+            Some(p) if p == current => None,
+            None if current == 0 => None,
+            p => Some(IntegrityFailureChange {
+                name: name.to_string(),
+                previous: p.unwrap_or(0),
+                current,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_monitor_compare() {
+        assert_eq!(IntegrityMonitor::compare("a", None, 0), None);
+        assert_eq!(IntegrityMonitor::compare("a", Some(3), 3), None);
+        assert_eq!(
+            IntegrityMonitor::compare("a", None, 2),
+            Some(IntegrityFailureChange {
+                name: "a".to_string(),
+                previous: 0,
+                current: 2,
+            })
+        );
+        assert_eq!(
+            IntegrityMonitor::compare("a", Some(2), 5),
+            Some(IntegrityFailureChange {
+                name: "a".to_string(),
+                previous: 2,
+                current: 5,
+            })
+        );
+    }
+
+    #[test]
+    fn test_activation_flags() {
+        let options = IntegrityActivation {
+            mode: IntegrityJournalMode::Bitmap,
+            recalculate: true,
+            ..Default::default()
+        };
+        let flags: u32 = options.to_flags().into();
+        assert_eq!(
+            flags,
+            libcryptsetup_rs_sys::CRYPT_ACTIVATE_NO_JOURNAL_BITMAP
+                | libcryptsetup_rs_sys::CRYPT_ACTIVATE_RECALCULATE
+        );
+    }
+}
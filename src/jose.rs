@@ -0,0 +1,461 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Minimal JOSE support for the clevis pins: JWK conversion of elliptic curve keys,
+//! JWS verification of Tang advertisements and the ECDH-ES and `dir` JWE algorithms
+//! with AES-GCM content encryption.
+
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    ec::{EcGroup, EcKey, EcPoint, EcPointRef},
+    ecdsa::EcdsaSig,
+    hash::{hash, MessageDigest},
+    nid::Nid,
+    pkey::{Private, Public},
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{clevis::ClevisJwe, err::LibcryptErr};
+
+/// Length of the AES-GCM IV
+const GCM_IV_LEN: usize = 12;
+/// Length of the AES-GCM authentication tag
+const GCM_TAG_LEN: usize = 16;
+
+pub(crate) fn b64u_encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+pub(crate) fn b64u_decode(s: &str) -> Result<Vec<u8>, LibcryptErr> {
+    base64::decode_config(s, base64::URL_SAFE_NO_PAD)
+        .map_err(|e| LibcryptErr::Other(format!("Invalid base64url encoding: {}", e)))
+}
+
+fn openssl_err(e: openssl::error::ErrorStack) -> LibcryptErr {
+    LibcryptErr::OpensslError(e)
+}
+
+/// JSON web key
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Jwk {
+    pub kty: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub d: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub k: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alg: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_ops: Option<Vec<String>>,
+}
+
+impl Jwk {
+    /// Whether the key may be used for the given operation
+    pub fn allows(&self, op: &str) -> bool {
+        match self.key_ops {
+            Some(ref ops) => ops.iter().any(|o| o == op),
+            None => false,
+        }
+    }
+
+    /// Compute the RFC 7638 thumbprint of an elliptic curve key
+    pub fn thumbprint(&self, digest: MessageDigest) -> Result<String, LibcryptErr> {
+        let (crv, x, y) = match (&self.crv, &self.x, &self.y) {
+            (Some(crv), Some(x), Some(y)) if self.kty == "EC" => (crv, x, y),
+            _ => {
+                return Err(LibcryptErr::Other(
+                    "Thumbprints are only supported for EC keys".to_string(),
+                ))
+            }
+        };
+        // Members in lexicographic order without whitespace
+        let canonical = format!(r#"{{"crv":"{}","kty":"EC","x":"{}","y":"{}"}}"#, crv, x, y);
+        hash(digest, canonical.as_bytes())
+            .map(|h| b64u_encode(&h))
+            .map_err(openssl_err)
+    }
+
+    /// Whether `kid` is the SHA-1 or SHA-256 thumbprint of the key
+    pub fn has_thumbprint(&self, kid: &str) -> bool {
+        [MessageDigest::sha1(), MessageDigest::sha256()]
+            .iter()
+            .any(|d| self.thumbprint(*d).ok().as_deref() == Some(kid))
+    }
+
+    pub fn group(&self) -> Result<EcGroup, LibcryptErr> {
+        let nid = match self.crv.as_deref() {
+            Some("P-256") => Nid::X9_62_PRIME256V1,
+            Some("P-384") => Nid::SECP384R1,
+            Some("P-521") => Nid::SECP521R1,
+            _ => {
+                return Err(LibcryptErr::Other(format!(
+                    "Unsupported curve {:?}",
+                    self.crv
+                )))
+            }
+        };
+        EcGroup::from_curve_name(nid).map_err(openssl_err)
+    }
+
+    /// Get the public key of an elliptic curve key, verifying that it lies on the curve
+    pub fn public_key(&self) -> Result<EcKey<Public>, LibcryptErr> {
+        let group = self.group()?;
+        let coordinate = |c: &Option<String>| -> Result<BigNum, LibcryptErr> {
+            match *c {
+                Some(ref c) => BigNum::from_slice(&b64u_decode(c)?).map_err(openssl_err),
+                None => Err(LibcryptErr::Other(
+                    "EC key is missing a coordinate".to_string(),
+                )),
+            }
+        };
+        let (x, y) = (coordinate(&self.x)?, coordinate(&self.y)?);
+        let key = EcKey::from_public_key_affine_coordinates(&group, &x, &y).map_err(openssl_err)?;
+        key.check_key().map_err(openssl_err)?;
+        Ok(key)
+    }
+
+    /// Create a public JWK from a point on the curve `crv`
+    pub fn from_point(
+        crv: &str,
+        group: &EcGroup,
+        point: &EcPointRef,
+        alg: Option<&str>,
+        key_ops: Option<&[&str]>,
+    ) -> Result<Self, LibcryptErr> {
+        let (x, y) = affine_coordinates(group, point)?;
+        Ok(Jwk {
+            kty: "EC".to_string(),
+            crv: Some(crv.to_string()),
+            x: Some(b64u_encode(&x)),
+            y: Some(b64u_encode(&y)),
+            alg: alg.map(|a| a.to_string()),
+            key_ops: key_ops.map(|ops| ops.iter().map(|o| o.to_string()).collect()),
+            ..Default::default()
+        })
+    }
+}
+
+fn field_size(group: &EcGroup) -> usize {
+    (group.degree() as usize + 7) / 8
+}
+
+/// Get the affine coordinates of a point padded to the field size
+pub(crate) fn affine_coordinates(
+    group: &EcGroup,
+    point: &EcPointRef,
+) -> Result<(Vec<u8>, Vec<u8>), LibcryptErr> {
+    let mut ctx = BigNumContext::new().map_err(openssl_err)?;
+    let mut x = BigNum::new().map_err(openssl_err)?;
+    let mut y = BigNum::new().map_err(openssl_err)?;
+    point
+        .affine_coordinates_gfp(group, &mut x, &mut y, &mut ctx)
+        .map_err(openssl_err)?;
+    let size = field_size(group) as i32;
+    Ok((
+        x.to_vec_padded(size).map_err(openssl_err)?,
+        y.to_vec_padded(size).map_err(openssl_err)?,
+    ))
+}
+
+/// Generate an ephemeral key on the curve of `group`
+pub(crate) fn generate_key(group: &EcGroup) -> Result<EcKey<Private>, LibcryptErr> {
+    EcKey::generate(group).map_err(openssl_err)
+}
+
+/// Multiply a point by a scalar
+pub(crate) fn mul(
+    group: &EcGroup,
+    point: &EcPointRef,
+    scalar: &openssl::bn::BigNumRef,
+) -> Result<EcPoint, LibcryptErr> {
+    let mut ctx = BigNumContext::new().map_err(openssl_err)?;
+    let mut result = EcPoint::new(group).map_err(openssl_err)?;
+    result
+        .mul2(group, point, scalar, &mut ctx)
+        .map_err(openssl_err)?;
+    Ok(result)
+}
+
+/// Add two points, negating `b` first if `subtract` is set
+pub(crate) fn add(
+    group: &EcGroup,
+    a: &EcPointRef,
+    b: &EcPointRef,
+    subtract: bool,
+) -> Result<EcPoint, LibcryptErr> {
+    let mut ctx = BigNumContext::new().map_err(openssl_err)?;
+    let mut b = b.to_owned(group).map_err(openssl_err)?;
+    if subtract {
+        b.invert2(group, &mut ctx).map_err(openssl_err)?;
+    }
+    let mut result = EcPoint::new(group).map_err(openssl_err)?;
+    result.add(group, a, &b, &mut ctx).map_err(openssl_err)?;
+    Ok(result)
+}
+
+/// Concat KDF from NIST SP 800-56A as profiled by RFC 7518 section 4.6.2
+pub(crate) fn concat_kdf(
+    z: &[u8],
+    algorithm: &str,
+    apu: &[u8],
+    apv: &[u8],
+    key_len: usize,
+) -> Result<Vec<u8>, LibcryptErr> {
+    let mut other_info = Vec::new();
+    for field in [algorithm.as_bytes(), apu, apv].iter() {
+        other_info.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        other_info.extend_from_slice(field);
+    }
+    other_info.extend_from_slice(&((key_len * 8) as u32).to_be_bytes());
+
+    let mut key = Vec::new();
+    let mut counter = 1u32;
+    while key.len() < key_len {
+        let mut input = counter.to_be_bytes().to_vec();
+        input.extend_from_slice(z);
+        input.extend_from_slice(&other_info);
+        key.extend_from_slice(&hash(MessageDigest::sha256(), &input).map_err(openssl_err)?);
+        counter += 1;
+    }
+    key.truncate(key_len);
+    Ok(key)
+}
+
+fn gcm_cipher(enc: &str) -> Result<(Cipher, usize), LibcryptErr> {
+    match enc {
+        "A128GCM" => Ok((Cipher::aes_128_gcm(), 16)),
+        "A192GCM" => Ok((Cipher::aes_192_gcm(), 24)),
+        "A256GCM" => Ok((Cipher::aes_256_gcm(), 32)),
+        _ => Err(LibcryptErr::Other(format!(
+            "Unsupported content encryption {}",
+            enc
+        ))),
+    }
+}
+
+/// Get the content encryption key length of a JWE `enc` value
+pub(crate) fn content_key_len(enc: &str) -> Result<usize, LibcryptErr> {
+    gcm_cipher(enc).map(|(_, len)| len)
+}
+
+/// Encrypt `plaintext` into a JWE with the given protected header and content key
+pub(crate) fn encrypt(
+    header: &serde_json::Value,
+    key: &[u8],
+    plaintext: &[u8],
+) -> Result<ClevisJwe, LibcryptErr> {
+    let enc = header
+        .get("enc")
+        .and_then(|e| e.as_str())
+        .ok_or_else(|| LibcryptErr::Other("JWE header has no enc".to_string()))?;
+    let (cipher, _) = gcm_cipher(enc)?;
+    let protected = b64u_encode(&serde_json::to_vec(header).map_err(LibcryptErr::JsonError)?);
+    let mut iv = [0u8; GCM_IV_LEN];
+    openssl::rand::rand_bytes(&mut iv).map_err(openssl_err)?;
+    let mut tag = [0u8; GCM_TAG_LEN];
+    let ciphertext = encrypt_aead(
+        cipher,
+        key,
+        Some(&iv),
+        protected.as_bytes(),
+        plaintext,
+        &mut tag,
+    )
+    .map_err(openssl_err)?;
+    Ok(ClevisJwe {
+        protected,
+        encrypted_key: String::new(),
+        iv: b64u_encode(&iv),
+        ciphertext: b64u_encode(&ciphertext),
+        tag: b64u_encode(&tag),
+    })
+}
+
+/// Decrypt a JWE with the content key
+pub(crate) fn decrypt(jwe: &ClevisJwe, key: &[u8]) -> Result<Vec<u8>, LibcryptErr> {
+    let header = jwe.header()?;
+    let enc = header
+        .get("enc")
+        .and_then(|e| e.as_str())
+        .ok_or_else(|| LibcryptErr::Other("JWE header has no enc".to_string()))?;
+    let (cipher, _) = gcm_cipher(enc)?;
+    decrypt_aead(
+        cipher,
+        key,
+        Some(&b64u_decode(&jwe.iv)?),
+        jwe.protected.as_bytes(),
+        &b64u_decode(&jwe.ciphertext)?,
+        &b64u_decode(&jwe.tag)?,
+    )
+    .map_err(|_| LibcryptErr::Other("JWE decryption failed".to_string()))
+}
+
+/// Derive the ECDH-ES content key from the shared point `z`
+pub(crate) fn ecdh_es_key(
+    header: &serde_json::Value,
+    group: &EcGroup,
+    z: &EcPointRef,
+) -> Result<Vec<u8>, LibcryptErr> {
+    let enc = header
+        .get("enc")
+        .and_then(|e| e.as_str())
+        .ok_or_else(|| LibcryptErr::Other("JWE header has no enc".to_string()))?;
+    let party_info = |name: &str| -> Result<Vec<u8>, LibcryptErr> {
+        match header.get(name).and_then(|p| p.as_str()) {
+            Some(p) => b64u_decode(p),
+            None => Ok(Vec::new()),
+        }
+    };
+    let (x, _) = affine_coordinates(group, z)?;
+    concat_kdf(
+        &x,
+        enc,
+        &party_info("apu")?,
+        &party_info("apv")?,
+        content_key_len(enc)?,
+    )
+}
+
+fn signature_digest(alg: &str) -> Result<(MessageDigest, usize), LibcryptErr> {
+    match alg {
+        "ES256" => Ok((MessageDigest::sha256(), 32)),
+        "ES384" => Ok((MessageDigest::sha384(), 48)),
+        "ES512" => Ok((MessageDigest::sha512(), 66)),
+        _ => Err(LibcryptErr::Other(format!(
+            "Unsupported signature algorithm {}",
+            alg
+        ))),
+    }
+}
+
+/// Verify that every `verify` key of the JWK set in the JWS payload signed it, and
+/// return the key set
+pub(crate) fn verify_advertisement(jws: &serde_json::Value) -> Result<Vec<Jwk>, LibcryptErr> {
+    let payload = jws
+        .get("payload")
+        .and_then(|p| p.as_str())
+        .ok_or_else(|| LibcryptErr::Other("Advertisement has no payload".to_string()))?;
+    let keys: Vec<Jwk> = serde_json::from_slice::<serde_json::Value>(&b64u_decode(payload)?)
+        .map_err(LibcryptErr::JsonError)
+        .and_then(|v| {
+            serde_json::from_value(v.get("keys").cloned().unwrap_or(serde_json::Value::Null))
+                .map_err(LibcryptErr::JsonError)
+        })?;
+
+    let signatures = match jws.get("signatures").and_then(|s| s.as_array()) {
+        Some(s) => s.clone(),
+        None => vec![jws.clone()],
+    };
+    let verify_keys = keys
+        .iter()
+        .filter(|k| k.allows("verify"))
+        .collect::<Vec<_>>();
+    if verify_keys.is_empty() {
+        return Err(LibcryptErr::Other(
+            "Advertisement contains no signing keys".to_string(),
+        ));
+    }
+    for key in verify_keys {
+        let public_key = key.public_key()?;
+        let verified = signatures
+            .iter()
+            .any(|signature| verify_signature(payload, signature, &public_key).unwrap_or(false));
+        if !verified {
+            return Err(LibcryptErr::Other(
+                "Advertisement is not signed by all of its signing keys".to_string(),
+            ));
+        }
+    }
+    Ok(keys)
+}
+
+fn verify_signature(
+    payload: &str,
+    signature: &serde_json::Value,
+    key: &EcKey<Public>,
+) -> Result<bool, LibcryptErr> {
+    let protected = signature
+        .get("protected")
+        .and_then(|p| p.as_str())
+        .ok_or_else(|| LibcryptErr::Other("Signature has no protected header".to_string()))?;
+    let header: serde_json::Value =
+        serde_json::from_slice(&b64u_decode(protected)?).map_err(LibcryptErr::JsonError)?;
+    let alg = header
+        .get("alg")
+        .and_then(|a| a.as_str())
+        .ok_or_else(|| LibcryptErr::Other("Signature has no alg".to_string()))?;
+    let (digest, len) = signature_digest(alg)?;
+    let raw = b64u_decode(
+        signature
+            .get("signature")
+            .and_then(|s| s.as_str())
+            .ok_or_else(|| LibcryptErr::Other("Signature is missing".to_string()))?,
+    )?;
+    if raw.len() != 2 * len {
+        return Ok(false);
+    }
+    let sig = EcdsaSig::from_private_components(
+        BigNum::from_slice(&raw[..len]).map_err(openssl_err)?,
+        BigNum::from_slice(&raw[len..]).map_err(openssl_err)?,
+    )
+    .map_err(openssl_err)?;
+    let signing_input = format!("{}.{}", protected, payload);
+    let hashed = hash(digest, signing_input.as_bytes()).map_err(openssl_err)?;
+    sig.verify(&hashed, key).map_err(openssl_err)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_concat_kdf() {
+        // RFC 7518 appendix C
+        let z = [
+            158, 86, 217, 29, 129, 113, 53, 211, 114, 131, 66, 131, 191, 132, 38, 156, 251, 49,
+            110, 163, 218, 128, 106, 72, 246, 218, 167, 121, 140, 254, 144, 196,
+        ];
+        let key = concat_kdf(&z, "A128GCM", b"Alice", b"Bob", 16).unwrap();
+        assert_eq!(b64u_encode(&key), "VqqN6vgjbSBcIijNcacQGg");
+    }
+
+    #[test]
+    fn test_thumbprint() {
+        let jwk = Jwk {
+            kty: "EC".to_string(),
+            crv: Some("P-256".to_string()),
+            x: Some("f83OJ3D2xF1Bg8vub9tLe1gHMzV76e8Tus9uPHvRVEU".to_string()),
+            y: Some("x_FEzRu9m36HLN_tue659LNpXW6pCyStikYjKIWI5a0".to_string()),
+            ..Default::default()
+        };
+        let sha256 = jwk.thumbprint(MessageDigest::sha256()).unwrap();
+        assert!(jwk.has_thumbprint(&sha256));
+        assert!(!jwk.has_thumbprint("AAAA"));
+        assert!(jwk.public_key().is_ok());
+    }
+
+    #[test]
+    fn test_ecdh_es_round_trip() {
+        let group = EcGroup::from_curve_name(Nid::SECP521R1).unwrap();
+        let recipient = generate_key(&group).unwrap();
+        let ephemeral = generate_key(&group).unwrap();
+        let header = serde_json::json!({"alg": "ECDH-ES", "enc": "A256GCM"});
+
+        let z = mul(&group, recipient.public_key(), ephemeral.private_key()).unwrap();
+        let key = ecdh_es_key(&header, &group, &z).unwrap();
+        let jwe = encrypt(&header, &key, b"secret").unwrap();
+
+        let z = mul(&group, ephemeral.public_key(), recipient.private_key()).unwrap();
+        let key = ecdh_es_key(&header, &group, &z).unwrap();
+        assert_eq!(decrypt(&jwe, &key).unwrap(), b"secret");
+        assert!(decrypt(&jwe, &[0u8; 32]).is_err());
+    }
+}
@@ -0,0 +1,325 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    os::raw::c_int,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Once,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
+
+use crate::{
+    backend::LibcryptBackend,
+    device::CryptDevice,
+    err::LibcryptErr,
+    keyfile::CryptKeyfileFlags,
+    log::CryptLogLevel,
+    luks2_token::{list_tokens, CryptLuks2Token, TokenList},
+};
+
+macro_rules! keyfile_token_type {
+    () => {
+        "libcryptsetup-rs-keyfile"
+    };
+}
+
+/// Token type of keyfile references handled by `CryptKeyfileToken`
+pub const KEYFILE_TOKEN_TYPE: &str = keyfile_token_type!();
+
+/// Directory of the udev symlinks to block devices by filesystem UUID
+const DISK_BY_UUID: &str = "/dev/disk/by-uuid";
+
+static REGISTER_HANDLER: Once = Once::new();
+static REGISTER_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Location of the keyfile referenced by a token
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum KeyfileLocation {
+    /// Path to a keyfile or block device
+    #[serde(rename = "keyfile")]
+    Path(PathBuf),
+    /// Filesystem UUID of a block device used as keyfile, resolved through
+    /// `/dev/disk/by-uuid` when the token is opened
+    #[serde(rename = "keyfile_uuid")]
+    Uuid(String),
+}
+
+impl KeyfileLocation {
+    /// Get the path the keyfile is read from
+    pub fn path(&self) -> Result<PathBuf, LibcryptErr> {
+        match *self {
+            KeyfileLocation::Path(ref path) => Ok(path.clone()),
+            KeyfileLocation::Uuid(ref uuid) => {
+                if uuid.is_empty() || uuid.contains('/') || uuid.starts_with('.') {
+                    return Err(LibcryptErr::Other(format!("Invalid keyfile UUID {}", uuid)));
+                }
+                Ok(PathBuf::from(DISK_BY_UUID).join(uuid))
+            }
+        }
+    }
+}
+
+/// Token referencing a keyfile that is read when the token is opened
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct KeyfileToken {
+    /// Keyslots unlocked by the keyfile
+    pub keyslots: Vec<String>,
+    /// Location of the keyfile
+    #[serde(flatten)]
+    pub location: KeyfileLocation,
+    /// Number of bytes to read, the whole keyfile if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keyfile_size: Option<u64>,
+    /// Offset in bytes at which to start reading
+    #[serde(default)]
+    pub keyfile_offset: u64,
+}
+
+impl KeyfileToken {
+    /// Parse token JSON as returned by `CryptLuks2Token::json_get`
+    pub fn from_json(json: &serde_json::Value) -> Result<Self, LibcryptErr> {
+        match json.get("type").and_then(|t| t.as_str()) {
+            Some(KEYFILE_TOKEN_TYPE) => (),
+            _ => {
+                return Err(LibcryptErr::Other(
+                    "Token is not a keyfile token".to_string(),
+                ))
+            }
+        }
+        let token: KeyfileToken =
+            serde_json::from_value(json.clone()).map_err(LibcryptErr::JsonError)?;
+        token.location.path()?;
+        if token.keyfile_size == Some(0) {
+            return Err(LibcryptErr::Other(
+                "Keyfile size must not be zero".to_string(),
+            ));
+        }
+        Ok(token)
+    }
+
+    /// Convert the token to JSON suitable for `CryptLuks2Token::json_set`
+    pub fn to_json(&self) -> Result<serde_json::Value, LibcryptErr> {
+        let mut value = serde_json::to_value(self).map_err(LibcryptErr::JsonError)?;
+        if let Some(map) = value.as_object_mut() {
+            map.insert(
+                "type".to_string(),
+                serde_json::Value::from(KEYFILE_TOKEN_TYPE),
+            );
+        }
+        Ok(value)
+    }
+
+    /// Read the referenced keyfile
+    fn read(&self, device: &mut CryptDevice) -> Result<Box<[u8]>, LibcryptErr> {
+        let contents = device.keyfile_handle().device_read(
+            &self.location.path()?,
+            self.keyfile_offset,
+            // Zero reads until the end of the keyfile
+            Some(self.keyfile_size.unwrap_or(0) as crate::size_t),
+            CryptKeyfileFlags::empty(),
+        )?;
+        Ok(Box::from(contents.as_ref()))
+    }
+}
+
+fn keyfile_token_open(
+    device: &mut CryptDevice,
+    token: c_int,
+    _: Option<&mut ()>,
+) -> Result<Box<[u8]>, LibcryptErr> {
+    let json = device.token_handle(token).json_get()?;
+    KeyfileToken::from_json(&json)?.read(device)
+}
+
+fn keyfile_token_free(mut buffer: Box<[u8]>) {
+    buffer.zeroize();
+}
+
+fn keyfile_token_validate(_: &mut CryptDevice, json: serde_json::Value) -> Result<(), LibcryptErr> {
+    KeyfileToken::from_json(&json).map(|_| ())
+}
+
+fn keyfile_token_dump(device: &mut CryptDevice, json: serde_json::Value) {
+    let token = match KeyfileToken::from_json(&json) {
+        Ok(t) => t,
+        Err(_) => return,
+    };
+    let location = match token.location {
+        KeyfileLocation::Path(ref path) => format!("\tKeyfile:    {}\n", path.display()),
+        KeyfileLocation::Uuid(ref uuid) => format!("\tKeyfile:    UUID={}\n", uuid),
+    };
+    let size = match token.keyfile_size {
+        Some(size) => format!("\tSize:       {} [bytes]\n", size),
+        None => String::new(),
+    };
+    let _ = device.logging_handle().log(
+        CryptLogLevel::Normal,
+        &format!(
+            "{}{}\tOffset:     {} [bytes]\n",
+            location, size, token.keyfile_offset
+        ),
+    );
+}
+
+c_token_handler_open!(c_keyfile_token_open, (), keyfile_token_open);
+c_token_handler_free!(c_keyfile_token_free, keyfile_token_free);
+c_token_handler_validate!(c_keyfile_token_validate, keyfile_token_validate);
+c_token_handler_dump!(c_keyfile_token_dump, keyfile_token_dump);
+
+/// Handle for the built-in keyfile reference token
+///
+/// The token records where a keyfile can be found, for example on removable media, so
+/// `CryptLuks2Token::activate_by_token` can unlock the device without the caller knowing
+/// the keyfile location.
+pub struct CryptKeyfileToken;
+
+impl CryptKeyfileToken {
+    /// Register the token handler with libcryptsetup
+    ///
+    /// Registration is process wide and only attempted once; concurrent callers wait
+    /// for it to finish. It must happen before tokens of this type are written or used
+    /// for activation.
+    pub fn register() -> Result<(), LibcryptErr> {
+        let mut result = Ok(());
+        REGISTER_HANDLER.call_once(|| {
+            result = CryptLuks2Token::register(
+                concat!(keyfile_token_type!(), "\0"),
+                Some(c_keyfile_token_open),
+                Some(c_keyfile_token_free),
+                Some(c_keyfile_token_validate),
+                Some(c_keyfile_token_dump),
+            );
+            REGISTER_FAILED.store(result.is_err(), Ordering::SeqCst);
+        });
+        if result.is_ok() && REGISTER_FAILED.load(Ordering::SeqCst) {
+            return Err(LibcryptErr::Other(
+                "Registering the keyfile token handler failed".to_string(),
+            ));
+        }
+        result
+    }
+
+    /// List the keyfile tokens of a device with a loaded LUKS2 header
+    ///
+    /// Keyfile tokens that cannot be parsed are skipped and reported in
+    /// `TokenList::malformed`.
+    pub fn list<B: LibcryptBackend>(
+        device: &mut B,
+    ) -> Result<TokenList<KeyfileToken>, LibcryptErr> {
+        list_tokens(device, &[KEYFILE_TOKEN_TYPE], KeyfileToken::from_json)
+    }
+
+    /// Register the handler and write a keyfile token, allocating a new token if `token`
+    /// is `None`
+    pub fn write<B: LibcryptBackend>(
+        device: &mut B,
+        token: Option<c_int>,
+        keyfile_token: &KeyfileToken,
+    ) -> Result<c_int, LibcryptErr> {
+        Self::register()?;
+        device.token_json_set(token, &keyfile_token.to_json()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::backend::SimulatedBackend;
+
+    #[test]
+    fn test_keyfile_token_json() {
+        let json = serde_json::json!({
+            "type": KEYFILE_TOKEN_TYPE,
+            "keyslots": ["0"],
+            "keyfile_uuid": "1234-ABCD",
+            "keyfile_size": 4096,
+            "keyfile_offset": 512,
+        });
+        let token = KeyfileToken::from_json(&json).unwrap();
+        assert_eq!(
+            token.location,
+            KeyfileLocation::Uuid("1234-ABCD".to_string())
+        );
+        assert_eq!(
+            token.location.path().unwrap(),
+            PathBuf::from("/dev/disk/by-uuid/1234-ABCD")
+        );
+        assert_eq!(token.to_json().unwrap(), json);
+
+        let token = KeyfileToken::from_json(&serde_json::json!({
+            "type": KEYFILE_TOKEN_TYPE,
+            "keyslots": [],
+            "keyfile": "/media/usb/key",
+        }))
+        .unwrap();
+        assert_eq!(
+            token.location.path().unwrap(),
+            PathBuf::from("/media/usb/key")
+        );
+        assert_eq!(token.keyfile_size, None);
+        assert_eq!(token.keyfile_offset, 0);
+
+        for invalid in [
+            serde_json::json!({"type": "luks2-keyring", "keyslots": [], "keyfile": "/k"}),
+            serde_json::json!({"type": KEYFILE_TOKEN_TYPE, "keyslots": []}),
+            serde_json::json!({
+                "type": KEYFILE_TOKEN_TYPE,
+                "keyslots": [],
+                "keyfile_uuid": "../sda",
+            }),
+            serde_json::json!({
+                "type": KEYFILE_TOKEN_TYPE,
+                "keyslots": [],
+                "keyfile": "/k",
+                "keyfile_size": 0,
+            }),
+        ]
+        .iter()
+        {
+            assert!(KeyfileToken::from_json(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn test_list() {
+        let mut backend = SimulatedBackend::new();
+        backend.format_luks2(("aes", "xts-plain64"), 64).unwrap();
+        backend
+            .keyslot_add_by_passphrase(None, b"", b"first")
+            .unwrap();
+        backend
+            .token_json_set(
+                None,
+                &serde_json::json!({"type": "luks2-keyring", "keyslots": ["0"]}),
+            )
+            .unwrap();
+        let keyfile_token = KeyfileToken {
+            keyslots: vec!["0".to_string()],
+            location: KeyfileLocation::Path(PathBuf::from("/media/usb/key")),
+            keyfile_size: Some(4096),
+            keyfile_offset: 0,
+        };
+        let token = backend
+            .token_json_set(None, &keyfile_token.to_json().unwrap())
+            .unwrap();
+        let malformed = backend
+            .token_json_set(
+                None,
+                &serde_json::json!({"type": KEYFILE_TOKEN_TYPE, "keyslots": ["0"]}),
+            )
+            .unwrap();
+        let list = CryptKeyfileToken::list(&mut backend).unwrap();
+        assert_eq!(list.tokens, vec![(token, keyfile_token)]);
+        assert_eq!(
+            list.malformed.iter().map(|(t, _)| *t).collect::<Vec<_>>(),
+            vec![malformed]
+        );
+    }
+}
@@ -10,9 +10,18 @@ use std::{
 };
 
 use crate::{
-    device::CryptDevice, err::LibcryptErr, format::EncryptionFormat, settings::CryptPbkdfType,
+    activate::CryptActivateFlags,
+    device::CryptDevice,
+    err::LibcryptErr,
+    format::EncryptionFormat,
+    luks2_token::{CryptTokenInfo, LUKS2_TOKENS_MAX},
+    settings::CryptPbkdfType,
+    Bool,
 };
 
+/// Number of keyslots available in a LUKS2 header
+pub(crate) const LUKS2_KEYSLOTS_MAX: c_int = 32;
+
 consts_to_from_enum!(
     /// Flags for tunable options when operating with volume keys
     CryptVolumeKeyFlag,
@@ -103,6 +112,105 @@ impl<'a> CryptKeyslot<'a> {
         })
     }
 
+    /// Re-create a keyslot in place with a new PBKDF or keyslot cipher
+    ///
+    /// The keyslot is re-wrapped under the same passphrase and keeps its number, and for
+    /// LUKS2 its priority and token assignments. `pbkdf` and `encryption` (keyslot cipher
+    /// and key size in bytes) default to the current settings of the device when `None`.
+    /// If the handle was created without a keyslot, the keyslot opened by `passphrase` is
+    /// converted. The PBKDF and keyslot encryption settings of the device are restored
+    /// afterwards. Returns the converted keyslot.
+    pub fn convert(
+        &mut self,
+        passphrase: &[u8],
+        pbkdf: Option<&CryptPbkdfType>,
+        encryption: Option<(&str, crate::size_t)>,
+    ) -> Result<c_int, LibcryptErr> {
+        let keyslot = self.open(self.keyslot, passphrase)?;
+        let is_luks2 = matches!(
+            self.reference.format_handle().get_type()?,
+            EncryptionFormat::Luks2
+        );
+        let (priority, tokens) = if is_luks2 {
+            let priority = CryptKeyslot::new(self.reference, Some(keyslot)).get_priority()?;
+            let mut tokens = Vec::new();
+            for token in 0..LUKS2_TOKENS_MAX {
+                let mut handle = self.reference.token_handle(token);
+                match handle.status()?.0 {
+                    CryptTokenInfo::Invalid | CryptTokenInfo::Inactive => continue,
+                    _ => (),
+                }
+                if let Bool::Yes = handle.is_assigned(keyslot)? {
+                    tokens.push(token);
+                }
+            }
+            (Some(priority), tokens)
+        } else {
+            (None, Vec::new())
+        };
+
+        let previous_pbkdf = match pbkdf {
+            Some(p) => {
+                let previous = self.reference.settings_handle().get_pbkdf_type()?;
+                self.reference.settings_handle().set_pbkdf_type(p)?;
+                Some(previous)
+            }
+            None => None,
+        };
+        let previous_encryption = match encryption {
+            Some(_) => {
+                let mut handle = CryptKeyslot::new(self.reference, None);
+                let (cipher, key_size) = handle.get_encryption()?;
+                Some((cipher.to_string(), key_size))
+            }
+            None => None,
+        };
+        let result = encryption
+            .map_or(Ok(()), |(cipher, key_size)| {
+                self.set_encryption(cipher, key_size)
+            })
+            .and_then(|_| self.change_by_passphrase(keyslot, keyslot, passphrase, passphrase));
+        if let Some(ref previous) = previous_pbkdf {
+            self.reference.settings_handle().set_pbkdf_type(previous)?;
+        }
+        if let Some((ref cipher, key_size)) = previous_encryption {
+            self.set_encryption(cipher, key_size)?;
+        }
+        if result? != keyslot {
+            return Err(LibcryptErr::Other(format!(
+                "Keyslot {} was moved during conversion",
+                keyslot
+            )));
+        }
+
+        if let Some(priority) = priority {
+            let mut handle = CryptKeyslot::new(self.reference, Some(keyslot));
+            if handle.get_priority()? as i32 != priority as i32 {
+                handle.set_priority(priority)?;
+            }
+        }
+        for token in tokens {
+            let mut handle = self.reference.token_handle(token);
+            if let Bool::No = handle.is_assigned(keyslot)? {
+                handle.assign_keyslot(keyslot)?;
+            }
+        }
+        self.open(keyslot, passphrase)
+    }
+
+    fn open(&mut self, keyslot: c_int, passphrase: &[u8]) -> Result<c_int, LibcryptErr> {
+        self.reference.activate_handle().activate_by_passphrase(
+            None,
+            if keyslot == libcryptsetup_rs_sys::CRYPT_ANY_SLOT {
+                None
+            } else {
+                Some(keyslot)
+            },
+            passphrase,
+            CryptActivateFlags::empty(),
+        )
+    }
+
     /// Add key slot using key file
     pub fn add_by_keyfile_device_offset(
         &mut self,
@@ -0,0 +1,129 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! AES key wrap (RFC 3394) used to protect escrowed volume keys
+
+use openssl::{
+    cipher::{Cipher, CipherRef},
+    cipher_ctx::{CipherCtx, CipherCtxFlags},
+};
+use zeroize::Zeroizing;
+
+use crate::err::LibcryptErr;
+
+/// Size of a key wrap block and of the integrity check value
+const KW_BLOCK_SIZE: usize = 8;
+
+fn cipher(kek: &[u8]) -> Result<&'static CipherRef, LibcryptErr> {
+    match kek.len() {
+        16 => Ok(Cipher::aes_128_wrap()),
+        24 => Ok(Cipher::aes_192_wrap()),
+        32 => Ok(Cipher::aes_256_wrap()),
+        len => Err(LibcryptErr::Other(format!(
+            "Invalid key encryption key length {}",
+            len
+        ))),
+    }
+}
+
+fn context() -> Result<CipherCtx, LibcryptErr> {
+    let mut ctx = CipherCtx::new().map_err(LibcryptErr::OpensslError)?;
+    ctx.set_flags(CipherCtxFlags::FLAG_WRAP_ALLOW);
+    Ok(ctx)
+}
+
+/// Wrap `key` with the key encryption key `kek` using AES key wrap
+///
+/// `key` must be a multiple of 8 bytes and at least 16 bytes long.
+pub(crate) fn wrap(kek: &[u8], key: &[u8]) -> Result<Vec<u8>, LibcryptErr> {
+    if key.len() < 2 * KW_BLOCK_SIZE || key.len() % KW_BLOCK_SIZE != 0 {
+        return Err(LibcryptErr::Other(format!(
+            "Cannot wrap key of length {}",
+            key.len()
+        )));
+    }
+    let mut ctx = context()?;
+    ctx.encrypt_init(Some(cipher(kek)?), Some(kek), None)
+        .map_err(LibcryptErr::OpensslError)?;
+    let mut wrapped = vec![0u8; key.len() + KW_BLOCK_SIZE];
+    let len = ctx
+        .cipher_update(key, Some(&mut wrapped))
+        .map_err(LibcryptErr::OpensslError)?;
+    wrapped.truncate(len);
+    Ok(wrapped)
+}
+
+/// Unwrap a key wrapped with `wrap`, checking its integrity
+///
+/// The unwrapped key is cleared from memory when dropped.
+pub(crate) fn unwrap(kek: &[u8], wrapped: &[u8]) -> Result<Zeroizing<Vec<u8>>, LibcryptErr> {
+    if wrapped.len() < 3 * KW_BLOCK_SIZE || wrapped.len() % KW_BLOCK_SIZE != 0 {
+        return Err(LibcryptErr::Other(format!(
+            "Invalid wrapped key length {}",
+            wrapped.len()
+        )));
+    }
+    let mut ctx = context()?;
+    ctx.decrypt_init(Some(cipher(kek)?), Some(kek), None)
+        .map_err(LibcryptErr::OpensslError)?;
+    let mut key = Zeroizing::new(vec![0u8; wrapped.len() + KW_BLOCK_SIZE]);
+    // OpenSSL compares the integrity check value in constant time
+    let len = ctx
+        .cipher_update(wrapped, Some(&mut key))
+        .map_err(|_| LibcryptErr::Other("Key unwrap integrity check failed".to_string()))?;
+    key.truncate(len);
+    Ok(key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_key_wrap_rfc_3394() {
+        let vectors = [
+            (
+                "000102030405060708090a0b0c0d0e0f",
+                "00112233445566778899aabbccddeeff",
+                "1fa68b0a8112b447aef34bd8fb5a7b829d3e862371d2cfe5",
+            ),
+            (
+                "000102030405060708090a0b0c0d0e0f1011121314151617",
+                "00112233445566778899aabbccddeeff",
+                "96778b25ae6ca435f92b5b97c050aed2468ab8a17ad84e5d",
+            ),
+            (
+                "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+                "00112233445566778899aabbccddeeff0001020304050607",
+                "a8f9bc1612c68b3ff6e6f4fbe30e71e4769c8b80a32cb8958cd5d17d6b254da1",
+            ),
+            (
+                "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+                "00112233445566778899aabbccddeeff000102030405060708090a0b0c0d0e0f",
+                "28c9f404c4b810f4cbccb35cfb87f8263f5786e2d80ed326cbc7f0e71a99f43bfb988b9b7a02dd21",
+            ),
+        ];
+        for &(kek, key, wrapped) in vectors.iter() {
+            assert_eq!(wrap(&hex(kek), &hex(key)).unwrap(), hex(wrapped));
+            assert_eq!(*unwrap(&hex(kek), &hex(wrapped)).unwrap(), hex(key));
+        }
+    }
+
+    #[test]
+    fn test_unwrap_wrong_kek() {
+        let kek = [1u8; 32];
+        let wrapped = wrap(&kek, &[7u8; 64]).unwrap();
+        assert!(unwrap(&[2u8; 32], &wrapped).is_err());
+        assert!(unwrap(&kek, &wrapped[..wrapped.len() - 8]).is_err());
+        assert!(wrap(&kek, &[7u8; 12]).is_err());
+        assert!(wrap(&[1u8; 20], &[7u8; 16]).is_err());
+    }
+}
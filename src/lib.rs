@@ -15,6 +15,7 @@
 // time.
 
 pub use either::Either;
+pub use zeroize::Zeroizing;
 
 use std::os::raw::c_int;
 
@@ -27,53 +28,126 @@ pub use activate::{
     CryptDeactivateFlags,
 };
 
+mod backend;
+pub use backend::{LibcryptBackend, SimulatedBackend, SimulatedOperation};
+
 mod backup;
 pub use backup::CryptBackup;
 
+mod benchmark;
+pub use benchmark::CryptBenchmark;
+
+mod clevis;
+#[cfg(feature = "clevis")]
+pub use clevis::{clevis_decrypt, ClevisPin};
+pub use clevis::{ClevisJwe, ClevisPolicy, ClevisToken, CryptClevis, CLEVIS_TOKEN_TYPE};
+
 mod context;
 pub use context::CryptContext;
 
+mod credential;
+pub use credential::CryptCredential;
+
 mod debug;
 pub use debug::{CryptDebug, CryptDebugLevel};
 
 mod device;
 pub use device::{CryptDevice, CryptInit};
 
+mod discover;
+pub use discover::{CryptDiscovery, DiscoveredFormat, DiscoveredVolume};
+
+mod dump;
+pub use dump::{DeviceDump, DumpDigest, DumpKeyslot, DumpSegment, DumpToken};
+
+mod erase;
+pub use erase::{CryptErase, EraseReport};
+
 mod err;
 pub use err::LibcryptErr;
 
+mod escrow;
+pub use escrow::{CryptEscrow, VolumeKeyEscrow, ESCROW_VERSION};
+
 mod format;
 pub use format::{
     CryptFormat, CryptParamsIntegrity, CryptParamsIntegrityRef, CryptParamsLuks2,
-    CryptParamsLuks2Ref, CryptParamsVerity, CryptVerityFlag, CryptVerityFlags, EncryptionFormat,
+    CryptParamsLuks2Ref, CryptParamsTcrypt, CryptParamsTcryptRef, CryptParamsVerity,
+    CryptParamsVerityRef, CryptTcryptFlag, CryptTcryptFlags, CryptVerityFlag, CryptVerityFlags,
+    EncryptionFormat,
+};
+
+mod integrity;
+pub use integrity::{
+    CryptIntegrity, IntegrityActivation, IntegrityFailureChange, IntegrityJournalMode,
+    IntegrityMonitor,
 };
 
+#[cfg(feature = "clevis")]
+mod jose;
+
 mod key;
 pub use key::CryptVolumeKey;
 
 mod keyfile;
 pub use keyfile::{CryptKeyfile, CryptKeyfileContents};
 
+mod keyfile_token;
+pub use keyfile_token::{CryptKeyfileToken, KeyfileLocation, KeyfileToken, KEYFILE_TOKEN_TYPE};
+
 mod keyslot;
 pub use keyslot::{
     CryptKeyslot, CryptVolumeKeyFlag, CryptVolumeKeyFlags, KeyslotInfo, KeyslotPriority,
 };
 
+mod keywrap;
+
 mod log;
 pub use log::{CryptLog, CryptLogLevel};
 
+mod luks2_check;
+pub use luks2_check::{CryptHeaderCheck, HeaderCopy, HeaderDiagnostic};
+
 mod luks2_flags;
 pub use luks2_flags::{CryptLuks2Flags, CryptRequirementFlag, CryptRequirementFlags};
 
+mod luks2_header;
+
+mod luks2_inplace;
+pub use luks2_inplace::{CryptInPlace, InPlaceHeader, MIN_DATA_SHIFT};
+
+mod luks2_metadata;
+pub use luks2_metadata::MetadataTransaction;
+
 mod luks2_reencrypt;
 pub use luks2_reencrypt::{
     CryptLuks2Reencrypt, CryptParamsReencrypt, CryptParamsReencryptRef,
     CryptReencryptDirectionInfo, CryptReencryptFlag, CryptReencryptFlags, CryptReencryptInfo,
-    CryptReencryptModeInfo,
+    CryptReencryptModeInfo, CryptReencryptStatus,
 };
 
+mod luks_migrate;
+pub use luks_migrate::{CryptMigration, MigrationIssue, MigrationReport};
+
 mod luks2_token;
-pub use luks2_token::{CryptLuks2Token, CryptTokenInfo};
+pub use luks2_token::{CryptLuks2Token, CryptTokenInfo, TokenList};
+
+mod policy;
+pub use policy::{PbkdfPolicy, PolicyFinding, PolicyReport, PolicyRule, SecurityPolicy};
+
+mod prompt;
+pub use prompt::{Passphrase, PassphrasePrompt};
+
+mod recovery_key;
+pub use recovery_key::{
+    CryptRecoveryKey, RecoveryKey, RecoveryKeyEnrollment, SYSTEMD_RECOVERY_TOKEN_TYPE,
+};
+
+mod reencrypt_resume;
+pub use reencrypt_resume::CryptReencryptResume;
+
+mod resize;
+pub use resize::{CryptResize, ResizeReport};
 
 mod runtime;
 pub use runtime::{ActiveDevice, CryptRuntime};
@@ -87,12 +161,39 @@ pub use settings::{
 mod status;
 pub use status::{CryptDeviceStatus, CryptStatusInfo};
 
+mod systemd_token;
+pub use systemd_token::{
+    CryptSystemdTokens, SystemdFido2Token, SystemdPkcs11Token, SystemdRecoveryToken, SystemdToken,
+    SystemdTpm2Token, SYSTEMD_FIDO2_TOKEN_TYPE, SYSTEMD_PKCS11_TOKEN_TYPE, SYSTEMD_TPM2_TOKEN_TYPE,
+};
+
+#[cfg(feature = "clevis")]
+mod tang;
+
+mod tcrypt;
+pub use tcrypt::{CryptTcrypt, TcryptInfo};
+
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+
 #[cfg(test)]
 mod tests;
 
+mod typed_device;
+pub use typed_device::{ActiveMapping, Device, Initialized, Luks1, Luks2, LuksFormat};
+
+mod unlock;
+pub use unlock::{UnlockFailure, UnlockPipeline, UnlockReport, UnlockStrategy};
+
 mod wipe;
 pub use wipe::{CryptWipe, CryptWipePattern};
 
+mod wipe_device;
+pub use wipe_device::{
+    CryptDeviceWipe, WipeCheckpoint, WipeProgress, WipeReport, WipeTarget, WipeVerification,
+    DEFAULT_CHECKPOINT_INTERVAL,
+};
+
 /// Re-export of `libc::size_t`
 pub use libc::size_t;
 
@@ -136,6 +237,25 @@ impl From<c_int> for Interrupt {
 mod test {
     use crate::tests;
 
+    #[cfg(feature = "clevis")]
+    #[ignore]
+    #[test]
+    fn test_clevis_bind_unlock() {
+        tests::clevis::test_clevis_bind_unlock();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_convert_keyslot_to_argon2id() {
+        tests::convert::test_convert_keyslot_to_argon2id();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_discover_luks2() {
+        tests::discover::test_discover_luks2();
+    }
+
     #[ignore]
     #[test]
     fn test_encrypt_by_password() {
@@ -153,4 +273,161 @@ mod test {
     fn test_unencrypted() {
         tests::encrypt::test_unecrypted();
     }
+
+    #[cfg(feature = "test-utils")]
+    #[ignore]
+    #[test]
+    fn test_fixtures_teardown() {
+        tests::fixtures::test_fixtures_teardown();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_erase_keyslots() {
+        tests::erase::test_erase_keyslots();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_erase_header() {
+        tests::erase::test_erase_header();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_escrow_round_trip() {
+        tests::escrow::test_escrow_round_trip();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_integrity_format_activate() {
+        tests::integrity::test_integrity_format_activate();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_keyfile_token_activate() {
+        tests::keyfile_token::test_keyfile_token_activate();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_metadata_transaction() {
+        tests::metadata::test_metadata_transaction();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_policy_audit() {
+        tests::policy::test_policy_audit();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_recovery_key_enroll() {
+        tests::recovery_key::test_recovery_key_enroll();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_reencrypt_interrupt_resume() {
+        tests::reencrypt::test_reencrypt_interrupt_resume();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_reencrypt_crash_recover() {
+        tests::reencrypt::test_reencrypt_crash_recover();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_inplace_detached_round_trip() {
+        tests::inplace::test_inplace_detached_round_trip();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_inplace_encrypt_data_shift() {
+        tests::inplace::test_inplace_encrypt_data_shift();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_migrate_round_trip() {
+        tests::migrate::test_migrate_round_trip();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_migrate_argon2id_blocks_downgrade() {
+        tests::migrate::test_migrate_argon2id_blocks_downgrade();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_repair_secondary_header() {
+        tests::repair::test_repair_secondary_header();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_resize_mapping() {
+        tests::resize::test_resize_mapping();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_systemd_token_removal() {
+        tests::systemd_token::test_systemd_token_removal();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_tcrypt_outer_volume() {
+        tests::tcrypt::test_tcrypt_outer_volume();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_tcrypt_hidden_volume() {
+        tests::tcrypt::test_tcrypt_hidden_volume();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_tcrypt_keyfile() {
+        tests::tcrypt::test_tcrypt_keyfile();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_tcrypt_pim() {
+        tests::tcrypt::test_tcrypt_pim();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_typed_device_lifecycle() {
+        tests::typed::test_typed_device_lifecycle();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_unlock_pipeline() {
+        tests::unlock::test_unlock_pipeline();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_wipe_mapping_resume() {
+        tests::wipe::test_wipe_mapping_resume();
+    }
+
+    #[ignore]
+    #[test]
+    fn test_wipe_data_segment() {
+        tests::wipe::test_wipe_data_segment();
+    }
 }
@@ -0,0 +1,582 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use crate::{
+    device::CryptInit,
+    err::LibcryptErr,
+    format::{empty_luks2_params, EncryptionFormat},
+    luks2_header::{self, json_ids, json_u64, Luks2HeaderCopy},
+};
+
+/// One of the two copies of a LUKS2 header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeaderCopy {
+    /// Copy at the start of the device
+    Primary,
+    /// Copy directly after the primary copy
+    Secondary,
+}
+
+impl Display for HeaderCopy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HeaderCopy::Primary => write!(f, "primary"),
+            HeaderCopy::Secondary => write!(f, "secondary"),
+        }
+    }
+}
+
+/// Problem found in the LUKS2 header of a device
+#[derive(Clone, Debug, PartialEq)]
+pub enum HeaderDiagnostic {
+    /// A header copy could not be read or has invalid magic, version or size
+    Unreadable(HeaderCopy, String),
+    /// The stored checksum does not match the header contents
+    ChecksumMismatch(HeaderCopy),
+    /// The checksum algorithm is not supported by the checker
+    UnsupportedChecksum(HeaderCopy, String),
+    /// The header offset stored in the binary header does not match its location
+    OffsetMismatch {
+        /// Header copy
+        copy: HeaderCopy,
+        /// Offset the copy was read from
+        expected: u64,
+        /// Offset stored in the copy
+        found: u64,
+    },
+    /// The JSON area cannot be parsed
+    InvalidJson(HeaderCopy, String),
+    /// The header copies have different sequence IDs
+    SeqidMismatch {
+        /// Sequence ID of the primary copy
+        primary: u64,
+        /// Sequence ID of the secondary copy
+        secondary: u64,
+    },
+    /// A binary header field differs between the copies
+    BinaryMismatch(String),
+    /// The copies have the same sequence ID but different JSON metadata
+    JsonMismatch,
+    /// An object references another object that does not exist
+    DanglingReference {
+        /// Referencing object such as `digest 0`
+        from: String,
+        /// Missing object such as `keyslot 3`
+        to: String,
+    },
+    /// A crypt segment is not verified by any digest
+    SegmentWithoutDigest(u32),
+    /// Two keyslot areas overlap
+    OverlappingKeyslots(u32, u32),
+    /// A keyslot area lies outside of the keyslots area of the header
+    KeyslotOutOfBounds {
+        /// Keyslot ID
+        keyslot: u32,
+        /// Offset of the keyslot area in bytes
+        offset: u64,
+        /// Length of the keyslot area in bytes
+        length: u64,
+    },
+}
+
+impl HeaderDiagnostic {
+    /// Whether `crypt_repair` can fix this problem by restoring one header copy from
+    /// the other
+    pub fn is_repairable(&self) -> bool {
+        matches!(
+            *self,
+            HeaderDiagnostic::Unreadable(..)
+                | HeaderDiagnostic::ChecksumMismatch(_)
+                | HeaderDiagnostic::OffsetMismatch { .. }
+                | HeaderDiagnostic::InvalidJson(..)
+                | HeaderDiagnostic::SeqidMismatch { .. }
+                | HeaderDiagnostic::BinaryMismatch(_)
+                | HeaderDiagnostic::JsonMismatch
+        )
+    }
+}
+
+impl Display for HeaderDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HeaderDiagnostic::Unreadable(copy, ref e) => {
+                write!(f, "The {} header copy cannot be read: {}", copy, e)
+            }
+            HeaderDiagnostic::ChecksumMismatch(copy) => {
+                write!(f, "The {} header copy has an invalid checksum", copy)
+            }
+            HeaderDiagnostic::UnsupportedChecksum(copy, ref alg) => write!(
+                f,
+                "The {} header copy uses unsupported checksum algorithm {}",
+                copy, alg
+            ),
+            HeaderDiagnostic::OffsetMismatch {
+                copy,
+                expected,
+                found,
+            } => write!(
+                f,
+                "The {} header copy is at offset {} but records offset {}",
+                copy, expected, found
+            ),
+            HeaderDiagnostic::InvalidJson(copy, ref e) => {
+                write!(f, "The {} header copy has invalid JSON: {}", copy, e)
+            }
+            HeaderDiagnostic::SeqidMismatch { primary, secondary } => write!(
+                f,
+                "Header copies have different sequence IDs: primary {}, secondary {}",
+                primary, secondary
+            ),
+            HeaderDiagnostic::BinaryMismatch(ref field) => {
+                write!(f, "Header copies have different {}", field)
+            }
+            HeaderDiagnostic::JsonMismatch => write!(
+                f,
+                "Header copies have the same sequence ID but different JSON metadata"
+            ),
+            HeaderDiagnostic::DanglingReference { ref from, ref to } => {
+                write!(f, "{} references missing {}", from, to)
+            }
+            HeaderDiagnostic::SegmentWithoutDigest(segment) => {
+                write!(f, "Segment {} is not assigned to any digest", segment)
+            }
+            HeaderDiagnostic::OverlappingKeyslots(a, b) => {
+                write!(f, "Areas of keyslots {} and {} overlap", a, b)
+            }
+            HeaderDiagnostic::KeyslotOutOfBounds {
+                keyslot,
+                offset,
+                length,
+            } => write!(
+                f,
+                "Area of keyslot {} at offset {} with length {} is outside of the keyslots area",
+                keyslot, offset, length
+            ),
+        }
+    }
+}
+
+/// Handle for checking and repairing LUKS2 headers
+pub struct CryptHeaderCheck;
+
+impl CryptHeaderCheck {
+    /// Read both LUKS2 header copies of a device and report any inconsistency
+    ///
+    /// The header is read directly from disk, so damaged headers that libcryptsetup
+    /// refuses to load can still be inspected. An empty list means that no problem
+    /// was found.
+    pub fn check(device_path: &Path) -> Result<Vec<HeaderDiagnostic>, LibcryptErr> {
+        let mut file = File::open(device_path).map_err(LibcryptErr::IOError)?;
+        Ok(check_file(&mut file).0)
+    }
+
+    /// Check the header and, if any problem repairable by `crypt_repair` was found,
+    /// back up the header area to `backup_file` and run the repair
+    ///
+    /// `backup_file` must not exist. The backup is a raw copy of both header copies
+    /// and the keyslots area, which can be restored with
+    /// `CryptBackup::header_restore`. Returns the diagnostics remaining after the
+    /// repair.
+    pub fn repair(
+        device_path: &Path,
+        backup_file: &Path,
+    ) -> Result<Vec<HeaderDiagnostic>, LibcryptErr> {
+        let mut file = File::open(device_path).map_err(LibcryptErr::IOError)?;
+        let (diagnostics, metadata_end) = check_file(&mut file);
+        if !diagnostics.iter().any(|d| d.is_repairable()) {
+            return Ok(diagnostics);
+        }
+        let metadata_end = metadata_end.ok_or_else(|| {
+            LibcryptErr::Other("Neither LUKS2 header copy is usable for repair".to_string())
+        })?;
+        backup_raw(&mut file, metadata_end, backup_file)?;
+        drop(file);
+
+        let mut device = CryptInit::init(device_path)?;
+        // libcryptsetup does not use the parameters when repairing LUKS2
+        device
+            .context_handle()
+            .repair(EncryptionFormat::Luks2, &mut empty_luks2_params())?;
+        Self::check(device_path)
+    }
+}
+
+/// A header copy that passed the checksum and JSON checks
+struct ValidCopy {
+    header: Luks2HeaderCopy,
+    json: serde_json::Value,
+}
+
+/// Check a single header copy, returning it if it can be used as a reference
+fn check_copy(
+    copy: HeaderCopy,
+    offset: u64,
+    header: Result<Luks2HeaderCopy, LibcryptErr>,
+    diagnostics: &mut Vec<HeaderDiagnostic>,
+) -> Option<ValidCopy> {
+    let header = match header {
+        Ok(h) => h,
+        Err(e) => {
+            diagnostics.push(HeaderDiagnostic::Unreadable(copy, e.to_string()));
+            return None;
+        }
+    };
+    let mut valid = true;
+    match header.compute_checksum() {
+        Some(checksum) => {
+            if header.binary.checksum[..checksum.len()] != checksum[..] {
+                diagnostics.push(HeaderDiagnostic::ChecksumMismatch(copy));
+                valid = false;
+            }
+        }
+        None => diagnostics.push(HeaderDiagnostic::UnsupportedChecksum(
+            copy,
+            header.binary.checksum_alg.clone(),
+        )),
+    }
+    if header.binary.hdr_offset != offset {
+        diagnostics.push(HeaderDiagnostic::OffsetMismatch {
+            copy,
+            expected: offset,
+            found: header.binary.hdr_offset,
+        });
+        valid = false;
+    }
+    let json = match header.json() {
+        Ok(j) => j,
+        Err(e) => {
+            diagnostics.push(HeaderDiagnostic::InvalidJson(copy, e.to_string()));
+            return None;
+        }
+    };
+    if valid {
+        Some(ValidCopy { header, json })
+    } else {
+        None
+    }
+}
+
+/// Check both header copies, returning the diagnostics and the end of the keyslots area
+/// according to the most recent valid copy
+fn check_file(file: &mut File) -> (Vec<HeaderDiagnostic>, Option<u64>) {
+    let mut diagnostics = Vec::new();
+    let primary_result = Luks2HeaderCopy::read(file, 0);
+    let secondary_offset = match primary_result {
+        Ok(ref p) => Some(p.binary.hdr_size),
+        Err(_) => None,
+    };
+    let secondary_result = luks2_header::read_luks2_secondary(file, primary_result.as_ref().ok());
+    let secondary_offset = secondary_offset.unwrap_or_else(|| {
+        secondary_result
+            .as_ref()
+            .map(|s| s.binary.hdr_offset)
+            .unwrap_or(0)
+    });
+
+    let primary = check_copy(HeaderCopy::Primary, 0, primary_result, &mut diagnostics);
+    let secondary = check_copy(
+        HeaderCopy::Secondary,
+        secondary_offset,
+        secondary_result,
+        &mut diagnostics,
+    );
+
+    if let (Some(ref p), Some(ref s)) = (&primary, &secondary) {
+        compare_copies(p, s, &mut diagnostics);
+    }
+
+    let reference = match (primary, secondary) {
+        (Some(p), Some(s)) => {
+            if s.header.binary.seqid > p.header.binary.seqid {
+                Some(s)
+            } else {
+                Some(p)
+            }
+        }
+        (Some(p), None) => Some(p),
+        (None, Some(s)) => Some(s),
+        (None, None) => None,
+    };
+    match reference {
+        Some(r) => {
+            let hdr_size = r.header.binary.hdr_size;
+            let keyslots_size = json_u64(&r.json["config"]["keyslots_size"]).unwrap_or(0);
+            diagnostics.extend(check_references(&r.json));
+            diagnostics.extend(check_keyslot_areas(&r.json, 2 * hdr_size, keyslots_size));
+            (diagnostics, Some(2 * hdr_size + keyslots_size))
+        }
+        None => (diagnostics, None),
+    }
+}
+
+fn compare_copies(
+    primary: &ValidCopy,
+    secondary: &ValidCopy,
+    diagnostics: &mut Vec<HeaderDiagnostic>,
+) {
+    let (p, s) = (&primary.header.binary, &secondary.header.binary);
+    if p.seqid != s.seqid {
+        diagnostics.push(HeaderDiagnostic::SeqidMismatch {
+            primary: p.seqid,
+            secondary: s.seqid,
+        });
+    } else if primary.json != secondary.json {
+        diagnostics.push(HeaderDiagnostic::JsonMismatch);
+    }
+    let fields = [
+        ("header size", p.hdr_size == s.hdr_size),
+        ("UUID", p.uuid == s.uuid),
+        ("label", p.label == s.label),
+        ("subsystem", p.subsystem == s.subsystem),
+    ];
+    diagnostics.extend(
+        fields
+            .iter()
+            .filter(|(_, equal)| !equal)
+            .map(|(name, _)| HeaderDiagnostic::BinaryMismatch(name.to_string())),
+    );
+}
+
+fn json_object_ids(value: &serde_json::Value) -> Vec<u32> {
+    value
+        .as_object()
+        .map(|o| o.keys().filter_map(|k| k.parse::<u32>().ok()).collect())
+        .unwrap_or_default()
+}
+
+/// Check that digests and tokens only reference existing keyslots and segments and
+/// that every crypt segment has a digest
+fn check_references(json: &serde_json::Value) -> Vec<HeaderDiagnostic> {
+    let keyslots = json_object_ids(&json["keyslots"]);
+    let segments = json_object_ids(&json["segments"]);
+    let mut diagnostics = Vec::new();
+    let mut dangling = |from: String, kind: &str, ids: Vec<u32>, existing: &[u32]| {
+        for id in ids.into_iter().filter(|id| !existing.contains(id)) {
+            diagnostics.push(HeaderDiagnostic::DanglingReference {
+                from: from.clone(),
+                to: format!("{} {}", kind, id),
+            });
+        }
+    };
+
+    let mut digested_segments = Vec::new();
+    for id in json_object_ids(&json["digests"]) {
+        let digest = &json["digests"][id.to_string()];
+        dangling(
+            format!("digest {}", id),
+            "keyslot",
+            json_ids(&digest["keyslots"]),
+            &keyslots,
+        );
+        let digest_segments = json_ids(&digest["segments"]);
+        dangling(
+            format!("digest {}", id),
+            "segment",
+            digest_segments.clone(),
+            &segments,
+        );
+        digested_segments.extend(digest_segments);
+    }
+    for id in json_object_ids(&json["tokens"]) {
+        dangling(
+            format!("token {}", id),
+            "keyslot",
+            json_ids(&json["tokens"][id.to_string()]["keyslots"]),
+            &keyslots,
+        );
+    }
+    for id in segments {
+        if json["segments"][id.to_string()]["type"] == "crypt" && !digested_segments.contains(&id) {
+            diagnostics.push(HeaderDiagnostic::SegmentWithoutDigest(id));
+        }
+    }
+    diagnostics
+}
+
+/// Check that keyslot areas lie inside the keyslots area and do not overlap
+fn check_keyslot_areas(
+    json: &serde_json::Value,
+    keyslots_offset: u64,
+    keyslots_size: u64,
+) -> Vec<HeaderDiagnostic> {
+    let areas = json_object_ids(&json["keyslots"])
+        .into_iter()
+        .map(|id| {
+            let area = &json["keyslots"][id.to_string()]["area"];
+            (
+                id,
+                (
+                    json_u64(&area["offset"]).unwrap_or(0),
+                    json_u64(&area["size"]).unwrap_or(0),
+                ),
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+    let mut diagnostics = Vec::new();
+    for (id, &(offset, length)) in areas.iter() {
+        if offset < keyslots_offset || offset + length > keyslots_offset + keyslots_size {
+            diagnostics.push(HeaderDiagnostic::KeyslotOutOfBounds {
+                keyslot: *id,
+                offset,
+                length,
+            });
+        }
+        for (other, &(other_offset, other_length)) in areas.range(id + 1..) {
+            if offset < other_offset + other_length && other_offset < offset + length {
+                diagnostics.push(HeaderDiagnostic::OverlappingKeyslots(*id, *other));
+            }
+        }
+    }
+    diagnostics
+}
+
+/// Copy the first `length` bytes of the device to a new file
+fn backup_raw(file: &mut File, length: u64, backup_file: &Path) -> Result<(), LibcryptErr> {
+    let mut backup = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(backup_file)
+        .map_err(LibcryptErr::IOError)?;
+    file.seek(SeekFrom::Start(0))
+        .map_err(LibcryptErr::IOError)?;
+    let copied = io::copy(&mut file.take(length), &mut backup).map_err(LibcryptErr::IOError)?;
+    if copied != length {
+        return Err(LibcryptErr::Other(format!(
+            "Header backup is truncated: {} of {} bytes copied",
+            copied, length
+        )));
+    }
+    backup.sync_all().map_err(LibcryptErr::IOError)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{env, fs::remove_file, io::Write};
+
+    use sha2::{Digest, Sha256};
+
+    use crate::luks2_header::{LUKS2_BINARY_SIZE, LUKS2_MAGIC_SECONDARY, LUKS_MAGIC};
+
+    const HDR_SIZE: u64 = 16384;
+
+    fn metadata() -> serde_json::Value {
+        serde_json::json!({
+            "keyslots": {
+                "0": { "type": "luks2", "area": { "offset": "32768", "size": "258048" } },
+                "1": { "type": "luks2", "area": { "offset": "290816", "size": "258048" } }
+            },
+            "tokens": {},
+            "segments": {
+                "0": { "type": "crypt", "offset": "16777216", "size": "dynamic" }
+            },
+            "digests": {
+                "0": { "type": "pbkdf2", "keyslots": ["0", "1"], "segments": ["0"] }
+            },
+            "config": { "json_size": "12288", "keyslots_size": "16744448" }
+        })
+    }
+
+    fn header_copy(offset: u64, seqid: u64, json: &serde_json::Value) -> Vec<u8> {
+        let mut buf = vec![0u8; HDR_SIZE as usize];
+        let magic = if offset == 0 {
+            LUKS_MAGIC
+        } else {
+            LUKS2_MAGIC_SECONDARY
+        };
+        buf[0..6].copy_from_slice(magic);
+        buf[6..8].copy_from_slice(&2u16.to_be_bytes());
+        buf[8..16].copy_from_slice(&HDR_SIZE.to_be_bytes());
+        buf[16..24].copy_from_slice(&seqid.to_be_bytes());
+        buf[72..78].copy_from_slice(b"sha256");
+        buf[168..172].copy_from_slice(b"uuid");
+        buf[256..264].copy_from_slice(&offset.to_be_bytes());
+        let json = serde_json::to_vec(json).unwrap();
+        buf[LUKS2_BINARY_SIZE..LUKS2_BINARY_SIZE + json.len()].copy_from_slice(&json);
+        let checksum = Sha256::digest(&buf);
+        buf[448..480].copy_from_slice(&checksum);
+        buf
+    }
+
+    fn check_bytes(name: &str, bytes: &[u8]) -> Vec<HeaderDiagnostic> {
+        let path = env::temp_dir().join(name);
+        File::create(&path)
+            .and_then(|mut f| f.write_all(bytes))
+            .unwrap();
+        let diagnostics = CryptHeaderCheck::check(&path).unwrap();
+        remove_file(&path).unwrap();
+        diagnostics
+    }
+
+    #[test]
+    fn test_consistent_header() {
+        let mut bytes = header_copy(0, 1, &metadata());
+        bytes.extend(header_copy(HDR_SIZE, 1, &metadata()));
+        assert_eq!(
+            check_bytes("libcryptsetup-rs-check-consistent", &bytes),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_damaged_secondary() {
+        let mut bytes = header_copy(0, 2, &metadata());
+        let mut secondary = header_copy(HDR_SIZE, 1, &metadata());
+        secondary[HDR_SIZE as usize - 1] = b'x';
+        bytes.extend(secondary);
+        let diagnostics = check_bytes("libcryptsetup-rs-check-damaged", &bytes);
+        assert_eq!(
+            diagnostics,
+            vec![HeaderDiagnostic::ChecksumMismatch(HeaderCopy::Secondary)]
+        );
+        assert!(diagnostics[0].is_repairable());
+
+        let mut bytes = header_copy(0, 2, &metadata());
+        bytes.extend(header_copy(HDR_SIZE, 1, &metadata()));
+        assert_eq!(
+            check_bytes("libcryptsetup-rs-check-seqid", &bytes),
+            vec![HeaderDiagnostic::SeqidMismatch {
+                primary: 2,
+                secondary: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn test_inconsistent_metadata() {
+        let mut json = metadata();
+        json["keyslots"]["1"]["area"]["offset"] = serde_json::json!("163840");
+        json["digests"]["0"]["keyslots"] = serde_json::json!(["0", "1", "4"]);
+        json["tokens"]["0"] = serde_json::json!({ "type": "test", "keyslots": ["7"] });
+        json["segments"]["1"] = serde_json::json!({ "type": "crypt", "offset": "0" });
+        let mut bytes = header_copy(0, 1, &json);
+        bytes.extend(header_copy(HDR_SIZE, 1, &json));
+        let diagnostics = check_bytes("libcryptsetup-rs-check-metadata", &bytes);
+        assert_eq!(
+            diagnostics,
+            vec![
+                HeaderDiagnostic::DanglingReference {
+                    from: "digest 0".to_string(),
+                    to: "keyslot 4".to_string(),
+                },
+                HeaderDiagnostic::DanglingReference {
+                    from: "token 0".to_string(),
+                    to: "keyslot 7".to_string(),
+                },
+                HeaderDiagnostic::SegmentWithoutDigest(1),
+                HeaderDiagnostic::OverlappingKeyslots(0, 1),
+            ]
+        );
+        assert!(diagnostics.iter().all(|d| !d.is_repairable()));
+    }
+}
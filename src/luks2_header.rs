@@ -0,0 +1,380 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    convert::TryInto,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+    str,
+};
+
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::{
+    device::{CryptDevice, CryptInit},
+    err::LibcryptErr,
+    format::EncryptionFormat,
+};
+
+/// Magic bytes of a LUKS1 header and the primary LUKS2 header
+pub(crate) const LUKS_MAGIC: &[u8] = b"LUKS\xba\xbe";
+/// Magic bytes of the secondary LUKS2 header
+pub(crate) const LUKS2_MAGIC_SECONDARY: &[u8] = b"SKUL\xba\xbe";
+/// Size of the binary part of a LUKS2 header
+pub(crate) const LUKS2_BINARY_SIZE: usize = 4096;
+/// Offsets at which a secondary LUKS2 header may be found when the primary is damaged
+pub(crate) const LUKS2_SECONDARY_OFFSETS: &[u64] = &[
+    0x4000, 0x8000, 0x10000, 0x20000, 0x40000, 0x80000, 0x100000, 0x200000, 0x400000,
+];
+
+const LUKS1_HEADER_SIZE: usize = 592;
+const LUKS1_KEYSLOT_ENABLED: u32 = 0x00AC_71F3;
+const LUKS1_NUM_KEYSLOTS: usize = 8;
+
+fn be_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(
+        buf[offset..offset + 2]
+            .try_into()
+            .expect("slice of length 2"),
+    )
+}
+
+fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(
+        buf[offset..offset + 4]
+            .try_into()
+            .expect("slice of length 4"),
+    )
+}
+
+fn be_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(
+        buf[offset..offset + 8]
+            .try_into()
+            .expect("slice of length 8"),
+    )
+}
+
+/// Read a NUL padded string field
+fn c_string(buf: &[u8]) -> Result<String, LibcryptErr> {
+    let end = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    str::from_utf8(&buf[..end])
+        .map(|s| s.to_string())
+        .map_err(LibcryptErr::Utf8Error)
+}
+
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> Result<(), LibcryptErr> {
+    file.seek(SeekFrom::Start(offset))
+        .and_then(|_| file.read_exact(buf))
+        .map_err(LibcryptErr::IOError)
+}
+
+/// Binary part of a LUKS2 header
+pub(crate) struct Luks2BinaryHeader {
+    pub magic: Vec<u8>,
+    pub version: u16,
+    pub hdr_size: u64,
+    pub seqid: u64,
+    pub label: String,
+    pub checksum_alg: String,
+    #[allow(dead_code)]
+    pub salt: Vec<u8>,
+    pub uuid: String,
+    pub subsystem: String,
+    pub hdr_offset: u64,
+    pub checksum: Vec<u8>,
+}
+
+impl Luks2BinaryHeader {
+    fn parse(buf: &[u8]) -> Result<Self, LibcryptErr> {
+        Ok(Luks2BinaryHeader {
+            magic: buf[0..6].to_vec(),
+            version: be_u16(buf, 6),
+            hdr_size: be_u64(buf, 8),
+            seqid: be_u64(buf, 16),
+            label: c_string(&buf[24..72])?,
+            checksum_alg: c_string(&buf[72..104])?,
+            salt: buf[104..168].to_vec(),
+            uuid: c_string(&buf[168..208])?,
+            subsystem: c_string(&buf[208..256])?,
+            hdr_offset: be_u64(buf, 256),
+            checksum: buf[448..512].to_vec(),
+        })
+    }
+}
+
+/// One copy of a LUKS2 header as read from disk
+pub(crate) struct Luks2HeaderCopy {
+    /// Parsed binary header
+    pub binary: Luks2BinaryHeader,
+    /// Raw bytes of the binary header
+    pub raw_binary: Vec<u8>,
+    /// Raw bytes of the JSON area including NUL padding
+    pub json_area: Vec<u8>,
+}
+
+impl Luks2HeaderCopy {
+    /// Read a header copy at `offset`, checking only magic and version
+    pub fn read(file: &mut File, offset: u64) -> Result<Self, LibcryptErr> {
+        let mut raw_binary = vec![0; LUKS2_BINARY_SIZE];
+        read_at(file, offset, &mut raw_binary)?;
+        let binary = Luks2BinaryHeader::parse(&raw_binary)?;
+        let expected_magic = if offset == 0 {
+            LUKS_MAGIC
+        } else {
+            LUKS2_MAGIC_SECONDARY
+        };
+        if binary.magic != expected_magic || binary.version != 2 {
+            return Err(LibcryptErr::Other(format!(
+                "No LUKS2 header found at offset {}",
+                offset
+            )));
+        }
+        if binary.hdr_size <= LUKS2_BINARY_SIZE as u64 || binary.hdr_size > 0x400000 {
+            return Err(LibcryptErr::Other(format!(
+                "Invalid LUKS2 header size {} at offset {}",
+                binary.hdr_size, offset
+            )));
+        }
+        let mut json_area = vec![0; binary.hdr_size as usize - LUKS2_BINARY_SIZE];
+        read_at(file, offset + LUKS2_BINARY_SIZE as u64, &mut json_area)?;
+        Ok(Luks2HeaderCopy {
+            binary,
+            raw_binary,
+            json_area,
+        })
+    }
+
+    /// Compute the checksum of this header copy with the algorithm named in the binary
+    /// header, or `None` if the algorithm is not supported
+    ///
+    /// The checksum covers the binary header with the checksum field zeroed followed by
+    /// the JSON area.
+    pub fn compute_checksum(&self) -> Option<Vec<u8>> {
+        let mut binary = self.raw_binary.clone();
+        for b in binary[448..512].iter_mut() {
+            *b = 0;
+        }
+        match self.binary.checksum_alg.as_str() {
+            "sha256" => {
+                let mut hasher = Sha256::new();
+                hasher.input(&binary);
+                hasher.input(&self.json_area);
+                Some(hasher.result().to_vec())
+            }
+            "sha512" => {
+                let mut hasher = Sha512::new();
+                hasher.input(&binary);
+                hasher.input(&self.json_area);
+                Some(hasher.result().to_vec())
+            }
+            _ => None,
+        }
+    }
+
+    /// Parse the JSON metadata of this header copy
+    pub fn json(&self) -> Result<serde_json::Value, LibcryptErr> {
+        let end = self
+            .json_area
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(self.json_area.len());
+        serde_json::from_slice(&self.json_area[..end]).map_err(LibcryptErr::JsonError)
+    }
+}
+
+/// Read the secondary LUKS2 header, using the primary header size if available and
+/// probing the known offsets otherwise
+pub(crate) fn read_luks2_secondary(
+    file: &mut File,
+    primary: Option<&Luks2HeaderCopy>,
+) -> Result<Luks2HeaderCopy, LibcryptErr> {
+    if let Some(p) = primary {
+        return Luks2HeaderCopy::read(file, p.binary.hdr_size);
+    }
+    LUKS2_SECONDARY_OFFSETS
+        .iter()
+        .filter_map(|offset| Luks2HeaderCopy::read(file, *offset).ok())
+        .next()
+        .ok_or_else(|| LibcryptErr::Other("No secondary LUKS2 header found".to_string()))
+}
+
+/// Read the LUKS2 header of a device, falling back to the secondary copy if the primary
+/// copy cannot be read
+pub(crate) fn read_luks2(path: &Path) -> Result<Luks2HeaderCopy, LibcryptErr> {
+    let mut file = File::open(path).map_err(LibcryptErr::IOError)?;
+    match Luks2HeaderCopy::read(&mut file, 0) {
+        Ok(h) => Ok(h),
+        Err(e) => read_luks2_secondary(&mut file, None).map_err(|_| e),
+    }
+}
+
+/// Read the LUKS version from the start of a device
+pub(crate) fn read_version(path: &Path) -> Result<u16, LibcryptErr> {
+    let mut file = File::open(path).map_err(LibcryptErr::IOError)?;
+    let mut buf = [0; 8];
+    read_at(&mut file, 0, &mut buf)?;
+    if &buf[0..6] != LUKS_MAGIC {
+        return Err(LibcryptErr::Other(format!(
+            "No LUKS header found on {}",
+            path.display()
+        )));
+    }
+    Ok(be_u16(&buf, 6))
+}
+
+/// Read the LUKS format of a device from its header
+pub(crate) fn read_format(path: &Path) -> Result<EncryptionFormat, LibcryptErr> {
+    match read_version(path)? {
+        1 => Ok(EncryptionFormat::Luks1),
+        2 => Ok(EncryptionFormat::Luks2),
+        v => Err(LibcryptErr::Other(format!(
+            "Unsupported LUKS version {}",
+            v
+        ))),
+    }
+}
+
+/// Initialize a crypt device and load its header
+pub(crate) fn load(path: &Path, format: EncryptionFormat) -> Result<CryptDevice, LibcryptErr> {
+    let mut device = CryptInit::init(path)?;
+    device.context_handle().load::<()>(format, None)?;
+    Ok(device)
+}
+
+/// Keyslot of a LUKS1 header
+pub(crate) struct Luks1Keyslot {
+    pub active: bool,
+    pub iterations: u32,
+    pub salt: Vec<u8>,
+    pub key_material_offset: u32,
+    pub stripes: u32,
+}
+
+/// LUKS1 header
+pub(crate) struct Luks1Header {
+    pub cipher_name: String,
+    pub cipher_mode: String,
+    pub hash_spec: String,
+    pub payload_offset: u32,
+    pub key_bytes: u32,
+    pub mk_digest: Vec<u8>,
+    pub mk_digest_salt: Vec<u8>,
+    pub mk_digest_iterations: u32,
+    pub uuid: String,
+    pub keyslots: Vec<Luks1Keyslot>,
+}
+
+/// Read the LUKS1 header of a device
+pub(crate) fn read_luks1(path: &Path) -> Result<Luks1Header, LibcryptErr> {
+    let mut file = File::open(path).map_err(LibcryptErr::IOError)?;
+    let mut buf = vec![0; LUKS1_HEADER_SIZE];
+    read_at(&mut file, 0, &mut buf)?;
+    if &buf[0..6] != LUKS_MAGIC || be_u16(&buf, 6) != 1 {
+        return Err(LibcryptErr::Other(format!(
+            "No LUKS1 header found on {}",
+            path.display()
+        )));
+    }
+    let keyslots = (0..LUKS1_NUM_KEYSLOTS)
+        .map(|i| {
+            let base = 208 + i * 48;
+            Luks1Keyslot {
+                active: be_u32(&buf, base) == LUKS1_KEYSLOT_ENABLED,
+                iterations: be_u32(&buf, base + 4),
+                salt: buf[base + 8..base + 40].to_vec(),
+                key_material_offset: be_u32(&buf, base + 40),
+                stripes: be_u32(&buf, base + 44),
+            }
+        })
+        .collect();
+    Ok(Luks1Header {
+        cipher_name: c_string(&buf[8..40])?,
+        cipher_mode: c_string(&buf[40..72])?,
+        hash_spec: c_string(&buf[72..104])?,
+        payload_offset: be_u32(&buf, 104),
+        key_bytes: be_u32(&buf, 108),
+        mk_digest: buf[112..132].to_vec(),
+        mk_digest_salt: buf[132..164].to_vec(),
+        mk_digest_iterations: be_u32(&buf, 164),
+        uuid: c_string(&buf[168..208])?,
+        keyslots,
+    })
+}
+
+/// Get a LUKS2 JSON number that may be encoded as either a string or an integer
+pub(crate) fn json_u64(value: &serde_json::Value) -> Option<u64> {
+    match *value {
+        serde_json::Value::String(ref s) => s.parse::<u64>().ok(),
+        serde_json::Value::Number(ref n) => n.as_u64(),
+        _ => None,
+    }
+}
+
+/// Get a list of object IDs such as `"keyslots": ["0", "1"]`
+pub(crate) fn json_ids(value: &serde_json::Value) -> Vec<u32> {
+    value
+        .as_array()
+        .map(|a| {
+            a.iter()
+                .filter_map(|v| json_u64(v).map(|id| id as u32))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Get a list of strings such as `"flags": ["allow-discards"]`
+pub(crate) fn json_strings(value: &serde_json::Value) -> Vec<String> {
+    value
+        .as_array()
+        .map(|a| {
+            a.iter()
+                .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_binary_header() {
+        let mut buf = vec![0u8; LUKS2_BINARY_SIZE];
+        buf[0..6].copy_from_slice(LUKS_MAGIC);
+        buf[6..8].copy_from_slice(&2u16.to_be_bytes());
+        buf[8..16].copy_from_slice(&16384u64.to_be_bytes());
+        buf[16..24].copy_from_slice(&7u64.to_be_bytes());
+        buf[24..29].copy_from_slice(b"label");
+        buf[72..78].copy_from_slice(b"sha256");
+        buf[208..214].copy_from_slice(b"system");
+        let header = Luks2BinaryHeader::parse(&buf).unwrap();
+        assert_eq!(header.version, 2);
+        assert_eq!(header.hdr_size, 16384);
+        assert_eq!(header.seqid, 7);
+        assert_eq!(header.label, "label");
+        assert_eq!(header.checksum_alg, "sha256");
+        assert_eq!(header.subsystem, "system");
+    }
+
+    #[test]
+    fn test_json_helpers() {
+        let json = serde_json::json!({
+            "offset": "32768",
+            "key_size": 64,
+            "keyslots": ["0", "3"],
+            "flags": ["allow-discards"],
+        });
+        assert_eq!(json_u64(&json["offset"]), Some(32768));
+        assert_eq!(json_u64(&json["key_size"]), Some(64));
+        assert_eq!(json_u64(&json["missing"]), None);
+        assert_eq!(json_ids(&json["keyslots"]), vec![0, 3]);
+        assert_eq!(
+            json_strings(&json["flags"]),
+            vec!["allow-discards".to_string()]
+        );
+    }
+}
@@ -0,0 +1,303 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    convert::TryInto,
+    env,
+    fs::{remove_file, File, OpenOptions},
+    os::raw::c_int,
+    path::{Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use either::Either;
+
+use crate::{
+    device::{device_size, CryptDevice, CryptInit},
+    err::LibcryptErr,
+    format::{CryptParamsIntegrity, CryptParamsLuks2, CryptParamsLuks2Ref, EncryptionFormat},
+    keyslot::CryptVolumeKeyFlags,
+    luks2_reencrypt::{
+        CryptParamsReencrypt, CryptReencryptDirectionInfo, CryptReencryptFlag, CryptReencryptFlags,
+        CryptReencryptModeInfo, ReencryptProgress,
+    },
+};
+
+/// Minimum data shift when storing the header on the data device - twice the size of
+/// the default LUKS2 header
+pub const MIN_DATA_SHIFT: u64 = 32 * 1024 * 1024;
+
+/// Location of the LUKS2 header created by in-place encryption
+pub enum InPlaceHeader<'a> {
+    /// Store the header at the start of the data device
+    ///
+    /// The last `data_shift` bytes of the device must not be in use, for example because
+    /// the filesystem was shrunk beforehand. The first half of the reduction holds the header.
+    Device {
+        /// Number of bytes by which the usable device size is reduced
+        data_shift: u64,
+    },
+    /// Store the header in a separate file or device
+    Detached(&'a Path),
+}
+
+/// Removes the temporary header file used while encrypting with the header on the data device
+struct TempHeader(PathBuf);
+
+impl TempHeader {
+    fn create(size: u64) -> Result<Self, LibcryptErr> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let path = env::temp_dir().join(format!(
+            "libcryptsetup-rs-header-{}-{}",
+            process::id(),
+            nanos
+        ));
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .and_then(|f| f.set_len(size))
+            .map_err(LibcryptErr::IOError)?;
+        Ok(TempHeader(path))
+    }
+}
+
+impl Drop for TempHeader {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.0);
+    }
+}
+
+/// Workflows for converting an existing plaintext device to LUKS2 and back in place
+pub struct CryptInPlace;
+
+impl CryptInPlace {
+    /// Check that an in-place encryption of `data_device` with the given header location
+    /// is possible
+    pub fn check_encrypt(data_device: &Path, header: &InPlaceHeader) -> Result<(), LibcryptErr> {
+        let device_size = device_size(data_device)?;
+        if let InPlaceHeader::Device { data_shift } = *header {
+            if data_shift % (2 * 4096) != 0 {
+                return Err(LibcryptErr::Other(
+                    "Data shift must be a multiple of twice the 4096-byte sector size".to_string(),
+                ));
+            }
+            if data_shift < MIN_DATA_SHIFT {
+                return Err(LibcryptErr::Other(format!(
+                    "Data shift of {} bytes is too small to hold a LUKS2 header; at least {} bytes are required",
+                    data_shift, MIN_DATA_SHIFT
+                )));
+            }
+            if device_size <= data_shift {
+                return Err(LibcryptErr::Other(format!(
+                    "Device of {} bytes is too small for a data shift of {} bytes",
+                    device_size, data_shift
+                )));
+            }
+        }
+        let mut device = CryptInit::init(data_device)?;
+        let mut context = device.context_handle();
+        if context.load::<()>(EncryptionFormat::Luks1, None).is_ok()
+            || context.load::<()>(EncryptionFormat::Luks2, None).is_ok()
+        {
+            return Err(LibcryptErr::Other(format!(
+                "Device {} already contains a LUKS header",
+                data_device.display()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Encrypt the plaintext contents of `data_device` in place
+    ///
+    /// A keyslot for `passphrase` is created and the loaded device is returned ready
+    /// for activation once encryption has finished. The data device of `luks2` is
+    /// replaced with `data_device`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn encrypt(
+        data_device: &Path,
+        header: InPlaceHeader,
+        cipher_and_mode: (&str, &str),
+        volume_key_size: usize,
+        passphrase: &[u8],
+        mut luks2: CryptParamsLuks2,
+        progress: Option<ReencryptProgress>,
+    ) -> Result<CryptDevice, LibcryptErr> {
+        Self::check_encrypt(data_device, &header)?;
+        luks2.data_device = data_device.to_path_buf();
+
+        let (mut device, keyslot) = match header {
+            InPlaceHeader::Detached(header_path) => {
+                if !header_path.exists() {
+                    File::create(header_path)
+                        .and_then(|f| f.set_len(4096))
+                        .map_err(LibcryptErr::IOError)?;
+                }
+                let keyslot = initialize(
+                    header_path,
+                    data_device,
+                    0,
+                    cipher_and_mode,
+                    volume_key_size,
+                    passphrase,
+                    CryptParamsReencrypt {
+                        mode: CryptReencryptModeInfo::Encrypt,
+                        direction: CryptReencryptDirectionInfo::Forward,
+                        resilience: "checksum".to_string(),
+                        hash: "sha256".to_string(),
+                        data_shift: 0,
+                        max_hotzone_size: 0,
+                        device_size: 0,
+                        luks2,
+                        flags: CryptReencryptFlags::new(vec![CryptReencryptFlag::InitializeOnly]),
+                    },
+                )?;
+                (
+                    CryptInit::init_with_data_device(Either::Right((header_path, data_device)))?,
+                    keyslot,
+                )
+            }
+            InPlaceHeader::Device { data_shift } => {
+                let temp_header = TempHeader::create(data_shift / 2)?;
+                let keyslot = initialize(
+                    &temp_header.0,
+                    data_device,
+                    data_shift / 2,
+                    cipher_and_mode,
+                    volume_key_size,
+                    passphrase,
+                    CryptParamsReencrypt {
+                        mode: CryptReencryptModeInfo::Encrypt,
+                        direction: CryptReencryptDirectionInfo::Backward,
+                        resilience: "datashift".to_string(),
+                        hash: "sha256".to_string(),
+                        data_shift: data_shift / 512,
+                        max_hotzone_size: 0,
+                        device_size: 0,
+                        luks2,
+                        flags: CryptReencryptFlags::new(vec![
+                            CryptReencryptFlag::InitializeOnly,
+                            CryptReencryptFlag::MoveFirstSegment,
+                        ]),
+                    },
+                )?;
+                let mut device = CryptInit::init(data_device)?;
+                device
+                    .backup_handle()
+                    .header_restore(EncryptionFormat::Luks2, &temp_header.0)?;
+                (device, keyslot)
+            }
+        };
+
+        device
+            .context_handle()
+            .load::<()>(EncryptionFormat::Luks2, None)?;
+        let mut reencrypt = device.reencrypt_handle();
+        reencrypt.reencrypt_init_from_metadata_by_passphrase(
+            None,
+            passphrase,
+            Some(keyslot),
+            CryptReencryptFlags::new(vec![CryptReencryptFlag::ResumeOnly]),
+        )?;
+        reencrypt.reencrypt(progress)?;
+        Ok(device)
+    }
+
+    /// Decrypt a LUKS2 device with a detached header in place
+    ///
+    /// The data segment must start at offset 0 of `data_device` so that the plaintext
+    /// is usable directly once decryption has finished. `name` must be the name of the
+    /// active mapping for online decryption or `None` for offline decryption.
+    pub fn decrypt(
+        data_device: &Path,
+        header: &Path,
+        name: Option<&str>,
+        passphrase: &[u8],
+        keyslot: Option<c_int>,
+        progress: Option<ReencryptProgress>,
+    ) -> Result<(), LibcryptErr> {
+        let mut device = CryptInit::init_with_data_device(Either::Right((header, data_device)))?;
+        device
+            .context_handle()
+            .load::<()>(EncryptionFormat::Luks2, None)?;
+        if device.status_handle().get_data_offset() != 0 {
+            return Err(LibcryptErr::Other(
+                "In-place decryption requires a detached header and a data offset of 0".to_string(),
+            ));
+        }
+        let sector_size = device.status_handle().get_sector_size() as u32;
+        let pbkdf = device.settings_handle().get_pbkdf_type()?;
+
+        let mut reencrypt = device.reencrypt_handle();
+        reencrypt.decrypt_init_by_passphrase(
+            name,
+            passphrase,
+            keyslot,
+            CryptParamsReencrypt {
+                mode: CryptReencryptModeInfo::Decrypt,
+                direction: CryptReencryptDirectionInfo::Forward,
+                resilience: "checksum".to_string(),
+                hash: "sha256".to_string(),
+                data_shift: 0,
+                max_hotzone_size: 0,
+                device_size: 0,
+                luks2: CryptParamsLuks2 {
+                    pbkdf,
+                    integrity: None,
+                    integrity_params: CryptParamsIntegrity::default(),
+                    data_alignment: 0,
+                    data_device: data_device.to_path_buf(),
+                    sector_size,
+                    label: String::new(),
+                    subsystem: String::new(),
+                },
+                flags: CryptReencryptFlags::empty(),
+            },
+        )?;
+        reencrypt.reencrypt(progress)
+    }
+}
+
+/// Format the LUKS2 header, add a keyslot and initialize the encryption metadata
+fn initialize(
+    header_path: &Path,
+    data_device: &Path,
+    data_offset: u64,
+    cipher_and_mode: (&str, &str),
+    volume_key_size: usize,
+    passphrase: &[u8],
+    params: CryptParamsReencrypt,
+) -> Result<c_int, LibcryptErr> {
+    let mut device = CryptInit::init_with_data_device(Either::Right((header_path, data_device)))?;
+    device.set_data_offset(data_offset / 4096)?;
+    {
+        let mut luks2_ref: CryptParamsLuks2Ref<'_> = (&params.luks2).try_into()?;
+        device
+            .context_handle()
+            .format::<libcryptsetup_rs_sys::crypt_params_luks2>(
+                EncryptionFormat::Luks2,
+                cipher_and_mode,
+                None,
+                Either::Right(volume_key_size),
+                Some(&mut luks2_ref.inner),
+            )?;
+    }
+    let keyslot =
+        device
+            .keyslot_handle(None)
+            .add_by_key(None, passphrase, CryptVolumeKeyFlags::empty())?;
+    device.reencrypt_handle().reencrypt_init_by_passphrase(
+        None,
+        passphrase,
+        libcryptsetup_rs_sys::CRYPT_ANY_SLOT,
+        keyslot,
+        cipher_and_mode,
+        params,
+    )?;
+    Ok(keyslot)
+}
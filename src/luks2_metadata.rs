@@ -0,0 +1,242 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{fs, os::raw::c_int, path::Path};
+
+use uuid::Uuid;
+
+use crate::{
+    activate::{CryptActivateFlag, CryptActivateFlags},
+    device::CryptDevice,
+    err::LibcryptErr,
+    format::EncryptionFormat,
+    keyslot::{CryptKeyslot, KeyslotInfo, KeyslotPriority},
+};
+
+/// Maximum length of a LUKS2 label or subsystem, excluding the terminating NUL byte
+const LUKS2_LABEL_MAX: usize = 47;
+
+/// Batch of LUKS2 metadata changes applied together
+///
+/// Every change rewrites the LUKS2 metadata, so applying several changes one after the
+/// other can leave the header half updated if one of them fails. A transaction validates
+/// all changes up front and takes a header backup before applying them, restoring the
+/// backup if any change fails.
+#[derive(Default)]
+pub struct MetadataTransaction {
+    label: Option<String>,
+    subsystem: Option<String>,
+    uuid: Option<Uuid>,
+    persistent_flags: Option<Vec<CryptActivateFlag>>,
+    priorities: Vec<(c_int, KeyslotPriority)>,
+}
+
+impl MetadataTransaction {
+    /// Create an empty transaction
+    pub fn new() -> Self {
+        MetadataTransaction::default()
+    }
+
+    /// Set the label, an empty string removes it
+    pub fn label(&mut self, label: &str) -> &mut Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    /// Set the subsystem, an empty string removes it
+    pub fn subsystem(&mut self, subsystem: &str) -> &mut Self {
+        self.subsystem = Some(subsystem.to_string());
+        self
+    }
+
+    /// Set the UUID
+    pub fn uuid(&mut self, uuid: Uuid) -> &mut Self {
+        self.uuid = Some(uuid);
+        self
+    }
+
+    /// Replace the persistent activation flags
+    pub fn persistent_flags(&mut self, flags: Vec<CryptActivateFlag>) -> &mut Self {
+        self.persistent_flags = Some(flags);
+        self
+    }
+
+    /// Set the priority of a keyslot
+    pub fn keyslot_priority(&mut self, keyslot: c_int, priority: KeyslotPriority) -> &mut Self {
+        self.priorities.retain(|(k, _)| *k != keyslot);
+        self.priorities.push((keyslot, priority));
+        self
+    }
+
+    /// Check the changes against a device with a loaded LUKS2 header
+    pub fn validate(&self, device: &mut CryptDevice) -> Result<(), LibcryptErr> {
+        match device.format_handle().get_type()? {
+            EncryptionFormat::Luks2 => (),
+            _ => {
+                return Err(LibcryptErr::Other(
+                    "Metadata transactions require a LUKS2 header".to_string(),
+                ))
+            }
+        }
+        for (name, value) in [("Label", &self.label), ("Subsystem", &self.subsystem)].iter() {
+            if let Some(ref value) = value {
+                validate_label(name, value)?;
+            }
+        }
+        if let Some(ref flags) = self.persistent_flags {
+            for flag in flags.iter() {
+                if !is_persistent_flag(*flag) {
+                    let flag: u32 = (*flag).into();
+                    return Err(LibcryptErr::Other(format!(
+                        "Activation flag {:#x} cannot be stored persistently",
+                        flag
+                    )));
+                }
+            }
+        }
+        let max_keyslots = CryptKeyslot::max_keyslots(EncryptionFormat::Luks2)?;
+        for &(keyslot, priority) in self.priorities.iter() {
+            if keyslot < 0 || keyslot >= max_keyslots {
+                return Err(LibcryptErr::Other(format!(
+                    "Keyslot {} is out of range",
+                    keyslot
+                )));
+            }
+            if let KeyslotPriority::Invalid = priority {
+                return Err(LibcryptErr::Other(format!(
+                    "Invalid priority for keyslot {}",
+                    keyslot
+                )));
+            }
+            match device.keyslot_handle(Some(keyslot)).status()? {
+                KeyslotInfo::Invalid | KeyslotInfo::Inactive => {
+                    return Err(LibcryptErr::Other(format!(
+                        "Keyslot {} is not in use",
+                        keyslot
+                    )))
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Validate and apply the changes to a device with a loaded LUKS2 header
+    ///
+    /// A header backup is written to `backup_file`, which must not exist yet. If any change
+    /// fails, the header is restored from the backup and the error of the failed change is
+    /// returned. The backup is removed once all changes have been applied.
+    pub fn commit(&self, device: &mut CryptDevice, backup_file: &Path) -> Result<(), LibcryptErr> {
+        self.validate(device)?;
+        device
+            .backup_handle()
+            .header_backup(EncryptionFormat::Luks2, backup_file)?;
+        match self.apply(device) {
+            Ok(()) => fs::remove_file(backup_file).map_err(LibcryptErr::IOError),
+            Err(e) => {
+                device
+                    .backup_handle()
+                    .header_restore(EncryptionFormat::Luks2, backup_file)?;
+                device
+                    .context_handle()
+                    .load::<()>(EncryptionFormat::Luks2, None)?;
+                Err(e)
+            }
+        }
+    }
+
+    fn apply(&self, device: &mut CryptDevice) -> Result<(), LibcryptErr> {
+        if self.label.is_some() || self.subsystem.is_some() {
+            let label = match self.label {
+                Some(ref l) => l.clone(),
+                None => device.status_handle().get_label()?.unwrap_or_default(),
+            };
+            let subsystem = match self.subsystem {
+                Some(ref s) => s.clone(),
+                None => device.status_handle().get_subsystem()?.unwrap_or_default(),
+            };
+            device
+                .context_handle()
+                .set_label(Some(&label), Some(&subsystem))?;
+        }
+        if let Some(uuid) = self.uuid {
+            device.context_handle().set_uuid(Some(uuid))?;
+        }
+        if let Some(ref flags) = self.persistent_flags {
+            device
+                .luks2_flag_handle::<CryptActivateFlags>()
+                .persistent_flags_set(CryptActivateFlags::new(flags.clone()))?;
+        }
+        for &(keyslot, priority) in self.priorities.iter() {
+            device
+                .keyslot_handle(Some(keyslot))
+                .set_priority(priority)?;
+        }
+        Ok(())
+    }
+}
+
+fn validate_label(name: &str, value: &str) -> Result<(), LibcryptErr> {
+    if value.len() > LUKS2_LABEL_MAX {
+        Err(LibcryptErr::Other(format!(
+            "{} is longer than {} bytes",
+            name, LUKS2_LABEL_MAX
+        )))
+    } else if value.contains('\0') {
+        Err(LibcryptErr::Other(format!("{} contains a NUL byte", name)))
+    } else {
+        Ok(())
+    }
+}
+
+/// Whether an activation flag can be stored in the LUKS2 header
+fn is_persistent_flag(flag: CryptActivateFlag) -> bool {
+    matches!(
+        flag,
+        CryptActivateFlag::AllowDiscards
+            | CryptActivateFlag::SameCpuCrypt
+            | CryptActivateFlag::SubmitFromCryptCpus
+            | CryptActivateFlag::NoJournal
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate_label() {
+        assert!(validate_label("Label", "").is_ok());
+        assert!(validate_label("Label", &"x".repeat(LUKS2_LABEL_MAX)).is_ok());
+        assert!(validate_label("Label", &"x".repeat(LUKS2_LABEL_MAX + 1)).is_err());
+        assert!(validate_label("Label", "a\0b").is_err());
+    }
+
+    #[test]
+    fn test_persistent_flags() {
+        assert!(is_persistent_flag(CryptActivateFlag::AllowDiscards));
+        assert!(!is_persistent_flag(CryptActivateFlag::Readonly));
+        assert!(!is_persistent_flag(CryptActivateFlag::KeyringKey));
+    }
+
+    #[test]
+    fn test_keyslot_priority_replaced() {
+        let mut transaction = MetadataTransaction::new();
+        transaction
+            .keyslot_priority(1, KeyslotPriority::Ignore)
+            .keyslot_priority(2, KeyslotPriority::Normal)
+            .keyslot_priority(1, KeyslotPriority::Prefer);
+        assert_eq!(
+            transaction
+                .priorities
+                .iter()
+                .map(|&(k, p)| (k, p as i32))
+                .collect::<Vec<_>>(),
+            vec![
+                (2, KeyslotPriority::Normal as i32),
+                (1, KeyslotPriority::Prefer as i32)
+            ]
+        );
+    }
+}
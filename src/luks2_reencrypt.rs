@@ -15,7 +15,8 @@ use crate::{
     format::{CryptParamsLuks2, CryptParamsLuks2Ref},
 };
 
-type ReencryptProgress = unsafe extern "C" fn(size: u64, offset: u64, *mut c_void) -> c_int;
+pub(crate) type ReencryptProgress =
+    unsafe extern "C" fn(size: u64, offset: u64, *mut c_void) -> c_int;
 
 consts_to_from_enum!(
     /// Encryption mode flags
@@ -130,6 +131,24 @@ impl<'a> TryInto<CryptParamsReencryptRef<'a>> for &'a CryptParamsReencrypt {
     }
 }
 
+/// Reencryption state recorded in the LUKS2 metadata
+pub struct CryptReencryptStatus {
+    /// Whether a reencryption is in progress and whether it was interrupted cleanly
+    pub info: CryptReencryptInfo,
+    /// Type of reencryption operation
+    pub mode: CryptReencryptModeInfo,
+    /// Start at beginning or end of disk
+    pub direction: CryptReencryptDirectionInfo,
+    /// Resilience type used to protect the hotzone
+    pub resilience: Option<String>,
+    /// Hash used for checksum resilience
+    pub hash: Option<String>,
+    /// Data shift in 512-byte sectors
+    pub data_shift: u64,
+    /// Reencryption flags
+    pub flags: CryptReencryptFlags,
+}
+
 /// Handle for reencryption operations
 pub struct CryptLuks2Reencrypt<'a> {
     reference: &'a mut CryptDevice,
@@ -149,26 +168,78 @@ impl<'a> CryptLuks2Reencrypt<'a> {
         keyslot_new: c_int,
         cipher_and_mode: (&str, &str),
         params: CryptParamsReencrypt,
+    ) -> Result<c_int, LibcryptErr> {
+        self.init_by_passphrase(
+            name,
+            passphrase,
+            keyslot_old,
+            keyslot_new,
+            Some(cipher_and_mode),
+            &params,
+        )
+    }
+
+    /// Initialize decryption metadata on a device by passphrase
+    ///
+    /// `params.mode` should be `CryptReencryptModeInfo::Decrypt`; no new cipher or keyslot
+    /// is used.
+    pub fn decrypt_init_by_passphrase(
+        &mut self,
+        name: Option<&str>,
+        passphrase: &[u8],
+        keyslot: Option<c_int>,
+        params: CryptParamsReencrypt,
+    ) -> Result<c_int, LibcryptErr> {
+        self.init_by_passphrase(
+            name,
+            passphrase,
+            keyslot.unwrap_or(libcryptsetup_rs_sys::CRYPT_ANY_SLOT),
+            libcryptsetup_rs_sys::CRYPT_ANY_SLOT,
+            None,
+            &params,
+        )
+    }
+
+    fn init_by_passphrase(
+        &mut self,
+        name: Option<&str>,
+        passphrase: &[u8],
+        keyslot_old: c_int,
+        keyslot_new: c_int,
+        cipher_and_mode: Option<(&str, &str)>,
+        params: &CryptParamsReencrypt,
     ) -> Result<c_int, LibcryptErr> {
         let name_cstring = match name {
             Some(n) => Some(to_cstring!(n)?),
             None => None,
         };
-        let (cipher, cipher_mode) = cipher_and_mode;
-        let params_reencrypt: CryptParamsReencryptRef<'_> = (&params).try_into()?;
+        let params_reencrypt: CryptParamsReencryptRef<'_> = params.try_into()?;
 
-        let cipher_cstring = to_cstring!(cipher)?;
-        let cipher_mode_cstring = to_cstring!(cipher_mode)?;
+        let (cipher_cstring, cipher_mode_cstring) = match cipher_and_mode {
+            Some((cipher, cipher_mode)) => {
+                (Some(to_cstring!(cipher)?), Some(to_cstring!(cipher_mode)?))
+            }
+            None => (None, None),
+        };
         errno_int_success!(unsafe {
             libcryptsetup_rs_sys::crypt_reencrypt_init_by_passphrase(
                 self.reference.as_ptr(),
-                name_cstring.map(|cs| cs.as_ptr()).unwrap_or(ptr::null()),
+                name_cstring
+                    .as_ref()
+                    .map(|cs| cs.as_ptr())
+                    .unwrap_or(ptr::null()),
                 to_byte_ptr!(passphrase),
                 passphrase.len(),
                 keyslot_old,
                 keyslot_new,
-                cipher_cstring.as_ptr(),
-                cipher_mode_cstring.as_ptr(),
+                cipher_cstring
+                    .as_ref()
+                    .map(|cs| cs.as_ptr())
+                    .unwrap_or(ptr::null()),
+                cipher_mode_cstring
+                    .as_ref()
+                    .map(|cs| cs.as_ptr())
+                    .unwrap_or(ptr::null()),
                 &params_reencrypt.inner as *const _,
             )
         })
@@ -208,6 +279,76 @@ impl<'a> CryptLuks2Reencrypt<'a> {
         })
     }
 
+    /// Initialize reencryption by passphrase using only the parameters stored in the
+    /// LUKS2 metadata
+    ///
+    /// This is used with `CryptReencryptFlag::ResumeOnly` to continue an interrupted
+    /// reencryption or with `CryptReencryptFlag::Recovery` to repair the metadata after
+    /// a crash.
+    pub fn reencrypt_init_from_metadata_by_passphrase(
+        &mut self,
+        name: Option<&str>,
+        passphrase: &[u8],
+        keyslot: Option<c_int>,
+        flags: CryptReencryptFlags,
+    ) -> Result<c_int, LibcryptErr> {
+        let name_cstring = match name {
+            Some(n) => Some(to_cstring!(n)?),
+            None => None,
+        };
+        let keyslot = keyslot.unwrap_or(libcryptsetup_rs_sys::CRYPT_ANY_SLOT);
+        let params = metadata_only_params(flags);
+        errno_int_success!(unsafe {
+            libcryptsetup_rs_sys::crypt_reencrypt_init_by_passphrase(
+                self.reference.as_ptr(),
+                name_cstring
+                    .as_ref()
+                    .map(|cs| cs.as_ptr())
+                    .unwrap_or(ptr::null()),
+                to_byte_ptr!(passphrase),
+                passphrase.len(),
+                keyslot,
+                keyslot,
+                ptr::null(),
+                ptr::null(),
+                &params as *const _,
+            )
+        })
+    }
+
+    /// Initialize reencryption by passphrase in a keyring using only the parameters
+    /// stored in the LUKS2 metadata
+    pub fn reencrypt_init_from_metadata_by_keyring(
+        &mut self,
+        name: Option<&str>,
+        key_description: &str,
+        keyslot: Option<c_int>,
+        flags: CryptReencryptFlags,
+    ) -> Result<c_int, LibcryptErr> {
+        let name_cstring = match name {
+            Some(n) => Some(to_cstring!(n)?),
+            None => None,
+        };
+        let description_cstring = to_cstring!(key_description)?;
+        let keyslot = keyslot.unwrap_or(libcryptsetup_rs_sys::CRYPT_ANY_SLOT);
+        let params = metadata_only_params(flags);
+        errno_int_success!(unsafe {
+            libcryptsetup_rs_sys::crypt_reencrypt_init_by_keyring(
+                self.reference.as_ptr(),
+                name_cstring
+                    .as_ref()
+                    .map(|cs| cs.as_ptr())
+                    .unwrap_or(ptr::null()),
+                description_cstring.as_ptr(),
+                keyslot,
+                keyslot,
+                ptr::null(),
+                ptr::null(),
+                &params as *const _,
+            )
+        })
+    }
+
     /// Run data reencryption
     pub fn reencrypt(&mut self, progress: Option<ReencryptProgress>) -> Result<(), LibcryptErr> {
         errno!(unsafe { libcryptsetup_rs_sys::crypt_reencrypt(self.reference.as_ptr(), progress) })
@@ -229,4 +370,49 @@ impl<'a> CryptLuks2Reencrypt<'a> {
             CryptReencryptInfo
         )
     }
+
+    /// LUKS2 reencryption status read back from the metadata
+    pub fn get_status(&mut self) -> Result<CryptReencryptStatus, LibcryptErr> {
+        let mut params = metadata_only_params(CryptReencryptFlags::empty());
+        let info = try_int_to_return!(
+            unsafe {
+                libcryptsetup_rs_sys::crypt_reencrypt_status(
+                    self.reference.as_ptr(),
+                    &mut params as *mut _,
+                )
+            },
+            CryptReencryptInfo
+        )?;
+        Ok(CryptReencryptStatus {
+            info,
+            mode: CryptReencryptModeInfo::try_from(params.mode)?,
+            direction: CryptReencryptDirectionInfo::try_from(params.direction)?,
+            resilience: match ptr_to_option!(params.resilience) {
+                Some(p) => Some(from_str_ptr_to_owned!(p)?),
+                None => None,
+            },
+            hash: match ptr_to_option!(params.hash) {
+                Some(p) => Some(from_str_ptr_to_owned!(p)?),
+                None => None,
+            },
+            data_shift: params.data_shift,
+            flags: CryptReencryptFlags::try_from(params.flags)?,
+        })
+    }
+}
+
+fn metadata_only_params(
+    flags: CryptReencryptFlags,
+) -> libcryptsetup_rs_sys::crypt_params_reencrypt {
+    libcryptsetup_rs_sys::crypt_params_reencrypt {
+        mode: CryptReencryptModeInfo::Reencrypt.into(),
+        direction: CryptReencryptDirectionInfo::Forward.into(),
+        resilience: ptr::null(),
+        hash: ptr::null(),
+        data_shift: 0,
+        max_hotzone_size: 0,
+        device_size: 0,
+        luks2: ptr::null(),
+        flags: flags.into(),
+    }
 }
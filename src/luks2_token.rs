@@ -4,10 +4,84 @@
 
 use std::{
     convert::TryFrom,
-    os::raw::{c_char, c_int},
+    os::raw::{c_char, c_int, c_void},
+    ptr, slice,
+    sync::Mutex,
 };
 
-use crate::{activate::CryptActivateFlags, device::CryptDevice, err::LibcryptErr, Bool};
+use zeroize::Zeroizing;
+
+use crate::{
+    activate::CryptActivateFlags, backend::LibcryptBackend, device::CryptDevice, err::LibcryptErr,
+    Bool,
+};
+
+/// Number of tokens available in a LUKS2 header
+pub(crate) const LUKS2_TOKENS_MAX: c_int = 32;
+
+/// Type of the built-in token referencing a passphrase in the kernel keyring
+pub(crate) const LUKS2_KEYRING_TOKEN_TYPE: &str = "luks2-keyring";
+
+/// Callbacks of a handler registered through `CryptLuks2Token::register`
+struct RegisteredHandler {
+    name: &'static str,
+    open: libcryptsetup_rs_sys::crypt_token_open_func,
+    buffer_free: libcryptsetup_rs_sys::crypt_token_buffer_free_func,
+}
+
+/// Handlers registered by this process, so tokens can be opened outside of
+/// `crypt_activate_by_token`
+static HANDLERS: Mutex<Vec<RegisteredHandler>> = Mutex::new(Vec::new());
+
+/// Keyslots a token of any type is assigned to
+pub(crate) fn token_keyslots(json: &serde_json::Value) -> Vec<c_int> {
+    json.get("keyslots")
+        .and_then(|k| k.as_array())
+        .map(|keyslots| {
+            keyslots
+                .iter()
+                .filter_map(|k| k.as_str().and_then(|s| s.parse::<c_int>().ok()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Tokens of one type in a LUKS2 header
+#[derive(Debug)]
+pub struct TokenList<T> {
+    /// Tokens that were parsed successfully
+    pub tokens: Vec<(c_int, T)>,
+    /// Tokens of the listed type that could not be parsed and were skipped
+    pub malformed: Vec<(c_int, LibcryptErr)>,
+}
+
+/// List the active tokens of one of `types`, parsing their JSON with `parse`
+pub(crate) fn list_tokens<B, T, F>(
+    device: &mut B,
+    types: &[&str],
+    parse: F,
+) -> Result<TokenList<T>, LibcryptErr>
+where
+    B: LibcryptBackend,
+    F: Fn(&serde_json::Value) -> Result<T, LibcryptErr>,
+{
+    let mut list = TokenList {
+        tokens: Vec::new(),
+        malformed: Vec::new(),
+    };
+    for token in 0..LUKS2_TOKENS_MAX {
+        match device.token_status(token)? {
+            (CryptTokenInfo::Invalid, _) | (CryptTokenInfo::Inactive, _) => continue,
+            (_, ref t) if !types.contains(&t.as_str()) => continue,
+            _ => (),
+        }
+        match device.token_json_get(token).and_then(|json| parse(&json)) {
+            Ok(parsed) => list.tokens.push((token, parsed)),
+            Err(e) => list.malformed.push((token, e)),
+        }
+    }
+    Ok(list)
+}
 
 consts_to_from_enum!(
     /// Wrapper enum for `CRYPT_TOKEN_*` values
@@ -67,6 +141,18 @@ impl<'a> CryptLuks2Token<'a> {
         })
     }
 
+    /// Remove the token from the header
+    pub fn remove(&mut self) -> Result<(), LibcryptErr> {
+        errno_int_success!(unsafe {
+            libcryptsetup_rs_sys::crypt_token_json_set(
+                self.reference.as_ptr(),
+                self.token,
+                std::ptr::null(),
+            )
+        })
+        .map(|_| ())
+    }
+
     /// Get the token info for a specific token
     pub fn status(&mut self) -> Result<(CryptTokenInfo, String), LibcryptErr> {
         let mut ptr: *const c_char = std::ptr::null();
@@ -156,7 +242,7 @@ impl<'a> CryptLuks2Token<'a> {
         };
         if rc == 0 {
             Ok(Bool::Yes)
-        } else if rc == libc::ENOENT {
+        } else if rc == -libc::ENOENT {
             Ok(Bool::No)
         } else {
             Err(LibcryptErr::IOError(std::io::Error::from_raw_os_error(-rc)))
@@ -164,6 +250,9 @@ impl<'a> CryptLuks2Token<'a> {
     }
 
     /// Register token handler
+    ///
+    /// libcryptsetup keeps a pointer to the handler, so it is leaked once registration
+    /// succeeds and stays valid for the lifetime of the process.
     pub fn register(
         name: &'static str,
         open: libcryptsetup_rs_sys::crypt_token_open_func,
@@ -174,35 +263,100 @@ impl<'a> CryptLuks2Token<'a> {
         if name.get(name.len() - 1..) != Some("\0") {
             return Err(LibcryptErr::NoNull(name));
         }
-        let handler = libcryptsetup_rs_sys::crypt_token_handler {
+        let handler = Box::into_raw(Box::new(libcryptsetup_rs_sys::crypt_token_handler {
             name: name.as_ptr() as *const c_char,
             open,
             buffer_free,
             validate,
             dump,
-        };
+        }));
+        let rc = unsafe { libcryptsetup_rs_sys::crypt_token_register(handler) };
+        if rc < 0 {
+            drop(unsafe { Box::from_raw(handler) });
+        } else {
+            HANDLERS
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(RegisteredHandler {
+                    name: &name[..name.len() - 1],
+                    open,
+                    buffer_free,
+                });
+        }
+        errno!(rc)
+    }
+
+    /// Get the passphrase of the token from the handler registered for its type
+    ///
+    /// This allows tokens to be used for operations libcryptsetup only accepts
+    /// passphrases for, such as reencryption. The open function of the handler receives
+    /// a null pointer as user data.
+    pub(crate) fn handler_passphrase(&mut self) -> Result<Zeroizing<Vec<u8>>, LibcryptErr> {
+        let type_ = self.status()?.1;
+        let (open, buffer_free) = HANDLERS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .find(|h| h.name == type_)
+            .and_then(|h| h.open.map(|open| (open, h.buffer_free)))
+            .ok_or_else(|| {
+                LibcryptErr::Other(format!("No handler is registered for token type {}", type_))
+            })?;
+        let mut buffer: *mut c_char = ptr::null_mut();
+        let mut buffer_len: crate::size_t = 0;
         errno!(unsafe {
-            libcryptsetup_rs_sys::crypt_token_register(
-                &handler as *const libcryptsetup_rs_sys::crypt_token_handler,
+            open(
+                self.reference.as_ptr(),
+                self.token,
+                &mut buffer as *mut _,
+                &mut buffer_len as *mut _,
+                ptr::null_mut(),
             )
-        })
+        })?;
+        let passphrase = Zeroizing::new(
+            unsafe { slice::from_raw_parts(buffer as *const u8, buffer_len) }.to_vec(),
+        );
+        // Release the buffer the same way libcryptsetup does
+        unsafe {
+            match buffer_free {
+                Some(free) => free(buffer as *mut c_void, buffer_len),
+                None => {
+                    ptr::write_bytes(buffer, 0, buffer_len);
+                    libc::free(buffer as *mut c_void);
+                }
+            }
+        }
+        Ok(passphrase)
     }
 
     /// Activate device or check key using a token
+    ///
+    /// Passing `None` as `name` only checks that the token unlocks a keyslot. `usrdata` is
+    /// passed to the open function of the token handler, which receives a null pointer if
+    /// it is `None`.
     pub fn activate_by_token<T>(
         &mut self,
-        name: &str,
+        name: Option<&str>,
         token: c_int,
-        usrdata: &mut T,
+        usrdata: Option<&mut T>,
         flags: CryptActivateFlags,
     ) -> Result<c_int, LibcryptErr> {
-        let name_cstring = to_cstring!(name)?;
+        let name_cstring_option = match name {
+            Some(n) => Some(to_cstring!(n)?),
+            None => None,
+        };
         errno_int_success!(unsafe {
             libcryptsetup_rs_sys::crypt_activate_by_token(
                 self.reference.as_ptr(),
-                name_cstring.as_ptr(),
+                match name_cstring_option {
+                    Some(ref cs) => cs.as_ptr(),
+                    None => std::ptr::null_mut(),
+                },
                 token,
-                usrdata as *mut _ as *mut std::os::raw::c_void,
+                match usrdata {
+                    Some(ud) => ud as *mut _ as *mut std::os::raw::c_void,
+                    None => std::ptr::null_mut(),
+                },
                 flags.into(),
             )
         })
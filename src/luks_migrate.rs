@@ -0,0 +1,484 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    fmt::{self, Display},
+    os::raw::c_int,
+    path::{Path, PathBuf},
+    ptr,
+};
+
+use crate::{
+    activate::CryptActivateFlags,
+    device::{CryptDevice, CryptInit},
+    discover::active_mappings,
+    dump::DeviceDump,
+    err::LibcryptErr,
+    format::{empty_luks2_params, EncryptionFormat},
+    keyslot::{CryptKeyslot, KeyslotInfo},
+    luks2_header::{self, Luks1Header},
+    settings::{CryptKdf, CryptSettings},
+};
+
+/// Size of one LUKS2 header copy created by converting a LUKS1 header
+const LUKS2_HDR_16K_LEN: u64 = 0x4000;
+/// Alignment of LUKS1 keyslot areas
+const LUKS1_ALIGN_KEYSLOTS: u64 = 4096;
+/// Number of anti-forensic stripes used by LUKS1 keyslots
+const LUKS1_STRIPES: u64 = 4000;
+
+/// Reason why a device cannot be migrated to another LUKS version
+#[derive(Clone, Debug, PartialEq)]
+pub enum MigrationIssue {
+    /// The device already uses the requested format
+    SameFormat,
+    /// The device has an active mapping with the given name
+    DeviceActive(String),
+    /// The LUKS1 keyslot area cannot be moved to make room for the LUKS2 header
+    InsufficientHeaderSpace {
+        /// Bytes needed before the data offset
+        required: u64,
+        /// Bytes available before the data offset
+        available: u64,
+    },
+    /// The header contains tokens, which LUKS1 does not support
+    TokensPresent(Vec<u32>),
+    /// The data segment uses authenticated encryption
+    IntegrityProtected(String),
+    /// A keyslot cannot be represented in LUKS1
+    IncompatibleKeyslot {
+        /// Keyslot ID
+        keyslot: u32,
+        /// Description of the incompatibility
+        reason: String,
+    },
+    /// The encryption sector size is not 512 bytes
+    UnsupportedSectorSize(u64),
+    /// The segment or digest layout cannot be represented in LUKS1
+    UnsupportedLayout(String),
+    /// The header has mandatory requirements such as an unfinished reencryption
+    Requirements(Vec<String>),
+}
+
+impl Display for MigrationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MigrationIssue::SameFormat => write!(f, "Device already uses the requested format"),
+            MigrationIssue::DeviceActive(ref name) => {
+                write!(f, "Device is active as {}", name)
+            }
+            MigrationIssue::InsufficientHeaderSpace {
+                required,
+                available,
+            } => write!(
+                f,
+                "Not enough space for the header: {} bytes required, {} bytes available",
+                required, available
+            ),
+            MigrationIssue::TokensPresent(ref tokens) => {
+                write!(f, "Header contains tokens {:?}", tokens)
+            }
+            MigrationIssue::IntegrityProtected(ref integrity) => {
+                write!(f, "Data segment uses integrity protection {}", integrity)
+            }
+            MigrationIssue::IncompatibleKeyslot {
+                keyslot,
+                ref reason,
+            } => write!(f, "Keyslot {} is not LUKS1 compatible: {}", keyslot, reason),
+            MigrationIssue::UnsupportedSectorSize(size) => {
+                write!(f, "Sector size {} is not supported by LUKS1", size)
+            }
+            MigrationIssue::UnsupportedLayout(ref reason) => {
+                write!(f, "Unsupported metadata layout: {}", reason)
+            }
+            MigrationIssue::Requirements(ref requirements) => {
+                write!(f, "Header has mandatory requirements {:?}", requirements)
+            }
+        }
+    }
+}
+
+/// Result of a successful migration
+pub struct MigrationReport {
+    /// Format of the header before the migration
+    pub from: EncryptionFormat,
+    /// Format of the header after the migration
+    pub to: EncryptionFormat,
+    /// Header backup taken before converting
+    pub backup_file: PathBuf,
+    /// Keyslots converted to argon2id
+    pub upgraded_keyslots: Vec<c_int>,
+    /// Keyslots verified to open with one of the supplied passphrases
+    pub verified_keyslots: Vec<c_int>,
+    /// Active keyslots for which no passphrase was supplied
+    pub unverified_keyslots: Vec<c_int>,
+}
+
+/// Handle for migrating devices between LUKS1 and LUKS2
+pub struct CryptMigration;
+
+impl CryptMigration {
+    /// Check whether the LUKS header on `device_path` can be converted to `target`
+    ///
+    /// An empty list means that no obstacle was found.
+    pub fn check(
+        device_path: &Path,
+        target: EncryptionFormat,
+    ) -> Result<Vec<MigrationIssue>, LibcryptErr> {
+        let source = luks2_header::read_format(device_path)?;
+        let dump = DeviceDump::read(device_path)?;
+        let mut issues = Vec::new();
+        match (source, target) {
+            (EncryptionFormat::Luks1, EncryptionFormat::Luks2) => {
+                issues.extend(luks1_to_luks2_issues(&luks2_header::read_luks1(
+                    device_path,
+                )?));
+            }
+            (EncryptionFormat::Luks2, EncryptionFormat::Luks1) => {
+                issues.extend(luks2_to_luks1_issues(&dump));
+            }
+            (EncryptionFormat::Luks1, EncryptionFormat::Luks1)
+            | (EncryptionFormat::Luks2, EncryptionFormat::Luks2) => {
+                issues.push(MigrationIssue::SameFormat);
+            }
+            _ => return Err(LibcryptErr::InvalidConversion),
+        }
+        issues.extend(
+            active_mappings("CRYPT-LUKS", &dump.uuid)
+                .into_iter()
+                .map(MigrationIssue::DeviceActive),
+        );
+        Ok(issues)
+    }
+
+    /// Convert the LUKS header on `device_path` to `target`
+    ///
+    /// The pre-flight checks from `CryptMigration::check` must pass and a header backup
+    /// is written to `backup_file`, which must not exist yet. Each passphrase must open a
+    /// keyslot both before and after the conversion; if any of them stops working, the
+    /// original header is restored from the backup. When converting to LUKS2,
+    /// `upgrade_to_argon2id` re-encrypts the keyslots opened by the passphrases with
+    /// argon2id in place.
+    pub fn migrate(
+        device_path: &Path,
+        target: EncryptionFormat,
+        backup_file: &Path,
+        passphrases: &[&[u8]],
+        upgrade_to_argon2id: bool,
+    ) -> Result<MigrationReport, LibcryptErr> {
+        let issues = Self::check(device_path, target)?;
+        if !issues.is_empty() {
+            return Err(LibcryptErr::Other(format!(
+                "Migration pre-flight checks failed: {}",
+                issues
+                    .iter()
+                    .map(|i| i.to_string())
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            )));
+        }
+        if upgrade_to_argon2id {
+            if let EncryptionFormat::Luks1 = target {
+                return Err(LibcryptErr::Other(
+                    "LUKS1 keyslots only support pbkdf2".to_string(),
+                ));
+            }
+        }
+        let source = luks2_header::read_format(device_path)?;
+
+        let mut device = luks2_header::load(device_path, source)?;
+        let keyslots = passphrases
+            .iter()
+            .map(|p| open_keyslot(&mut device, None, p))
+            .collect::<Result<Vec<_>, _>>()?;
+        device.backup_handle().header_backup(source, backup_file)?;
+        // libcryptsetup ignores the parameters of conversions between LUKS versions
+        match target {
+            EncryptionFormat::Luks1 => device.context_handle().convert(
+                target,
+                &mut libcryptsetup_rs_sys::crypt_params_luks1 {
+                    hash: ptr::null(),
+                    data_alignment: 0,
+                    data_device: ptr::null(),
+                },
+            )?,
+            _ => device
+                .context_handle()
+                .convert(target, &mut empty_luks2_params())?,
+        }
+        drop(device);
+
+        match finish(
+            device_path,
+            target,
+            passphrases,
+            &keyslots,
+            upgrade_to_argon2id,
+        ) {
+            Ok((upgraded_keyslots, unverified_keyslots)) => Ok(MigrationReport {
+                from: source,
+                to: target,
+                backup_file: backup_file.to_path_buf(),
+                upgraded_keyslots,
+                verified_keyslots: keyslots.iter().fold(Vec::new(), |mut acc, k| {
+                    if !acc.contains(k) {
+                        acc.push(*k);
+                    }
+                    acc
+                }),
+                unverified_keyslots,
+            }),
+            Err(e) => {
+                CryptInit::init(device_path)?
+                    .backup_handle()
+                    .header_restore(source, backup_file)?;
+                Err(e)
+            }
+        }
+    }
+}
+
+fn open_keyslot(
+    device: &mut CryptDevice,
+    keyslot: Option<c_int>,
+    passphrase: &[u8],
+) -> Result<c_int, LibcryptErr> {
+    device.activate_handle().activate_by_passphrase(
+        None,
+        keyslot,
+        passphrase,
+        CryptActivateFlags::empty(),
+    )
+}
+
+/// Upgrade and verify keyslots after conversion, returning the upgraded keyslots and
+/// the active keyslots that could not be verified
+fn finish(
+    device_path: &Path,
+    target: EncryptionFormat,
+    passphrases: &[&[u8]],
+    keyslots: &[c_int],
+    upgrade_to_argon2id: bool,
+) -> Result<(Vec<c_int>, Vec<c_int>), LibcryptErr> {
+    let mut device = luks2_header::load(device_path, target)?;
+    let mut upgraded = Vec::new();
+    if upgrade_to_argon2id {
+        let pbkdf = CryptSettings::get_pbkdf_type_params(&CryptKdf::Argon2Id)?;
+        device.settings_handle().set_pbkdf_type(&pbkdf)?;
+        for (passphrase, keyslot) in passphrases.iter().zip(keyslots.iter()) {
+            if upgraded.contains(keyslot) {
+                continue;
+            }
+            device
+                .keyslot_handle(None)
+                .change_by_passphrase(*keyslot, *keyslot, passphrase, passphrase)?;
+            upgraded.push(*keyslot);
+        }
+    }
+
+    let mut device = luks2_header::load(device_path, target)?;
+    for (passphrase, keyslot) in passphrases.iter().zip(keyslots.iter()) {
+        open_keyslot(&mut device, Some(*keyslot), passphrase).map_err(|e| {
+            LibcryptErr::Other(format!(
+                "Keyslot {} no longer opens after conversion: {}",
+                keyslot, e
+            ))
+        })?;
+    }
+    let max_keyslots = CryptKeyslot::max_keyslots(target)?;
+    let mut unverified = Vec::new();
+    for keyslot in 0..max_keyslots {
+        match device.keyslot_handle(Some(keyslot)).status()? {
+            KeyslotInfo::Active | KeyslotInfo::ActiveLast if !keyslots.contains(&keyslot) => {
+                unverified.push(keyslot)
+            }
+            _ => (),
+        }
+    }
+    Ok((upgraded, unverified))
+}
+
+/// Check that the LUKS1 keyslot area can be moved behind the two LUKS2 header copies
+fn luks1_to_luks2_issues(header: &Luks1Header) -> Vec<MigrationIssue> {
+    let available = u64::from(header.payload_offset) * 512;
+    // A payload offset of 0 means the header is detached and there is nothing to move
+    // out of the way of the data.
+    if available == 0 {
+        return Vec::new();
+    }
+    let af_size = u64::from(header.key_bytes) * LUKS1_STRIPES;
+    let keyslots_end = header
+        .keyslots
+        .iter()
+        .map(|k| u64::from(k.key_material_offset) * 512 + af_size)
+        .max()
+        .unwrap_or(0);
+    let rounded_end =
+        (keyslots_end + LUKS1_ALIGN_KEYSLOTS - 1) / LUKS1_ALIGN_KEYSLOTS * LUKS1_ALIGN_KEYSLOTS;
+    let required = rounded_end + 2 * LUKS2_HDR_16K_LEN - LUKS1_ALIGN_KEYSLOTS;
+    if required > available {
+        vec![MigrationIssue::InsufficientHeaderSpace {
+            required,
+            available,
+        }]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Check for LUKS2 features that cannot be represented in a LUKS1 header
+fn luks2_to_luks1_issues(dump: &DeviceDump) -> Vec<MigrationIssue> {
+    let mut issues = Vec::new();
+    if !dump.requirements.is_empty() {
+        issues.push(MigrationIssue::Requirements(dump.requirements.clone()));
+    }
+    if !dump.tokens.is_empty() {
+        issues.push(MigrationIssue::TokensPresent(
+            dump.tokens.keys().cloned().collect(),
+        ));
+    }
+    if dump.segments.len() != 1 {
+        issues.push(MigrationIssue::UnsupportedLayout(format!(
+            "{} data segments",
+            dump.segments.len()
+        )));
+    }
+    if dump.digests.len() != 1 {
+        issues.push(MigrationIssue::UnsupportedLayout(format!(
+            "{} digests",
+            dump.digests.len()
+        )));
+    }
+    if let Some(digest) = dump.digests.values().find(|d| d.type_ != "pbkdf2") {
+        issues.push(MigrationIssue::UnsupportedLayout(format!(
+            "{} digest",
+            digest.type_
+        )));
+    }
+    if let Some(integrity) = dump
+        .segments
+        .values()
+        .filter_map(|s| s.integrity.clone())
+        .next()
+    {
+        issues.push(MigrationIssue::IntegrityProtected(integrity));
+    }
+    if dump.sector_size != 512 {
+        issues.push(MigrationIssue::UnsupportedSectorSize(dump.sector_size));
+    }
+    for (id, keyslot) in dump.keyslots.iter() {
+        let reason = if *id >= 8 {
+            Some("LUKS1 only has keyslots 0 to 7".to_string())
+        } else if keyslot.pbkdf != "pbkdf2" {
+            Some(format!("{} key derivation", keyslot.pbkdf))
+        } else if keyslot.af_stripes != Some(LUKS1_STRIPES) {
+            Some("anti-forensic splitter does not use 4000 stripes".to_string())
+        } else if keyslot.cipher.as_ref() != Some(&dump.cipher) {
+            Some("keyslot cipher differs from the data segment cipher".to_string())
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            issues.push(MigrationIssue::IncompatibleKeyslot {
+                keyslot: *id,
+                reason,
+            });
+        }
+    }
+    issues
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::luks2_header::Luks1Keyslot;
+
+    fn luks1_header(payload_offset: u32) -> Luks1Header {
+        Luks1Header {
+            cipher_name: "aes".to_string(),
+            cipher_mode: "xts-plain64".to_string(),
+            hash_spec: "sha256".to_string(),
+            payload_offset,
+            key_bytes: 64,
+            mk_digest: vec![0; 20],
+            mk_digest_salt: vec![0; 32],
+            mk_digest_iterations: 1000,
+            uuid: "uuid".to_string(),
+            keyslots: (0..8)
+                .map(|i| Luks1Keyslot {
+                    active: i == 0,
+                    iterations: 1000,
+                    salt: vec![0; 32],
+                    key_material_offset: 8 + i * 504,
+                    stripes: 4000,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_luks1_header_space() {
+        assert!(luks1_to_luks2_issues(&luks1_header(4096)).is_empty());
+        assert!(luks1_to_luks2_issues(&luks1_header(0)).is_empty());
+        assert_eq!(
+            luks1_to_luks2_issues(&luks1_header(4095)),
+            vec![MigrationIssue::InsufficientHeaderSpace {
+                required: 2_097_152,
+                available: 2_096_640,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_luks2_to_luks1_issues() {
+        let json = serde_json::json!({
+            "keyslots": {
+                "0": {
+                    "type": "luks2",
+                    "key_size": 64,
+                    "af": { "type": "luks1", "stripes": 4000, "hash": "sha256" },
+                    "area": { "type": "raw", "offset": "32768", "size": "258048",
+                              "encryption": "aes-xts-plain64", "key_size": 64 },
+                    "kdf": { "type": "pbkdf2", "hash": "sha256", "iterations": 1000,
+                             "salt": "" }
+                },
+                "9": {
+                    "type": "luks2",
+                    "key_size": 64,
+                    "af": { "type": "luks1", "stripes": 4000, "hash": "sha256" },
+                    "area": { "type": "raw", "offset": "290816", "size": "258048",
+                              "encryption": "aes-xts-plain64", "key_size": 64 },
+                    "kdf": { "type": "argon2id", "time": 4, "memory": 1048576, "cpus": 4,
+                             "salt": "" }
+                }
+            },
+            "tokens": {
+                "0": { "type": "luks2-keyring", "keyslots": ["0"], "key_description": "k" }
+            },
+            "segments": {
+                "0": { "type": "crypt", "offset": "16777216", "size": "dynamic",
+                       "iv_tweak": "0", "encryption": "aes-xts-plain64", "sector_size": 4096 }
+            },
+            "digests": {
+                "0": { "type": "pbkdf2", "keyslots": ["0", "9"], "segments": ["0"],
+                       "hash": "sha256", "iterations": 1000, "salt": "", "digest": "" }
+            },
+            "config": { "json_size": "12288", "keyslots_size": "16744448" }
+        });
+        let dump = DeviceDump::from_luks2_json(1, 16384, "uuid", "", "", &json).unwrap();
+        let issues = luks2_to_luks1_issues(&dump);
+        assert_eq!(
+            issues,
+            vec![
+                MigrationIssue::TokensPresent(vec![0]),
+                MigrationIssue::UnsupportedSectorSize(4096),
+                MigrationIssue::IncompatibleKeyslot {
+                    keyslot: 9,
+                    reason: "LUKS1 only has keyslots 0 to 7".to_string(),
+                },
+            ]
+        );
+    }
+}
@@ -292,26 +292,41 @@ macro_rules! c_progress_callback {
 
 #[macro_export]
 /// Create a C-compatible open callback compatible with `CryptTokenHandler`
+///
+/// The safe function borrows the device for the duration of the callback and returns the
+/// passphrase as a boxed slice, which must be released with a callback created by
+/// `c_token_handler_free!`.
 macro_rules! c_token_handler_open {
     ( $fn_name:ident, $type:ty, $safe_fn_name:ident ) => {
         extern "C" fn $fn_name(
             cd: *mut libcryptsetup_rs_sys::crypt_device,
             token_id: std::os::raw::c_int,
             buffer: *mut *mut std::os::raw::c_char,
-            buffer_len: *mut $crate::SizeT,
+            buffer_len: *mut $crate::size_t,
             usrptr: *mut std::os::raw::c_void,
         ) -> std::os::raw::c_int {
-            let device = $crate::device::CryptDevice::from_ptr(cd);
+            // The device is owned by the caller of the callback and must not be freed
+            let mut device = std::mem::ManuallyDrop::new($crate::CryptDevice::from_ptr(cd));
             let generic_ptr = usrptr as *mut $type;
             let generic_ref = unsafe { generic_ptr.as_mut() };
 
-            let buffer: Result<Box<[u8]>, $crate::err::LibcryptErr> =
-                $safe_fn_name(device, token_id, generic_ref);
-            match buffer {
-                Ok(()) => {
-                    *buffer = Box::into_raw(buffer) as *mut std::os::raw::c_char;
+            let boxed_slice: Result<Box<[u8]>, $crate::LibcryptErr> =
+                $safe_fn_name(&mut device, token_id, generic_ref);
+            match boxed_slice {
+                Ok(boxed_slice) => {
+                    let len = boxed_slice.len();
+                    unsafe {
+                        *buffer =
+                            Box::into_raw(boxed_slice) as *mut u8 as *mut std::os::raw::c_char;
+                        *buffer_len = len as $crate::size_t;
+                    }
                     0
                 }
+                // Pass errno on so libcryptsetup can tell unavailable tokens from failures
+                Err($crate::LibcryptErr::IOError(ref e)) => match e.raw_os_error() {
+                    Some(errno) if errno > 0 => -errno,
+                    _ => -1,
+                },
                 Err(_) => -1,
             }
         }
@@ -322,7 +337,7 @@ macro_rules! c_token_handler_open {
 /// Create a C-compatible callback for free compatible with `CryptTokenHandler`
 macro_rules! c_token_handler_free {
     ( $fn_name:ident, $safe_fn_name:ident ) => {
-        extern "C" fn $fn_name(buffer: *mut std::os::raw::c_void, buffer_len: $crate::SizeT) {
+        extern "C" fn $fn_name(buffer: *mut std::os::raw::c_void, buffer_len: $crate::size_t) {
             let boxed_slice = unsafe {
                 Box::from_raw(std::slice::from_raw_parts_mut(
                     buffer as *mut u8,
@@ -341,10 +356,11 @@ macro_rules! c_token_handler_validate {
     ( $fn_name:ident, $safe_fn_name:ident ) => {
         extern "C" fn $fn_name(
             cd: *mut libcryptsetup_rs_sys::crypt_device,
-            json: *mut std::os::raw::c_char,
+            json: *const std::os::raw::c_char,
         ) -> std::os::raw::c_int {
-            let device = $crate::device::CryptDevice::from_ptr(cd);
-            let s = match from_str_ptr!(json) {
+            // The device is owned by the caller of the callback and must not be freed
+            let mut device = std::mem::ManuallyDrop::new($crate::CryptDevice::from_ptr(cd));
+            let s = match unsafe { std::ffi::CStr::from_ptr(json) }.to_str() {
                 Ok(s) => s,
                 Err(_) => return -1,
             };
@@ -353,7 +369,7 @@ macro_rules! c_token_handler_validate {
                 Err(_) => return -1,
             };
 
-            let rc: Result<(), $crate::err::LibcryptErr> = $safe_fn_name(device, json_obj);
+            let rc: Result<(), $crate::LibcryptErr> = $safe_fn_name(&mut device, json_obj);
             match rc {
                 Ok(()) => 0,
                 Err(_) => -1,
@@ -363,15 +379,16 @@ macro_rules! c_token_handler_validate {
 }
 
 #[macro_export]
-/// Create a C-compatible callback for compatible with `CryptTokenHandler`
+/// Create a C-compatible callback for dump compatible with `CryptTokenHandler`
 macro_rules! c_token_handler_dump {
     ( $fn_name:ident, $safe_fn_name:ident ) => {
         extern "C" fn $fn_name(
             cd: *mut libcryptsetup_rs_sys::crypt_device,
-            json: *mut std::os::raw::c_char,
+            json: *const std::os::raw::c_char,
         ) {
-            let device = $crate::device::CryptDevice::from_ptr(cd);
-            let s = match from_str_ptr!(json) {
+            // The device is owned by the caller of the callback and must not be freed
+            let mut device = std::mem::ManuallyDrop::new($crate::CryptDevice::from_ptr(cd));
+            let s = match unsafe { std::ffi::CStr::from_ptr(json) }.to_str() {
                 Ok(s) => s,
                 Err(_) => return,
             };
@@ -380,7 +397,7 @@ macro_rules! c_token_handler_dump {
                 Err(_) => return,
             };
 
-            $safe_fn_name(device, json_obj)
+            $safe_fn_name(&mut device, json_obj)
         }
     };
 }
@@ -405,6 +422,22 @@ mod test {
 
     c_progress_callback!(progress_callback, u64, safe_progress_callback);
 
+    fn safe_token_open(
+        _device: &mut crate::CryptDevice,
+        token_id: std::os::raw::c_int,
+        usrdata: Option<&mut u8>,
+    ) -> Result<Box<[u8]>, crate::LibcryptErr> {
+        Ok(vec![*usrdata.unwrap(); token_id as usize].into_boxed_slice())
+    }
+
+    c_token_handler_open!(token_open, u8, safe_token_open);
+
+    fn safe_token_free(buffer: Box<[u8]>) {
+        assert_eq!(&*buffer, &[7, 7, 7]);
+    }
+
+    c_token_handler_free!(token_free, safe_token_free);
+
     #[test]
     fn test_c_confirm_callback() {
         let ret = confirm_callback(
@@ -447,4 +480,20 @@ mod test {
         assert_eq!(0, ret);
         assert_eq!(Interrupt::No, Interrupt::from(ret));
     }
+
+    #[test]
+    fn test_c_token_handler_open_free() {
+        let mut buffer: *mut std::os::raw::c_char = std::ptr::null_mut();
+        let mut buffer_len: crate::size_t = 0;
+        let ret = token_open(
+            std::ptr::null_mut(),
+            3,
+            &mut buffer as *mut _,
+            &mut buffer_len as *mut _,
+            &mut 7u8 as *mut _ as *mut std::os::raw::c_void,
+        );
+        assert_eq!(0, ret);
+        assert_eq!(3, buffer_len);
+        token_free(buffer as *mut std::os::raw::c_void, buffer_len);
+    }
 }
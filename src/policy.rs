@@ -0,0 +1,441 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    fmt::{self, Display},
+    os::raw::c_int,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    device::CryptDevice,
+    err::LibcryptErr,
+    format::EncryptionFormat,
+    keyslot::{CryptKeyslot, KeyslotInfo},
+    luks2_token::{CryptTokenInfo, LUKS2_TOKENS_MAX},
+};
+
+/// Requirements on keyslots using one key derivation function
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PbkdfPolicy {
+    /// Name of the key derivation function: `pbkdf2`, `argon2i` or `argon2id`
+    pub kdf: String,
+    /// Minimum number of PBKDF2 iterations or minimum Argon2 time cost
+    pub min_iterations: Option<u32>,
+    /// Minimum Argon2 memory cost in kilobytes
+    pub min_memory_kb: Option<u32>,
+    /// Minimum Argon2 parallel threads
+    pub min_parallel_threads: Option<u32>,
+}
+
+/// Declarative security policy for LUKS volumes
+///
+/// Rules that are left empty or `None` are not evaluated. A policy can be deserialized
+/// from JSON, for example
+/// `{"allowed_ciphers": ["aes-xts-plain64"], "min_volume_key_size": 64, "require_luks2": true}`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SecurityPolicy {
+    /// Allowed data segment ciphers as `cipher-mode`, for example `aes-xts-plain64`
+    pub allowed_ciphers: Vec<String>,
+    /// Minimum volume key size in bytes
+    pub min_volume_key_size: Option<u32>,
+    /// Allowed key derivation functions for keyslots and their minimum parameters
+    pub allowed_pbkdfs: Vec<PbkdfPolicy>,
+    /// Minimum encryption sector size in bytes
+    pub min_sector_size: Option<u32>,
+    /// Maximum number of active keyslots
+    pub max_active_keyslots: Option<u32>,
+    /// Fail if the header contains keyslots not bound to the volume key
+    pub disallow_unbound_keyslots: bool,
+    /// Fail if the header is not LUKS2
+    pub require_luks2: bool,
+    /// Token types of which at least one token must be present, for example `systemd-tpm2`
+    pub required_token_types: Vec<String>,
+}
+
+/// Rule of a security policy
+#[derive(Clone, Debug, PartialEq)]
+pub enum PolicyRule {
+    /// The header must be LUKS2
+    Luks2Required,
+    /// The data segment cipher must be allowed
+    Cipher,
+    /// The volume key must be large enough
+    VolumeKeySize,
+    /// The key derivation function of a keyslot must be allowed and strong enough
+    Pbkdf(c_int),
+    /// The encryption sector size must be large enough
+    SectorSize,
+    /// The number of active keyslots must not exceed the maximum
+    ActiveKeyslots,
+    /// A keyslot must not be unbound
+    UnboundKeyslot(c_int),
+    /// A token of the given type must be present
+    RequiredToken(String),
+}
+
+impl Display for PolicyRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PolicyRule::Luks2Required => write!(f, "LUKS2 required"),
+            PolicyRule::Cipher => write!(f, "cipher"),
+            PolicyRule::VolumeKeySize => write!(f, "volume key size"),
+            PolicyRule::Pbkdf(keyslot) => write!(f, "PBKDF of keyslot {}", keyslot),
+            PolicyRule::SectorSize => write!(f, "sector size"),
+            PolicyRule::ActiveKeyslots => write!(f, "active keyslots"),
+            PolicyRule::UnboundKeyslot(keyslot) => write!(f, "unbound keyslot {}", keyslot),
+            PolicyRule::RequiredToken(ref type_) => write!(f, "{} token", type_),
+        }
+    }
+}
+
+/// Result of evaluating one rule
+#[derive(Clone, Debug, PartialEq)]
+pub struct PolicyFinding {
+    /// Evaluated rule
+    pub rule: PolicyRule,
+    /// Whether the volume satisfies the rule
+    pub passed: bool,
+    /// Description of the observed value
+    pub detail: String,
+}
+
+impl Display for PolicyFinding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {}: {}",
+            if self.passed { "PASS" } else { "FAIL" },
+            self.rule,
+            self.detail
+        )
+    }
+}
+
+/// Findings of a policy audit
+#[derive(Clone, Debug, PartialEq)]
+pub struct PolicyReport {
+    /// Findings in evaluation order
+    pub findings: Vec<PolicyFinding>,
+}
+
+impl PolicyReport {
+    /// Whether the volume satisfies every evaluated rule
+    pub fn passed(&self) -> bool {
+        self.findings.iter().all(|f| f.passed)
+    }
+
+    /// Get the findings of rules the volume does not satisfy
+    pub fn failures(&self) -> Vec<&PolicyFinding> {
+        self.findings.iter().filter(|f| !f.passed).collect()
+    }
+}
+
+impl Display for PolicyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for finding in self.findings.iter() {
+            writeln!(f, "{}", finding)?;
+        }
+        Ok(())
+    }
+}
+
+/// Key derivation parameters of a keyslot
+struct KeyslotFacts {
+    keyslot: c_int,
+    unbound: bool,
+    kdf: &'static str,
+    iterations: u32,
+    max_memory_kb: u32,
+    parallel_threads: u32,
+}
+
+/// Properties of a volume evaluated by a policy
+struct VolumeFacts {
+    luks2: bool,
+    cipher: String,
+    volume_key_size: u32,
+    sector_size: u32,
+    keyslots: Vec<KeyslotFacts>,
+    token_types: Vec<String>,
+}
+
+impl SecurityPolicy {
+    /// Evaluate the policy against a device with a loaded LUKS header
+    pub fn audit(&self, device: &mut CryptDevice) -> Result<PolicyReport, LibcryptErr> {
+        Ok(self.evaluate(&collect(device)?))
+    }
+
+    fn evaluate(&self, facts: &VolumeFacts) -> PolicyReport {
+        let mut findings = Vec::new();
+        if self.require_luks2 {
+            findings.push(PolicyFinding {
+                rule: PolicyRule::Luks2Required,
+                passed: facts.luks2,
+                detail: format!("header is {}", if facts.luks2 { "LUKS2" } else { "LUKS1" }),
+            });
+        }
+        if !self.allowed_ciphers.is_empty() {
+            findings.push(PolicyFinding {
+                rule: PolicyRule::Cipher,
+                passed: self.allowed_ciphers.contains(&facts.cipher),
+                detail: format!("cipher is {}", facts.cipher),
+            });
+        }
+        if let Some(min) = self.min_volume_key_size {
+            findings.push(PolicyFinding {
+                rule: PolicyRule::VolumeKeySize,
+                passed: facts.volume_key_size >= min,
+                detail: format!(
+                    "volume key is {} bytes, minimum is {}",
+                    facts.volume_key_size, min
+                ),
+            });
+        }
+        if let Some(min) = self.min_sector_size {
+            findings.push(PolicyFinding {
+                rule: PolicyRule::SectorSize,
+                passed: facts.sector_size >= min,
+                detail: format!(
+                    "sector size is {} bytes, minimum is {}",
+                    facts.sector_size, min
+                ),
+            });
+        }
+        if let Some(max) = self.max_active_keyslots {
+            let active = facts.keyslots.iter().filter(|k| !k.unbound).count() as u32;
+            findings.push(PolicyFinding {
+                rule: PolicyRule::ActiveKeyslots,
+                passed: active <= max,
+                detail: format!("{} active keyslots, maximum is {}", active, max),
+            });
+        }
+        for keyslot in facts.keyslots.iter() {
+            if self.disallow_unbound_keyslots && keyslot.unbound {
+                findings.push(PolicyFinding {
+                    rule: PolicyRule::UnboundKeyslot(keyslot.keyslot),
+                    passed: false,
+                    detail: "keyslot is not bound to the volume key".to_string(),
+                });
+            }
+            if !self.allowed_pbkdfs.is_empty() {
+                let (passed, detail) = self.evaluate_pbkdf(keyslot);
+                findings.push(PolicyFinding {
+                    rule: PolicyRule::Pbkdf(keyslot.keyslot),
+                    passed,
+                    detail,
+                });
+            }
+        }
+        for type_ in self.required_token_types.iter() {
+            let count = facts.token_types.iter().filter(|t| *t == type_).count();
+            findings.push(PolicyFinding {
+                rule: PolicyRule::RequiredToken(type_.clone()),
+                passed: count > 0,
+                detail: format!("{} {} tokens present", count, type_),
+            });
+        }
+        PolicyReport { findings }
+    }
+
+    fn evaluate_pbkdf(&self, keyslot: &KeyslotFacts) -> (bool, String) {
+        let pbkdf = match self.allowed_pbkdfs.iter().find(|p| p.kdf == keyslot.kdf) {
+            Some(p) => p,
+            None => return (false, format!("{} is not allowed", keyslot.kdf)),
+        };
+        let mut failures = Vec::new();
+        let checks = [
+            ("iterations", keyslot.iterations, pbkdf.min_iterations),
+            ("memory", keyslot.max_memory_kb, pbkdf.min_memory_kb),
+            (
+                "threads",
+                keyslot.parallel_threads,
+                pbkdf.min_parallel_threads,
+            ),
+        ];
+        for &(name, value, min) in checks.iter() {
+            if let Some(min) = min {
+                if value < min {
+                    failures.push(format!("{} {} below minimum {}", name, value, min));
+                }
+            }
+        }
+        if failures.is_empty() {
+            (
+                true,
+                format!(
+                    "{} with {} iterations, {} KiB memory, {} threads",
+                    keyslot.kdf,
+                    keyslot.iterations,
+                    keyslot.max_memory_kb,
+                    keyslot.parallel_threads
+                ),
+            )
+        } else {
+            (false, format!("{}: {}", keyslot.kdf, failures.join(", ")))
+        }
+    }
+}
+
+fn collect(device: &mut CryptDevice) -> Result<VolumeFacts, LibcryptErr> {
+    let format = device.format_handle().get_type()?;
+    let luks2 = match format {
+        EncryptionFormat::Luks2 => true,
+        EncryptionFormat::Luks1 => false,
+        _ => {
+            return Err(LibcryptErr::Other(
+                "Policy audits are only supported for LUKS devices".to_string(),
+            ))
+        }
+    };
+    let mut status = device.status_handle();
+    let cipher = format!("{}-{}", status.get_cipher()?, status.get_cipher_mode()?);
+    let volume_key_size = status.get_volume_key_size() as u32;
+    let sector_size = status.get_sector_size() as u32;
+
+    let mut keyslots = Vec::new();
+    for keyslot in 0..CryptKeyslot::max_keyslots(format)? {
+        let unbound = match device.keyslot_handle(Some(keyslot)).status()? {
+            KeyslotInfo::Active | KeyslotInfo::ActiveLast => false,
+            KeyslotInfo::Unbound => true,
+            KeyslotInfo::Invalid | KeyslotInfo::Inactive => continue,
+        };
+        let pbkdf = device.keyslot_handle(Some(keyslot)).get_pbkdf()?;
+        keyslots.push(KeyslotFacts {
+            keyslot,
+            unbound,
+            kdf: pbkdf.type_.as_str(),
+            iterations: pbkdf.iterations,
+            max_memory_kb: pbkdf.max_memory_kb,
+            parallel_threads: pbkdf.parallel_threads,
+        });
+    }
+
+    let mut token_types = Vec::new();
+    if luks2 {
+        for token in 0..LUKS2_TOKENS_MAX {
+            let (info, type_) = device.token_handle(token).status()?;
+            match info {
+                CryptTokenInfo::Invalid | CryptTokenInfo::Inactive => (),
+                _ => token_types.push(type_),
+            }
+        }
+    }
+
+    Ok(VolumeFacts {
+        luks2,
+        cipher,
+        volume_key_size,
+        sector_size,
+        keyslots,
+        token_types,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn facts() -> VolumeFacts {
+        VolumeFacts {
+            luks2: true,
+            cipher: "aes-xts-plain64".to_string(),
+            volume_key_size: 64,
+            sector_size: 4096,
+            keyslots: vec![
+                KeyslotFacts {
+                    keyslot: 0,
+                    unbound: false,
+                    kdf: "argon2id",
+                    iterations: 4,
+                    max_memory_kb: 1024 * 1024,
+                    parallel_threads: 4,
+                },
+                KeyslotFacts {
+                    keyslot: 1,
+                    unbound: false,
+                    kdf: "pbkdf2",
+                    iterations: 1000,
+                    max_memory_kb: 0,
+                    parallel_threads: 0,
+                },
+                KeyslotFacts {
+                    keyslot: 2,
+                    unbound: true,
+                    kdf: "argon2id",
+                    iterations: 4,
+                    max_memory_kb: 64 * 1024,
+                    parallel_threads: 1,
+                },
+            ],
+            token_types: vec!["systemd-tpm2".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_empty_policy() {
+        let report = SecurityPolicy::default().evaluate(&facts());
+        assert!(report.findings.is_empty());
+        assert!(report.passed());
+    }
+
+    #[test]
+    fn test_policy_findings() {
+        let policy: SecurityPolicy = serde_json::from_str(
+            r#"{
+                "allowed_ciphers": ["aes-xts-plain64"],
+                "min_volume_key_size": 64,
+                "allowed_pbkdfs": [{"kdf": "argon2id", "min_memory_kb": 524288}],
+                "min_sector_size": 4096,
+                "max_active_keyslots": 1,
+                "disallow_unbound_keyslots": true,
+                "require_luks2": true,
+                "required_token_types": ["systemd-tpm2", "clevis"]
+            }"#,
+        )
+        .unwrap();
+        let report = policy.evaluate(&facts());
+        assert!(!report.passed());
+        let failed = report
+            .failures()
+            .into_iter()
+            .map(|f| f.rule.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            failed,
+            vec![
+                PolicyRule::ActiveKeyslots,
+                PolicyRule::Pbkdf(1),
+                PolicyRule::UnboundKeyslot(2),
+                PolicyRule::Pbkdf(2),
+                PolicyRule::RequiredToken("clevis".to_string()),
+            ]
+        );
+        assert_eq!(report.findings.len(), 11);
+        assert_eq!(
+            report.failures()[1].to_string(),
+            "[FAIL] PBKDF of keyslot 1: pbkdf2 is not allowed"
+        );
+    }
+
+    #[test]
+    fn test_pbkdf_minimums() {
+        let policy = SecurityPolicy {
+            allowed_pbkdfs: vec![PbkdfPolicy {
+                kdf: "pbkdf2".to_string(),
+                min_iterations: Some(100_000),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let report = policy.evaluate(&facts());
+        assert_eq!(
+            report.findings[1].detail,
+            "pbkdf2: iterations 1000 below minimum 100000"
+        );
+    }
+}
@@ -0,0 +1,399 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    convert::TryFrom,
+    io::{self, Write},
+    os::raw::c_int,
+    time::{Duration, Instant},
+};
+
+use zeroize::Zeroizing;
+
+use crate::{
+    activate::CryptActivateFlags, device::CryptDevice, err::LibcryptErr, log::CryptLogLevel,
+};
+
+/// Maximum length of an interactively entered passphrase, as in `cryptsetup`
+const MAX_PASSPHRASE_SIZE: usize = 512;
+/// Default number of passphrase attempts, as in `cryptsetup`
+const DEFAULT_TRIES: u32 = 3;
+
+/// Passphrase that is cleared from memory when dropped
+pub type Passphrase = Zeroizing<Vec<u8>>;
+
+/// Disables echo on a terminal until dropped
+struct EchoGuard {
+    fd: c_int,
+    original: libc::termios,
+}
+
+impl EchoGuard {
+    fn disable(fd: c_int) -> Result<Self, LibcryptErr> {
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut original) } < 0 {
+            return Err(LibcryptErr::IOError(io::Error::last_os_error()));
+        }
+        let mut termios = original;
+        termios.c_lflag &= !libc::ECHO;
+        if unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &termios) } < 0 {
+            return Err(LibcryptErr::IOError(io::Error::last_os_error()));
+        }
+        Ok(EchoGuard { fd, original })
+    }
+}
+
+impl Drop for EchoGuard {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(self.fd, libc::TCSAFLUSH, &self.original) };
+    }
+}
+
+/// Wait until `fd` is readable or `deadline` has passed
+fn wait_readable(fd: c_int, deadline: Option<Instant>) -> Result<(), LibcryptErr> {
+    loop {
+        let timeout = match deadline {
+            Some(d) => {
+                let now = Instant::now();
+                if now >= d {
+                    return Err(LibcryptErr::IOError(io::Error::from_raw_os_error(
+                        libc::ETIMEDOUT,
+                    )));
+                }
+                let remaining = d - now;
+                // Round up so the deadline is not missed by less than a millisecond
+                (remaining.as_secs() * 1000 + u64::from(remaining.subsec_micros() + 999) / 1000)
+                    .min(c_int::MAX as u64) as c_int
+            }
+            None => -1,
+        };
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+            i if i < 0 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(LibcryptErr::IOError(err));
+                }
+            }
+            0 => (),
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Read one line from `fd` without consuming any input after the newline
+///
+/// The trailing newline is not part of the returned passphrase. End of input terminates
+/// the line as well.
+fn read_line(fd: c_int, deadline: Option<Instant>) -> Result<Passphrase, LibcryptErr> {
+    let mut line = Passphrase::new(Vec::with_capacity(MAX_PASSPHRASE_SIZE + 1));
+    loop {
+        wait_readable(fd, deadline)?;
+        let mut byte = 0u8;
+        match unsafe { libc::read(fd, &mut byte as *mut u8 as *mut libc::c_void, 1) } {
+            i if i < 0 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(LibcryptErr::IOError(err));
+                }
+            }
+            0 => break,
+            _ => {
+                if byte == b'\n' {
+                    break;
+                }
+                if line.len() == MAX_PASSPHRASE_SIZE {
+                    return Err(LibcryptErr::Other(format!(
+                        "Passphrase is longer than {} bytes",
+                        MAX_PASSPHRASE_SIZE
+                    )));
+                }
+                line.push(byte);
+            }
+        }
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(line)
+}
+
+fn is_eperm(err: &LibcryptErr) -> bool {
+    match *err {
+        LibcryptErr::IOError(ref e) => e.raw_os_error() == Some(libc::EPERM),
+        _ => false,
+    }
+}
+
+/// Call `f` with `context` and passphrases from `read` until it succeeds, fails with an
+/// error other than `EPERM` or `tries` passphrases have been rejected
+///
+/// `rejected` is called with `context` before reading the next passphrase.
+fn retry_with<C, T, R, F, N>(
+    context: &mut C,
+    tries: u32,
+    mut read: R,
+    mut f: F,
+    mut rejected: N,
+) -> Result<T, LibcryptErr>
+where
+    R: FnMut() -> Result<Passphrase, LibcryptErr>,
+    F: FnMut(&mut C, &Passphrase) -> Result<T, LibcryptErr>,
+    N: FnMut(&mut C),
+{
+    let mut attempt = 1;
+    loop {
+        let passphrase = read()?;
+        match f(context, &passphrase) {
+            Err(ref e) if is_eperm(e) && attempt < tries => {
+                rejected(context);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Prompt for passphrases on the terminal
+///
+/// If stdin is a terminal, the prompt is written to stderr and the passphrase is read
+/// with echo disabled. Otherwise one line is read from stdin without prompting, so
+/// passphrases can be piped in by scripts. In that case confirmation is skipped and only
+/// a single attempt is made.
+pub struct PassphrasePrompt {
+    prompt: String,
+    confirm: bool,
+    tries: u32,
+    timeout: Option<Duration>,
+}
+
+impl PassphrasePrompt {
+    /// Create a prompt with the given message, without confirmation or timeout, that
+    /// allows three attempts
+    pub fn new(prompt: &str) -> Self {
+        PassphrasePrompt {
+            prompt: prompt.to_string(),
+            confirm: false,
+            tries: DEFAULT_TRIES,
+            timeout: None,
+        }
+    }
+
+    /// Ask for the passphrase a second time and fail if they differ, for new passphrases
+    pub fn confirm(&mut self, confirm: bool) -> &mut Self {
+        self.confirm = confirm;
+        self
+    }
+
+    /// Set the number of attempts allowed by `retry` and `activate`
+    pub fn tries(&mut self, tries: u32) -> &mut Self {
+        self.tries = tries.max(1);
+        self
+    }
+
+    /// Fail with `ETIMEDOUT` if a passphrase is not entered within `timeout`
+    ///
+    /// The timeout covers all attempts made by `retry` and `activate` together.
+    pub fn timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn is_interactive() -> bool {
+        unsafe { libc::isatty(libc::STDIN_FILENO) == 1 }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.timeout.map(|t| Instant::now() + t)
+    }
+
+    fn read_interactive(
+        &self,
+        prompt: &str,
+        deadline: Option<Instant>,
+    ) -> Result<Passphrase, LibcryptErr> {
+        let mut stderr = io::stderr();
+        write!(stderr, "{}: ", prompt)
+            .and_then(|_| stderr.flush())
+            .map_err(LibcryptErr::IOError)?;
+        let guard = EchoGuard::disable(libc::STDIN_FILENO)?;
+        let result = read_line(libc::STDIN_FILENO, deadline);
+        drop(guard);
+        // The newline was not echoed
+        let _ = writeln!(stderr);
+        result
+    }
+
+    /// Read a passphrase
+    pub fn read(&self) -> Result<Passphrase, LibcryptErr> {
+        self.read_until(self.deadline())
+    }
+
+    fn read_until(&self, deadline: Option<Instant>) -> Result<Passphrase, LibcryptErr> {
+        let passphrase = if Self::is_interactive() {
+            let passphrase = self.read_interactive(&self.prompt, deadline)?;
+            if self.confirm {
+                let verify = self.read_interactive("Verify passphrase", deadline)?;
+                if *verify != *passphrase {
+                    return Err(LibcryptErr::Other("Passphrases do not match".to_string()));
+                }
+            }
+            passphrase
+        } else {
+            read_line(libc::STDIN_FILENO, deadline)?
+        };
+        if passphrase.is_empty() {
+            return Err(LibcryptErr::Other("No passphrase supplied".to_string()));
+        }
+        Ok(passphrase)
+    }
+
+    /// Call `f` with `device` and a passphrase, prompting again while it fails with `EPERM`
+    ///
+    /// libcryptsetup reports passphrases that do not unlock any keyslot with `EPERM`.
+    /// Rejected passphrases are reported through the log of `device`. Other errors are
+    /// returned immediately.
+    pub fn retry<T, F>(&self, device: &mut CryptDevice, f: F) -> Result<T, LibcryptErr>
+    where
+        F: FnMut(&mut CryptDevice, &Passphrase) -> Result<T, LibcryptErr>,
+    {
+        let tries = if Self::is_interactive() {
+            self.tries
+        } else {
+            1
+        };
+        let deadline = self.deadline();
+        retry_with(
+            device,
+            tries,
+            || self.read_until(deadline),
+            f,
+            |device| {
+                let _ = device.logging_handle().log(
+                    CryptLogLevel::Error,
+                    "No key available with this passphrase.\n",
+                );
+            },
+        )
+    }
+
+    /// Prompt for a passphrase and activate the device with it
+    ///
+    /// Passing `None` as `name` only checks the passphrase. Returns the keyslot that was
+    /// unlocked.
+    pub fn activate(
+        &self,
+        device: &mut CryptDevice,
+        name: Option<&str>,
+        keyslot: Option<c_int>,
+        flags: CryptActivateFlags,
+    ) -> Result<c_int, LibcryptErr> {
+        let flags: u32 = flags.into();
+        self.retry(device, |device, passphrase| {
+            device.activate_handle().activate_by_passphrase(
+                name,
+                keyslot,
+                passphrase,
+                CryptActivateFlags::try_from(flags)?,
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pipe() -> (c_int, c_int) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        (fds[0], fds[1])
+    }
+
+    fn write_all(fd: c_int, data: &[u8]) {
+        let written = unsafe { libc::write(fd, data.as_ptr() as *const libc::c_void, data.len()) };
+        assert_eq!(written, data.len() as isize);
+    }
+
+    #[test]
+    fn test_read_line() {
+        let (read_fd, write_fd) = pipe();
+        write_all(write_fd, b"first\r\nsecond\n");
+        assert_eq!(read_line(read_fd, None).unwrap().as_slice(), b"first");
+        assert_eq!(read_line(read_fd, None).unwrap().as_slice(), b"second");
+
+        let start = Instant::now();
+        match read_line(read_fd, Some(start + Duration::from_millis(50))) {
+            Err(LibcryptErr::IOError(e)) => assert_eq!(e.raw_os_error(), Some(libc::ETIMEDOUT)),
+            _ => panic!("Expected a timeout"),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        write_all(write_fd, &[b'a'; MAX_PASSPHRASE_SIZE + 2]);
+        assert!(read_line(read_fd, None).is_err());
+
+        unsafe { libc::close(write_fd) };
+        assert_eq!(read_line(read_fd, None).unwrap().as_slice(), b"a");
+        assert!(read_line(read_fd, None).unwrap().is_empty());
+        unsafe { libc::close(read_fd) };
+    }
+
+    #[test]
+    fn test_retry_with() {
+        let eperm = || LibcryptErr::IOError(io::Error::from_raw_os_error(libc::EPERM));
+        let mut inputs = vec![b"wrong".to_vec(), b"right".to_vec()].into_iter();
+        // Attempts and rejections
+        let mut counts = (0, 0);
+        let result = retry_with(
+            &mut counts,
+            3,
+            || Ok(Passphrase::new(inputs.next().unwrap())),
+            |counts, p| {
+                counts.0 += 1;
+                if p.as_slice() == b"right" {
+                    Ok(counts.0)
+                } else {
+                    Err(eperm())
+                }
+            },
+            |counts| counts.1 += 1,
+        );
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(counts, (2, 1));
+
+        let mut counts = (0, 0);
+        let result: Result<(), _> = retry_with(
+            &mut counts,
+            2,
+            || Ok(Passphrase::new(b"wrong".to_vec())),
+            |counts, _| {
+                counts.0 += 1;
+                Err(eperm())
+            },
+            |counts| counts.1 += 1,
+        );
+        assert!(is_eperm(&result.unwrap_err()));
+        assert_eq!(counts, (2, 1));
+
+        let mut counts = (0, 0);
+        let result: Result<(), _> = retry_with(
+            &mut counts,
+            3,
+            || Ok(Passphrase::new(b"any".to_vec())),
+            |counts, _| {
+                counts.0 += 1;
+                Err(LibcryptErr::IOError(io::Error::from_raw_os_error(
+                    libc::EINVAL,
+                )))
+            },
+            |counts| counts.1 += 1,
+        );
+        assert!(result.is_err());
+        assert_eq!(counts, (1, 0));
+    }
+}
@@ -0,0 +1,230 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{fs::File, io::Read, os::raw::c_int};
+
+use zeroize::Zeroizing;
+
+use crate::{
+    activate::CryptActivateFlags, device::CryptDevice, err::LibcryptErr,
+    settings::high_entropy_pbkdf,
+};
+
+/// Token type systemd uses to mark keyslots holding a recovery key
+pub const SYSTEMD_RECOVERY_TOKEN_TYPE: &str = "systemd-recovery";
+
+/// Alphabet of the modhex encoding used by YubiKeys and systemd recovery keys
+const MODHEX_ALPHABET: &[u8; 16] = b"cbdefghijklnrtuv";
+/// Number of random bytes in a recovery key
+const RECOVERY_KEY_BYTES: usize = 32;
+/// Number of encoded bytes between two dashes
+const RECOVERY_KEY_GROUP_BYTES: usize = 4;
+/// Length of a recovery key with dashes between the groups
+const RECOVERY_KEY_FORMATTED_LENGTH: usize =
+    RECOVERY_KEY_BYTES * 2 + RECOVERY_KEY_BYTES / RECOVERY_KEY_GROUP_BYTES - 1;
+
+/// Recovery key in the format generated by `systemd-cryptenroll --recovery-key`
+///
+/// The key is 256 bits of randomness encoded as modhex in eight dash separated groups of
+/// eight characters. The formatted string, including the dashes, is used as the passphrase
+/// of the keyslot. The key is cleared from memory when dropped.
+pub struct RecoveryKey(Zeroizing<String>);
+
+impl RecoveryKey {
+    /// Generate a new recovery key from the kernel random number generator
+    pub fn generate() -> Result<Self, LibcryptErr> {
+        let mut bytes = Zeroizing::new([0u8; RECOVERY_KEY_BYTES]);
+        File::open("/dev/urandom")
+            .and_then(|mut f| f.read_exact(&mut *bytes))
+            .map_err(LibcryptErr::IOError)?;
+        Ok(Self::from_bytes(&bytes))
+    }
+
+    fn from_bytes(bytes: &[u8; RECOVERY_KEY_BYTES]) -> Self {
+        let mut key = Zeroizing::new(String::with_capacity(RECOVERY_KEY_FORMATTED_LENGTH));
+        for (i, byte) in bytes.iter().enumerate() {
+            if i > 0 && i % RECOVERY_KEY_GROUP_BYTES == 0 {
+                key.push('-');
+            }
+            key.push(MODHEX_ALPHABET[usize::from(byte >> 4)] as char);
+            key.push(MODHEX_ALPHABET[usize::from(byte & 0xf)] as char);
+        }
+        RecoveryKey(key)
+    }
+
+    /// Validate and normalize a recovery key typed by a user
+    ///
+    /// Surrounding whitespace is ignored and the key may be typed in any case, with or
+    /// without the dashes between the groups.
+    pub fn parse(input: &str) -> Result<Self, LibcryptErr> {
+        let input = input.trim();
+        let with_dashes = match input.len() {
+            l if l == RECOVERY_KEY_BYTES * 2 => false,
+            l if l == RECOVERY_KEY_FORMATTED_LENGTH => true,
+            _ => {
+                return Err(LibcryptErr::Other(
+                    "Recovery key has an invalid length".to_string(),
+                ))
+            }
+        };
+        let input = input.as_bytes();
+        let mut bytes = Zeroizing::new([0u8; RECOVERY_KEY_BYTES]);
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let k = if with_dashes {
+                let k = i * 2 + i / RECOVERY_KEY_GROUP_BYTES;
+                if i > 0 && i % RECOVERY_KEY_GROUP_BYTES == 0 && input[k - 1] != b'-' {
+                    return Err(LibcryptErr::Other(
+                        "Recovery key groups must be separated by dashes".to_string(),
+                    ));
+                }
+                k
+            } else {
+                i * 2
+            };
+            *byte = (decode_modhex(input[k])? << 4) | decode_modhex(input[k + 1])?;
+        }
+        Ok(Self::from_bytes(&bytes))
+    }
+
+    /// Get the formatted recovery key
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+fn decode_modhex(c: u8) -> Result<u8, LibcryptErr> {
+    let c = c.to_ascii_lowercase();
+    MODHEX_ALPHABET
+        .iter()
+        .position(|m| *m == c)
+        .map(|p| p as u8)
+        .ok_or_else(|| LibcryptErr::Other("Recovery key contains an invalid character".to_string()))
+}
+
+/// Result of enrolling a recovery key
+pub struct RecoveryKeyEnrollment {
+    /// Generated recovery key to show to the user
+    pub key: RecoveryKey,
+    /// Keyslot holding the recovery key
+    pub keyslot: c_int,
+    /// `systemd-recovery` token referencing the keyslot
+    pub token: c_int,
+}
+
+/// Handle for systemd compatible recovery keys
+pub struct CryptRecoveryKey;
+
+impl CryptRecoveryKey {
+    /// Generate a recovery key and enroll it on a device with a loaded LUKS2 header
+    ///
+    /// `passphrase` must unlock an existing keyslot. The recovery key is added as a new
+    /// passphrase keyslot and a `systemd-recovery` token is assigned to it, so the
+    /// enrollment is recognized by `systemd-cryptenroll` and `systemd-cryptsetup`.
+    pub fn enroll(
+        device: &mut CryptDevice,
+        passphrase: &[u8],
+    ) -> Result<RecoveryKeyEnrollment, LibcryptErr> {
+        let key = RecoveryKey::generate()?;
+        let previous_pbkdf = device.settings_handle().get_pbkdf_type()?;
+        device
+            .settings_handle()
+            .set_pbkdf_type(&high_entropy_pbkdf())?;
+        let result = device
+            .keyslot_handle(None)
+            .add_by_passphrase(passphrase, key.as_str().as_bytes());
+        device.settings_handle().set_pbkdf_type(&previous_pbkdf)?;
+        let keyslot = result?;
+
+        let token = device
+            .token_handle(libcryptsetup_rs_sys::CRYPT_ANY_TOKEN)
+            .json_set(
+                &serde_json::json!({
+                    "type": SYSTEMD_RECOVERY_TOKEN_TYPE,
+                    "keyslots": [keyslot.to_string()],
+                }),
+                true,
+            );
+        match token {
+            Ok(token) => Ok(RecoveryKeyEnrollment {
+                key,
+                keyslot,
+                token,
+            }),
+            Err(e) => {
+                device.keyslot_handle(Some(keyslot)).destroy()?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Normalize a recovery key typed by a user and activate the device with it
+    ///
+    /// Passing `None` as `name` only checks the recovery key. Returns the keyslot that
+    /// was unlocked.
+    pub fn activate(
+        device: &mut CryptDevice,
+        name: Option<&str>,
+        input: &str,
+        flags: CryptActivateFlags,
+    ) -> Result<c_int, LibcryptErr> {
+        let key = RecoveryKey::parse(input)?;
+        device
+            .activate_handle()
+            .activate_by_passphrase(name, None, key.as_str().as_bytes(), flags)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY: &str = "cbdefghi-jklnrtuv-vutrnlkj-ihgfedbc-cccccccc-vvvvvvvv-bbbbbbbb-dddddddd";
+
+    #[test]
+    fn test_recovery_key_format() {
+        let mut bytes = [0u8; RECOVERY_KEY_BYTES];
+        for (i, b) in bytes[..8].iter_mut().enumerate() {
+            *b = (2 * i as u8) << 4 | (2 * i as u8 + 1);
+        }
+        for (i, b) in bytes[8..16].iter_mut().enumerate() {
+            *b = (15 - 2 * i as u8) << 4 | (14 - 2 * i as u8);
+        }
+        for b in bytes[20..24].iter_mut() {
+            *b = 0xff;
+        }
+        for b in bytes[24..28].iter_mut() {
+            *b = 0x11;
+        }
+        for b in bytes[28..].iter_mut() {
+            *b = 0x22;
+        }
+        let key = RecoveryKey::from_bytes(&bytes);
+        assert_eq!(key.as_str(), KEY);
+        assert_eq!(key.as_str().len(), RECOVERY_KEY_FORMATTED_LENGTH);
+    }
+
+    #[test]
+    fn test_recovery_key_parse() {
+        assert_eq!(RecoveryKey::parse(KEY).unwrap().as_str(), KEY);
+        assert_eq!(
+            RecoveryKey::parse(&format!("  {}\n", KEY.to_uppercase()))
+                .unwrap()
+                .as_str(),
+            KEY
+        );
+        assert_eq!(
+            RecoveryKey::parse(&KEY.replace("-", "")).unwrap().as_str(),
+            KEY
+        );
+        assert!(RecoveryKey::parse(&KEY[1..]).is_err());
+        assert!(RecoveryKey::parse(&KEY.replace("-", ":")).is_err());
+        assert!(RecoveryKey::parse(&KEY.replace("c", "a")).is_err());
+
+        let generated = RecoveryKey::generate().unwrap();
+        assert_eq!(
+            RecoveryKey::parse(generated.as_str()).unwrap().as_str(),
+            generated.as_str()
+        );
+    }
+}
@@ -0,0 +1,298 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::os::raw::c_int;
+
+use zeroize::Zeroizing;
+
+use crate::{
+    backend::LibcryptBackend,
+    credential::{resolve_token, CryptCredential, ResolvedToken},
+    device::CryptDevice,
+    err::LibcryptErr,
+    format::EncryptionFormat,
+    keyslot::{CryptVolumeKeyFlag, CryptVolumeKeyFlags, KeyslotInfo},
+    luks2_reencrypt::{
+        CryptReencryptFlag, CryptReencryptFlags, CryptReencryptInfo, CryptReencryptStatus,
+        ReencryptProgress,
+    },
+    luks2_token::{list_tokens, token_keyslots},
+    settings::high_entropy_pbkdf,
+};
+
+/// Size in bytes of the random passphrase of temporary keyslots
+const TEMPORARY_PASSPHRASE_BYTES: usize = 32;
+
+/// Token type marking the temporary keyslots added to resume by volume keys
+const TEMPORARY_TOKEN_TYPE: &str = "libcryptsetup-rs-reencrypt";
+
+/// Destroy the keyslots marked by temporary tokens and remove the tokens
+///
+/// Such tokens are only left behind if the process was interrupted while resuming by
+/// volume keys. Returns the keyslots that were destroyed.
+fn remove_temporary_keyslots<B: LibcryptBackend>(
+    device: &mut B,
+) -> Result<Vec<c_int>, LibcryptErr> {
+    let list = list_tokens(device, &[TEMPORARY_TOKEN_TYPE], |json| {
+        Ok(token_keyslots(json))
+    })?;
+    let mut destroyed = Vec::new();
+    for (token, keyslots) in list.tokens {
+        for keyslot in keyslots {
+            match device.keyslot_status(keyslot)? {
+                KeyslotInfo::Invalid | KeyslotInfo::Inactive => (),
+                _ => {
+                    device.keyslot_destroy(keyslot)?;
+                    destroyed.push(keyslot);
+                }
+            }
+        }
+        device.token_remove(token)?;
+    }
+    Ok(destroyed)
+}
+
+/// Handle for inspecting, recovering and resuming LUKS2 reencryption
+///
+/// The LUKS2 header must be loaded before using this handle.
+pub struct CryptReencryptResume<'a> {
+    reference: &'a mut CryptDevice,
+}
+
+impl<'a> CryptReencryptResume<'a> {
+    pub(crate) fn new(reference: &'a mut CryptDevice) -> Self {
+        CryptReencryptResume { reference }
+    }
+
+    /// Get the reencryption state recorded in the metadata
+    pub fn status(&mut self) -> Result<CryptReencryptStatus, LibcryptErr> {
+        self.reference.reencrypt_handle().get_status()
+    }
+
+    /// Repair the metadata of a reencryption that was interrupted by a crash or power loss
+    ///
+    /// Returns `true` if recovery was performed and `false` if no recovery was required.
+    pub fn recover(&mut self, credential: &CryptCredential) -> Result<bool, LibcryptErr> {
+        match self.status()?.info {
+            CryptReencryptInfo::Crash => (),
+            _ => return Ok(false),
+        }
+        self.init(None, credential, CryptReencryptFlag::Recovery)?;
+        self.reference
+            .context_handle()
+            .load::<()>(EncryptionFormat::Luks2, None)?;
+        Ok(true)
+    }
+
+    /// Continue an interrupted reencryption until it is finished or interrupted again by
+    /// the progress callback
+    ///
+    /// Crash recovery is performed first if necessary, and temporary keyslots left behind
+    /// by an interrupted resume by volume keys are destroyed. `name` must be the name of the
+    /// active mapping for online reencryption or `None` for offline reencryption. The
+    /// returned status has `CryptReencryptInfo::None` if the reencryption has been finalized.
+    pub fn resume(
+        &mut self,
+        name: Option<&str>,
+        credential: &CryptCredential,
+        progress: Option<ReencryptProgress>,
+    ) -> Result<CryptReencryptStatus, LibcryptErr> {
+        self.recover(credential)?;
+        let status = self.status()?;
+        match status.info {
+            CryptReencryptInfo::None => return Ok(status),
+            CryptReencryptInfo::Invalid => {
+                return Err(LibcryptErr::Other(
+                    "LUKS2 reencryption metadata is invalid".to_string(),
+                ))
+            }
+            _ => (),
+        }
+        self.init(name, credential, CryptReencryptFlag::ResumeOnly)?;
+        self.reference.reencrypt_handle().reencrypt(progress)?;
+        self.status()
+    }
+
+    fn init(
+        &mut self,
+        name: Option<&str>,
+        credential: &CryptCredential,
+        flag: CryptReencryptFlag,
+    ) -> Result<c_int, LibcryptErr> {
+        remove_temporary_keyslots(self.reference)?;
+        match *credential {
+            CryptCredential::Passphrase {
+                passphrase,
+                keyslot,
+            } => self.init_by_passphrase(name, passphrase, keyslot, flag),
+            CryptCredential::Keyring {
+                key_description,
+                keyslot,
+            } => self
+                .reference
+                .reencrypt_handle()
+                .reencrypt_init_from_metadata_by_keyring(
+                    name,
+                    key_description,
+                    keyslot,
+                    CryptReencryptFlags::new(vec![flag]),
+                ),
+            CryptCredential::VolumeKeys(volume_keys) => {
+                self.init_by_volume_keys(name, volume_keys, flag)
+            }
+            CryptCredential::Token(token) => self.init_by_token(name, token, flag),
+        }
+    }
+
+    fn init_by_passphrase(
+        &mut self,
+        name: Option<&str>,
+        passphrase: &[u8],
+        keyslot: Option<c_int>,
+        flag: CryptReencryptFlag,
+    ) -> Result<c_int, LibcryptErr> {
+        self.reference
+            .reencrypt_handle()
+            .reencrypt_init_from_metadata_by_passphrase(
+                name,
+                passphrase,
+                keyslot,
+                CryptReencryptFlags::new(vec![flag]),
+            )
+    }
+
+    fn init_by_token(
+        &mut self,
+        name: Option<&str>,
+        token: c_int,
+        flag: CryptReencryptFlag,
+    ) -> Result<c_int, LibcryptErr> {
+        match resolve_token(self.reference, token)? {
+            ResolvedToken::Keyring {
+                key_description,
+                keyslot,
+            } => self
+                .reference
+                .reencrypt_handle()
+                .reencrypt_init_from_metadata_by_keyring(
+                    name,
+                    &key_description,
+                    keyslot,
+                    CryptReencryptFlags::new(vec![flag]),
+                ),
+            ResolvedToken::Passphrase {
+                passphrase,
+                keyslot,
+            } => self.init_by_passphrase(name, &passphrase, keyslot, flag),
+        }
+    }
+
+    /// libcryptsetup can only initialize reencryption from a keyslot, so a temporary keyslot
+    /// with a random passphrase is added for each volume key and destroyed afterwards. The
+    /// keyslots are assigned to a token of type `TEMPORARY_TOKEN_TYPE` as they are added, so
+    /// that keyslots left behind by a crash are removed by the next call to `init`. A crash
+    /// between adding a keyslot and assigning it leaves one unmarked keyslot that nobody
+    /// knows the passphrase of.
+    fn init_by_volume_keys(
+        &mut self,
+        name: Option<&str>,
+        volume_keys: &[&[u8]],
+        flag: CryptReencryptFlag,
+    ) -> Result<c_int, LibcryptErr> {
+        let mut passphrase = Zeroizing::new(vec![0u8; TEMPORARY_PASSPHRASE_BYTES]);
+        openssl::rand::rand_bytes(&mut passphrase).map_err(LibcryptErr::OpensslError)?;
+
+        let marker = self.reference.token_json_set(
+            None,
+            &serde_json::json!({"type": TEMPORARY_TOKEN_TYPE, "keyslots": []}),
+        )?;
+        let previous_pbkdf = self.reference.settings_handle().get_pbkdf_type()?;
+        self.reference
+            .settings_handle()
+            .set_pbkdf_type(&high_entropy_pbkdf())?;
+        let mut result = Ok(0);
+        for volume_key in volume_keys.iter() {
+            if let Err(e) = self
+                .reference
+                .keyslot_handle(None)
+                .add_by_key(
+                    Some(volume_key),
+                    &passphrase,
+                    CryptVolumeKeyFlags::new(vec![CryptVolumeKeyFlag::DigestReuse]),
+                )
+                .and_then(|keyslot| self.reference.token_assign_keyslot(marker, keyslot))
+            {
+                result = Err(e);
+                break;
+            }
+        }
+        let pbkdf_result = self
+            .reference
+            .settings_handle()
+            .set_pbkdf_type(&previous_pbkdf);
+
+        if result.is_ok() {
+            result = self.init_by_passphrase(name, &passphrase, None, flag);
+        }
+        let removed = remove_temporary_keyslots(self.reference);
+        if result.is_ok() {
+            removed?;
+        }
+        result.and_then(|keyslot| pbkdf_result.map(|_| keyslot))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{activate::CryptActivateFlags, backend::SimulatedBackend};
+
+    #[test]
+    fn test_remove_temporary_keyslots() {
+        let mut backend = SimulatedBackend::new();
+        backend.format_luks2(("aes", "xts-plain64"), 64).unwrap();
+        let keyslot = backend
+            .keyslot_add_by_passphrase(None, b"", b"passphrase")
+            .unwrap();
+        let keyring = backend
+            .token_json_set(
+                None,
+                &serde_json::json!({"type": "luks2-keyring", "keyslots": []}),
+            )
+            .unwrap();
+        backend.token_assign_keyslot(keyring, keyslot).unwrap();
+        let marker = backend
+            .token_json_set(
+                None,
+                &serde_json::json!({"type": TEMPORARY_TOKEN_TYPE, "keyslots": []}),
+            )
+            .unwrap();
+        let mut temporary = Vec::new();
+        for _ in 0..2 {
+            let t = backend
+                .keyslot_add_by_passphrase(None, b"passphrase", b"temporary")
+                .unwrap();
+            backend.token_assign_keyslot(marker, t).unwrap();
+            temporary.push(t);
+        }
+
+        assert_eq!(remove_temporary_keyslots(&mut backend).unwrap(), temporary);
+        for t in temporary {
+            assert!(matches!(
+                backend.keyslot_status(t).unwrap(),
+                KeyslotInfo::Inactive
+            ));
+        }
+        assert!(backend.token_json_get(marker).is_err());
+        assert!(backend.token_json_get(keyring).is_ok());
+        assert_eq!(
+            backend
+                .activate_by_passphrase(None, None, b"passphrase", CryptActivateFlags::empty())
+                .unwrap(),
+            keyslot
+        );
+        assert!(remove_temporary_keyslots(&mut backend).unwrap().is_empty());
+    }
+}
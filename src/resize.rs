@@ -0,0 +1,167 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::Path;
+
+use crate::{
+    activate::{CryptActivateFlag, CryptActivateFlags},
+    credential::CryptCredential,
+    device::{device_size, CryptDevice, CryptInit},
+    err::LibcryptErr,
+    format::EncryptionFormat,
+    runtime::ActiveDevice,
+};
+
+/// Size of the sectors used by device-mapper for sizes and offsets
+const SECTOR_SIZE: u64 = 512;
+
+/// Result of a successful resize
+pub struct ResizeReport {
+    /// Geometry of the mapping before the resize
+    pub before: ActiveDevice,
+    /// Geometry of the mapping after the resize
+    pub after: ActiveDevice,
+    /// Largest size in bytes the mapping could be resized to
+    pub max_size: u64,
+    /// Encryption sector size in bytes
+    pub sector_size: u64,
+}
+
+/// Handle for resizing active mappings
+pub struct CryptResize;
+
+impl CryptResize {
+    /// Get the largest size in bytes the active mapping `name` can be resized to
+    ///
+    /// This is the size of the backing device after the data offset, rounded down to the
+    /// encryption sector size.
+    pub fn max_size(name: &str, header: Option<&Path>) -> Result<u64, LibcryptErr> {
+        let mut device = CryptInit::init_by_name_and_header(name, header)?;
+        max_size(&mut device)
+    }
+
+    /// Resize the active mapping `name` to `new_size` bytes, or to the largest possible
+    /// size if `new_size` is `None`
+    ///
+    /// `header` is the detached header of the mapping, if any. `new_size` must be a
+    /// multiple of the encryption sector size. LUKS2 mappings whose volume key is kept in
+    /// the kernel keyring need the key to be loaded again before resizing, which requires
+    /// `credential`; it is not used for other mappings.
+    pub fn resize(
+        name: &str,
+        header: Option<&Path>,
+        new_size: Option<u64>,
+        credential: Option<&CryptCredential>,
+    ) -> Result<ResizeReport, LibcryptErr> {
+        let mut device = CryptInit::init_by_name_and_header(name, header)?;
+        let sector_size = u64::from(device.status_handle().get_sector_size() as u32);
+        let max_size = max_size(&mut device)?;
+        let size = match new_size {
+            Some(size) => {
+                check_size(size, sector_size, max_size)?;
+                size
+            }
+            None => max_size,
+        };
+
+        let before = device.runtime_handle(name).get_active_device()?;
+        let flags: u32 = device
+            .runtime_handle(name)
+            .get_active_device()?
+            .flags
+            .into();
+        let keyring_key: u32 = CryptActivateFlag::KeyringKey.into();
+        let is_luks2 = matches!(
+            device.format_handle().get_type(),
+            Ok(EncryptionFormat::Luks2)
+        );
+        if is_luks2 && flags & keyring_key != 0 {
+            match credential {
+                Some(credential) => load_volume_key(&mut device, credential)?,
+                None => {
+                    return Err(LibcryptErr::Other(format!(
+                        "The volume key of {} is in the kernel keyring, a credential is \
+                         required to resize it",
+                        name
+                    )))
+                }
+            }
+        }
+
+        device.context_handle().resize(name, size / SECTOR_SIZE)?;
+        let after = device.runtime_handle(name).get_active_device()?;
+        Ok(ResizeReport {
+            before,
+            after,
+            max_size,
+            sector_size,
+        })
+    }
+}
+
+fn max_size(device: &mut CryptDevice) -> Result<u64, LibcryptErr> {
+    let sector_size = u64::from(device.status_handle().get_sector_size() as u32);
+    let offset = device.status_handle().get_data_offset() * SECTOR_SIZE;
+    let data_path = device.status_handle().get_device_path()?.to_path_buf();
+    let size = device_size(&data_path)?;
+    if size <= offset {
+        return Err(LibcryptErr::Other(format!(
+            "Device {} is smaller than the data offset",
+            data_path.display()
+        )));
+    }
+    Ok(align_down(size - offset, sector_size))
+}
+
+fn align_down(size: u64, sector_size: u64) -> u64 {
+    size / sector_size * sector_size
+}
+
+fn check_size(size: u64, sector_size: u64, max_size: u64) -> Result<(), LibcryptErr> {
+    if size == 0 {
+        Err(LibcryptErr::Other("Size must not be 0".to_string()))
+    } else if size % sector_size != 0 {
+        Err(LibcryptErr::Other(format!(
+            "Size {} is not aligned to the encryption sector size {}",
+            size, sector_size
+        )))
+    } else if size > max_size {
+        Err(LibcryptErr::Other(format!(
+            "Size {} exceeds the maximum size {}",
+            size, max_size
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Load the volume key into the kernel keyring without activating a mapping
+fn load_volume_key(
+    device: &mut CryptDevice,
+    credential: &CryptCredential,
+) -> Result<(), LibcryptErr> {
+    credential
+        .activate(
+            device,
+            None,
+            CryptActivateFlags::new(vec![CryptActivateFlag::KeyringKey]),
+        )
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_size() {
+        let max_size = align_down(64 * 1024 * 1024 - 16 * 1024 * 1024 + 1000, 4096);
+        assert_eq!(max_size, 48 * 1024 * 1024);
+        assert!(check_size(0, 4096, max_size).is_err());
+        assert!(check_size(4096 + 512, 4096, max_size).is_err());
+        assert!(check_size(max_size + 4096, 4096, max_size).is_err());
+        assert!(check_size(max_size, 4096, max_size).is_ok());
+        assert!(check_size(4096 + 512, 512, max_size).is_ok());
+    }
+}
@@ -32,8 +32,17 @@ pub enum CryptKdf {
 }
 
 impl CryptKdf {
+    /// Name of the key derivation function as used in LUKS2 metadata
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            CryptKdf::Pbkdf2 => "pbkdf2",
+            CryptKdf::Argon2I => "argon2i",
+            CryptKdf::Argon2Id => "argon2id",
+        }
+    }
+
     /// Convert to a `char *` for C
-    fn as_ptr(&self) -> *const c_char {
+    pub(crate) fn as_ptr(&self) -> *const c_char {
         match *self {
             CryptKdf::Pbkdf2 => libcryptsetup_rs_sys::CRYPT_KDF_PBKDF2.as_ptr() as *const c_char,
             CryptKdf::Argon2I => libcryptsetup_rs_sys::CRYPT_KDF_ARGON2I.as_ptr() as *const c_char,
@@ -96,6 +105,23 @@ pub struct CryptPbkdfType {
     pub flags: CryptPbkdfFlags,
 }
 
+/// PBKDF for keyslots whose passphrase is a random key
+///
+/// Such passphrases have full key entropy, so a memory hard or slow key derivation adds
+/// nothing but unlock latency. This matches the minimal PBKDF systemd uses for recovery
+/// keys.
+pub(crate) fn high_entropy_pbkdf() -> CryptPbkdfType {
+    CryptPbkdfType {
+        type_: CryptKdf::Pbkdf2,
+        hash: "sha512".to_string(),
+        time_ms: 0,
+        iterations: 1000,
+        max_memory_kb: 0,
+        parallel_threads: 0,
+        flags: CryptPbkdfFlags::new(vec![CryptPbkdfFlag::NoBenchmark]),
+    }
+}
+
 impl TryFrom<libcryptsetup_rs_sys::crypt_pbkdf_type> for CryptPbkdfType {
     type Error = LibcryptErr;
 
@@ -135,6 +161,8 @@ impl<'a> TryFrom<&'a libcryptsetup_rs_sys::crypt_pbkdf_type> for CryptPbkdfType
 pub struct CryptPbkdfTypeRef<'a> {
     /// Field containing a `crypt_pbkdf_type` that contains pointers valid for the supplied struct lifetime
     pub inner: crypt_pbkdf_type,
+    #[allow(dead_code)]
+    hash_cstring: Option<CString>,
     phantomdata: PhantomData<&'a ()>,
 }
 
@@ -143,6 +171,7 @@ impl<'a> CryptPbkdfTypeRef<'a> {
     pub fn new(inner: crypt_pbkdf_type) -> Self {
         CryptPbkdfTypeRef {
             inner,
+            hash_cstring: None,
             phantomdata: PhantomData,
         }
     }
@@ -152,14 +181,10 @@ impl<'a> TryInto<CryptPbkdfTypeRef<'a>> for &'a CryptPbkdfType {
     type Error = LibcryptErr;
 
     fn try_into(self) -> Result<CryptPbkdfTypeRef<'a>, Self::Error> {
+        let hash_cstring = to_cstring!(self.hash)?;
         let inner = libcryptsetup_rs_sys::crypt_pbkdf_type {
             type_: self.type_.as_ptr(),
-            hash: {
-                let bytes = self.hash.as_bytes();
-                CString::new(bytes)
-                    .map_err(LibcryptErr::NullError)?
-                    .as_ptr()
-            },
+            hash: hash_cstring.as_ptr(),
             time_ms: self.time_ms,
             iterations: self.iterations,
             max_memory_kb: self.max_memory_kb,
@@ -168,6 +193,7 @@ impl<'a> TryInto<CryptPbkdfTypeRef<'a>> for &'a CryptPbkdfType {
         };
         Ok(CryptPbkdfTypeRef {
             inner,
+            hash_cstring: Some(hash_cstring),
             phantomdata: PhantomData,
         })
     }
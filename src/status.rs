@@ -6,6 +6,7 @@ use std::{convert::TryFrom, os::raw::c_int, path::Path, str::FromStr};
 
 use crate::{
     device::CryptDevice,
+    dump::DeviceDump,
     err::LibcryptErr,
     format::{CryptParamsIntegrity, CryptParamsVerity},
 };
@@ -47,6 +48,18 @@ impl<'a> CryptDeviceStatus<'a> {
         errno!(unsafe { libcryptsetup_rs_sys::crypt_dump(self.reference.as_ptr()) })
     }
 
+    /// Get structured info about the LUKS header of the device
+    ///
+    /// The header is read directly from the metadata device so that digests, segments
+    /// and persistent flags not exposed by libcryptsetup are included.
+    pub fn device_dump(&mut self) -> Result<DeviceDump, LibcryptErr> {
+        let path = match self.get_metadata_device_path()? {
+            Some(p) => p.to_path_buf(),
+            None => self.get_device_path()?.to_path_buf(),
+        };
+        DeviceDump::read(&path)
+    }
+
     /// Get cipher used by device
     pub fn get_cipher(&mut self) -> Result<String, LibcryptErr> {
         from_str_ptr_to_owned!(libcryptsetup_rs_sys::crypt_get_cipher(
@@ -69,6 +82,24 @@ impl<'a> CryptDeviceStatus<'a> {
         .and_then(|e| Uuid::from_str(e).map_err(LibcryptErr::UuidError))
     }
 
+    /// Get LUKS2 device label or `None` for formats without labels
+    pub fn get_label(&mut self) -> Result<Option<String>, LibcryptErr> {
+        let ptr = unsafe { libcryptsetup_rs_sys::crypt_get_label(self.reference.as_ptr()) };
+        if ptr.is_null() {
+            return Ok(None);
+        }
+        from_str_ptr_to_owned!(ptr).map(Some)
+    }
+
+    /// Get LUKS2 device subsystem or `None` for formats without subsystems
+    pub fn get_subsystem(&mut self) -> Result<Option<String>, LibcryptErr> {
+        let ptr = unsafe { libcryptsetup_rs_sys::crypt_get_subsystem(self.reference.as_ptr()) };
+        if ptr.is_null() {
+            return Ok(None);
+        }
+        from_str_ptr_to_owned!(ptr).map(Some)
+    }
+
     /// Get path to underlying device
     pub fn get_device_path(&mut self) -> Result<&Path, LibcryptErr> {
         from_str_ptr!(libcryptsetup_rs_sys::crypt_get_device_name(
@@ -0,0 +1,462 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::os::raw::c_int;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    backend::LibcryptBackend,
+    err::LibcryptErr,
+    keyslot::{KeyslotInfo, LUKS2_KEYSLOTS_MAX},
+    luks2_token::{list_tokens, token_keyslots, CryptTokenInfo, TokenList, LUKS2_TOKENS_MAX},
+    recovery_key::SYSTEMD_RECOVERY_TOKEN_TYPE,
+};
+
+/// Token type of TPM2 enrollments
+pub const SYSTEMD_TPM2_TOKEN_TYPE: &str = "systemd-tpm2";
+/// Token type of FIDO2 enrollments
+pub const SYSTEMD_FIDO2_TOKEN_TYPE: &str = "systemd-fido2";
+/// Token type of PKCS#11 enrollments
+pub const SYSTEMD_PKCS11_TOKEN_TYPE: &str = "systemd-pkcs11";
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+/// `systemd-tpm2` token
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SystemdTpm2Token {
+    /// Keyslots unlocked by the token
+    pub keyslots: Vec<String>,
+    /// Base64 encoded sealed key blob
+    #[serde(rename = "tpm2-blob")]
+    pub blob: String,
+    /// PCRs the key is bound to
+    #[serde(rename = "tpm2-pcrs")]
+    pub pcrs: Vec<u32>,
+    /// PCR bank such as `sha256`, `sha1` for enrollments made by older systemd versions
+    #[serde(
+        rename = "tpm2-pcr-bank",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub pcr_bank: Option<String>,
+    /// Algorithm of the primary key such as `ecc` or `rsa`
+    #[serde(
+        rename = "tpm2-primary-alg",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub primary_alg: Option<String>,
+    /// Hex encoded hash of the TPM2 policy
+    #[serde(rename = "tpm2-policy-hash")]
+    pub policy_hash: String,
+    /// Whether a PIN is required in addition to the TPM2
+    #[serde(rename = "tpm2-pin", default, skip_serializing_if = "is_false")]
+    pub pin: bool,
+    /// PCRs covered by a signed policy
+    #[serde(
+        rename = "tpm2-pubkey-pcrs",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub pubkey_pcrs: Vec<u32>,
+    /// Base64 encoded public key used to verify signed policies
+    #[serde(
+        rename = "tpm2-pubkey",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub pubkey: Option<String>,
+}
+
+/// `systemd-fido2` token
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SystemdFido2Token {
+    /// Keyslots unlocked by the token
+    pub keyslots: Vec<String>,
+    /// Base64 encoded credential ID
+    #[serde(rename = "fido2-credential")]
+    pub credential: String,
+    /// Base64 encoded salt passed to the hmac-secret extension
+    #[serde(rename = "fido2-salt")]
+    pub salt: String,
+    /// Relying party ID, `io.systemd.cryptsetup` by default
+    #[serde(rename = "fido2-rp", default, skip_serializing_if = "Option::is_none")]
+    pub relying_party: Option<String>,
+    /// Whether the client PIN is required
+    #[serde(rename = "fido2-clientPin-required", default)]
+    pub client_pin_required: bool,
+    /// Whether user presence is required
+    #[serde(rename = "fido2-up-required", default)]
+    pub user_presence_required: bool,
+    /// Whether user verification is required
+    #[serde(rename = "fido2-uv-required", default)]
+    pub user_verification_required: bool,
+}
+
+/// `systemd-pkcs11` token
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SystemdPkcs11Token {
+    /// Keyslots unlocked by the token
+    pub keyslots: Vec<String>,
+    /// PKCS#11 URI of the certificate or private key
+    #[serde(rename = "pkcs11-uri")]
+    pub uri: String,
+    /// Base64 encoded passphrase encrypted with the public key
+    #[serde(rename = "pkcs11-key")]
+    pub key: String,
+}
+
+/// `systemd-recovery` token
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SystemdRecoveryToken {
+    /// Keyslots holding the recovery key
+    pub keyslots: Vec<String>,
+}
+
+/// Token written by `systemd-cryptenroll`
+#[derive(Clone, Debug, PartialEq)]
+pub enum SystemdToken {
+    #[allow(missing_docs)]
+    Tpm2(SystemdTpm2Token),
+    #[allow(missing_docs)]
+    Fido2(SystemdFido2Token),
+    #[allow(missing_docs)]
+    Pkcs11(SystemdPkcs11Token),
+    #[allow(missing_docs)]
+    Recovery(SystemdRecoveryToken),
+}
+
+impl SystemdToken {
+    /// Parse token JSON as returned by `CryptLuks2Token::json_get`
+    ///
+    /// Returns `None` for tokens that were not written by `systemd-cryptenroll`.
+    pub fn from_json(json: &serde_json::Value) -> Result<Option<Self>, LibcryptErr> {
+        let type_ = match json.get("type").and_then(|t| t.as_str()) {
+            Some(t) => t,
+            None => return Err(LibcryptErr::Other("Token has no type".to_string())),
+        };
+        Ok(Some(match type_ {
+            SYSTEMD_TPM2_TOKEN_TYPE => SystemdToken::Tpm2(parse(json)?),
+            SYSTEMD_FIDO2_TOKEN_TYPE => SystemdToken::Fido2(parse(json)?),
+            SYSTEMD_PKCS11_TOKEN_TYPE => SystemdToken::Pkcs11(parse(json)?),
+            SYSTEMD_RECOVERY_TOKEN_TYPE => SystemdToken::Recovery(parse(json)?),
+            _ => return Ok(None),
+        }))
+    }
+
+    /// Convert the token to JSON suitable for `CryptLuks2Token::json_set`
+    pub fn to_json(&self) -> Result<serde_json::Value, LibcryptErr> {
+        let (type_, value) = match *self {
+            SystemdToken::Tpm2(ref t) => (SYSTEMD_TPM2_TOKEN_TYPE, serde_json::to_value(t)),
+            SystemdToken::Fido2(ref t) => (SYSTEMD_FIDO2_TOKEN_TYPE, serde_json::to_value(t)),
+            SystemdToken::Pkcs11(ref t) => (SYSTEMD_PKCS11_TOKEN_TYPE, serde_json::to_value(t)),
+            SystemdToken::Recovery(ref t) => (SYSTEMD_RECOVERY_TOKEN_TYPE, serde_json::to_value(t)),
+        };
+        let mut value = value.map_err(LibcryptErr::JsonError)?;
+        if let Some(map) = value.as_object_mut() {
+            map.insert("type".to_string(), serde_json::Value::from(type_));
+        }
+        Ok(value)
+    }
+
+    /// Get the token type
+    pub fn type_(&self) -> &'static str {
+        match *self {
+            SystemdToken::Tpm2(_) => SYSTEMD_TPM2_TOKEN_TYPE,
+            SystemdToken::Fido2(_) => SYSTEMD_FIDO2_TOKEN_TYPE,
+            SystemdToken::Pkcs11(_) => SYSTEMD_PKCS11_TOKEN_TYPE,
+            SystemdToken::Recovery(_) => SYSTEMD_RECOVERY_TOKEN_TYPE,
+        }
+    }
+
+    /// Get the keyslots unlocked by the token
+    pub fn keyslots(&self) -> Result<Vec<c_int>, LibcryptErr> {
+        let keyslots = match *self {
+            SystemdToken::Tpm2(ref t) => &t.keyslots,
+            SystemdToken::Fido2(ref t) => &t.keyslots,
+            SystemdToken::Pkcs11(ref t) => &t.keyslots,
+            SystemdToken::Recovery(ref t) => &t.keyslots,
+        };
+        keyslots
+            .iter()
+            .map(|k| {
+                k.parse::<c_int>()
+                    .map_err(|_| LibcryptErr::Other(format!("Invalid keyslot {}", k)))
+            })
+            .collect()
+    }
+}
+
+fn parse<T: DeserializeOwned>(json: &serde_json::Value) -> Result<T, LibcryptErr> {
+    serde_json::from_value(json.clone()).map_err(LibcryptErr::JsonError)
+}
+
+/// Handle for tokens enrolled by `systemd-cryptenroll`
+pub struct CryptSystemdTokens;
+
+impl CryptSystemdTokens {
+    /// List the systemd tokens of a device with a loaded LUKS2 header
+    ///
+    /// Tokens of other types are skipped. Systemd tokens that cannot be parsed, for example
+    /// because they were written by a newer systemd version, are skipped and reported in
+    /// `TokenList::malformed`.
+    pub fn list<B: LibcryptBackend>(
+        device: &mut B,
+    ) -> Result<TokenList<SystemdToken>, LibcryptErr> {
+        list_tokens(
+            device,
+            &[
+                SYSTEMD_TPM2_TOKEN_TYPE,
+                SYSTEMD_FIDO2_TOKEN_TYPE,
+                SYSTEMD_PKCS11_TOKEN_TYPE,
+                SYSTEMD_RECOVERY_TOKEN_TYPE,
+            ],
+            |json| {
+                SystemdToken::from_json(json)?
+                    .ok_or_else(|| LibcryptErr::Other("Token is not a systemd token".to_string()))
+            },
+        )
+    }
+
+    /// Remove a systemd token together with the keyslots it unlocks
+    ///
+    /// Malformed systemd tokens can be removed as well, in which case the keyslots are
+    /// taken from the generic `keyslots` field of the token. Keyslots that are also
+    /// referenced by another token are kept. The removal is refused if it would leave the
+    /// device without an active keyslot. Returns the destroyed keyslots.
+    pub fn remove<B: LibcryptBackend>(
+        device: &mut B,
+        token: c_int,
+    ) -> Result<Vec<c_int>, LibcryptErr> {
+        let list = Self::list(device)?;
+        let keyslots = match list.tokens.iter().find(|(t, _)| *t == token) {
+            Some((_, systemd_token)) => systemd_token.keyslots()?,
+            None if list.malformed.iter().any(|(t, _)| *t == token) => {
+                token_keyslots(&device.token_json_get(token)?)
+            }
+            None => {
+                return Err(LibcryptErr::Other(format!(
+                    "Token {} is not a systemd token",
+                    token
+                )))
+            }
+        };
+        let mut shared = Vec::new();
+        for other in 0..LUKS2_TOKENS_MAX {
+            if other == token {
+                continue;
+            }
+            match device.token_status(other)?.0 {
+                CryptTokenInfo::Invalid | CryptTokenInfo::Inactive => continue,
+                _ => (),
+            }
+            for keyslot in token_keyslots(&device.token_json_get(other)?) {
+                shared.push(keyslot);
+            }
+        }
+        let destroy = keyslots
+            .into_iter()
+            .filter(|k| !shared.contains(k))
+            .collect::<Vec<_>>();
+
+        let mut remaining = 0;
+        for keyslot in 0..LUKS2_KEYSLOTS_MAX {
+            if destroy.contains(&keyslot) {
+                continue;
+            }
+            match device.keyslot_status(keyslot)? {
+                KeyslotInfo::Active | KeyslotInfo::ActiveLast => remaining += 1,
+                _ => (),
+            }
+        }
+        if remaining == 0 {
+            return Err(LibcryptErr::Other(format!(
+                "Removing token {} would leave no active keyslot",
+                token
+            )));
+        }
+
+        device.token_remove(token)?;
+        for keyslot in destroy.iter() {
+            device.keyslot_destroy(*keyslot)?;
+        }
+        Ok(destroy)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::backend::SimulatedBackend;
+
+    #[test]
+    fn test_parse_tpm2() {
+        let json = serde_json::json!({
+            "type": "systemd-tpm2",
+            "keyslots": ["1"],
+            "tpm2-blob": "AJ4AIA==",
+            "tpm2-pcrs": [0, 7],
+            "tpm2-pcr-bank": "sha256",
+            "tpm2-primary-alg": "ecc",
+            "tpm2-policy-hash": "c4d6a0f1",
+            "tpm2-pin": true
+        });
+        let token = SystemdToken::from_json(&json).unwrap().unwrap();
+        match token {
+            SystemdToken::Tpm2(ref t) => {
+                assert_eq!(t.pcrs, vec![0, 7]);
+                assert_eq!(t.pcr_bank, Some("sha256".to_string()));
+                assert_eq!(t.policy_hash, "c4d6a0f1");
+                assert!(t.pin);
+                assert!(t.pubkey.is_none());
+            }
+            _ => panic!("Expected a TPM2 token"),
+        }
+        assert_eq!(token.keyslots().unwrap(), vec![1]);
+        assert_eq!(token.to_json().unwrap(), json);
+    }
+
+    #[test]
+    fn test_parse_fido2_pkcs11_recovery() {
+        let fido2 = serde_json::json!({
+            "type": "systemd-fido2",
+            "keyslots": ["2"],
+            "fido2-credential": "Y3JlZA==",
+            "fido2-salt": "c2FsdA==",
+            "fido2-rp": "io.systemd.cryptsetup",
+            "fido2-clientPin-required": true,
+            "fido2-up-required": true,
+            "fido2-uv-required": false
+        });
+        match SystemdToken::from_json(&fido2).unwrap() {
+            Some(SystemdToken::Fido2(t)) => {
+                assert_eq!(t.credential, "Y3JlZA==");
+                assert_eq!(t.relying_party, Some("io.systemd.cryptsetup".to_string()));
+                assert!(t.client_pin_required && !t.user_verification_required);
+            }
+            _ => panic!("Expected a FIDO2 token"),
+        }
+
+        let pkcs11 = serde_json::json!({
+            "type": "systemd-pkcs11",
+            "keyslots": ["3"],
+            "pkcs11-uri": "pkcs11:token=YubiKey;id=%02",
+            "pkcs11-key": "a2V5"
+        });
+        match SystemdToken::from_json(&pkcs11).unwrap() {
+            Some(SystemdToken::Pkcs11(t)) => assert_eq!(t.uri, "pkcs11:token=YubiKey;id=%02"),
+            _ => panic!("Expected a PKCS#11 token"),
+        }
+
+        let recovery = serde_json::json!({"type": "systemd-recovery", "keyslots": ["4"]});
+        let token = SystemdToken::from_json(&recovery).unwrap().unwrap();
+        assert_eq!(token.type_(), SYSTEMD_RECOVERY_TOKEN_TYPE);
+        assert_eq!(token.keyslots().unwrap(), vec![4]);
+
+        let keyring = serde_json::json!({"type": "luks2-keyring", "keyslots": ["0"]});
+        assert_eq!(SystemdToken::from_json(&keyring).unwrap(), None);
+        assert_eq!(token_keyslots(&keyring), vec![0]);
+
+        let invalid = serde_json::json!({"type": "systemd-pkcs11", "keyslots": ["3"]});
+        assert!(SystemdToken::from_json(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_list_remove() {
+        let mut backend = SimulatedBackend::new();
+        backend.format_luks2(("aes", "xts-plain64"), 64).unwrap();
+        backend
+            .keyslot_add_by_passphrase(None, b"", b"first")
+            .unwrap();
+        backend
+            .keyslot_add_by_passphrase(None, b"first", b"recovery")
+            .unwrap();
+        let recovery = |keyslot: &str| serde_json::json!({"type": SYSTEMD_RECOVERY_TOKEN_TYPE, "keyslots": [keyslot]});
+        let first = backend.token_json_set(None, &recovery("1")).unwrap();
+        let second = backend.token_json_set(None, &recovery("0")).unwrap();
+        let keyring = backend
+            .token_json_set(
+                None,
+                &serde_json::json!({"type": "luks2-keyring", "keyslots": ["1"]}),
+            )
+            .unwrap();
+
+        let tokens = CryptSystemdTokens::list(&mut backend).unwrap().tokens;
+        assert_eq!(
+            tokens.iter().map(|(t, _)| *t).collect::<Vec<_>>(),
+            vec![first, second]
+        );
+        assert!(CryptSystemdTokens::remove(&mut backend, keyring).is_err());
+
+        assert_eq!(
+            CryptSystemdTokens::remove(&mut backend, second).unwrap(),
+            vec![0]
+        );
+        assert!(matches!(
+            backend.keyslot_status(0).unwrap(),
+            KeyslotInfo::Inactive
+        ));
+        // Keyslot 1 is still referenced by the keyring token
+        assert_eq!(
+            CryptSystemdTokens::remove(&mut backend, first).unwrap(),
+            Vec::<c_int>::new()
+        );
+
+        backend.token_remove(keyring).unwrap();
+        let last = backend.token_json_set(None, &recovery("1")).unwrap();
+        assert!(CryptSystemdTokens::remove(&mut backend, last).is_err());
+        assert_eq!(
+            CryptSystemdTokens::list(&mut backend).unwrap().tokens.len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_list_remove_malformed() {
+        let mut backend = SimulatedBackend::new();
+        backend.format_luks2(("aes", "xts-plain64"), 64).unwrap();
+        backend
+            .keyslot_add_by_passphrase(None, b"", b"first")
+            .unwrap();
+        backend
+            .keyslot_add_by_passphrase(None, b"first", b"tpm2")
+            .unwrap();
+        let recovery = backend
+            .token_json_set(
+                None,
+                &serde_json::json!({"type": SYSTEMD_RECOVERY_TOKEN_TYPE, "keyslots": ["0"]}),
+            )
+            .unwrap();
+        // A TPM2 token without the required blob and policy hash
+        let malformed = backend
+            .token_json_set(
+                None,
+                &serde_json::json!({"type": SYSTEMD_TPM2_TOKEN_TYPE, "keyslots": ["1"]}),
+            )
+            .unwrap();
+
+        let list = CryptSystemdTokens::list(&mut backend).unwrap();
+        assert_eq!(
+            list.tokens.iter().map(|(t, _)| *t).collect::<Vec<_>>(),
+            vec![recovery]
+        );
+        assert_eq!(
+            list.malformed.iter().map(|(t, _)| *t).collect::<Vec<_>>(),
+            vec![malformed]
+        );
+
+        assert_eq!(
+            CryptSystemdTokens::remove(&mut backend, malformed).unwrap(),
+            vec![1]
+        );
+        assert!(CryptSystemdTokens::list(&mut backend)
+            .unwrap()
+            .malformed
+            .is_empty());
+    }
+}
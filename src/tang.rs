@@ -0,0 +1,302 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Client side of the Tang protocol
+//!
+//! Binding encrypts with ECDH-ES against the exchange key of the server. Recovery uses
+//! the McCallum-Relyea exchange: the ephemeral public key of the JWE is blinded with a
+//! fresh client key before it is sent to the server, so the server never learns the
+//! shared secret and the client never learns the server key.
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use openssl::hash::MessageDigest;
+
+use crate::{
+    clevis::ClevisJwe,
+    err::LibcryptErr,
+    jose::{self, Jwk},
+};
+
+/// Timeout for connecting to and exchanging data with a Tang server
+const TANG_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Send an HTTP/1.1 request to a plain HTTP URL and return the response body
+fn http_request(
+    url: &str,
+    method: &str,
+    body: Option<(&str, &[u8])>,
+) -> Result<Vec<u8>, LibcryptErr> {
+    let rest = url.strip_prefix("http://").ok_or_else(|| {
+        LibcryptErr::Other(format!("Only http:// Tang URLs are supported: {}", url))
+    })?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let address = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+
+    let mut stream = TcpStream::connect(&address).map_err(LibcryptErr::IOError)?;
+    stream
+        .set_read_timeout(Some(TANG_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(TANG_TIMEOUT)))
+        .map_err(LibcryptErr::IOError)?;
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+        method, path, authority
+    )
+    .into_bytes();
+    match body {
+        Some((content_type, body)) => {
+            request.extend_from_slice(
+                format!(
+                    "Content-Type: {}\r\nContent-Length: {}\r\n\r\n",
+                    content_type,
+                    body.len()
+                )
+                .as_bytes(),
+            );
+            request.extend_from_slice(body);
+        }
+        None => request.extend_from_slice(b"\r\n"),
+    }
+    stream.write_all(&request).map_err(LibcryptErr::IOError)?;
+
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .map_err(LibcryptErr::IOError)?;
+    parse_response(&response)
+}
+
+/// Parse an HTTP/1.x response read until the connection was closed
+fn parse_response(response: &[u8]) -> Result<Vec<u8>, LibcryptErr> {
+    let invalid = || LibcryptErr::Other("Invalid HTTP response from Tang server".to_string());
+    let header_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(invalid)?;
+    let head = std::str::from_utf8(&response[..header_end]).map_err(|_| invalid())?;
+    let body = &response[header_end + 4..];
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(invalid)?;
+    if status != 200 {
+        return Err(LibcryptErr::Other(format!(
+            "Tang server responded with status {}",
+            status
+        )));
+    }
+
+    let mut content_length = None;
+    let mut chunked = false;
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim().to_lowercase();
+        let value = parts.next().unwrap_or("").trim();
+        if name == "content-length" {
+            content_length = Some(value.parse::<usize>().map_err(|_| invalid())?);
+        } else if name == "transfer-encoding" && value.to_lowercase().contains("chunked") {
+            chunked = true;
+        }
+    }
+    if chunked {
+        decode_chunked(body).ok_or_else(invalid)
+    } else {
+        match content_length {
+            Some(len) if len <= body.len() => Ok(body[..len].to_vec()),
+            Some(_) => Err(invalid()),
+            None => Ok(body.to_vec()),
+        }
+    }
+}
+
+fn decode_chunked(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|w| w == b"\r\n")?;
+        let size_field = std::str::from_utf8(&body[..line_end]).ok()?;
+        let size = usize::from_str_radix(size_field.split(';').next()?.trim(), 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(decoded);
+        }
+        if body.len() < size + 2 {
+            return None;
+        }
+        decoded.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
+
+/// Fetch the advertisement of the Tang server at `url`
+pub(crate) fn fetch_advertisement(url: &str) -> Result<serde_json::Value, LibcryptErr> {
+    let body = http_request(&format!("{}/adv", url.trim_end_matches('/')), "GET", None)?;
+    serde_json::from_slice(&body).map_err(LibcryptErr::JsonError)
+}
+
+/// Check an advertisement and select its exchange key
+///
+/// If `thumbprint` is given, one of the signing keys must have it as SHA-1 or SHA-256
+/// thumbprint.
+fn exchange_key(
+    advertisement: &serde_json::Value,
+    thumbprint: Option<&str>,
+) -> Result<Jwk, LibcryptErr> {
+    let keys = jose::verify_advertisement(advertisement)?;
+    if let Some(thp) = thumbprint {
+        if !keys
+            .iter()
+            .any(|k| k.allows("verify") && k.has_thumbprint(thp))
+        {
+            return Err(LibcryptErr::Other(format!(
+                "Advertisement is not signed by a key with thumbprint {}",
+                thp
+            )));
+        }
+    }
+    keys.into_iter()
+        .find(|k| k.allows("deriveKey") && k.alg.as_deref() == Some("ECMR"))
+        .ok_or_else(|| LibcryptErr::Other("Advertisement has no exchange key".to_string()))
+}
+
+/// Encrypt `plaintext` for the Tang server at `url`
+///
+/// `advertisement` is fetched from the server if not given. Without `thumbprint` the
+/// advertisement is trusted as long as it is correctly self-signed.
+pub(crate) fn encrypt(
+    url: &str,
+    thumbprint: Option<&str>,
+    advertisement: Option<&serde_json::Value>,
+    plaintext: &[u8],
+) -> Result<ClevisJwe, LibcryptErr> {
+    let advertisement = match advertisement {
+        Some(adv) => adv.clone(),
+        None => fetch_advertisement(url)?,
+    };
+    let server_key = exchange_key(&advertisement, thumbprint)?;
+    let group = server_key.group()?;
+    let server_public = server_key.public_key()?;
+    let ephemeral = jose::generate_key(&group)?;
+    let crv = server_key.crv.clone().unwrap_or_default();
+
+    let payload = advertisement
+        .get("payload")
+        .and_then(|p| p.as_str())
+        .ok_or_else(|| LibcryptErr::Other("Advertisement has no payload".to_string()))?;
+    let keys: serde_json::Value =
+        serde_json::from_slice(&jose::b64u_decode(payload)?).map_err(LibcryptErr::JsonError)?;
+    let header = serde_json::json!({
+        "alg": "ECDH-ES",
+        "enc": "A256GCM",
+        "kid": server_key.thumbprint(MessageDigest::sha256())?,
+        "epk": Jwk::from_point(&crv, &group, ephemeral.public_key(), Some("ECDH-ES"), None)?,
+        "clevis": {
+            "pin": "tang",
+            "tang": {
+                "url": url,
+                "adv": keys,
+            },
+        },
+    });
+    let z = jose::mul(&group, server_public.public_key(), ephemeral.private_key())?;
+    let key = jose::ecdh_es_key(&header, &group, &z)?;
+    jose::encrypt(&header, &key, plaintext)
+}
+
+/// Decrypt a JWE bound to a Tang server with the McCallum-Relyea exchange
+pub(crate) fn decrypt(jwe: &ClevisJwe) -> Result<Vec<u8>, LibcryptErr> {
+    let header = jwe.header()?;
+    let missing = |what: &str| LibcryptErr::Other(format!("Tang JWE has no {}", what));
+    let kid = header
+        .get("kid")
+        .and_then(|k| k.as_str())
+        .ok_or_else(|| missing("kid"))?;
+    let tang = header
+        .get("clevis")
+        .and_then(|c| c.get("tang"))
+        .ok_or_else(|| missing("tang configuration"))?;
+    let url = tang
+        .get("url")
+        .and_then(|u| u.as_str())
+        .ok_or_else(|| missing("url"))?;
+    let keys: Vec<Jwk> = serde_json::from_value(
+        tang.get("adv")
+            .and_then(|a| a.get("keys"))
+            .cloned()
+            .ok_or_else(|| missing("advertisement"))?,
+    )
+    .map_err(LibcryptErr::JsonError)?;
+    let server_key = keys
+        .iter()
+        .find(|k| k.allows("deriveKey") && k.has_thumbprint(kid))
+        .ok_or_else(|| missing("matching exchange key"))?;
+    let epk: Jwk =
+        serde_json::from_value(header.get("epk").cloned().ok_or_else(|| missing("epk"))?)
+            .map_err(LibcryptErr::JsonError)?;
+
+    let group = server_key.group()?;
+    let crv = server_key.crv.clone().unwrap_or_default();
+    let server_public = server_key.public_key()?;
+    let epk_public = epk.public_key()?;
+    let client = jose::generate_key(&group)?;
+
+    // X = E + cG, the server returns Y = sX = sE + csG
+    let blinded = jose::add(&group, epk_public.public_key(), client.public_key(), false)?;
+    let request = Jwk::from_point(&crv, &group, &blinded, Some("ECMR"), Some(&["deriveKey"]))?;
+    let body = http_request(
+        &format!("{}/rec/{}", url.trim_end_matches('/'), kid),
+        "POST",
+        Some((
+            "application/jwk+json",
+            &serde_json::to_vec(&request).map_err(LibcryptErr::JsonError)?,
+        )),
+    )?;
+    let response: Jwk = serde_json::from_slice(&body).map_err(LibcryptErr::JsonError)?;
+    if response.crv != server_key.crv {
+        return Err(LibcryptErr::Other(
+            "Tang server responded with a key on a different curve".to_string(),
+        ));
+    }
+    let response_public = response.public_key()?;
+
+    // Z = sE = Y - cS
+    let unblind = jose::mul(&group, server_public.public_key(), client.private_key())?;
+    let z = jose::add(&group, response_public.public_key(), &unblind, true)?;
+    let key = jose::ecdh_es_key(&header, &group, &z)?;
+    jose::decrypt(jwe, &key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_response() {
+        assert_eq!(
+            parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").unwrap(),
+            b"hello"
+        );
+        assert_eq!(
+            parse_response(
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n1\r\n!\r\n0\r\n\r\n"
+            )
+            .unwrap(),
+            b"hello!"
+        );
+        assert!(parse_response(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").is_err());
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nhello").is_err());
+    }
+}
@@ -0,0 +1,83 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::convert::TryInto;
+
+use crate::{
+    activate::{CryptActivateFlag, CryptActivateFlags},
+    device::CryptDevice,
+    err::LibcryptErr,
+    format::{CryptParamsTcrypt, CryptParamsTcryptRef, EncryptionFormat},
+};
+
+/// Header parameters of a loaded TrueCrypt or VeraCrypt container
+#[derive(Clone, Debug, PartialEq)]
+pub struct TcryptInfo {
+    /// Cipher chain such as `aes` or `serpent-aes`
+    pub cipher: String,
+    /// Cipher mode such as `xts-plain64`
+    pub cipher_mode: String,
+    /// Size of the volume key in bytes
+    pub volume_key_size: u32,
+    /// Offset of the encrypted data in 512-byte sectors
+    pub data_offset: u64,
+    /// IV offset in 512-byte sectors
+    pub iv_offset: u64,
+}
+
+/// Handle for TrueCrypt and VeraCrypt operations
+pub struct CryptTcrypt<'a> {
+    reference: &'a mut CryptDevice,
+}
+
+impl<'a> CryptTcrypt<'a> {
+    pub(crate) fn new(reference: &'a mut CryptDevice) -> Self {
+        CryptTcrypt { reference }
+    }
+
+    /// Decrypt and load the container header, returning its parameters
+    ///
+    /// The header is located using the hidden, backup and system header flags in `params`.
+    /// VeraCrypt containers additionally require the `VeraModes` flag, and a non-zero
+    /// `veracrypt_pim` restricts the key derivation to the VeraCrypt iteration counts.
+    pub fn load(&mut self, params: &CryptParamsTcrypt) -> Result<TcryptInfo, LibcryptErr> {
+        let mut params_ref: CryptParamsTcryptRef<'_> = params.try_into()?;
+        self.reference
+            .context_handle()
+            .load::<libcryptsetup_rs_sys::crypt_params_tcrypt>(
+                EncryptionFormat::Tcrypt,
+                Some(&mut params_ref.inner),
+            )?;
+        let mut status = self.reference.status_handle();
+        Ok(TcryptInfo {
+            cipher: status.get_cipher()?,
+            cipher_mode: status.get_cipher_mode()?,
+            volume_key_size: status.get_volume_key_size() as u32,
+            data_offset: status.get_data_offset(),
+            iv_offset: status.get_iv_offset(),
+        })
+    }
+
+    /// Dump the loaded header, including the PBKDF2 hash, to the log output
+    pub fn dump(&mut self) -> Result<(), LibcryptErr> {
+        self.reference.status_handle().dump()
+    }
+
+    /// Activate the loaded container using the volume key decrypted from the header
+    ///
+    /// Hidden volume protection is not supported, so writing to the outer volume of a
+    /// container with a hidden volume can overwrite the hidden volume.
+    pub fn activate(&mut self, name: &str, readonly: bool) -> Result<(), LibcryptErr> {
+        let flags = if readonly {
+            vec![CryptActivateFlag::Readonly]
+        } else {
+            Vec::new()
+        };
+        self.reference.activate_handle().activate_by_volume_key(
+            Some(name),
+            None,
+            CryptActivateFlags::new(flags),
+        )
+    }
+}
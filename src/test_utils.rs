@@ -0,0 +1,469 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Loop device fixtures for integration tests of code built on this crate
+//!
+//! All fixtures require root. Loop devices, backing files and device mapper mappings
+//! registered with a fixture are torn down when the fixture is dropped, including
+//! during unwinding after a panic. Setting `DO_CLEANUP=false` keeps them for debugging.
+
+use std::{
+    convert::TryInto,
+    env,
+    fs::{copy, remove_file, File, OpenOptions},
+    io::{self, Write},
+    os::{raw::c_int, unix::io::AsRawFd},
+    path::{Path, PathBuf},
+};
+
+use loopdev::{LoopControl, LoopDevice};
+use rand::random;
+
+use crate::{
+    activate::{CryptActivateFlags, CryptDeactivateFlag, CryptDeactivateFlags},
+    device::{CryptDevice, CryptInit},
+    err::LibcryptErr,
+    format::{
+        CryptParamsIntegrity, CryptParamsIntegrityRef, CryptParamsVerity, CryptParamsVerityRef,
+        CryptVerityFlag, CryptVerityFlags, EncryptionFormat,
+    },
+    keyslot::CryptVolumeKeyFlags,
+    Either,
+};
+
+/// `LOOP_SET_BLOCK_SIZE` ioctl request number
+const LOOP_SET_BLOCK_SIZE: libc::c_ulong = 0x4C09;
+
+/// Hash algorithm of the verity fixture
+const VERITY_HASH_NAME: &str = "sha256";
+
+/// Contents of the backing file of a test loop device
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BackingFill {
+    /// Sparse file that reads as zeros without allocating space
+    Sparse,
+    /// File filled with zeros
+    Zeros,
+    /// File filled with random data
+    Random,
+}
+
+/// Options for creating a test loop device
+pub struct LoopDeviceOptions {
+    /// Size of the device in bytes; must be a multiple of 4096
+    pub size: u64,
+    /// Contents of the backing file
+    pub fill: BackingFill,
+    /// Logical sector size of the loop device or `None` for the kernel default of 512
+    pub sector_size: Option<u32>,
+    /// Directory in which to create the backing file
+    pub directory: PathBuf,
+    /// Detach the device and remove the backing file on drop
+    pub cleanup: bool,
+}
+
+impl Default for LoopDeviceOptions {
+    /// 64 MiB sparse device in `$TEST_DIR` (default `/tmp`), with cleanup controlled by
+    /// `DO_CLEANUP`
+    fn default() -> Self {
+        LoopDeviceOptions {
+            size: 64 * 1024 * 1024,
+            fill: BackingFill::Sparse,
+            sector_size: None,
+            directory: PathBuf::from(env::var("TEST_DIR").unwrap_or_else(|_| "/tmp".to_string())),
+            cleanup: env::var("DO_CLEANUP")
+                .ok()
+                .and_then(|v| v.parse::<bool>().ok())
+                .unwrap_or(true),
+        }
+    }
+}
+
+fn backing_file_path(options: &LoopDeviceOptions) -> Result<PathBuf, io::Error> {
+    if !options.directory.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not a directory", options.directory.display()),
+        ));
+    }
+    let name = base64::encode_config(
+        &random::<[u8; 12]>(),
+        base64::Config::new(base64::CharacterSet::UrlSafe, false),
+    );
+    Ok(options.directory.join(name))
+}
+
+fn create_backing_file(options: &LoopDeviceOptions) -> Result<PathBuf, io::Error> {
+    let path = backing_file_path(options)?;
+    let mut file = File::create(&path)?;
+    match options.fill {
+        BackingFill::Sparse => file.set_len(options.size)?,
+        BackingFill::Zeros | BackingFill::Random => {
+            let mut written = 0;
+            while written < options.size {
+                let buf: Vec<u8> = if options.fill == BackingFill::Zeros {
+                    vec![0; 4096]
+                } else {
+                    (0..4096).map(|_| random::<u8>()).collect()
+                };
+                file.write_all(&buf)?;
+                written += 4096;
+            }
+        }
+    }
+    file.sync_all()?;
+    Ok(path)
+}
+
+fn check_root() -> Result<(), LibcryptErr> {
+    if nix::unistd::Uid::effective().is_root() {
+        Ok(())
+    } else {
+        Err(LibcryptErr::Other(
+            "Loop device fixtures must be run as root".to_string(),
+        ))
+    }
+}
+
+/// Loop device backed by a temporary file that is detached and removed on drop
+pub struct TestLoopDevice {
+    loop_device: LoopDevice,
+    path: PathBuf,
+    backing_file: PathBuf,
+    cleanup: bool,
+    mappings: Vec<String>,
+}
+
+impl TestLoopDevice {
+    /// Create a backing file and attach it to the next free loop device
+    pub fn new(options: &LoopDeviceOptions) -> Result<Self, LibcryptErr> {
+        check_root()?;
+        if options.size == 0 || options.size % 4096 != 0 {
+            return Err(LibcryptErr::Other(
+                "Loop device size must be a non-zero multiple of 4096".to_string(),
+            ));
+        }
+        let backing_file = create_backing_file(options).map_err(LibcryptErr::IOError)?;
+        Self::attach(backing_file, options)
+    }
+
+    /// Attach a copy of the image file `image` to the next free loop device
+    ///
+    /// The size and fill of `options` are ignored.
+    pub fn from_image(image: &Path, options: &LoopDeviceOptions) -> Result<Self, LibcryptErr> {
+        check_root()?;
+        let backing_file = backing_file_path(options).map_err(LibcryptErr::IOError)?;
+        if let Err(e) = copy(image, &backing_file) {
+            let _ = remove_file(&backing_file);
+            return Err(LibcryptErr::IOError(e));
+        }
+        Self::attach(backing_file, options)
+    }
+
+    fn attach(backing_file: PathBuf, options: &LoopDeviceOptions) -> Result<Self, LibcryptErr> {
+        let loop_device = match LoopControl::open().and_then(|c| c.next_free()) {
+            Ok(d) => d,
+            Err(e) => {
+                let _ = remove_file(&backing_file);
+                return Err(LibcryptErr::IOError(e));
+            }
+        };
+        if let Err(e) = loop_device.attach_file(&backing_file) {
+            let _ = remove_file(&backing_file);
+            return Err(LibcryptErr::IOError(e));
+        }
+        let path = match loop_device.path() {
+            Some(p) => p,
+            None => {
+                let _ = loop_device.detach();
+                let _ = remove_file(&backing_file);
+                return Err(LibcryptErr::IOError(io::Error::from(
+                    io::ErrorKind::NotFound,
+                )));
+            }
+        };
+        let device = TestLoopDevice {
+            loop_device,
+            path,
+            backing_file,
+            cleanup: options.cleanup,
+            mappings: Vec::new(),
+        };
+        if let Some(sector_size) = options.sector_size {
+            device.set_sector_size(sector_size)?;
+        }
+        Ok(device)
+    }
+
+    fn set_sector_size(&self, sector_size: u32) -> Result<(), LibcryptErr> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.path)
+            .map_err(LibcryptErr::IOError)?;
+        let ret = unsafe {
+            libc::ioctl(
+                file.as_raw_fd(),
+                LOOP_SET_BLOCK_SIZE as _,
+                libc::c_ulong::from(sector_size),
+            )
+        };
+        if ret < 0 {
+            Err(LibcryptErr::IOError(io::Error::last_os_error()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Path of the loop device
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Path of the backing file
+    pub fn backing_file(&self) -> &Path {
+        &self.backing_file
+    }
+
+    /// Register a device mapper mapping to be deactivated before the loop device is
+    /// detached
+    pub fn track_mapping(&mut self, name: &str) {
+        if !self.mappings.iter().any(|m| m == name) {
+            self.mappings.push(name.to_string());
+        }
+    }
+
+    /// Initialize a crypt device on the loop device
+    pub fn init(&self) -> Result<CryptDevice, LibcryptErr> {
+        CryptInit::init(&self.path)
+    }
+}
+
+impl Drop for TestLoopDevice {
+    fn drop(&mut self) {
+        if !self.cleanup {
+            return;
+        }
+        for name in self.mappings.drain(..).rev() {
+            if let Ok(mut device) = CryptInit::init_by_name_and_header(&name, None) {
+                let _ = device.activate_handle().deactivate(
+                    &name,
+                    CryptDeactivateFlags::new(vec![CryptDeactivateFlag::Force]),
+                );
+            }
+        }
+        let _ = self.loop_device.detach();
+        let _ = remove_file(&self.backing_file);
+    }
+}
+
+/// Loop device formatted with a LUKS header and a single passphrase keyslot
+pub struct LuksFixture {
+    /// Underlying loop device
+    pub device: TestLoopDevice,
+    /// Format of the header, either LUKS1 or LUKS2
+    pub format: EncryptionFormat,
+    /// Passphrase of `keyslot`
+    pub passphrase: Vec<u8>,
+    /// Keyslot created during formatting
+    pub keyslot: c_int,
+}
+
+impl LuksFixture {
+    fn new(
+        options: &LoopDeviceOptions,
+        format: EncryptionFormat,
+        passphrase: &[u8],
+    ) -> Result<Self, LibcryptErr> {
+        let device = TestLoopDevice::new(options)?;
+        let mut crypt_device = device.init()?;
+        crypt_device.context_handle().format::<()>(
+            format,
+            ("aes", "xts-plain64"),
+            None,
+            Either::Right(512 / 8),
+            None,
+        )?;
+        let keyslot = crypt_device.keyslot_handle(None).add_by_key(
+            None,
+            passphrase,
+            CryptVolumeKeyFlags::empty(),
+        )?;
+        Ok(LuksFixture {
+            device,
+            format,
+            passphrase: passphrase.to_vec(),
+            keyslot,
+        })
+    }
+
+    /// Create a loop device formatted as LUKS1 with `aes-xts-plain64`
+    pub fn luks1(options: &LoopDeviceOptions, passphrase: &[u8]) -> Result<Self, LibcryptErr> {
+        Self::new(options, EncryptionFormat::Luks1, passphrase)
+    }
+
+    /// Create a loop device formatted as LUKS2 with `aes-xts-plain64`
+    pub fn luks2(options: &LoopDeviceOptions, passphrase: &[u8]) -> Result<Self, LibcryptErr> {
+        Self::new(options, EncryptionFormat::Luks2, passphrase)
+    }
+
+    /// Initialize a crypt device on the fixture and load its header
+    pub fn load(&self) -> Result<CryptDevice, LibcryptErr> {
+        let mut device = self.device.init()?;
+        device.context_handle().load::<()>(self.format, None)?;
+        Ok(device)
+    }
+
+    /// Activate the device with the fixture passphrase, returning the path of the mapping
+    pub fn activate(&mut self, name: &str) -> Result<PathBuf, LibcryptErr> {
+        self.load()?.activate_handle().activate_by_passphrase(
+            Some(name),
+            Some(self.keyslot),
+            &self.passphrase,
+            CryptActivateFlags::empty(),
+        )?;
+        self.device.track_mapping(name);
+        Ok(Path::new("/dev/mapper").join(name))
+    }
+}
+
+/// Loop device formatted with dm-verity, with the hash tree stored after the data area
+pub struct VerityFixture {
+    /// Underlying loop device
+    pub device: TestLoopDevice,
+    /// Size of the data area in bytes
+    pub data_size: u64,
+    /// Root hash of the hash tree
+    pub root_hash: Vec<u8>,
+}
+
+impl VerityFixture {
+    /// Create a loop device whose first half is verity protected data and whose second
+    /// half holds the verity superblock and hash tree
+    pub fn new(options: &LoopDeviceOptions) -> Result<Self, LibcryptErr> {
+        let device = TestLoopDevice::new(options)?;
+        let data_size = options.size / 2 / 4096 * 4096;
+        let params = CryptParamsVerity {
+            hash_name: VERITY_HASH_NAME.to_string(),
+            data_device: device.path().to_path_buf(),
+            hash_device: device.path().to_path_buf(),
+            fec_device: PathBuf::new(),
+            salt: random::<[u8; 32]>().to_vec(),
+            hash_type: 1,
+            data_block_size: 4096,
+            hash_block_size: 4096,
+            data_size: data_size / 4096,
+            hash_area_offset: data_size,
+            fec_area_offset: 0,
+            fec_roots: 0,
+            flags: CryptVerityFlags::new(vec![CryptVerityFlag::CreateHash]),
+        };
+        let mut params_ref: CryptParamsVerityRef<'_> = (&params).try_into()?;
+        let mut crypt_device = device.init()?;
+        crypt_device
+            .context_handle()
+            .format::<libcryptsetup_rs_sys::crypt_params_verity>(
+                EncryptionFormat::Verity,
+                ("", ""),
+                None,
+                Either::Right(0),
+                Some(&mut params_ref.inner),
+            )?;
+        let mut root_hash = vec![0; 64];
+        let (_, size) = crypt_device.volume_key_handle().get(
+            libcryptsetup_rs_sys::CRYPT_ANY_SLOT,
+            &mut root_hash,
+            "",
+        )?;
+        root_hash.truncate(size);
+        Ok(VerityFixture {
+            device,
+            data_size,
+            root_hash,
+        })
+    }
+
+    /// Activate the verity device read-only, returning the path of the mapping
+    pub fn activate(&mut self, name: &str) -> Result<PathBuf, LibcryptErr> {
+        // The superblock is only found at the hash area offset given when loading
+        let params = CryptParamsVerity {
+            hash_name: VERITY_HASH_NAME.to_string(),
+            data_device: self.device.path().to_path_buf(),
+            hash_device: self.device.path().to_path_buf(),
+            fec_device: PathBuf::new(),
+            salt: Vec::new(),
+            hash_type: 1,
+            data_block_size: 4096,
+            hash_block_size: 4096,
+            data_size: self.data_size / 4096,
+            hash_area_offset: self.data_size,
+            fec_area_offset: 0,
+            fec_roots: 0,
+            flags: CryptVerityFlags::empty(),
+        };
+        let mut params_ref: CryptParamsVerityRef<'_> = (&params).try_into()?;
+        let mut crypt_device = self.device.init()?;
+        crypt_device
+            .context_handle()
+            .load::<libcryptsetup_rs_sys::crypt_params_verity>(
+                EncryptionFormat::Verity,
+                Some(&mut params_ref.inner),
+            )?;
+        crypt_device.activate_handle().activate_by_volume_key(
+            Some(name),
+            Some(&self.root_hash),
+            CryptActivateFlags::empty(),
+        )?;
+        self.device.track_mapping(name);
+        Ok(Path::new("/dev/mapper").join(name))
+    }
+}
+
+/// Loop device formatted with a standalone dm-integrity superblock using `crc32c`
+pub struct IntegrityFixture {
+    /// Underlying loop device
+    pub device: TestLoopDevice,
+}
+
+impl IntegrityFixture {
+    /// Create and format a loop device with dm-integrity
+    pub fn new(options: &LoopDeviceOptions) -> Result<Self, LibcryptErr> {
+        let device = TestLoopDevice::new(options)?;
+        let params = CryptParamsIntegrity {
+            tag_size: 4,
+            sector_size: options.sector_size.unwrap_or(512),
+            integrity: "crc32c".to_string(),
+            ..CryptParamsIntegrity::default()
+        };
+        let mut params_ref: CryptParamsIntegrityRef<'_> = (&params).try_into()?;
+        device
+            .init()?
+            .context_handle()
+            .format::<libcryptsetup_rs_sys::crypt_params_integrity>(
+                EncryptionFormat::Integrity,
+                ("", ""),
+                None,
+                Either::Right(0),
+                Some(&mut params_ref.inner),
+            )?;
+        Ok(IntegrityFixture { device })
+    }
+
+    /// Activate the integrity device, returning the path of the mapping
+    ///
+    /// Sectors that were never written through the mapping fail integrity checks when read.
+    pub fn activate(&mut self, name: &str) -> Result<PathBuf, LibcryptErr> {
+        let mut crypt_device = self.device.init()?;
+        crypt_device
+            .context_handle()
+            .load::<()>(EncryptionFormat::Integrity, None)?;
+        crypt_device.activate_handle().activate_by_volume_key(
+            Some(name),
+            None,
+            CryptActivateFlags::empty(),
+        )?;
+        self.device.track_mapping(name);
+        Ok(Path::new("/dev/mapper").join(name))
+    }
+}
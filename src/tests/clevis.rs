@@ -0,0 +1,41 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::{
+    activate::CryptActivateFlags,
+    clevis::{ClevisPin, ClevisPolicy, CryptClevis},
+    format::EncryptionFormat,
+    tests::{loopback, tang::TangServer},
+};
+
+pub fn test_clevis_bind_unlock() {
+    let server = TangServer::start();
+    loopback::use_luks(EncryptionFormat::Luks2, b"abadpassphrase", |fixture| {
+        let mut dev = fixture.load()?;
+
+        let pin = ClevisPin::Tang {
+            url: server.url(),
+            thumbprint: Some(server.thumbprint()),
+            advertisement: None,
+        };
+        let (keyslot, token) = CryptClevis::bind(&mut dev, b"abadpassphrase", &pin)?;
+
+        let tokens = CryptClevis::list(&mut dev)?.tokens;
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].0, token);
+        assert_eq!(tokens[0].1.keyslots()?, vec![keyslot]);
+        match tokens[0].1.jwe.policy()? {
+            ClevisPolicy::Tang { url, .. } => assert_eq!(url, server.url()),
+            _ => panic!("Expected a tang policy"),
+        }
+
+        assert_eq!(
+            CryptClevis::unlock(&mut dev, None, CryptActivateFlags::empty())?,
+            keyslot
+        );
+        Ok(())
+    })
+    .expect("Should succeed");
+    server.stop();
+}
@@ -0,0 +1,79 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use libc::c_int;
+
+use crate::{
+    device::{CryptDevice, CryptInit},
+    err::LibcryptErr,
+    format::EncryptionFormat,
+    keyslot::KeyslotPriority,
+    settings::{CryptKdf, CryptSettings},
+    tests::loopback,
+    Bool,
+};
+
+const PASSPHRASE: &[u8] = b"abadpassphrase";
+
+fn encryption(
+    dev: &mut CryptDevice,
+    keyslot: Option<c_int>,
+) -> Result<(String, crate::size_t), LibcryptErr> {
+    let mut handle = dev.keyslot_handle(keyslot);
+    let (cipher, key_size) = handle.get_encryption()?;
+    Ok((cipher.to_string(), key_size))
+}
+
+pub fn test_convert_keyslot_to_argon2id() {
+    loopback::use_luks(EncryptionFormat::Luks2, b"fixturepassphrase", |fixture| {
+        let dev_path = fixture.device.path();
+        let mut dev = fixture.load()?;
+        let pbkdf2 = CryptSettings::get_pbkdf_type_params(&CryptKdf::Pbkdf2)?;
+        dev.settings_handle().set_pbkdf_type(&pbkdf2)?;
+        dev.keyslot_handle(Some(3))
+            .add_by_passphrase(&fixture.passphrase, PASSPHRASE)?;
+        dev.keyslot_handle(Some(3))
+            .set_priority(KeyslotPriority::Prefer)?;
+        let token = dev
+            .token_handle(0)
+            .luks2_keyring_set("convert-test", true)?;
+        dev.token_handle(token).assign_keyslot(3)?;
+
+        let mut argon2id = CryptSettings::get_pbkdf_type_params(&CryptKdf::Argon2Id)?;
+        argon2id.max_memory_kb = 32 * 1024;
+        argon2id.parallel_threads = 1;
+        assert_eq!(
+            dev.keyslot_handle(None)
+                .convert(PASSPHRASE, Some(&argon2id), None)?,
+            3
+        );
+        drop(dev);
+
+        let mut dev = CryptInit::init(dev_path)?;
+        dev.context_handle()
+            .load::<()>(EncryptionFormat::Luks2, None)?;
+        match dev.keyslot_handle(Some(3)).get_pbkdf()?.type_ {
+            CryptKdf::Argon2Id => (),
+            _ => panic!("Keyslot should use argon2id"),
+        }
+        match dev.keyslot_handle(Some(3)).get_priority()? {
+            KeyslotPriority::Prefer => (),
+            _ => panic!("Keyslot priority should be preserved"),
+        }
+        assert_eq!(dev.token_handle(token).is_assigned(3)?, Bool::Yes);
+        let default = encryption(&mut dev, None)?;
+        dev.keyslot_handle(Some(3)).convert(
+            PASSPHRASE,
+            None,
+            Some(("aes-cbc-essiv:sha256", 32)),
+        )?;
+        assert_eq!(
+            encryption(&mut dev, Some(3))?,
+            ("aes-cbc-essiv:sha256".to_string(), 32)
+        );
+        assert_eq!(encryption(&mut dev, None)?, default);
+        Ok(())
+    })
+    .expect("Should succeed");
+}
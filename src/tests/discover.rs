@@ -0,0 +1,42 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::{
+    activate::{CryptActivateFlags, CryptDeactivateFlags},
+    discover::{CryptDiscovery, DiscoveredFormat},
+    format::EncryptionFormat,
+    tests::loopback,
+};
+
+pub fn test_discover_luks2() {
+    loopback::use_luks(EncryptionFormat::Luks2, b"abadpassphrase", |fixture| {
+        let dev_path = fixture.device.path();
+        let mut dev = fixture.load()?;
+        dev.context_handle()
+            .set_label(Some("discover-label"), Some("discover-subsystem"))?;
+        dev.activate_handle().activate_by_passphrase(
+            Some("test-discover"),
+            None,
+            b"abadpassphrase",
+            CryptActivateFlags::empty(),
+        )?;
+        let uuid = dev.status_handle().get_uuid()?.to_string();
+
+        let volume =
+            CryptDiscovery::find_by_uuid(&uuid, &[])?.expect("Loop device should be discovered");
+        assert_eq!(volume.path, dev_path);
+        assert_eq!(volume.format, DiscoveredFormat::Luks2);
+        assert_eq!(volume.label.as_deref(), Some("discover-label"));
+        assert_eq!(volume.subsystem.as_deref(), Some("discover-subsystem"));
+        assert_eq!(volume.active_mappings, vec!["test-discover".to_string()]);
+
+        let image =
+            CryptDiscovery::probe(fixture.device.backing_file())?.expect("Image should be probed");
+        assert_eq!(image.uuid, Some(uuid));
+
+        dev.activate_handle()
+            .deactivate("test-discover", CryptDeactivateFlags::empty())
+    })
+    .expect("Should succeed");
+}
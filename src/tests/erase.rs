@@ -0,0 +1,92 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    fs::OpenOptions,
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+use crate::{
+    device::CryptInit, dump::DeviceDump, erase::CryptErase, err::LibcryptErr,
+    format::EncryptionFormat, keyslot::KeyslotInfo, tests::loopback, wipe::CryptWipePattern,
+};
+
+/// Bytes written to unused space at the end of the keyslots area
+const REMNANT: &[u8] = b"remnant of an old keyslot";
+
+pub fn test_erase_keyslots() {
+    loopback::use_luks(EncryptionFormat::Luks2, b"abadpassphrase", |fixture| {
+        let dev_path = fixture.device.path();
+        let mut dev = fixture.load()?;
+        let first = fixture.keyslot;
+        let second = dev
+            .keyslot_handle(None)
+            .add_by_passphrase(b"abadpassphrase", b"anotherbadpassphrase")?;
+        dev.token_handle(0).luks2_keyring_set("erase-test", true)?;
+        drop(dev);
+
+        let dump = DeviceDump::read(dev_path)?;
+        let keyslots_area = (2 * dump.metadata_size, dump.keyslots_size);
+        let remnant_offset = keyslots_area.0 + keyslots_area.1 - 4096;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(dev_path)
+            .map_err(LibcryptErr::IOError)?;
+        file.seek(SeekFrom::Start(remnant_offset))
+            .and_then(|_| file.write_all(REMNANT))
+            .and_then(|_| file.sync_all())
+            .map_err(LibcryptErr::IOError)?;
+
+        let report = CryptErase::erase(dev_path, CryptWipePattern::Zero, false)?;
+        assert_eq!(report.destroyed_keyslots, vec![first, second]);
+        assert_eq!(report.removed_tokens, vec![0]);
+        assert_eq!(report.wiped_areas, vec![keyslots_area]);
+        assert_eq!(report.wiped_header_length, None);
+
+        let mut remnant = vec![0u8; REMNANT.len()];
+        file.seek(SeekFrom::Start(remnant_offset))
+            .and_then(|_| file.read_exact(&mut remnant))
+            .map_err(LibcryptErr::IOError)?;
+        assert!(remnant.iter().all(|b| *b == 0));
+
+        let mut dev = CryptInit::init(dev_path)?;
+        dev.context_handle()
+            .load::<()>(EncryptionFormat::Luks2, None)?;
+        match dev.keyslot_handle(Some(first)).status()? {
+            KeyslotInfo::Inactive => (),
+            _ => panic!("Keyslot should be inactive"),
+        }
+        assert!(dev
+            .activate_handle()
+            .activate_by_passphrase(
+                None,
+                None,
+                b"abadpassphrase",
+                crate::activate::CryptActivateFlags::empty()
+            )
+            .is_err());
+        Ok(())
+    })
+    .expect("Should succeed");
+}
+
+pub fn test_erase_header() {
+    loopback::use_luks(EncryptionFormat::Luks1, b"abadpassphrase", |fixture| {
+        let dev_path = fixture.device.path();
+        let report = CryptErase::erase(dev_path, CryptWipePattern::Zero, true)?;
+        assert_eq!(report.destroyed_keyslots, vec![fixture.keyslot]);
+        // The keyslots area of LUKS1 covers all eight keyslot areas
+        assert_eq!(report.wiped_areas.len(), 1);
+        assert!(report.wiped_header_length.is_some());
+
+        let mut dev = CryptInit::init(dev_path)?;
+        assert!(dev
+            .context_handle()
+            .load::<()>(EncryptionFormat::Luks1, None)
+            .is_err());
+        Ok(())
+    })
+    .expect("Should succeed");
+}
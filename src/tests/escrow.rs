@@ -0,0 +1,42 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::{
+    activate::CryptActivateFlags,
+    device::CryptInit,
+    escrow::{CryptEscrow, VolumeKeyEscrow},
+    format::EncryptionFormat,
+    tests::loopback,
+};
+
+const KEK: [u8; 32] = [0x5a; 32];
+
+pub fn test_escrow_round_trip() {
+    loopback::use_luks(EncryptionFormat::Luks2, b"abadpassphrase", |fixture| {
+        let dev_path = fixture.device.path();
+        let mut dev = fixture.load()?;
+        let keyslot = fixture.keyslot;
+        let json = CryptEscrow::export(&mut dev, None, b"abadpassphrase", &KEK)?.to_json()?;
+        dev.keyslot_handle(Some(keyslot)).destroy()?;
+        drop(dev);
+
+        let escrow = VolumeKeyEscrow::from_json(&json)?;
+        let mut dev = CryptInit::init(dev_path)?;
+        dev.context_handle()
+            .load::<()>(EncryptionFormat::Luks2, None)?;
+        assert!(CryptEscrow::restore(&mut dev, &escrow, &[0u8; 32], None, b"new").is_err());
+        let restored = CryptEscrow::restore(&mut dev, &escrow, &KEK, None, b"recovered")?;
+        assert_eq!(
+            dev.activate_handle().activate_by_passphrase(
+                None,
+                None,
+                b"recovered",
+                CryptActivateFlags::empty()
+            )?,
+            restored
+        );
+        Ok(())
+    })
+    .expect("Should succeed");
+}
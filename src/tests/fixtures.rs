@@ -0,0 +1,42 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::PathBuf;
+
+use crate::test_utils::{IntegrityFixture, LoopDeviceOptions, LuksFixture, VerityFixture};
+
+pub fn test_fixtures_teardown() {
+    let options = LoopDeviceOptions::default();
+    let (mapping, backing_file) = {
+        let mut fixture = LuksFixture::luks2(&options, b"abadpassphrase").expect("Should succeed");
+        let mapping = fixture
+            .activate("test-fixture-luks2")
+            .expect("Should succeed");
+        assert!(mapping.exists());
+        (mapping, fixture.device.backing_file().to_path_buf())
+    };
+    assert!(!mapping.exists());
+    assert!(!backing_file.exists());
+
+    let mut verity = VerityFixture::new(&options).expect("Should succeed");
+    assert_eq!(verity.root_hash.len(), 32);
+    verity
+        .activate("test-fixture-verity")
+        .expect("Should succeed");
+
+    let mut integrity = IntegrityFixture::new(&options).expect("Should succeed");
+    integrity
+        .activate("test-fixture-integrity")
+        .expect("Should succeed");
+
+    let result = std::panic::catch_unwind(|| {
+        let mut fixture = LuksFixture::luks1(&options, b"abadpassphrase").expect("Should succeed");
+        fixture
+            .activate("test-fixture-luks1")
+            .expect("Should succeed");
+        panic!("Fixture must be torn down during unwinding");
+    });
+    assert!(result.is_err());
+    assert!(!PathBuf::from("/dev/mapper/test-fixture-luks1").exists());
+}
@@ -0,0 +1,140 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    env,
+    fs::{remove_file, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    activate::{CryptActivateFlags, CryptDeactivateFlags},
+    err::LibcryptErr,
+    format::{CryptParamsIntegrity, CryptParamsLuks2},
+    luks2_inplace::{CryptInPlace, InPlaceHeader, MIN_DATA_SHIFT},
+    settings::{CryptSettings, LuksType},
+    tests::loopback,
+};
+
+const PASSPHRASE: &str = "abadpassphrase";
+const MARKER: &[u8] = b"plaintext written before encryption";
+
+fn luks2_params() -> Result<CryptParamsLuks2, LibcryptErr> {
+    Ok(CryptParamsLuks2 {
+        pbkdf: CryptSettings::get_pbkdf_default(&LuksType::Luks2)?,
+        integrity: None,
+        integrity_params: CryptParamsIntegrity::default(),
+        data_alignment: 0,
+        data_device: PathBuf::new(),
+        sector_size: 512,
+        label: String::new(),
+        subsystem: String::new(),
+    })
+}
+
+fn write_marker(path: &Path) -> Result<(), LibcryptErr> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .map_err(LibcryptErr::IOError)?;
+    file.write_all(MARKER).map_err(LibcryptErr::IOError)?;
+    file.sync_all().map_err(LibcryptErr::IOError)
+}
+
+fn read_marker(path: &Path) -> Result<Vec<u8>, LibcryptErr> {
+    let mut buffer = vec![0; MARKER.len()];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut buffer))
+        .map_err(LibcryptErr::IOError)?;
+    Ok(buffer)
+}
+
+pub fn test_inplace_detached_round_trip() {
+    loopback::use_loopback(
+        64 * 1024 * 1024,
+        super::format_with_zeros(),
+        super::do_cleanup(),
+        |dev_path, _file_path| {
+            let header_path = env::temp_dir().join("libcryptsetup-rs-inplace-header");
+            write_marker(dev_path)?;
+
+            let mut dev = CryptInPlace::encrypt(
+                dev_path,
+                InPlaceHeader::Detached(&header_path),
+                ("aes", "xts-plain64"),
+                512 / 8,
+                PASSPHRASE.as_bytes(),
+                luks2_params()?,
+                None,
+            )?;
+            assert_ne!(read_marker(dev_path)?, MARKER);
+
+            let name = "test-inplace-detached";
+            dev.activate_handle().activate_by_passphrase(
+                Some(name),
+                None,
+                PASSPHRASE.as_bytes(),
+                CryptActivateFlags::empty(),
+            )?;
+            let mapped = read_marker(&Path::new("/dev/mapper").join(name));
+            dev.activate_handle()
+                .deactivate(name, CryptDeactivateFlags::empty())?;
+            assert_eq!(mapped?, MARKER);
+
+            CryptInPlace::decrypt(
+                dev_path,
+                &header_path,
+                None,
+                PASSPHRASE.as_bytes(),
+                None,
+                None,
+            )?;
+            assert_eq!(read_marker(dev_path)?, MARKER);
+            remove_file(&header_path).map_err(LibcryptErr::IOError)
+        },
+    )
+    .expect("Should succeed");
+}
+
+pub fn test_inplace_encrypt_data_shift() {
+    loopback::use_loopback(
+        128 * 1024 * 1024,
+        super::format_with_zeros(),
+        super::do_cleanup(),
+        |dev_path, _file_path| {
+            write_marker(dev_path)?;
+
+            let mut dev = CryptInPlace::encrypt(
+                dev_path,
+                InPlaceHeader::Device {
+                    data_shift: MIN_DATA_SHIFT,
+                },
+                ("aes", "xts-plain64"),
+                512 / 8,
+                PASSPHRASE.as_bytes(),
+                luks2_params()?,
+                None,
+            )?;
+            assert_eq!(
+                dev.status_handle().get_data_offset(),
+                MIN_DATA_SHIFT / 2 / 512
+            );
+
+            let name = "test-inplace-data-shift";
+            dev.activate_handle().activate_by_passphrase(
+                Some(name),
+                None,
+                PASSPHRASE.as_bytes(),
+                CryptActivateFlags::empty(),
+            )?;
+            let mapped = read_marker(&Path::new("/dev/mapper").join(name));
+            dev.activate_handle()
+                .deactivate(name, CryptDeactivateFlags::empty())?;
+            assert_eq!(mapped?, MARKER);
+            Ok(())
+        },
+    )
+    .expect("Should succeed");
+}
@@ -0,0 +1,60 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::{
+    activate::CryptDeactivateFlags,
+    device::CryptInit,
+    format::CryptParamsIntegrity,
+    integrity::{IntegrityActivation, IntegrityJournalMode, IntegrityMonitor},
+    tests::loopback,
+};
+
+fn integrity_params() -> CryptParamsIntegrity {
+    CryptParamsIntegrity {
+        tag_size: 4,
+        sector_size: 512,
+        integrity: "crc32c".to_string(),
+        ..CryptParamsIntegrity::default()
+    }
+}
+
+pub fn test_integrity_format_activate() {
+    loopback::use_loopback(
+        64 * 1024 * 1024,
+        super::format_with_zeros(),
+        super::do_cleanup(),
+        |dev_path, _file_path| {
+            let device_name = "test-integrity";
+            let params = integrity_params();
+
+            let mut dev = CryptInit::init(dev_path)?;
+            dev.integrity_handle().format(&params, None)?;
+
+            let mut dev = CryptInit::init(dev_path)?;
+            let mut integrity = dev.integrity_handle();
+            integrity.load(None)?;
+            integrity.activate(
+                device_name,
+                None,
+                &IntegrityActivation {
+                    mode: IntegrityJournalMode::Bitmap,
+                    ..Default::default()
+                },
+            )?;
+
+            let mut monitor = IntegrityMonitor::new(vec![device_name]);
+            let changes = monitor.poll();
+            let failures = integrity.failures(device_name);
+
+            if super::do_cleanup() {
+                integrity.deactivate(device_name, CryptDeactivateFlags::empty())?;
+            }
+
+            assert_eq!(changes?, Vec::new());
+            assert_eq!(failures?, 0);
+            Ok(())
+        },
+    )
+    .expect("Should succeed");
+}
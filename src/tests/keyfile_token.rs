@@ -0,0 +1,62 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{fs::remove_file, path::PathBuf};
+
+use rand::random;
+
+use crate::{
+    activate::{CryptActivateFlags, CryptDeactivateFlags},
+    format::EncryptionFormat,
+    keyfile_token::{CryptKeyfileToken, KeyfileLocation, KeyfileToken},
+    tests::loopback,
+};
+
+pub fn test_keyfile_token_activate() {
+    loopback::use_luks(EncryptionFormat::Luks2, b"abadpassphrase", |fixture| {
+        let file_path = fixture.device.backing_file();
+        let keyfile_path = PathBuf::from(format!("{}-key", file_path.display()));
+        let keyfile: Vec<u8> = (0..4096).map(|_| random::<u8>()).collect();
+        std::fs::write(&keyfile_path, &keyfile).map_err(crate::LibcryptErr::IOError)?;
+
+        let mut dev = fixture.load()?;
+        let keyslot = dev
+            .keyslot_handle(None)
+            .add_by_passphrase(b"abadpassphrase", &keyfile[512..1536])?;
+
+        let token = CryptKeyfileToken::write(
+            &mut dev,
+            None,
+            &KeyfileToken {
+                keyslots: vec![keyslot.to_string()],
+                location: KeyfileLocation::Path(keyfile_path.clone()),
+                keyfile_size: Some(1024),
+                keyfile_offset: 512,
+            },
+        )?;
+        let tokens = CryptKeyfileToken::list(&mut dev)?.tokens;
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].0, token);
+
+        let name = "test-keyfile-token";
+        let result = dev.token_handle(token).activate_by_token::<()>(
+            Some(name),
+            token,
+            None,
+            CryptActivateFlags::empty(),
+        );
+        remove_file(&keyfile_path).map_err(crate::LibcryptErr::IOError)?;
+        assert_eq!(result?, keyslot);
+        dev.activate_handle()
+            .deactivate(name, CryptDeactivateFlags::empty())?;
+
+        // The keyfile is no longer available
+        assert!(dev
+            .token_handle(token)
+            .activate_by_token::<()>(Some(name), token, None, CryptActivateFlags::empty())
+            .is_err());
+        Ok(())
+    })
+    .expect("Should succeed");
+}
@@ -2,43 +2,36 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::{
-    env,
-    fs::{remove_file, File},
-    io::{self, Write},
-    path::{Path, PathBuf},
-};
-
-use loopdev::LoopControl;
-use rand::random;
-
-use crate::err::LibcryptErr;
+use std::path::Path;
 
-fn setup_backing_file(size_in_bytes: usize, with_zeros: bool) -> Result<PathBuf, io::Error> {
-    let mut i = 0;
+use crate::{
+    err::LibcryptErr,
+    format::EncryptionFormat,
+    test_utils::{BackingFill, LoopDeviceOptions, LuksFixture, TestLoopDevice},
+};
 
-    let b64_string = base64::encode_config(
-        &random::<[u8; 12]>(),
-        base64::Config::new(base64::CharacterSet::UrlSafe, false),
-    );
-    let directory = PathBuf::from(env::var("TEST_DIR").unwrap_or_else(|_| "/tmp".to_string()));
-    assert!(directory.exists() && directory.is_dir());
-    let mut file_path = PathBuf::new();
-    file_path.push(directory);
-    file_path.push(b64_string.to_string());
+fn options(file_size: usize, fill: BackingFill, cleanup: bool) -> LoopDeviceOptions {
+    LoopDeviceOptions {
+        size: file_size as u64,
+        fill,
+        cleanup,
+        ..LoopDeviceOptions::default()
+    }
+}
 
-    let mut f = File::create(&file_path)?;
-    while i < size_in_bytes {
-        let len = if with_zeros {
-            f.write(&[0; 4096])?
-        } else {
-            let buf: Vec<_> = (0..4096).map(|_| random::<u8>()).collect();
-            f.write(&buf)?
-        };
-        assert_eq!(len, 4096);
-        i += len;
+fn fill(with_zeros: bool) -> BackingFill {
+    if with_zeros {
+        BackingFill::Zeros
+    } else {
+        BackingFill::Random
     }
-    Ok(file_path)
+}
+
+fn attach<F>(device: TestLoopDevice, func: F) -> Result<(), LibcryptErr>
+where
+    F: Fn(&Path, &Path) -> Result<(), LibcryptErr>,
+{
+    func(device.path(), device.backing_file())
 }
 
 pub fn use_loopback<F>(
@@ -50,27 +43,36 @@ pub fn use_loopback<F>(
 where
     F: Fn(&Path, &Path) -> Result<(), LibcryptErr>,
 {
-    if !nix::unistd::Uid::effective().is_root() {
-        panic!("Must be root to run tests");
-    }
-    let ctrl = LoopControl::open();
-    let dev = ctrl
-        .and_then(|ref c| c.next_free())
-        .map_err(LibcryptErr::IOError)?;
+    attach(
+        TestLoopDevice::new(&options(file_size, fill(with_zeros), cleanup))?,
+        func,
+    )
+}
 
-    let path = setup_backing_file(file_size, with_zeros).map_err(LibcryptErr::IOError)?;
-    let attach_result = dev.attach_file(&path);
-    let test_result = attach_result
-        .map_err(LibcryptErr::IOError)
-        .and_then(|_| match dev.path() {
-            Some(ref d) => func(d, &path),
-            _ => Err(LibcryptErr::IOError(io::Error::from(
-                io::ErrorKind::NotFound,
-            ))),
-        });
-    let detach_result = if cleanup { dev.detach() } else { Ok(()) };
-    detach_result
-        .and_then(|_| if cleanup { remove_file(&path) } else { Ok(()) })
-        .map_err(LibcryptErr::IOError)
-        .and(test_result)
+/// Format a 64 MiB loop device as `format` with a keyslot for `passphrase`
+pub fn use_luks<F>(format: EncryptionFormat, passphrase: &[u8], func: F) -> Result<(), LibcryptErr>
+where
+    F: Fn(&LuksFixture) -> Result<(), LibcryptErr>,
+{
+    let options = options(
+        64 * 1024 * 1024,
+        fill(super::format_with_zeros()),
+        super::do_cleanup(),
+    );
+    let fixture = match format {
+        EncryptionFormat::Luks1 => LuksFixture::luks1(&options, passphrase)?,
+        _ => LuksFixture::luks2(&options, passphrase)?,
+    };
+    func(&fixture)
+}
+
+/// Attach a copy of the image file `image` to a loop device
+pub fn use_image<F>(image: &Path, cleanup: bool, func: F) -> Result<(), LibcryptErr>
+where
+    F: Fn(&Path, &Path) -> Result<(), LibcryptErr>,
+{
+    attach(
+        TestLoopDevice::from_image(image, &options(0, BackingFill::Sparse, cleanup))?,
+        func,
+    )
 }
@@ -0,0 +1,64 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::env::temp_dir;
+
+use uuid::Uuid;
+
+use crate::{
+    activate::{CryptActivateFlag, CryptActivateFlags},
+    format::EncryptionFormat,
+    keyslot::KeyslotPriority,
+    luks2_metadata::MetadataTransaction,
+    tests::loopback,
+};
+
+pub fn test_metadata_transaction() {
+    loopback::use_luks(EncryptionFormat::Luks2, b"abadpassphrase", |fixture| {
+        let mut dev = fixture.load()?;
+        let keyslot = fixture.keyslot;
+
+        let backup = temp_dir().join("libcryptsetup-rs-metadata-backup");
+        let uuid = Uuid::from_bytes([
+            0x0c, 0x8d, 0x2b, 0x43, 0x6e, 0x5a, 0x4f, 0x1b, 0x9a, 0x37, 0x1e, 0x64, 0xd2, 0x0f,
+            0x88, 0x21,
+        ]);
+        MetadataTransaction::new()
+            .label("data")
+            .subsystem("test")
+            .uuid(uuid)
+            .persistent_flags(vec![CryptActivateFlag::AllowDiscards])
+            .keyslot_priority(keyslot, KeyslotPriority::Prefer)
+            .commit(&mut dev, &backup)?;
+        assert!(!backup.exists());
+
+        assert_eq!(dev.status_handle().get_label()?, Some("data".to_string()));
+        assert_eq!(
+            dev.status_handle().get_subsystem()?,
+            Some("test".to_string())
+        );
+        assert_eq!(dev.status_handle().get_uuid()?, uuid);
+        let flags: u32 = dev
+            .luks2_flag_handle::<CryptActivateFlags>()
+            .persistent_flags_get()?
+            .into();
+        let allow_discards: u32 = CryptActivateFlag::AllowDiscards.into();
+        assert_eq!(flags & allow_discards, allow_discards);
+        match dev.keyslot_handle(Some(keyslot)).get_priority()? {
+            KeyslotPriority::Prefer => (),
+            _ => panic!("Keyslot priority was not updated"),
+        }
+
+        // Invalid transactions are rejected before anything is written
+        assert!(MetadataTransaction::new()
+            .label("other")
+            .keyslot_priority(keyslot + 1, KeyslotPriority::Ignore)
+            .commit(&mut dev, &backup)
+            .is_err());
+        assert!(!backup.exists());
+        assert_eq!(dev.status_handle().get_label()?, Some("data".to_string()));
+        Ok(())
+    })
+    .expect("Should succeed");
+}
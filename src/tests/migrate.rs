@@ -0,0 +1,95 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{env, fs::remove_file};
+
+use crate::{
+    err::LibcryptErr,
+    format::EncryptionFormat,
+    luks_migrate::{CryptMigration, MigrationIssue},
+    test_utils::LuksFixture,
+    tests::loopback,
+};
+
+const PASSPHRASE: &str = "abadpassphrase";
+const OTHER_PASSPHRASE: &str = "anotherbadpassphrase";
+
+fn add_other_keyslot(fixture: &LuksFixture) -> Result<(), LibcryptErr> {
+    fixture
+        .load()?
+        .keyslot_handle(None)
+        .add_by_passphrase(PASSPHRASE.as_bytes(), OTHER_PASSPHRASE.as_bytes())
+        .map(|_| ())
+}
+
+pub fn test_migrate_round_trip() {
+    loopback::use_luks(EncryptionFormat::Luks1, PASSPHRASE.as_bytes(), |fixture| {
+        let dev_path = fixture.device.path();
+        let backup_to_luks2 = env::temp_dir().join("libcryptsetup-rs-migrate-luks2");
+        let backup_to_luks1 = env::temp_dir().join("libcryptsetup-rs-migrate-luks1");
+        add_other_keyslot(fixture)?;
+
+        let report = CryptMigration::migrate(
+            dev_path,
+            EncryptionFormat::Luks2,
+            &backup_to_luks2,
+            &[PASSPHRASE.as_bytes()],
+            false,
+        )?;
+        assert_eq!(report.verified_keyslots, vec![0]);
+        assert_eq!(report.unverified_keyslots, vec![1]);
+        assert!(report.upgraded_keyslots.is_empty());
+        assert!(backup_to_luks2.exists());
+
+        let report = CryptMigration::migrate(
+            dev_path,
+            EncryptionFormat::Luks1,
+            &backup_to_luks1,
+            &[PASSPHRASE.as_bytes(), OTHER_PASSPHRASE.as_bytes()],
+            false,
+        )?;
+        assert_eq!(report.verified_keyslots, vec![0, 1]);
+        assert!(report.unverified_keyslots.is_empty());
+
+        remove_file(&backup_to_luks2).map_err(LibcryptErr::IOError)?;
+        remove_file(&backup_to_luks1).map_err(LibcryptErr::IOError)
+    })
+    .expect("Should succeed");
+}
+
+pub fn test_migrate_argon2id_blocks_downgrade() {
+    loopback::use_luks(EncryptionFormat::Luks1, PASSPHRASE.as_bytes(), |fixture| {
+        let dev_path = fixture.device.path();
+        let backup = env::temp_dir().join("libcryptsetup-rs-migrate-argon2id");
+        add_other_keyslot(fixture)?;
+
+        let report = CryptMigration::migrate(
+            dev_path,
+            EncryptionFormat::Luks2,
+            &backup,
+            &[PASSPHRASE.as_bytes()],
+            true,
+        )?;
+        assert_eq!(report.upgraded_keyslots, vec![0]);
+
+        let issues = CryptMigration::check(dev_path, EncryptionFormat::Luks1)?;
+        assert_eq!(
+            issues,
+            vec![MigrationIssue::IncompatibleKeyslot {
+                keyslot: 0,
+                reason: "argon2id key derivation".to_string(),
+            }]
+        );
+        assert!(CryptMigration::migrate(
+            dev_path,
+            EncryptionFormat::Luks1,
+            &env::temp_dir().join("libcryptsetup-rs-migrate-unused"),
+            &[PASSPHRASE.as_bytes()],
+            false,
+        )
+        .is_err());
+        remove_file(&backup).map_err(LibcryptErr::IOError)
+    })
+    .expect("Should succeed");
+}
@@ -4,8 +4,33 @@
 
 use std::env::var;
 
+#[cfg(feature = "clevis")]
+pub mod clevis;
+pub mod convert;
+pub mod discover;
 pub mod encrypt;
+pub mod erase;
+pub mod escrow;
+#[cfg(feature = "test-utils")]
+pub mod fixtures;
+pub mod inplace;
+pub mod integrity;
+pub mod keyfile_token;
 pub mod loopback;
+pub mod metadata;
+pub mod migrate;
+pub mod policy;
+pub mod recovery_key;
+pub mod reencrypt;
+pub mod repair;
+pub mod resize;
+pub mod systemd_token;
+#[cfg(feature = "clevis")]
+pub mod tang;
+pub mod tcrypt;
+pub mod typed;
+pub mod unlock;
+pub mod wipe;
 
 fn format_with_zeros() -> bool {
     var("FORMAT_WITH_ZEROS")
@@ -0,0 +1,45 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::{
+    format::EncryptionFormat,
+    policy::{PbkdfPolicy, PolicyRule, SecurityPolicy},
+    tests::loopback,
+};
+
+pub fn test_policy_audit() {
+    loopback::use_luks(EncryptionFormat::Luks2, b"abadpassphrase", |fixture| {
+        let mut dev = fixture.load()?;
+
+        let policy = SecurityPolicy {
+            allowed_ciphers: vec!["aes-xts-plain64".to_string()],
+            min_volume_key_size: Some(64),
+            allowed_pbkdfs: vec![PbkdfPolicy {
+                // The fixture keyslot uses the default argon2 variant
+                kdf: "pbkdf2".to_string(),
+                ..Default::default()
+            }],
+            max_active_keyslots: Some(1),
+            require_luks2: true,
+            required_token_types: vec!["systemd-tpm2".to_string()],
+            ..Default::default()
+        };
+        let report = policy.audit(&mut dev)?;
+        let failed = report
+            .failures()
+            .into_iter()
+            .map(|f| f.rule.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            failed,
+            vec![
+                PolicyRule::Pbkdf(fixture.keyslot),
+                PolicyRule::RequiredToken("systemd-tpm2".to_string())
+            ]
+        );
+        assert_eq!(report.findings.len(), 6);
+        Ok(())
+    })
+    .expect("Should succeed");
+}
@@ -0,0 +1,40 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::{
+    activate::CryptActivateFlags,
+    format::EncryptionFormat,
+    recovery_key::{CryptRecoveryKey, SYSTEMD_RECOVERY_TOKEN_TYPE},
+    tests::loopback,
+};
+
+pub fn test_recovery_key_enroll() {
+    loopback::use_luks(EncryptionFormat::Luks2, b"abadpassphrase", |fixture| {
+        let mut dev = fixture.load()?;
+
+        let enrollment = CryptRecoveryKey::enroll(&mut dev, b"abadpassphrase")?;
+        let json = dev.token_handle(enrollment.token).json_get()?;
+        assert_eq!(json["type"], SYSTEMD_RECOVERY_TOKEN_TYPE);
+        assert_eq!(
+            json["keyslots"],
+            serde_json::json!([enrollment.keyslot.to_string()])
+        );
+        let pbkdf = dev.keyslot_handle(Some(enrollment.keyslot)).get_pbkdf()?;
+        assert_eq!(pbkdf.iterations, 1000);
+
+        let typed = enrollment.key.as_str().replace("-", "").to_uppercase();
+        let keyslot =
+            CryptRecoveryKey::activate(&mut dev, None, &typed, CryptActivateFlags::empty())?;
+        assert_eq!(keyslot, enrollment.keyslot);
+        assert!(CryptRecoveryKey::activate(
+            &mut dev,
+            None,
+            "abadpassphrase",
+            CryptActivateFlags::empty()
+        )
+        .is_err());
+        Ok(())
+    })
+    .expect("Should succeed");
+}
@@ -0,0 +1,328 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    fs::OpenOptions,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    activate::{CryptActivateFlags, CryptDeactivateFlags},
+    credential::CryptCredential,
+    device::{CryptDevice, CryptInit},
+    err::LibcryptErr,
+    format::{CryptParamsIntegrity, CryptParamsLuks2, EncryptionFormat},
+    keyslot::{CryptKeyslot, CryptVolumeKeyFlags},
+    luks2_reencrypt::{
+        CryptParamsReencrypt, CryptReencryptDirectionInfo, CryptReencryptFlags, CryptReencryptInfo,
+        CryptReencryptModeInfo, ReencryptProgress,
+    },
+    settings::{CryptSettings, LuksType},
+    tests::loopback,
+    Either, Interrupt,
+};
+
+const PASSPHRASE: &str = "abadpassphrase";
+/// Device-mapper device between the loop device and LUKS2 used to make writes fail
+const FAULTY_DEVICE: &str = "libcryptsetup-rs-test-faulty";
+const DATA_DEVICE: &str = "libcryptsetup-rs-test-reencrypt-data";
+const PATTERN_BLOCK_SIZE: usize = 1024 * 1024;
+
+fn safe_interrupt_progress(_size: u64, offset: u64, _usrdata: Option<&mut ()>) -> Interrupt {
+    if offset > 0 {
+        Interrupt::Yes
+    } else {
+        Interrupt::No
+    }
+}
+
+c_progress_callback!(interrupt_progress, (), safe_interrupt_progress);
+
+/// Whether the table failing data writes has been swapped in
+static WRITES_FAILING: AtomicBool = AtomicBool::new(false);
+
+/// Make writes to the data area fail once the first hotzone has been reencrypted, so the
+/// next hotzone is left half written as after a crash
+fn safe_fail_writes_progress(_size: u64, offset: u64, _usrdata: Option<&mut ()>) -> Interrupt {
+    if offset > 0 && !WRITES_FAILING.swap(true, Ordering::SeqCst) {
+        // Resuming the device switches to the table loaded before reencryption started
+        if dmsetup(&["resume", FAULTY_DEVICE], None).is_err() {
+            return Interrupt::Yes;
+        }
+    }
+    Interrupt::No
+}
+
+c_progress_callback!(fail_writes_progress, (), safe_fail_writes_progress);
+
+fn dmsetup(args: &[&str], table: Option<&str>) -> Result<(), LibcryptErr> {
+    let mut child = Command::new("dmsetup")
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(LibcryptErr::IOError)?;
+    if let Some(table) = table {
+        child
+            .stdin
+            .as_mut()
+            .expect("stdin is piped")
+            .write_all(table.as_bytes())
+            .map_err(LibcryptErr::IOError)?;
+    }
+    drop(child.stdin.take());
+    let status = child.wait().map_err(LibcryptErr::IOError)?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(LibcryptErr::Other(format!("dmsetup {:?} failed", args)))
+    }
+}
+
+fn linear_table(dev_path: &Path, sectors: u64) -> String {
+    format!("0 {} linear {} 0\n", sectors, dev_path.display())
+}
+
+/// Table that keeps the LUKS2 header writable but fails every write to the data area
+fn failing_writes_table(dev_path: &Path, sectors: u64, data_offset: u64) -> String {
+    format!(
+        "0 {offset} linear {dev} 0\n\
+         {offset} {data} flakey {dev} {offset} 0 1 1 error_writes\n",
+        dev = dev_path.display(),
+        offset = data_offset,
+        data = sectors - data_offset,
+    )
+}
+
+fn reencrypt_params() -> Result<CryptParamsReencrypt, LibcryptErr> {
+    Ok(CryptParamsReencrypt {
+        mode: CryptReencryptModeInfo::Reencrypt,
+        direction: CryptReencryptDirectionInfo::Forward,
+        resilience: "checksum".to_string(),
+        hash: "sha256".to_string(),
+        data_shift: 0,
+        max_hotzone_size: 8 * 1024 * 1024 / 512,
+        device_size: 0,
+        luks2: CryptParamsLuks2 {
+            pbkdf: CryptSettings::get_pbkdf_default(&LuksType::Luks2)?,
+            integrity: None,
+            integrity_params: CryptParamsIntegrity::default(),
+            data_alignment: 0,
+            data_device: PathBuf::new(),
+            sector_size: 512,
+            label: String::new(),
+            subsystem: String::new(),
+        },
+        flags: CryptReencryptFlags::empty(),
+    })
+}
+
+fn format(dev_path: &Path) -> Result<CryptDevice, LibcryptErr> {
+    let mut dev = CryptInit::init(dev_path)?;
+    dev.context_handle().format::<()>(
+        EncryptionFormat::Luks2,
+        ("aes", "xts-plain64"),
+        None,
+        Either::Right(512 / 8),
+        None,
+    )?;
+    dev.keyslot_handle(None).add_by_key(
+        None,
+        PASSPHRASE.as_bytes(),
+        CryptVolumeKeyFlags::empty(),
+    )?;
+    Ok(dev)
+}
+
+fn reencrypt(dev_path: &Path, progress: ReencryptProgress) -> Result<(), LibcryptErr> {
+    let mut dev = CryptInit::init(dev_path)?;
+    dev.context_handle()
+        .load::<()>(EncryptionFormat::Luks2, None)?;
+    let mut reencrypt = dev.reencrypt_handle();
+    reencrypt.reencrypt_init_by_passphrase(
+        None,
+        PASSPHRASE.as_bytes(),
+        libcryptsetup_rs_sys::CRYPT_ANY_SLOT,
+        libcryptsetup_rs_sys::CRYPT_ANY_SLOT,
+        ("aes", "xts-plain64"),
+        reencrypt_params()?,
+    )?;
+    reencrypt.reencrypt(Some(progress))
+}
+
+/// Call `f` with the path of the activated plaintext device
+fn with_data_device<F>(dev: &mut CryptDevice, f: F) -> Result<(), LibcryptErr>
+where
+    F: FnOnce(&Path) -> Result<(), LibcryptErr>,
+{
+    dev.activate_handle().activate_by_passphrase(
+        Some(DATA_DEVICE),
+        None,
+        PASSPHRASE.as_bytes(),
+        CryptActivateFlags::empty(),
+    )?;
+    let result = f(&Path::new("/dev/mapper").join(DATA_DEVICE));
+    dev.activate_handle()
+        .deactivate(DATA_DEVICE, CryptDeactivateFlags::empty())?;
+    result
+}
+
+fn pattern_block(index: usize) -> Vec<u8> {
+    (0..PATTERN_BLOCK_SIZE)
+        .map(|i| (index.wrapping_mul(31) ^ (i / 512)) as u8)
+        .collect()
+}
+
+fn write_pattern(path: &Path, blocks: usize) -> Result<(), LibcryptErr> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .map_err(LibcryptErr::IOError)?;
+    for index in 0..blocks {
+        file.write_all(&pattern_block(index))
+            .map_err(LibcryptErr::IOError)?;
+    }
+    file.sync_all().map_err(LibcryptErr::IOError)
+}
+
+fn check_pattern(path: &Path, blocks: usize) -> Result<(), LibcryptErr> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .open(path)
+        .map_err(LibcryptErr::IOError)?;
+    let mut block = vec![0u8; PATTERN_BLOCK_SIZE];
+    for index in 0..blocks {
+        file.read_exact(&mut block).map_err(LibcryptErr::IOError)?;
+        assert!(
+            block == pattern_block(index),
+            "Block {} differs after reencryption",
+            index
+        );
+    }
+    Ok(())
+}
+
+/// Volume keys of the old and new segment of an ongoing reencryption
+fn volume_keys(dev: &mut CryptDevice) -> Result<Vec<Vec<u8>>, LibcryptErr> {
+    let mut keys = Vec::new();
+    for keyslot in 0..CryptKeyslot::max_keyslots(EncryptionFormat::Luks2)? {
+        let mut key = vec![0u8; 512 / 8];
+        // The reencryption keyslot and inactive keyslots hold no volume key
+        if let Ok((_, size)) = dev.volume_key_handle().get(keyslot, &mut key, PASSPHRASE) {
+            key.truncate(size);
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+    }
+    Ok(keys)
+}
+
+pub fn test_reencrypt_interrupt_resume() {
+    loopback::use_loopback(
+        128 * 1024 * 1024,
+        super::format_with_zeros(),
+        super::do_cleanup(),
+        |dev_path, _file_path| {
+            format(dev_path)?;
+            reencrypt(dev_path, interrupt_progress)?;
+
+            let mut dev = CryptInit::init(dev_path)?;
+            dev.context_handle()
+                .load::<()>(EncryptionFormat::Luks2, None)?;
+            match dev.reencrypt_resume_handle().status()?.info {
+                CryptReencryptInfo::Clean => (),
+                _ => panic!("Interrupted reencryption should be in a clean state"),
+            }
+
+            let keys = volume_keys(&mut dev)?;
+            assert_eq!(keys.len(), 2);
+            let keys = keys.iter().map(|k| k.as_slice()).collect::<Vec<_>>();
+            let credential = CryptCredential::VolumeKeys(&keys);
+            let mut resume = dev.reencrypt_resume_handle();
+            assert!(!resume.recover(&credential)?);
+            match resume.resume(None, &credential, None)?.info {
+                CryptReencryptInfo::None => (),
+                _ => panic!("Resumed reencryption should have finished"),
+            }
+
+            dev.activate_handle().activate_by_passphrase(
+                None,
+                None,
+                PASSPHRASE.as_bytes(),
+                CryptActivateFlags::empty(),
+            )?;
+            Ok(())
+        },
+    )
+    .expect("Should succeed");
+}
+
+fn crash_and_recover(faulty_path: &Path, dev_path: &Path, sectors: u64) -> Result<(), LibcryptErr> {
+    let mut dev = format(faulty_path)?;
+    let data_offset = dev.status_handle().get_data_offset();
+    let blocks = ((sectors - data_offset) * 512) as usize / PATTERN_BLOCK_SIZE;
+    with_data_device(&mut dev, |path| write_pattern(path, blocks))?;
+
+    dmsetup(
+        &["load", FAULTY_DEVICE],
+        Some(&failing_writes_table(dev_path, sectors, data_offset)),
+    )?;
+    WRITES_FAILING.store(false, Ordering::SeqCst);
+    assert!(reencrypt(faulty_path, fail_writes_progress).is_err());
+    assert!(WRITES_FAILING.load(Ordering::SeqCst));
+    dmsetup(
+        &["load", FAULTY_DEVICE],
+        Some(&linear_table(dev_path, sectors)),
+    )?;
+    dmsetup(&["resume", FAULTY_DEVICE], None)?;
+
+    let mut dev = CryptInit::init(faulty_path)?;
+    dev.context_handle()
+        .load::<()>(EncryptionFormat::Luks2, None)?;
+    let mut resume = dev.reencrypt_resume_handle();
+    match resume.status()?.info {
+        CryptReencryptInfo::Crash => (),
+        _ => panic!("Reencryption failing to write a hotzone should need recovery"),
+    }
+    let credential = CryptCredential::Passphrase {
+        passphrase: PASSPHRASE.as_bytes(),
+        keyslot: None,
+    };
+    assert!(resume.recover(&credential)?);
+    match resume.status()?.info {
+        CryptReencryptInfo::Clean => (),
+        _ => panic!("Recovered reencryption should be in a clean state"),
+    }
+    match resume.resume(None, &credential, None)?.info {
+        CryptReencryptInfo::None => (),
+        _ => panic!("Resumed reencryption should have finished"),
+    }
+    with_data_device(&mut dev, |path| check_pattern(path, blocks))
+}
+
+pub fn test_reencrypt_crash_recover() {
+    loopback::use_loopback(
+        128 * 1024 * 1024,
+        super::format_with_zeros(),
+        super::do_cleanup(),
+        |dev_path, _file_path| {
+            let sectors = 128 * 1024 * 1024 / 512;
+            dmsetup(
+                &["create", FAULTY_DEVICE],
+                Some(&linear_table(dev_path, sectors)),
+            )?;
+            let result = crash_and_recover(
+                &Path::new("/dev/mapper").join(FAULTY_DEVICE),
+                dev_path,
+                sectors,
+            );
+            dmsetup(&["remove", FAULTY_DEVICE], None)?;
+            result
+        },
+    )
+    .expect("Should succeed");
+}
@@ -0,0 +1,45 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    env,
+    fs::{remove_file, OpenOptions},
+    io::{Seek, SeekFrom, Write},
+};
+
+use crate::{
+    err::LibcryptErr,
+    format::EncryptionFormat,
+    luks2_check::{CryptHeaderCheck, HeaderCopy, HeaderDiagnostic},
+    tests::loopback,
+};
+
+pub fn test_repair_secondary_header() {
+    loopback::use_luks(EncryptionFormat::Luks2, b"abadpassphrase", |fixture| {
+        let dev_path = fixture.device.path();
+        let backup_file = env::temp_dir().join("libcryptsetup-rs-repair-backup");
+        let dev = fixture.load()?;
+        drop(dev);
+        assert_eq!(CryptHeaderCheck::check(dev_path)?, vec![]);
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(dev_path)
+            .map_err(LibcryptErr::IOError)?;
+        file.seek(SeekFrom::Start(2 * 16384 - 1))
+            .and_then(|_| file.write_all(b"x"))
+            .and_then(|_| file.sync_all())
+            .map_err(LibcryptErr::IOError)?;
+        drop(file);
+        assert_eq!(
+            CryptHeaderCheck::check(dev_path)?,
+            vec![HeaderDiagnostic::ChecksumMismatch(HeaderCopy::Secondary)]
+        );
+
+        assert_eq!(CryptHeaderCheck::repair(dev_path, &backup_file)?, vec![]);
+        assert!(backup_file.exists());
+        remove_file(&backup_file).map_err(LibcryptErr::IOError)
+    })
+    .expect("Should succeed");
+}
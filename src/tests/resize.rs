@@ -0,0 +1,52 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::{
+    activate::{CryptActivateFlags, CryptDeactivateFlags},
+    credential::CryptCredential,
+    format::EncryptionFormat,
+    resize::CryptResize,
+    tests::loopback,
+};
+
+const PASSPHRASE: &[u8] = b"abadpassphrase";
+
+pub fn test_resize_mapping() {
+    loopback::use_luks(EncryptionFormat::Luks2, PASSPHRASE, |fixture| {
+        let mut dev = fixture.load()?;
+        dev.activate_handle().activate_by_passphrase(
+            Some("test-resize"),
+            None,
+            PASSPHRASE,
+            CryptActivateFlags::empty(),
+        )?;
+        let credential = CryptCredential::Passphrase {
+            passphrase: PASSPHRASE,
+            keyslot: None,
+        };
+
+        let max_size = CryptResize::max_size("test-resize", None)?;
+        assert!(CryptResize::resize("test-resize", None, Some(1000), Some(&credential)).is_err());
+        assert!(
+            CryptResize::resize("test-resize", None, Some(max_size + 512), Some(&credential))
+                .is_err()
+        );
+
+        let report = CryptResize::resize(
+            "test-resize",
+            None,
+            Some(16 * 1024 * 1024),
+            Some(&credential),
+        )?;
+        assert_eq!(report.before.size * 512, max_size);
+        assert_eq!(report.after.size, 16 * 1024 * 1024 / 512);
+
+        let report = CryptResize::resize("test-resize", None, None, Some(&credential))?;
+        assert_eq!(report.after.size * 512, max_size);
+
+        dev.activate_handle()
+            .deactivate("test-resize", CryptDeactivateFlags::empty())
+    })
+    .expect("Should succeed");
+}
@@ -0,0 +1,59 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::{
+    format::EncryptionFormat,
+    keyslot::KeyslotInfo,
+    recovery_key::CryptRecoveryKey,
+    systemd_token::{CryptSystemdTokens, SystemdToken},
+    tests::loopback,
+};
+
+pub fn test_systemd_token_removal() {
+    loopback::use_luks(EncryptionFormat::Luks2, b"abadpassphrase", |fixture| {
+        let mut dev = fixture.load()?;
+        let fido2_keyslot = dev
+            .keyslot_handle(None)
+            .add_by_passphrase(b"abadpassphrase", b"fido2secret")?;
+        let fido2_token = dev
+            .token_handle(libcryptsetup_rs_sys::CRYPT_ANY_TOKEN)
+            .json_set(
+                &serde_json::json!({
+                    "type": "systemd-fido2",
+                    "keyslots": [fido2_keyslot.to_string()],
+                    "fido2-credential": "Y3JlZA==",
+                    "fido2-salt": "c2FsdA==",
+                    "fido2-rp": "io.systemd.cryptsetup",
+                    "fido2-clientPin-required": false,
+                    "fido2-up-required": true,
+                    "fido2-uv-required": false
+                }),
+                true,
+            )?;
+        let recovery = CryptRecoveryKey::enroll(&mut dev, b"abadpassphrase")?;
+
+        let tokens = CryptSystemdTokens::list(&mut dev)?.tokens;
+        assert_eq!(tokens.len(), 2);
+        match tokens.iter().find(|(t, _)| *t == fido2_token) {
+            Some((_, SystemdToken::Fido2(t))) => {
+                assert_eq!(t.relying_party, Some("io.systemd.cryptsetup".to_string()))
+            }
+            _ => panic!("FIDO2 token not found"),
+        }
+
+        assert_eq!(
+            CryptSystemdTokens::remove(&mut dev, fido2_token)?,
+            vec![fido2_keyslot]
+        );
+        match dev.keyslot_handle(Some(fido2_keyslot)).status()? {
+            KeyslotInfo::Inactive => (),
+            _ => panic!("FIDO2 keyslot was not destroyed"),
+        }
+        let tokens = CryptSystemdTokens::list(&mut dev)?.tokens;
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].0, recovery.token);
+        Ok(())
+    })
+    .expect("Should succeed");
+}
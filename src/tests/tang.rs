@@ -0,0 +1,227 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Minimal Tang server for testing clevis bindings without network access
+
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+use openssl::{
+    ec::{EcGroup, EcKey},
+    ecdsa::EcdsaSig,
+    hash::{hash, MessageDigest},
+    nid::Nid,
+    pkey::Private,
+};
+
+use crate::jose::{self, Jwk};
+
+const CURVE: &str = "P-521";
+/// Size in bytes of P-521 field elements
+const FIELD_BYTES: i32 = 66;
+
+pub struct TangServer {
+    port: u16,
+    thumbprint: String,
+    advertisement: serde_json::Value,
+    stopped: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl TangServer {
+    /// Generate a signing and an exchange key and serve them on a local port
+    pub fn start() -> Self {
+        let group = EcGroup::from_curve_name(Nid::SECP521R1).unwrap();
+        let signing_key = jose::generate_key(&group).unwrap();
+        let exchange_key = jose::generate_key(&group).unwrap();
+        let signing_jwk = Jwk::from_point(
+            CURVE,
+            &group,
+            signing_key.public_key(),
+            Some("ES512"),
+            Some(&["verify"]),
+        )
+        .unwrap();
+        let exchange_jwk = Jwk::from_point(
+            CURVE,
+            &group,
+            exchange_key.public_key(),
+            Some("ECMR"),
+            Some(&["deriveKey"]),
+        )
+        .unwrap();
+        let thumbprint = signing_jwk.thumbprint(MessageDigest::sha256()).unwrap();
+        let advertisement = sign(
+            &serde_json::json!({"keys": [signing_jwk, exchange_jwk]}),
+            &signing_key,
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread_stopped = Arc::clone(&stopped);
+        let thread_advertisement = serde_json::to_vec(&advertisement).unwrap();
+        let handle = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if thread_stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    serve(
+                        stream,
+                        &thread_advertisement,
+                        &group,
+                        &exchange_key,
+                        &exchange_jwk,
+                    );
+                }
+            }
+        });
+        TangServer {
+            port,
+            thumbprint,
+            advertisement,
+            stopped,
+            handle,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    /// SHA-256 thumbprint of the signing key
+    pub fn thumbprint(&self) -> String {
+        self.thumbprint.clone()
+    }
+
+    pub fn advertisement(&self) -> serde_json::Value {
+        self.advertisement.clone()
+    }
+
+    /// Stop serving requests; the port is closed when this returns
+    pub fn stop(self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        let _ = TcpStream::connect(("127.0.0.1", self.port));
+        self.handle.join().unwrap();
+    }
+}
+
+/// Sign a payload as flattened JWS with ES512
+fn sign(payload: &serde_json::Value, key: &EcKey<Private>) -> serde_json::Value {
+    let protected = jose::b64u_encode(br#"{"alg":"ES512","cty":"jwk-set+json"}"#);
+    let payload = jose::b64u_encode(&serde_json::to_vec(payload).unwrap());
+    let digest = hash(
+        MessageDigest::sha512(),
+        format!("{}.{}", protected, payload).as_bytes(),
+    )
+    .unwrap();
+    let sig = EcdsaSig::sign(&digest, key).unwrap();
+    let mut raw = sig.r().to_vec_padded(FIELD_BYTES).unwrap();
+    raw.extend(sig.s().to_vec_padded(FIELD_BYTES).unwrap());
+    serde_json::json!({
+        "payload": payload,
+        "protected": protected,
+        "signature": jose::b64u_encode(&raw),
+    })
+}
+
+fn serve(
+    mut stream: TcpStream,
+    advertisement: &[u8],
+    group: &EcGroup,
+    exchange_key: &EcKey<Private>,
+    exchange_jwk: &Jwk,
+) {
+    let (request_line, body) = match read_request(&mut stream) {
+        Some(r) => r,
+        None => return,
+    };
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/adv")) | (Some("GET"), Some("/adv/")) => {
+            Some(("application/jose+json", advertisement.to_vec()))
+        }
+        (Some("POST"), Some(path)) if path.starts_with("/rec/") => {
+            let kid = &path["/rec/".len()..];
+            if exchange_jwk.has_thumbprint(kid) {
+                recover(&body, group, exchange_key)
+                    .map(|jwk| ("application/jwk+json", serde_json::to_vec(&jwk).unwrap()))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    };
+    let _ = match response {
+        Some((content_type, body)) => stream
+            .write_all(
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    content_type,
+                    body.len()
+                )
+                .as_bytes(),
+            )
+            .and_then(|_| stream.write_all(&body)),
+        None => stream
+            .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+    };
+}
+
+/// Compute sX for the blinded client key X
+fn recover(body: &[u8], group: &EcGroup, exchange_key: &EcKey<Private>) -> Option<Jwk> {
+    let request: Jwk = serde_json::from_slice(body).ok()?;
+    if request.alg.as_deref() != Some("ECMR") || !request.allows("deriveKey") {
+        return None;
+    }
+    let x = request.public_key().ok()?;
+    let y = jose::mul(group, x.public_key(), exchange_key.private_key()).ok()?;
+    Jwk::from_point(CURVE, group, &y, Some("ECMR"), Some(&["deriveKey"])).ok()
+}
+
+fn read_request(stream: &mut TcpStream) -> Option<(String, Vec<u8>)> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    let header_end = loop {
+        if let Some(i) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+        let read = stream.read(&mut buf).ok()?;
+        if read == 0 {
+            return None;
+        }
+        data.extend_from_slice(&buf[..read]);
+    };
+    let head = String::from_utf8(data[..header_end].to_vec()).ok()?;
+    let content_length = head
+        .split("\r\n")
+        .filter_map(|l| {
+            let mut parts = l.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case("content-length") => {
+                    value.trim().parse::<usize>().ok()
+                }
+                _ => None,
+            }
+        })
+        .next()
+        .unwrap_or(0);
+    let mut body = data[header_end + 4..].to_vec();
+    while body.len() < content_length {
+        let read = stream.read(&mut buf).ok()?;
+        if read == 0 {
+            return None;
+        }
+        body.extend_from_slice(&buf[..read]);
+    }
+    Some((head.split("\r\n").next()?.to_string(), body))
+}
@@ -0,0 +1,189 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use zeroize::Zeroizing;
+
+use crate::{
+    activate::CryptDeactivateFlags,
+    device::{CryptDevice, CryptInit},
+    format::{CryptParamsTcrypt, CryptTcryptFlag, CryptTcryptFlags},
+    tests::loopback,
+};
+
+const PASSPHRASE: &[u8] = b"outerpassphrase";
+const HIDDEN_PASSPHRASE: &[u8] = b"hiddenpassphrase";
+const PIM: u32 = 7;
+
+fn test_data(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/data/tcrypt")
+        .join(name)
+}
+
+fn params(
+    passphrase: &[u8],
+    keyfiles: Vec<PathBuf>,
+    pim: u32,
+    flags: Vec<CryptTcryptFlag>,
+) -> CryptParamsTcrypt {
+    let mut flags = flags;
+    flags.push(CryptTcryptFlag::VeraModes);
+    CryptParamsTcrypt {
+        passphrase: Zeroizing::new(passphrase.to_vec()),
+        keyfiles,
+        hash_name: None,
+        cipher: None,
+        mode: None,
+        key_size: 0,
+        flags: CryptTcryptFlags::new(flags),
+        veracrypt_pim: pim,
+    }
+}
+
+fn read_mapping(dev: &mut CryptDevice, name: &str) -> Vec<u8> {
+    dev.tcrypt_handle()
+        .activate(name, true)
+        .expect("Should activate");
+    let mut buf = vec![0u8; 16];
+    let read =
+        File::open(Path::new("/dev/mapper").join(name)).and_then(|mut f| f.read_exact(&mut buf));
+    dev.activate_handle()
+        .deactivate(name, CryptDeactivateFlags::empty())
+        .expect("Should deactivate");
+    read.expect("Should read mapping");
+    buf
+}
+
+pub fn test_tcrypt_outer_volume() {
+    loopback::use_image(
+        &test_data("outer.img"),
+        super::do_cleanup(),
+        |dev_path, _file_path| {
+            let mut dev = CryptInit::init(dev_path)?;
+            let info = dev
+                .tcrypt_handle()
+                .load(&params(PASSPHRASE, Vec::new(), 0, Vec::new()))?;
+            assert_eq!(info.cipher, "aes");
+            assert_eq!(info.cipher_mode, "xts-plain64");
+            assert_eq!(info.volume_key_size, 64);
+            assert_eq!(info.data_offset, 256);
+            assert_eq!(info.iv_offset, 256);
+            dev.tcrypt_handle().dump()?;
+            assert!(read_mapping(&mut dev, "test-tcrypt-outer").starts_with(b"outer volume"));
+
+            let mut dev = CryptInit::init(dev_path)?;
+            let info = dev.tcrypt_handle().load(&params(
+                PASSPHRASE,
+                Vec::new(),
+                0,
+                vec![CryptTcryptFlag::BackupHeader],
+            ))?;
+            assert_eq!(info.data_offset, 256);
+
+            let mut dev = CryptInit::init(dev_path)?;
+            assert!(dev
+                .tcrypt_handle()
+                .load(&params(
+                    PASSPHRASE,
+                    Vec::new(),
+                    0,
+                    vec![CryptTcryptFlag::HiddenHeader],
+                ))
+                .is_err());
+            Ok(())
+        },
+    )
+    .expect("Should succeed");
+}
+
+pub fn test_tcrypt_hidden_volume() {
+    loopback::use_image(
+        &test_data("hidden.img"),
+        super::do_cleanup(),
+        |dev_path, _file_path| {
+            let mut dev = CryptInit::init(dev_path)?;
+            dev.tcrypt_handle()
+                .load(&params(PASSPHRASE, Vec::new(), 0, Vec::new()))?;
+            assert!(read_mapping(&mut dev, "test-tcrypt-outer").starts_with(b"outer volume"));
+
+            let mut dev = CryptInit::init(dev_path)?;
+            assert!(dev
+                .tcrypt_handle()
+                .load(&params(
+                    PASSPHRASE,
+                    Vec::new(),
+                    0,
+                    vec![CryptTcryptFlag::HiddenHeader],
+                ))
+                .is_err());
+            let info = dev.tcrypt_handle().load(&params(
+                HIDDEN_PASSPHRASE,
+                Vec::new(),
+                0,
+                vec![CryptTcryptFlag::HiddenHeader],
+            ))?;
+            assert_eq!(info.data_offset, 304);
+            assert!(read_mapping(&mut dev, "test-tcrypt-hidden").starts_with(b"hidden volume"));
+
+            let mut dev = CryptInit::init(dev_path)?;
+            let info = dev.tcrypt_handle().load(&params(
+                HIDDEN_PASSPHRASE,
+                Vec::new(),
+                0,
+                vec![CryptTcryptFlag::HiddenHeader, CryptTcryptFlag::BackupHeader],
+            ))?;
+            assert_eq!(info.data_offset, 304);
+            Ok(())
+        },
+    )
+    .expect("Should succeed");
+}
+
+pub fn test_tcrypt_keyfile() {
+    loopback::use_image(
+        &test_data("keyfile.img"),
+        super::do_cleanup(),
+        |dev_path, _file_path| {
+            let mut dev = CryptInit::init(dev_path)?;
+            assert!(dev
+                .tcrypt_handle()
+                .load(&params(PASSPHRASE, Vec::new(), 0, Vec::new()))
+                .is_err());
+            dev.tcrypt_handle().load(&params(
+                PASSPHRASE,
+                vec![test_data("keyfile")],
+                0,
+                Vec::new(),
+            ))?;
+            assert!(read_mapping(&mut dev, "test-tcrypt-keyfile").starts_with(b"outer volume"));
+            Ok(())
+        },
+    )
+    .expect("Should succeed");
+}
+
+pub fn test_tcrypt_pim() {
+    loopback::use_image(
+        &test_data("pim.img"),
+        super::do_cleanup(),
+        |dev_path, _file_path| {
+            let mut dev = CryptInit::init(dev_path)?;
+            assert!(dev
+                .tcrypt_handle()
+                .load(&params(PASSPHRASE, Vec::new(), PIM + 1, Vec::new()))
+                .is_err());
+            dev.tcrypt_handle()
+                .load(&params(PASSPHRASE, Vec::new(), PIM, Vec::new()))?;
+            assert!(read_mapping(&mut dev, "test-tcrypt-pim").starts_with(b"outer volume"));
+            Ok(())
+        },
+    )
+    .expect("Should succeed");
+}
@@ -0,0 +1,63 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use either::Either;
+
+use crate::{
+    activate::{CryptActivateFlags, CryptDeactivateFlags},
+    err::LibcryptErr,
+    status::CryptStatusInfo,
+    tests::loopback,
+    typed_device::{Device, Initialized},
+};
+
+const PASSPHRASE: &str = "abadpassphrase";
+
+pub fn test_typed_device_lifecycle() {
+    loopback::use_loopback(
+        1024 * 1024 * 1024,
+        super::format_with_zeros(),
+        super::do_cleanup(),
+        |dev_path, _file_path| {
+            let mut dev = Device::<Initialized>::init(dev_path)?.format_luks2(
+                ("aes", "xts-plain64"),
+                None,
+                Either::Right(512 / 8),
+                None,
+            )?;
+            let keyslot = dev
+                .keyslot_handle(None)
+                .add_by_passphrase(&[], PASSPHRASE.as_bytes())?;
+            dev.token_handle(0).json_set(
+                &serde_json::json!({ "type": "test", "keyslots": [keyslot.to_string()] }),
+                true,
+            )?;
+            drop(dev);
+
+            let mut dev = match Device::<Initialized>::init(dev_path)?.load()? {
+                Either::Right(dev) => dev,
+                Either::Left(_) => {
+                    return Err(LibcryptErr::Other("Expected a LUKS2 device".to_string()))
+                }
+            };
+            assert_eq!(dev.check_passphrase(None, PASSPHRASE.as_bytes())?, keyslot);
+            let mut mapping = dev.activate_by_passphrase(
+                "test-typed-device",
+                None,
+                PASSPHRASE.as_bytes(),
+                CryptActivateFlags::empty(),
+            )?;
+            let status = mapping.status()?;
+            mapping.deactivate(CryptDeactivateFlags::empty())?;
+            match status {
+                CryptStatusInfo::Active => (),
+                _ => return Err(LibcryptErr::Other("Mapping was not active".to_string())),
+            }
+
+            assert!(Device::<Initialized>::init(dev_path)?.load_luks1().is_err());
+            Ok(())
+        },
+    )
+    .expect("Should succeed");
+}
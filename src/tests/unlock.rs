@@ -0,0 +1,94 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    fs::remove_file,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use rand::random;
+
+use crate::{
+    activate::CryptDeactivateFlags,
+    format::EncryptionFormat,
+    tests::loopback,
+    unlock::{UnlockPipeline, UnlockStrategy},
+};
+
+pub fn test_unlock_pipeline() {
+    loopback::use_luks(EncryptionFormat::Luks2, b"abadpassphrase", |fixture| {
+        let file_path = fixture.device.backing_file();
+        let keyfile_path = PathBuf::from(format!("{}-key", file_path.display()));
+        let keyfile: Vec<u8> = (0..1024).map(|_| random::<u8>()).collect();
+        std::fs::write(&keyfile_path, &keyfile).map_err(crate::LibcryptErr::IOError)?;
+
+        let mut dev = fixture.load()?;
+        let keyslot = dev
+            .keyslot_handle(None)
+            .add_by_passphrase(b"abadpassphrase", &keyfile)?;
+        // No handler is registered for this token type
+        let token = dev.token_handle(0).json_set(
+            &serde_json::json!({
+                "type": "libcryptsetup-rs-unregistered",
+                "keyslots": [keyslot.to_string()],
+            }),
+            true,
+        )?;
+
+        let mut pipeline = UnlockPipeline::new();
+        pipeline
+            .strategy(UnlockStrategy::Token(None), Some(Duration::from_secs(30)))
+            .strategy(
+                UnlockStrategy::Token(Some(token)),
+                Some(Duration::from_secs(30)),
+            )
+            .strategy(
+                UnlockStrategy::Keyring("libcryptsetup-rs:missing".to_string()),
+                None,
+            )
+            .strategy(
+                UnlockStrategy::Keyfile {
+                    path: PathBuf::from(format!("{}-missing", file_path.display())),
+                    size: None,
+                    offset: 0,
+                },
+                Some(Duration::from_millis(300)),
+            )
+            .strategy(
+                UnlockStrategy::Keyfile {
+                    path: keyfile_path.clone(),
+                    size: None,
+                    offset: 0,
+                },
+                None,
+            );
+
+        let start = Instant::now();
+        let result = pipeline.check(&mut dev).and_then(|report| {
+            let name = "test-unlock-pipeline";
+            pipeline.unlock(&mut dev, Some(name))?;
+            dev.activate_handle()
+                .deactivate(name, CryptDeactivateFlags::empty())?;
+            Ok(report)
+        });
+        remove_file(&keyfile_path).map_err(crate::LibcryptErr::IOError)?;
+        let report = result?;
+        assert_eq!(report.keyslot, keyslot);
+        assert_eq!(report.token, None);
+        assert_eq!(report.failures.len(), 4);
+        assert_eq!(report.failures[0].strategy, UnlockStrategy::Token(None));
+        assert_eq!(
+            report.failures[1].strategy,
+            UnlockStrategy::Token(Some(token))
+        );
+        // Tokens without a handler are not waited for
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        // Without the keyfile every strategy fails
+        assert!(pipeline.check(&mut dev).is_err());
+        Ok(())
+    })
+    .expect("Should succeed");
+}
@@ -0,0 +1,105 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{env, path::PathBuf, process};
+
+use crate::{
+    activate::{CryptActivateFlags, CryptDeactivateFlags},
+    device::CryptInit,
+    format::EncryptionFormat,
+    tests::loopback,
+    wipe::CryptWipePattern,
+    wipe_device::{CryptDeviceWipe, WipeCheckpoint, WipeTarget, WipeVerification},
+};
+
+const INTERVAL: u64 = 4 * 1024 * 1024;
+
+/// A checkpoint file path no other test run uses
+fn checkpoint_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!(
+        "libcryptsetup-rs-wipe-{}-{}-{:x}",
+        name,
+        process::id(),
+        rand::random::<u64>()
+    ))
+}
+
+pub fn test_wipe_mapping_resume() {
+    loopback::use_luks(EncryptionFormat::Luks2, b"abadpassphrase", |fixture| {
+        let checkpoint_file = checkpoint_path("resume");
+        let mut dev = fixture.load()?;
+        dev.activate_handle().activate_by_passphrase(
+            Some("test-wipe"),
+            None,
+            b"abadpassphrase",
+            CryptActivateFlags::empty(),
+        )?;
+
+        let mut interrupt = |_: &crate::wipe_device::WipeProgress| false;
+        assert!(CryptDeviceWipe::wipe(
+            WipeTarget::Mapping("test-wipe"),
+            CryptWipePattern::Zero,
+            &checkpoint_file,
+            INTERVAL,
+            WipeVerification::None,
+            Some(&mut interrupt),
+        )
+        .is_err());
+        assert_eq!(WipeCheckpoint::read(&checkpoint_file)?.completed, INTERVAL);
+
+        let mut calls = 0;
+        let mut count = |_: &crate::wipe_device::WipeProgress| {
+            calls += 1;
+            true
+        };
+        let report = CryptDeviceWipe::wipe(
+            WipeTarget::Mapping("test-wipe"),
+            CryptWipePattern::Zero,
+            &checkpoint_file,
+            INTERVAL,
+            WipeVerification::Full,
+            Some(&mut count),
+        )?;
+        assert_eq!(report.resumed_from, INTERVAL);
+        assert_eq!(
+            report.verified_blocks,
+            (report.length - INTERVAL) / (1024 * 1024)
+        );
+        assert_eq!(calls as u64, (report.length - 1) / INTERVAL);
+        assert!(!checkpoint_file.exists());
+
+        dev.activate_handle()
+            .deactivate("test-wipe", CryptDeactivateFlags::empty())
+    })
+    .expect("Should succeed");
+}
+
+pub fn test_wipe_data_segment() {
+    loopback::use_luks(EncryptionFormat::Luks2, b"abadpassphrase", |fixture| {
+        let dev_path = fixture.device.path();
+        let checkpoint_file = checkpoint_path("segment");
+        let mut dev = fixture.load()?;
+        let data_offset = dev.status_handle().get_data_offset() * 512;
+        drop(dev);
+
+        let report = CryptDeviceWipe::wipe(
+            WipeTarget::DataSegment(dev_path),
+            CryptWipePattern::Zero,
+            &checkpoint_file,
+            INTERVAL,
+            WipeVerification::Sampled(4),
+            None,
+        )?;
+        assert_eq!(report.offset, data_offset);
+        assert_eq!(report.length, 64 * 1024 * 1024 - data_offset);
+        assert_eq!(report.resumed_from, 0);
+        assert!(report.verified_blocks > 0);
+
+        let mut dev = CryptInit::init(dev_path)?;
+        dev.context_handle()
+            .load::<()>(EncryptionFormat::Luks2, None)
+            .map(|_| ())
+    })
+    .expect("Should succeed");
+}
@@ -0,0 +1,362 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{convert::TryInto, marker::PhantomData, os::raw::c_int, path::Path};
+
+use either::Either;
+use uuid::Uuid;
+
+use crate::{
+    activate::{CryptActivateFlags, CryptActivation, CryptDeactivateFlags},
+    backup::CryptBackup,
+    device::{CryptDevice, CryptInit},
+    err::LibcryptErr,
+    format::{CryptParamsLuks2, CryptParamsLuks2Ref, EncryptionFormat},
+    key::CryptVolumeKey,
+    keyfile::CryptKeyfile,
+    keyslot::CryptKeyslot,
+    luks2_flags::CryptLuks2Flags,
+    luks2_reencrypt::CryptLuks2Reencrypt,
+    luks2_token::CryptLuks2Token,
+    reencrypt_resume::CryptReencryptResume,
+    runtime::CryptRuntime,
+    settings::CryptSettings,
+    status::{CryptDeviceStatus, CryptStatusInfo},
+    wipe::CryptWipe,
+};
+
+/// State of a device that has been initialized but has no header loaded
+pub struct Initialized;
+
+/// Format of a device with a loaded LUKS1 header
+pub struct Luks1;
+
+/// Format of a device with a loaded LUKS2 header
+pub struct Luks2;
+
+/// Trait implemented by the LUKS formats a `Device` can be typed by
+pub trait LuksFormat {
+    /// Get the `EncryptionFormat` corresponding to this type
+    fn encryption_format() -> EncryptionFormat;
+}
+
+impl LuksFormat for Luks1 {
+    fn encryption_format() -> EncryptionFormat {
+        EncryptionFormat::Luks1
+    }
+}
+
+impl LuksFormat for Luks2 {
+    fn encryption_format() -> EncryptionFormat {
+        EncryptionFormat::Luks2
+    }
+}
+
+/// Crypt device typed by its state and header format
+///
+/// A `Device<Initialized>` only allows formatting and loading a header. Formatting or
+/// loading consumes it and returns a `Device<Luks1>` or `Device<Luks2>` exposing the
+/// operations valid for that format, so that keyslot and token operations cannot be
+/// attempted on a device without a header and LUKS2-only operations cannot be
+/// attempted on a LUKS1 device. The untyped `CryptDevice` remains reachable through
+/// `as_untyped` for anything not covered here.
+pub struct Device<S> {
+    device: CryptDevice,
+    state: PhantomData<S>,
+}
+
+impl<S> Device<S> {
+    fn new(device: CryptDevice) -> Self {
+        Device {
+            device,
+            state: PhantomData,
+        }
+    }
+
+    /// Get the untyped crypt device
+    pub fn as_untyped(&mut self) -> &mut CryptDevice {
+        &mut self.device
+    }
+
+    /// Convert into the untyped crypt device
+    pub fn into_untyped(self) -> CryptDevice {
+        self.device
+    }
+
+    /// Get a settings option handle
+    pub fn settings_handle(&mut self) -> CryptSettings {
+        self.device.settings_handle()
+    }
+
+    /// Get crypt device status option handle
+    pub fn status_handle(&mut self) -> CryptDeviceStatus {
+        self.device.status_handle()
+    }
+}
+
+impl Device<Initialized> {
+    /// Initialize by device path
+    pub fn init(device_path: &Path) -> Result<Self, LibcryptErr> {
+        CryptInit::init(device_path).map(Device::new)
+    }
+
+    /// Initialize by device path or a header path and a data device path
+    pub fn init_with_data_device(
+        device_paths: Either<&Path, (&Path, &Path)>,
+    ) -> Result<Self, LibcryptErr> {
+        CryptInit::init_with_data_device(device_paths).map(Device::new)
+    }
+
+    /// Format the device as LUKS1
+    ///
+    /// For `volume_key parameter`, either the volume key or the desired length of the
+    /// generated volume key can be specified, not both at once
+    pub fn format_luks1(
+        mut self,
+        cipher_and_mode: (&str, &str),
+        uuid: Option<Uuid>,
+        volume_key: Either<&[u8], usize>,
+    ) -> Result<Device<Luks1>, LibcryptErr> {
+        self.device.context_handle().format::<()>(
+            EncryptionFormat::Luks1,
+            cipher_and_mode,
+            uuid,
+            volume_key,
+            None,
+        )?;
+        Ok(Device::new(self.device))
+    }
+
+    /// Format the device as LUKS2
+    ///
+    /// For `volume_key parameter`, either the volume key or the desired length of the
+    /// generated volume key can be specified, not both at once
+    pub fn format_luks2(
+        mut self,
+        cipher_and_mode: (&str, &str),
+        uuid: Option<Uuid>,
+        volume_key: Either<&[u8], usize>,
+        params: Option<&CryptParamsLuks2>,
+    ) -> Result<Device<Luks2>, LibcryptErr> {
+        let mut params_ref: Option<CryptParamsLuks2Ref<'_>> = match params {
+            Some(p) => Some(p.try_into()?),
+            None => None,
+        };
+        self.device
+            .context_handle()
+            .format::<libcryptsetup_rs_sys::crypt_params_luks2>(
+                EncryptionFormat::Luks2,
+                cipher_and_mode,
+                uuid,
+                volume_key,
+                params_ref.as_mut().map(|p| &mut p.inner),
+            )?;
+        Ok(Device::new(self.device))
+    }
+
+    /// Load a LUKS1 header
+    pub fn load_luks1(self) -> Result<Device<Luks1>, LibcryptErr> {
+        self.load_format::<Luks1>()
+    }
+
+    /// Load a LUKS2 header
+    pub fn load_luks2(self) -> Result<Device<Luks2>, LibcryptErr> {
+        self.load_format::<Luks2>()
+    }
+
+    /// Load a LUKS header of either version
+    pub fn load(mut self) -> Result<Either<Device<Luks1>, Device<Luks2>>, LibcryptErr> {
+        self.device
+            .context_handle()
+            .load::<()>(EncryptionFormat::Luks2, None)
+            .map(|_| ())
+            .or_else(|_| {
+                self.device
+                    .context_handle()
+                    .load::<()>(EncryptionFormat::Luks1, None)
+                    .map(|_| ())
+            })?;
+        match self.device.format_handle().get_type()? {
+            EncryptionFormat::Luks1 => Ok(Either::Left(Device::new(self.device))),
+            EncryptionFormat::Luks2 => Ok(Either::Right(Device::new(self.device))),
+            _ => Err(LibcryptErr::InvalidConversion),
+        }
+    }
+
+    fn load_format<F: LuksFormat>(mut self) -> Result<Device<F>, LibcryptErr> {
+        self.device
+            .context_handle()
+            .load::<()>(F::encryption_format(), None)?;
+        Ok(Device::new(self.device))
+    }
+}
+
+impl<F: LuksFormat> Device<F> {
+    /// Get a keyslot option handle
+    pub fn keyslot_handle(&mut self, keyslot: Option<c_int>) -> CryptKeyslot {
+        self.device.keyslot_handle(keyslot)
+    }
+
+    /// Get activation option handle
+    pub fn activate_handle(&mut self) -> CryptActivation {
+        self.device.activate_handle()
+    }
+
+    /// Get volume key option handle
+    pub fn volume_key_handle(&mut self) -> CryptVolumeKey {
+        self.device.volume_key_handle()
+    }
+
+    /// Get crypt device backup option handle
+    pub fn backup_handle(&mut self) -> CryptBackup {
+        self.device.backup_handle()
+    }
+
+    /// Get crypt device keyfile option handle
+    pub fn keyfile_handle(&mut self) -> CryptKeyfile {
+        self.device.keyfile_handle()
+    }
+
+    /// Get crypt device wipe option handle
+    pub fn wipe_handle(&mut self) -> CryptWipe {
+        self.device.wipe_handle()
+    }
+
+    /// Check a passphrase without activating the device, returning the keyslot it opens
+    pub fn check_passphrase(
+        &mut self,
+        keyslot: Option<c_int>,
+        passphrase: &[u8],
+    ) -> Result<c_int, LibcryptErr> {
+        self.device.activate_handle().activate_by_passphrase(
+            None,
+            keyslot,
+            passphrase,
+            CryptActivateFlags::empty(),
+        )
+    }
+
+    /// Activate the device by passphrase
+    pub fn activate_by_passphrase(
+        &mut self,
+        name: &str,
+        keyslot: Option<c_int>,
+        passphrase: &[u8],
+        flags: CryptActivateFlags,
+    ) -> Result<ActiveMapping<F>, LibcryptErr> {
+        self.device.activate_handle().activate_by_passphrase(
+            Some(name),
+            keyslot,
+            passphrase,
+            flags,
+        )?;
+        Ok(ActiveMapping {
+            device: self,
+            name: name.to_string(),
+        })
+    }
+
+    /// Get a handle for an existing mapping of this device
+    pub fn active_mapping(&mut self, name: &str) -> Result<ActiveMapping<F>, LibcryptErr> {
+        match self.device.status_handle().status(name)? {
+            CryptStatusInfo::Active | CryptStatusInfo::Busy => Ok(ActiveMapping {
+                device: self,
+                name: name.to_string(),
+            }),
+            _ => Err(LibcryptErr::Other(format!("Device {} is not active", name))),
+        }
+    }
+}
+
+impl Device<Luks2> {
+    /// Get crypt device LUKS2 token option handle
+    pub fn token_handle(&mut self, token: c_int) -> CryptLuks2Token {
+        self.device.token_handle(token)
+    }
+
+    /// Get LUKS2 flags option handle
+    pub fn luks2_flag_handle<T>(&mut self) -> CryptLuks2Flags<T> {
+        self.device.luks2_flag_handle()
+    }
+
+    /// Get crypt device reencryption option handle
+    pub fn reencrypt_handle(&mut self) -> CryptLuks2Reencrypt {
+        self.device.reencrypt_handle()
+    }
+
+    /// Get crypt device reencryption recovery and resume option handle
+    pub fn reencrypt_resume_handle(&mut self) -> CryptReencryptResume {
+        self.device.reencrypt_resume_handle()
+    }
+
+    /// Set LUKS2 device label and subsystem
+    pub fn set_label(
+        &mut self,
+        label: Option<&str>,
+        subsystem_label: Option<&str>,
+    ) -> Result<(), LibcryptErr> {
+        self.device
+            .context_handle()
+            .set_label(label, subsystem_label)
+    }
+}
+
+/// Handle for an active mapping of a typed device
+///
+/// The mapping is not deactivated when the handle is dropped.
+pub struct ActiveMapping<'a, F> {
+    device: &'a mut Device<F>,
+    name: String,
+}
+
+impl<'a, F: LuksFormat> ActiveMapping<'a, F> {
+    /// Get the name of the mapping
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the status of the mapping
+    pub fn status(&mut self) -> Result<CryptStatusInfo, LibcryptErr> {
+        self.device.device.status_handle().status(&self.name)
+    }
+
+    /// Get a runtime attribute option handle for the mapping
+    pub fn runtime_handle(&mut self) -> CryptRuntime {
+        self.device.device.runtime_handle(&self.name)
+    }
+
+    /// Resize the mapping to `new_size` sectors, or to the full device if `new_size` is 0
+    pub fn resize(&mut self, new_size: u64) -> Result<(), LibcryptErr> {
+        self.device
+            .device
+            .context_handle()
+            .resize(&self.name, new_size)
+    }
+
+    /// Suspend the mapping
+    pub fn suspend(&mut self) -> Result<(), LibcryptErr> {
+        self.device.device.context_handle().suspend(&self.name)
+    }
+
+    /// Resume the suspended mapping using a passphrase
+    pub fn resume_by_passphrase(
+        &mut self,
+        keyslot: Option<c_int>,
+        passphrase: &str,
+    ) -> Result<c_int, LibcryptErr> {
+        self.device.device.context_handle().resume_by_passphrase(
+            &self.name,
+            keyslot.unwrap_or(libcryptsetup_rs_sys::CRYPT_ANY_SLOT),
+            passphrase,
+        )
+    }
+
+    /// Deactivate the mapping
+    pub fn deactivate(self, flags: CryptDeactivateFlags) -> Result<(), LibcryptErr> {
+        self.device
+            .device
+            .activate_handle()
+            .deactivate(&self.name, flags)
+    }
+}
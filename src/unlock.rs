@@ -0,0 +1,399 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    convert::TryFrom,
+    fmt::{self, Display},
+    io,
+    os::raw::c_int,
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    activate::CryptActivateFlags,
+    device::CryptDevice,
+    err::LibcryptErr,
+    luks2_token::{CryptTokenInfo, LUKS2_TOKENS_MAX},
+    prompt::PassphrasePrompt,
+};
+
+/// Interval between attempts of a strategy waiting for its credential to appear
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Mechanism used to unlock a device
+#[derive(Clone, Debug, PartialEq)]
+pub enum UnlockStrategy {
+    /// Activate with a token through its registered handler, or with every active token
+    /// in order if `None`
+    Token(Option<c_int>),
+    /// Activate with a passphrase stored in the kernel keyring under the given description
+    Keyring(String),
+    /// Activate with a keyfile or part of it
+    Keyfile {
+        /// Path to the keyfile
+        path: PathBuf,
+        /// Number of bytes to read, the whole keyfile if `None`
+        size: Option<crate::size_t>,
+        /// Offset in bytes at which to start reading
+        offset: u64,
+    },
+    /// Prompt for a passphrase with `PassphrasePrompt`
+    Prompt {
+        /// Prompt message
+        message: String,
+        /// Number of passphrase attempts
+        tries: u32,
+    },
+}
+
+impl Display for UnlockStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UnlockStrategy::Token(Some(token)) => write!(f, "token {}", token),
+            UnlockStrategy::Token(None) => write!(f, "tokens"),
+            UnlockStrategy::Keyring(ref description) => {
+                write!(f, "keyring key {}", description)
+            }
+            UnlockStrategy::Keyfile { ref path, .. } => write!(f, "keyfile {}", path.display()),
+            UnlockStrategy::Prompt { .. } => write!(f, "passphrase prompt"),
+        }
+    }
+}
+
+/// Failed attempt of a strategy
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnlockFailure {
+    /// Strategy that was attempted, with tokens tried individually
+    pub strategy: UnlockStrategy,
+    /// Description of the error
+    pub error: String,
+}
+
+impl Display for UnlockFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.strategy, self.error)
+    }
+}
+
+/// Outcome of a successful unlock
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnlockReport {
+    /// Strategy that unlocked the device
+    pub strategy: UnlockStrategy,
+    /// Keyslot that was unlocked
+    pub keyslot: c_int,
+    /// Token that unlocked the keyslot, if any
+    pub token: Option<c_int>,
+    /// Strategies that failed before, in the order they were attempted
+    pub failures: Vec<UnlockFailure>,
+}
+
+/// Whether an attempt failed because its credential is not available yet, for example
+/// because the removable media holding a keyfile is not inserted
+fn is_unavailable(err: &LibcryptErr) -> bool {
+    match *err {
+        LibcryptErr::IOError(ref e) => matches!(
+            e.raw_os_error(),
+            Some(libc::ENOENT) | Some(libc::EAGAIN) | Some(libc::ENODEV)
+        ),
+        _ => false,
+    }
+}
+
+/// Whether a token with this status has a handler libcryptsetup can open it with
+fn is_handled(status: &CryptTokenInfo) -> bool {
+    matches!(*status, CryptTokenInfo::Internal | CryptTokenInfo::External)
+}
+
+/// Repeat `attempt` while its credential is unavailable and `deadline` has not passed
+fn attempt_until<T, F>(deadline: Option<Instant>, mut attempt: F) -> Result<T, LibcryptErr>
+where
+    F: FnMut() -> Result<T, LibcryptErr>,
+{
+    loop {
+        match attempt() {
+            Err(ref e)
+                if is_unavailable(e)
+                    && deadline.map_or(false, |d| Instant::now() + RETRY_INTERVAL < d) =>
+            {
+                thread::sleep(RETRY_INTERVAL)
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Ordered list of strategies tried until one unlocks the device
+///
+/// Each strategy may have a timeout. Prompts fail if no passphrase is entered in time.
+/// Other strategies are retried until the timeout expires while their credential is
+/// unavailable, for example while a keyfile does not exist or a token handler reports
+/// `ENOENT`. A credential that is present but rejected fails the strategy immediately, as
+/// does a token without a registered handler. Tokens without a handler are skipped when
+/// trying every token.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UnlockPipeline {
+    strategies: Vec<(UnlockStrategy, Option<Duration>)>,
+    keyslot: Option<c_int>,
+    flags: u32,
+}
+
+impl UnlockPipeline {
+    /// Create an empty pipeline
+    pub fn new() -> Self {
+        UnlockPipeline::default()
+    }
+
+    /// Append a strategy with an optional timeout
+    pub fn strategy(&mut self, strategy: UnlockStrategy, timeout: Option<Duration>) -> &mut Self {
+        self.strategies.push((strategy, timeout));
+        self
+    }
+
+    /// Restrict keyring, keyfile and prompt strategies to one keyslot
+    pub fn keyslot(&mut self, keyslot: Option<c_int>) -> &mut Self {
+        self.keyslot = keyslot;
+        self
+    }
+
+    /// Set the flags used for activation
+    pub fn flags(&mut self, flags: CryptActivateFlags) -> &mut Self {
+        self.flags = flags.into();
+        self
+    }
+
+    /// Try the strategies in order until one activates the device as `name`
+    ///
+    /// Passing `None` as `name` only checks which strategy can unlock the device, without
+    /// activating it. Fails with an error listing every failed attempt if no strategy
+    /// succeeds.
+    pub fn unlock(
+        &self,
+        device: &mut CryptDevice,
+        name: Option<&str>,
+    ) -> Result<UnlockReport, LibcryptErr> {
+        let mut failures = Vec::new();
+        for (strategy, timeout) in self.strategies.iter() {
+            let deadline = timeout.map(|t| Instant::now() + t);
+            let attempts = match *strategy {
+                UnlockStrategy::Token(None) => match Self::handled_tokens(device) {
+                    Ok(ref tokens) if tokens.is_empty() => {
+                        failures.push(UnlockFailure {
+                            strategy: strategy.clone(),
+                            error: "Device has no tokens with a registered handler".to_string(),
+                        });
+                        continue;
+                    }
+                    Ok(tokens) => tokens
+                        .into_iter()
+                        .map(|t| UnlockStrategy::Token(Some(t)))
+                        .collect(),
+                    Err(e) => {
+                        failures.push(UnlockFailure {
+                            strategy: strategy.clone(),
+                            error: e.to_string(),
+                        });
+                        continue;
+                    }
+                },
+                _ => vec![strategy.clone()],
+            };
+            for attempt in attempts {
+                match self.attempt(device, name, &attempt, *timeout, deadline) {
+                    Ok(keyslot) => {
+                        let token = match attempt {
+                            UnlockStrategy::Token(token) => token,
+                            _ => None,
+                        };
+                        return Ok(UnlockReport {
+                            strategy: attempt,
+                            keyslot,
+                            token,
+                            failures,
+                        });
+                    }
+                    Err(e) => failures.push(UnlockFailure {
+                        strategy: attempt,
+                        error: e.to_string(),
+                    }),
+                }
+            }
+        }
+        Err(LibcryptErr::Other(if failures.is_empty() {
+            "No unlock strategies configured".to_string()
+        } else {
+            format!(
+                "No unlock strategy succeeded: {}",
+                failures
+                    .iter()
+                    .map(|f| f.to_string())
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            )
+        }))
+    }
+
+    /// Check which strategy can unlock the device without activating it
+    pub fn check(&self, device: &mut CryptDevice) -> Result<UnlockReport, LibcryptErr> {
+        self.unlock(device, None)
+    }
+
+    /// Tokens that libcryptsetup can open through a registered handler
+    fn handled_tokens(device: &mut CryptDevice) -> Result<Vec<c_int>, LibcryptErr> {
+        let mut tokens = Vec::new();
+        for token in 0..LUKS2_TOKENS_MAX {
+            if is_handled(&device.token_handle(token).status()?.0) {
+                tokens.push(token);
+            }
+        }
+        Ok(tokens)
+    }
+
+    fn attempt(
+        &self,
+        device: &mut CryptDevice,
+        name: Option<&str>,
+        strategy: &UnlockStrategy,
+        timeout: Option<Duration>,
+        deadline: Option<Instant>,
+    ) -> Result<c_int, LibcryptErr> {
+        let flags = self.flags;
+        match *strategy {
+            UnlockStrategy::Token(token) => {
+                let token = token.unwrap_or(libcryptsetup_rs_sys::CRYPT_ANY_TOKEN);
+                if token != libcryptsetup_rs_sys::CRYPT_ANY_TOKEN {
+                    // libcryptsetup reports a missing handler as ENOENT, which would
+                    // otherwise be retried until the timeout
+                    let (status, type_) = device.token_handle(token).status()?;
+                    if !is_handled(&status) {
+                        return Err(LibcryptErr::Other(match status {
+                            CryptTokenInfo::Invalid | CryptTokenInfo::Inactive => {
+                                "Token is not active".to_string()
+                            }
+                            _ => format!("No handler is registered for token type {}", type_),
+                        }));
+                    }
+                }
+                attempt_until(deadline, || {
+                    device.token_handle(token).activate_by_token::<()>(
+                        name,
+                        token,
+                        None,
+                        CryptActivateFlags::try_from(flags)?,
+                    )
+                })
+            }
+            UnlockStrategy::Keyring(ref description) => attempt_until(deadline, || {
+                device.activate_handle().activate_by_keyring(
+                    name,
+                    description,
+                    self.keyslot,
+                    CryptActivateFlags::try_from(flags)?,
+                )
+            }),
+            UnlockStrategy::Keyfile {
+                ref path,
+                size,
+                offset,
+            } => attempt_until(deadline, || {
+                // libcryptsetup reports a missing keyfile as EINVAL
+                if !path.exists() {
+                    return Err(LibcryptErr::IOError(io::Error::from_raw_os_error(
+                        libc::ENOENT,
+                    )));
+                }
+                device.activate_handle().activate_by_keyfile_device_offset(
+                    name,
+                    self.keyslot,
+                    path,
+                    size,
+                    offset,
+                    CryptActivateFlags::try_from(flags)?,
+                )
+            }),
+            UnlockStrategy::Prompt { ref message, tries } => {
+                let mut prompt = PassphrasePrompt::new(message);
+                prompt.tries(tries).timeout(timeout);
+                prompt.activate(
+                    device,
+                    name,
+                    self.keyslot,
+                    CryptActivateFlags::try_from(flags)?,
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_strategy_display() {
+        assert_eq!(UnlockStrategy::Token(Some(2)).to_string(), "token 2");
+        assert_eq!(UnlockStrategy::Token(None).to_string(), "tokens");
+        assert_eq!(
+            UnlockStrategy::Keyring("cryptsetup:root".to_string()).to_string(),
+            "keyring key cryptsetup:root"
+        );
+        assert_eq!(
+            UnlockFailure {
+                strategy: UnlockStrategy::Keyfile {
+                    path: PathBuf::from("/media/usb/key"),
+                    size: None,
+                    offset: 0,
+                },
+                error: "missing".to_string(),
+            }
+            .to_string(),
+            "keyfile /media/usb/key: missing"
+        );
+    }
+
+    #[test]
+    fn test_attempt_until() {
+        let unavailable = || LibcryptErr::IOError(std::io::Error::from_raw_os_error(libc::ENOENT));
+        let rejected = || LibcryptErr::IOError(std::io::Error::from_raw_os_error(libc::EPERM));
+
+        let mut attempts = 0;
+        let result = attempt_until(Some(Instant::now() + Duration::from_secs(5)), || {
+            attempts += 1;
+            if attempts < 3 {
+                Err(unavailable())
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let mut attempts = 0;
+        let result: Result<(), _> = attempt_until(None, || {
+            attempts += 1;
+            Err(unavailable())
+        });
+        assert!(is_unavailable(&result.unwrap_err()));
+        assert_eq!(attempts, 1);
+
+        let mut attempts = 0;
+        let start = Instant::now();
+        let result: Result<(), _> = attempt_until(Some(start + Duration::from_millis(350)), || {
+            attempts += 1;
+            Err(unavailable())
+        });
+        assert!(result.is_err());
+        assert!(attempts > 1 && start.elapsed() < Duration::from_millis(350));
+
+        let mut attempts = 0;
+        let result: Result<(), _> =
+            attempt_until(Some(Instant::now() + Duration::from_secs(5)), || {
+                attempts += 1;
+                Err(rejected())
+            });
+        assert!(!is_unavailable(&result.unwrap_err()));
+        assert_eq!(attempts, 1);
+    }
+}
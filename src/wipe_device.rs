@@ -0,0 +1,398 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    device::{device_size, CryptDevice, CryptInit},
+    err::LibcryptErr,
+    format::EncryptionFormat,
+    wipe::CryptWipePattern,
+};
+
+/// Default size of the range wiped between two checkpoints
+pub const DEFAULT_CHECKPOINT_INTERVAL: u64 = 64 * 1024 * 1024;
+/// Block size used for wiping and verification
+const WIPE_BLOCK_SIZE: u64 = 1024 * 1024;
+
+/// Target of a whole-device wipe
+pub enum WipeTarget<'a> {
+    /// Data segment of the LUKS device whose header is on the given path, wiped directly
+    DataSegment(&'a Path),
+    /// Active dm-crypt mapping with the given name, so that the wipe pattern is encrypted
+    /// before it reaches the disk
+    Mapping(&'a str),
+}
+
+/// Read-back verification performed after each wiped range
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WipeVerification {
+    /// Do not verify
+    None,
+    /// Verify every n-th block
+    Sampled(u64),
+    /// Verify every block
+    Full,
+}
+
+/// Progress of a running wipe
+#[derive(Clone, Debug)]
+pub struct WipeProgress {
+    /// Bytes wiped so far, including bytes wiped before resuming
+    pub completed: u64,
+    /// Total number of bytes to wipe
+    pub total: u64,
+    /// Throughput in bytes per second since the wipe was started or resumed
+    pub bytes_per_second: f64,
+    /// Estimated time until the wipe is complete
+    pub eta: Option<Duration>,
+}
+
+/// Result of a completed wipe
+#[derive(Clone, Debug)]
+pub struct WipeReport {
+    /// Path of the wiped device
+    pub device: PathBuf,
+    /// Offset of the wiped range in bytes
+    pub offset: u64,
+    /// Length of the wiped range in bytes
+    pub length: u64,
+    /// Number of bytes already wiped when the wipe was resumed from a checkpoint
+    pub resumed_from: u64,
+    /// Number of blocks read back and verified
+    pub verified_blocks: u64,
+    /// Time spent wiping in this run
+    pub elapsed: Duration,
+}
+
+/// Persistent state of an interrupted wipe
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WipeCheckpoint {
+    /// Path of the device being wiped
+    pub device: PathBuf,
+    /// Offset of the wiped range in bytes
+    pub offset: u64,
+    /// Length of the wiped range in bytes
+    pub length: u64,
+    /// Wipe pattern as `crypt_wipe_pattern` value
+    pub pattern: u32,
+    /// Number of bytes of the range that have been wiped
+    pub completed: u64,
+}
+
+impl WipeCheckpoint {
+    /// Read a checkpoint file
+    pub fn read(path: &Path) -> Result<Self, LibcryptErr> {
+        let contents = fs::read(path).map_err(LibcryptErr::IOError)?;
+        serde_json::from_slice(&contents)
+            .map_err(|e| LibcryptErr::Other(format!("Invalid wipe checkpoint: {}", e)))
+    }
+
+    /// Atomically write a checkpoint file
+    pub fn write(&self, path: &Path) -> Result<(), LibcryptErr> {
+        let contents = serde_json::to_vec(self)
+            .map_err(|e| LibcryptErr::Other(format!("Invalid wipe checkpoint: {}", e)))?;
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        File::create(&tmp_path)
+            .and_then(|mut f| f.write_all(&contents).and_then(|_| f.sync_all()))
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .map_err(LibcryptErr::IOError)
+    }
+}
+
+/// Handle for wiping whole devices
+pub struct CryptDeviceWipe;
+
+impl CryptDeviceWipe {
+    /// Wipe the whole data area of `target` with `pattern`
+    ///
+    /// The range is wiped in chunks of `checkpoint_interval` bytes and the progress is
+    /// recorded in `checkpoint_file` after every chunk. If `checkpoint_file` exists when
+    /// the wipe is started, the wipe resumes after the last recorded chunk; the checkpoint
+    /// must describe the same device, range and pattern. The checkpoint file is removed
+    /// once the wipe is complete.
+    ///
+    /// Verification reads back the wiped blocks and is only supported for
+    /// `CryptWipePattern::Zero`. When wiping through a mapping, the zeros are verified
+    /// through the mapping while the disk receives ciphertext.
+    ///
+    /// `progress` is called after every chunk; returning `false` interrupts the wipe,
+    /// leaving the checkpoint in place for a later resume.
+    pub fn wipe(
+        target: WipeTarget,
+        pattern: CryptWipePattern,
+        checkpoint_file: &Path,
+        checkpoint_interval: u64,
+        verification: WipeVerification,
+        mut progress: Option<&mut dyn FnMut(&WipeProgress) -> bool>,
+    ) -> Result<WipeReport, LibcryptErr> {
+        match (pattern, verification) {
+            (_, WipeVerification::None) | (CryptWipePattern::Zero, _) => (),
+            _ => {
+                return Err(LibcryptErr::Other(
+                    "Only zero wipes can be verified".to_string(),
+                ))
+            }
+        }
+        if let WipeVerification::Sampled(0) = verification {
+            return Err(LibcryptErr::InvalidConversion);
+        }
+        if checkpoint_interval == 0 || checkpoint_interval % WIPE_BLOCK_SIZE != 0 {
+            return Err(LibcryptErr::Other(format!(
+                "Checkpoint interval must be a non-zero multiple of {}",
+                WIPE_BLOCK_SIZE
+            )));
+        }
+
+        let pattern_value: u32 = pattern.into();
+        let (mut device, device_path, offset, length) = open_target(target)?;
+        let checkpoint = if checkpoint_file.exists() {
+            let checkpoint = WipeCheckpoint::read(checkpoint_file)?;
+            if checkpoint.device != device_path
+                || checkpoint.offset != offset
+                || checkpoint.length != length
+                || checkpoint.pattern != pattern_value
+                || checkpoint.completed > length
+            {
+                return Err(LibcryptErr::Other(format!(
+                    "Checkpoint {} does not match the wipe being started",
+                    checkpoint_file.display()
+                )));
+            }
+            checkpoint
+        } else {
+            WipeCheckpoint {
+                device: device_path.clone(),
+                offset,
+                length,
+                pattern: pattern_value,
+                completed: 0,
+            }
+        };
+        let resumed_from = checkpoint.completed;
+
+        let start = Instant::now();
+        let mut checkpoint = checkpoint;
+        let mut verified_blocks = 0;
+        while checkpoint.completed < length {
+            let chunk_offset = offset + checkpoint.completed;
+            let chunk_length = std::cmp::min(checkpoint_interval, length - checkpoint.completed);
+            device.wipe_handle().wipe::<()>(
+                &device_path,
+                pattern,
+                chunk_offset,
+                chunk_length,
+                WIPE_BLOCK_SIZE as usize,
+                false,
+                None,
+                &mut (),
+            )?;
+            verified_blocks += verify(
+                &device_path,
+                chunk_offset,
+                chunk_length,
+                checkpoint.completed / WIPE_BLOCK_SIZE,
+                verification,
+            )?;
+            checkpoint.completed += chunk_length;
+            checkpoint.write(checkpoint_file)?;
+
+            if let Some(ref mut callback) = progress {
+                let report = progress_since(&start, resumed_from, checkpoint.completed, length);
+                if !callback(&report) && checkpoint.completed < length {
+                    return Err(LibcryptErr::Other(format!(
+                        "Wipe interrupted at offset {}, resume with checkpoint {}",
+                        offset + checkpoint.completed,
+                        checkpoint_file.display()
+                    )));
+                }
+            }
+        }
+        fs::remove_file(checkpoint_file).map_err(LibcryptErr::IOError)?;
+
+        Ok(WipeReport {
+            device: device_path,
+            offset,
+            length,
+            resumed_from,
+            verified_blocks,
+            elapsed: start.elapsed(),
+        })
+    }
+}
+
+fn open_target(target: WipeTarget) -> Result<(CryptDevice, PathBuf, u64, u64), LibcryptErr> {
+    match target {
+        WipeTarget::DataSegment(path) => {
+            let mut device = CryptInit::init(path)?;
+            if device
+                .context_handle()
+                .load::<()>(EncryptionFormat::Luks2, None)
+                .is_err()
+            {
+                device
+                    .context_handle()
+                    .load::<()>(EncryptionFormat::Luks1, None)?;
+            }
+            let offset = device.status_handle().get_data_offset() * 512;
+            let data_path = device.status_handle().get_device_path()?.to_path_buf();
+            let size = device_size(&data_path)?;
+            if size <= offset {
+                return Err(LibcryptErr::Other(format!(
+                    "Device {} has no data segment",
+                    data_path.display()
+                )));
+            }
+            Ok((device, data_path, offset, size - offset))
+        }
+        WipeTarget::Mapping(name) => {
+            let mapping_path = PathBuf::from("/dev/mapper").join(name);
+            let device = CryptInit::init(&mapping_path)?;
+            let size = device_size(&mapping_path)?;
+            Ok((device, mapping_path, 0, size))
+        }
+    }
+}
+
+fn progress_since(start: &Instant, resumed_from: u64, completed: u64, total: u64) -> WipeProgress {
+    let elapsed = start.elapsed();
+    let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+    let bytes_per_second = if seconds > 0.0 {
+        (completed - resumed_from) as f64 / seconds
+    } else {
+        0.0
+    };
+    let eta = if bytes_per_second > 0.0 {
+        Some(Duration::from_secs(
+            ((total - completed) as f64 / bytes_per_second).ceil() as u64,
+        ))
+    } else {
+        None
+    };
+    WipeProgress {
+        completed,
+        total,
+        bytes_per_second,
+        eta,
+    }
+}
+
+/// Verify the blocks of a wiped chunk selected by `verification`, returning the number
+/// of verified blocks
+///
+/// `first_block` is the index of the first block of the chunk within the wiped range,
+/// so that sampling is independent of where a wipe was resumed.
+fn verify(
+    device_path: &Path,
+    offset: u64,
+    length: u64,
+    first_block: u64,
+    verification: WipeVerification,
+) -> Result<u64, LibcryptErr> {
+    let every = match verification {
+        WipeVerification::None => return Ok(0),
+        WipeVerification::Sampled(n) => n,
+        WipeVerification::Full => 1,
+    };
+    let mut file = File::open(device_path).map_err(LibcryptErr::IOError)?;
+    let mut buffer = vec![0u8; WIPE_BLOCK_SIZE as usize];
+    let mut verified = 0;
+    for block in sampled_blocks(offset, length, first_block, every) {
+        let (block_offset, block_length) = block;
+        read_block(
+            &mut file,
+            block_offset,
+            &mut buffer[..block_length as usize],
+        )
+        .map_err(LibcryptErr::IOError)?;
+        if buffer[..block_length as usize].iter().any(|b| *b != 0) {
+            return Err(LibcryptErr::Other(format!(
+                "Verification failed for block at offset {}",
+                block_offset
+            )));
+        }
+        verified += 1;
+    }
+    Ok(verified)
+}
+
+/// Get `(offset, length)` of every `every`-th block of a chunk
+fn sampled_blocks(offset: u64, length: u64, first_block: u64, every: u64) -> Vec<(u64, u64)> {
+    let blocks = (length + WIPE_BLOCK_SIZE - 1) / WIPE_BLOCK_SIZE;
+    (0..blocks)
+        .filter(|i| (first_block + i) % every == 0)
+        .map(|i| {
+            let block_offset = i * WIPE_BLOCK_SIZE;
+            (
+                offset + block_offset,
+                std::cmp::min(WIPE_BLOCK_SIZE, length - block_offset),
+            )
+        })
+        .collect()
+}
+
+fn read_block(file: &mut File, offset: u64, buffer: &mut [u8]) -> Result<(), io::Error> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buffer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sampled_blocks() {
+        let mb = WIPE_BLOCK_SIZE;
+        assert_eq!(
+            sampled_blocks(4096, 3 * mb, 0, 1),
+            vec![(4096, mb), (4096 + mb, mb), (4096 + 2 * mb, mb)]
+        );
+        assert_eq!(
+            sampled_blocks(0, 5 * mb + 512, 0, 2),
+            vec![(0, mb), (2 * mb, mb), (4 * mb, mb)]
+        );
+        assert_eq!(sampled_blocks(0, 2 * mb + 512, 1, 2), vec![(mb, mb)]);
+        assert_eq!(
+            sampled_blocks(0, 2 * mb + 512, 0, 2),
+            vec![(0, mb), (2 * mb, 512)]
+        );
+    }
+
+    #[test]
+    fn test_progress_eta() {
+        let start = Instant::now() - Duration::from_secs(10);
+        let progress = progress_since(&start, 100, 1100, 2100);
+        assert!(progress.bytes_per_second > 90.0 && progress.bytes_per_second <= 100.0);
+        let eta = progress.eta.unwrap().as_secs();
+        assert!((10..=12).contains(&eta));
+    }
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let path = std::env::temp_dir().join(format!(
+            "libcryptsetup-rs-wipe-checkpoint-{}-{:x}",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        let checkpoint = WipeCheckpoint {
+            device: PathBuf::from("/dev/loop0"),
+            offset: 16 * 1024 * 1024,
+            length: 48 * 1024 * 1024,
+            pattern: CryptWipePattern::Zero.into(),
+            completed: 32 * 1024 * 1024,
+        };
+        checkpoint.write(&path).unwrap();
+        assert_eq!(WipeCheckpoint::read(&path).unwrap(), checkpoint);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Tests running the `cryptsetup-rs` binary against loop devices
+//!
+//! These tests must be run as root with `cargo test --features test-utils -- --ignored`.
+
+use std::{
+    fs::{remove_file, write},
+    path::{Path, PathBuf},
+    process::{self, Command},
+};
+
+use libcryptsetup_rs::{
+    test_utils::{LoopDeviceOptions, TestLoopDevice},
+    EncryptionFormat,
+};
+
+const PASSPHRASE: &[u8] = b"abadpassphrase";
+
+/// Key file removed on drop
+struct KeyFile(PathBuf);
+
+impl KeyFile {
+    fn new(name: &str) -> Self {
+        let path = LoopDeviceOptions::default().directory.join(format!(
+            "cli-{}-{}.key",
+            name,
+            process::id()
+        ));
+        write(&path, PASSPHRASE).expect("key file must be writable");
+        KeyFile(path)
+    }
+}
+
+impl Drop for KeyFile {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.0);
+    }
+}
+
+/// Run the binary and return its standard output, panicking if it fails
+fn cryptsetup(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_cryptsetup-rs"))
+        .args(args)
+        .output()
+        .expect("cryptsetup-rs must be executable");
+    assert!(
+        output.status.success(),
+        "cryptsetup-rs {} failed: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn path_str(path: &Path) -> &str {
+    path.to_str().expect("test paths are valid UTF-8")
+}
+
+fn volume_key_size(device: &TestLoopDevice) -> usize {
+    let mut dev = device.init().unwrap();
+    dev.context_handle()
+        .load::<()>(EncryptionFormat::Luks2, None)
+        .unwrap();
+    dev.status_handle().get_volume_key_size() as usize
+}
+
+#[ignore]
+#[test]
+fn test_cli_format_open_close() {
+    let mut device = TestLoopDevice::new(&LoopDeviceOptions::default()).unwrap();
+    let key_file = KeyFile::new("open");
+    let dev_path = path_str(device.path()).to_string();
+    let key_path = path_str(&key_file.0).to_string();
+
+    let output = cryptsetup(&["luksFormat", "-q", "--key-file", &key_path, &dev_path]);
+    assert!(output.contains("Key slot 0 created."));
+
+    device.track_mapping("cli-test-open");
+    cryptsetup(&["open", "--key-file", &key_path, &dev_path, "cli-test-open"]);
+    assert!(Path::new("/dev/mapper/cli-test-open").exists());
+    let output = cryptsetup(&["status", "cli-test-open"]);
+    assert!(output.contains("keysize: 512 bits"));
+
+    cryptsetup(&["close", "cli-test-open"]);
+    assert!(!Path::new("/dev/mapper/cli-test-open").exists());
+}
+
+#[ignore]
+#[test]
+fn test_cli_reencrypt() {
+    let device = TestLoopDevice::new(&LoopDeviceOptions::default()).unwrap();
+    let key_file = KeyFile::new("reencrypt");
+    let dev_path = path_str(device.path()).to_string();
+    let key_path = path_str(&key_file.0).to_string();
+
+    cryptsetup(&[
+        "luksFormat",
+        "-q",
+        "--key-size",
+        "256",
+        "--key-file",
+        &key_path,
+        &dev_path,
+    ]);
+    assert_eq!(volume_key_size(&device), 32);
+
+    // Without --key-size the current volume key size is kept
+    cryptsetup(&["reencrypt", "--key-file", &key_path, &dev_path]);
+    assert_eq!(volume_key_size(&device), 32);
+
+    cryptsetup(&[
+        "reencrypt",
+        "--key-size",
+        "512",
+        "--key-file",
+        &key_path,
+        &dev_path,
+    ]);
+    assert_eq!(volume_key_size(&device), 64);
+}
@@ -0,0 +1,165 @@
+#!/usr/bin/env python3
+# This Source Code Form is subject to the terms of the Mozilla Public
+# License, v. 2.0. If a copy of the MPL was not distributed with this
+# file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+"""Generate the VeraCrypt test containers used by the TCRYPT tests.
+
+Each container uses PBKDF2-SHA512 and AES-XTS and has the following layout:
+
+    0x00000  volume header
+    0x10000  hidden volume header, zeros in containers without a hidden volume
+    0x20000  volume data (32 KiB), the hidden volume is its last 8 KiB
+    0x28000  backup header
+    0x38000  hidden volume backup header
+
+All containers are unlocked with PASSPHRASE:
+
+    outer.img    the passphrase only
+    hidden.img   additionally contains a hidden volume unlocked with HIDDEN_PASSPHRASE
+    keyfile.img  additionally requires the keyfile `keyfile`
+    pim.img      additionally requires the PIM
+
+The first sector of each volume starts with its name. The containers were checked to
+load with libcryptsetup 2.x.
+
+Requires the `cryptography` package.
+"""
+
+import hashlib
+import os
+import struct
+import zlib
+
+from cryptography.hazmat.backends import default_backend
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
+
+PASSPHRASE = b"outerpassphrase"
+HIDDEN_PASSPHRASE = b"hiddenpassphrase"
+PIM = 7
+
+SECTOR_SIZE = 512
+HEADER_AREA = 0x10000
+DATA_OFFSET = 2 * HEADER_AREA
+OUTER_SIZE = 32 * 1024
+HIDDEN_SIZE = 8 * 1024
+HIDDEN_OFFSET = DATA_OFFSET + OUTER_SIZE - HIDDEN_SIZE
+IMAGE_SIZE = DATA_OFFSET + OUTER_SIZE + 2 * HEADER_AREA
+KEY_POOL_LEN = 64
+
+
+def xts(key, tweak, data):
+    encryptor = Cipher(
+        algorithms.AES(key), modes.XTS(tweak), backend=default_backend()
+    ).encryptor()
+    return encryptor.update(data) + encryptor.finalize()
+
+
+def crc32_update(crc, byte):
+    # CRC-32 without the final inversion, as used for keyfile pools
+    return (~zlib.crc32(bytes([byte]), ~crc & 0xFFFFFFFF)) & 0xFFFFFFFF
+
+
+def keyfile_pool(passphrase, keyfiles):
+    if not keyfiles:
+        return passphrase
+    pool = bytearray(KEY_POOL_LEN)
+    for keyfile in keyfiles:
+        crc = 0xFFFFFFFF
+        j = 0
+        for byte in keyfile:
+            crc = crc32_update(crc, byte)
+            for shift in (24, 16, 8, 0):
+                pool[j] = (pool[j] + (crc >> shift)) & 0xFF
+                j += 1
+            j %= KEY_POOL_LEN
+    for i, byte in enumerate(passphrase):
+        pool[i] = (pool[i] + byte) & 0xFF
+    return bytes(pool)
+
+
+def header(passphrase, keyfiles, pim, master_key, offset, size, hidden_size):
+    iterations = 15000 + pim * 1000 if pim else 500000
+    salt = os.urandom(64)
+    header_key = hashlib.pbkdf2_hmac(
+        "sha512", keyfile_pool(passphrase, keyfiles), salt, iterations, 64
+    )
+    keys = master_key.ljust(256, b"\0")
+    fields = struct.pack(
+        ">4sHHI16sQQQQII120s",
+        b"VERA",
+        5,
+        0x010B,
+        zlib.crc32(keys),
+        b"",
+        hidden_size,
+        size,
+        offset,
+        size,
+        0,
+        SECTOR_SIZE,
+        b"",
+    )
+    fields += struct.pack(">I", zlib.crc32(fields))
+    return salt + xts(header_key, bytes(16), fields + keys)
+
+
+def encrypt_data(master_key, offset, plaintext):
+    out = b""
+    for i in range(0, len(plaintext), SECTOR_SIZE):
+        tweak = struct.pack("<QQ", (offset + i) // SECTOR_SIZE, 0)
+        out += xts(master_key, tweak, plaintext[i : i + SECTOR_SIZE])
+    return out
+
+
+def container(directory, name, passphrase, keyfiles=(), pim=0, hidden=False):
+    outer_key = os.urandom(64)
+    image = bytearray(IMAGE_SIZE)
+    headers = [
+        (0, header(passphrase, keyfiles, pim, outer_key, DATA_OFFSET, OUTER_SIZE, 0))
+    ]
+    outer_data = b"outer volume".ljust(OUTER_SIZE, b"\0")
+    image[DATA_OFFSET : DATA_OFFSET + OUTER_SIZE] = encrypt_data(
+        outer_key, DATA_OFFSET, outer_data
+    )
+    if hidden:
+        hidden_key = os.urandom(64)
+        headers.append(
+            (
+                HEADER_AREA,
+                header(
+                    HIDDEN_PASSPHRASE,
+                    [],
+                    0,
+                    hidden_key,
+                    HIDDEN_OFFSET,
+                    HIDDEN_SIZE,
+                    HIDDEN_SIZE,
+                ),
+            )
+        )
+        hidden_data = b"hidden volume".ljust(HIDDEN_SIZE, b"\0")
+        image[HIDDEN_OFFSET : HIDDEN_OFFSET + HIDDEN_SIZE] = encrypt_data(
+            hidden_key, HIDDEN_OFFSET, hidden_data
+        )
+    backup = IMAGE_SIZE - 2 * HEADER_AREA
+    for offset, data in headers:
+        image[offset : offset + len(data)] = data
+        image[backup + offset : backup + offset + len(data)] = data
+    with open(os.path.join(directory, name), "wb") as f:
+        f.write(image)
+
+
+def main():
+    directory = os.path.dirname(os.path.abspath(__file__))
+    keyfile = os.urandom(64)
+    with open(os.path.join(directory, "keyfile"), "wb") as f:
+        f.write(keyfile)
+    container(directory, "outer.img", PASSPHRASE)
+    container(directory, "hidden.img", PASSPHRASE, hidden=True)
+    container(directory, "keyfile.img", PASSPHRASE, keyfiles=[keyfile])
+    container(directory, "pim.img", PASSPHRASE, pim=PIM)
+
+
+if __name__ == "__main__":
+    main()